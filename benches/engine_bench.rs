@@ -1,3 +1,6 @@
+// `ParameterizedBenchmark` is deprecated in favour of benchmark groups.
+#![allow(deprecated)]
+
 #[macro_use]
 extern crate criterion;
 
@@ -18,7 +21,7 @@ fn set_bench(c: &mut Criterion) {
                 },
                 |(mut store, _temp_dir)| {
                     for i in 1..(1 << 12) {
-                        store.set(format!("key{}", i), "value".to_string());
+                        store.set(format!("key{}", i), "value".to_string()).unwrap();
                     }
                 },
                 BatchSize::SmallInput,
@@ -30,7 +33,7 @@ fn set_bench(c: &mut Criterion) {
             b.iter_batched(
                 || {
                     let temp_dir = TempDir::new().unwrap();
-                    (SledKvsEngine::open(&temp_dir.path()).unwrap(), temp_dir)
+                    (SledKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
                 },
                 |(mut db, _temp_dir)| {
                     for i in 1..(1 << 12) {
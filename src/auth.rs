@@ -0,0 +1,465 @@
+//! Authentication and per-key-prefix access control for `Server`
+//!
+//! Users are listed in a JSON credentials file kept on the server host. Each
+//! one logs in with a password, stored as a salted PBKDF2-HMAC-SHA256 hash
+//! produced by [`hash_secret`], or a token from [`generate_token`], and is
+//! granted rights on key prefixes:
+//!
+//! ```json
+//! {
+//!   "users": [
+//!     { "name": "alice", "password": "pbkdf2-sha256:...",
+//!       "grants": { "app/": "write", "": "read", "sessions:": "write" } },
+//!     { "name": "ops", "token": "sha256:...", "grants": { "*:": "admin" } }
+//!   ]
+//! }
+//! ```
+//!
+//! A token `kvs_<id>_<secret>` starts with a public id naming the user it
+//! belongs to, so logging in with it checks a single hash. Its secret is
+//! random, which makes a plain SHA-256 digest as safe as a slow PBKDF2 one.
+//! After [`MAX_FAILED_LOGINS`] failures in a row, logins from the same
+//! address are refused for [`FAILED_LOGIN_WINDOW`].
+//!
+//! A grant `namespace:prefix` covers the keys starting with `prefix` in that
+//! namespace, `*:prefix` those of every namespace, and a grant without a colon
+//! the keys of the default namespace. Namespace names never contain a colon,
+//! so the prefix itself may.
+//!
+//! Rights are cumulative: `admin` implies `write`, which implies `read`. A key
+//! is accessible when any prefix it starts with grants the right needed.
+//! Operations on a whole namespace, such as backups, need `admin` on its empty
+//! prefix, and server-wide ones `admin` on the empty prefix of the default
+//! namespace. A backup of the default namespace holds every namespace, so it
+//! needs `admin` on all of them.
+use crate::engine::{check_namespace, DEFAULT_NAMESPACE};
+use crate::errors::{MyError, Result};
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const SALT_LEN: usize = 16;
+const DIGEST_LEN: usize = 32;
+/// Iterations of new hashes, the OWASP recommendation for PBKDF2-HMAC-SHA256.
+/// Each hash stores its own count, so raising this keeps old hashes valid.
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+const ANY_NAMESPACE: &str = "*";
+const TOKEN_PREFIX: &str = "kvs";
+const TOKEN_HASH_SCHEME: &str = "sha256";
+const TOKEN_ID_LEN: usize = 8;
+const TOKEN_SECRET_LEN: usize = 32;
+/// Failed logins from one address before it is refused.
+pub const MAX_FAILED_LOGINS: u32 = 10;
+/// How long an address stays refused after too many failed logins.
+pub const FAILED_LOGIN_WINDOW: Duration = Duration::from_secs(60);
+/// Addresses with failures tracked before the expired ones are dropped.
+const TRACKED_PEERS: usize = 4096;
+
+/// Access level granted on a key prefix.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Right {
+    /// Get keys
+    Read,
+    /// Set and remove keys
+    Write,
+    /// Server-wide operations
+    Admin,
+}
+
+impl FromStr for Right {
+    type Err = MyError;
+
+    /// Parses `read`, `write` or `admin`.
+    fn from_str(s: &str) -> Result<Right> {
+        match s.to_ascii_lowercase().as_str() {
+            "read" => Ok(Right::Read),
+            "write" => Ok(Right::Write),
+            "admin" => Ok(Right::Admin),
+            _ => Err(MyError::StringError(format!(
+                "Invalid right {}, expected read, write or admin",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Right {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Right::Read => write!(f, "read"),
+            Right::Write => write!(f, "write"),
+            Right::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// What a client logs in with.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Credentials {
+    Password { user: String, password: String },
+    Token { token: String },
+}
+
+impl Credentials {
+    pub fn password(user: impl Into<String>, password: impl Into<String>) -> Credentials {
+        Credentials::Password {
+            user: user.into(),
+            password: password.into(),
+        }
+    }
+
+    pub fn token(token: impl Into<String>) -> Credentials {
+        Credentials::Token {
+            token: token.into(),
+        }
+    }
+}
+
+/// Hides the secrets, as requests are logged.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::Password { user, .. } => f
+                .debug_struct("Password")
+                .field("user", user)
+                .finish_non_exhaustive(),
+            Credentials::Token { .. } => f.debug_struct("Token").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// A user of the credentials file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct User {
+    name: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    grants: BTreeMap<String, Right>,
+}
+
+impl User {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether a grant on `namespace` and a prefix of `key` gives `right` or
+    /// above.
+    pub(crate) fn allows(&self, namespace: &str, key: &[u8], right: Right) -> bool {
+        self.grants.iter().any(|(grant, granted)| {
+            let (scope, prefix) = split_grant(grant);
+            *granted >= right
+                && (scope == ANY_NAMESPACE || scope == namespace)
+                && key.starts_with(prefix.as_bytes())
+        })
+    }
+}
+
+/// Splits a grant into its namespace, `*` for all of them, and its prefix.
+fn split_grant(grant: &str) -> (&str, &str) {
+    match grant.find(':') {
+        Some(colon) => (&grant[..colon], &grant[colon + 1..]),
+        None => (DEFAULT_NAMESPACE, grant),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialsFile {
+    users: Vec<User>,
+}
+
+/// The users a `Server` accepts and their rights.
+#[derive(Debug)]
+pub struct Acl {
+    users: Vec<Arc<User>>,
+    /// Token digest and user by token id
+    tokens: HashMap<String, (Vec<u8>, Arc<User>)>,
+    /// Failed logins and the time of the first one, by address
+    failures: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl Acl {
+    /// Reads and validates a credentials file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Acl> {
+        let path = path.as_ref();
+        Acl::from_json(&fs::read_to_string(path)?)
+            .map_err(|e| MyError::StringError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses and validates the content of a credentials file.
+    pub fn from_json(json: &str) -> Result<Acl> {
+        let file: CredentialsFile = serde_json::from_str(json)?;
+        let mut names = HashSet::new();
+        let mut ids = HashSet::new();
+        for user in &file.users {
+            if !names.insert(user.name.as_str()) {
+                return Err(MyError::StringError(format!(
+                    "User {} is listed twice",
+                    user.name
+                )));
+            }
+            if user.password.is_some() == user.token.is_some() {
+                return Err(MyError::StringError(format!(
+                    "User {} needs either a password or a token",
+                    user.name
+                )));
+            }
+            if let Some(hash) = &user.password {
+                parse_hash(hash).ok_or_else(|| {
+                    MyError::StringError(format!(
+                        "The password of user {} is not a hash from kvs-admin hash-secret",
+                        user.name
+                    ))
+                })?;
+            }
+            if let Some(hash) = &user.token {
+                let (id, _) = parse_token_hash(hash).ok_or_else(|| {
+                    MyError::StringError(format!(
+                        "The token of user {} is not a hash from kvs-admin generate-token",
+                        user.name
+                    ))
+                })?;
+                if !ids.insert(id) {
+                    return Err(MyError::StringError(format!(
+                        "The token id of user {} is used twice",
+                        user.name
+                    )));
+                }
+            }
+            for grant in user.grants.keys() {
+                let (scope, _) = split_grant(grant);
+                if scope != ANY_NAMESPACE {
+                    check_namespace(scope).map_err(|e| {
+                        MyError::StringError(format!(
+                            "Grant {:?} of user {}: {}",
+                            grant, user.name, e
+                        ))
+                    })?;
+                }
+            }
+        }
+        let users: Vec<_> = file.users.into_iter().map(Arc::new).collect();
+        let tokens = users
+            .iter()
+            .filter_map(|user| {
+                let (id, digest) = parse_token_hash(user.token.as_ref()?)?;
+                Some((id.to_owned(), (digest, Arc::clone(user))))
+            })
+            .collect();
+        Ok(Acl {
+            users,
+            tokens,
+            failures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Finds the user these credentials belong to, unless `peer` failed to
+    /// log in too often lately.
+    pub(crate) fn authenticate(
+        &self,
+        credentials: &Credentials,
+        peer: IpAddr,
+    ) -> Result<Arc<User>> {
+        if self.refused(peer) {
+            return Err(MyError::Unauthorized(format!(
+                "Too many failed logins, retry in {} seconds",
+                FAILED_LOGIN_WINDOW.as_secs()
+            )));
+        }
+        let found = match credentials {
+            Credentials::Password { user, password } => self
+                .users
+                .iter()
+                .find(|candidate| {
+                    candidate.name == *user
+                        && candidate
+                            .password
+                            .as_ref()
+                            .is_some_and(|hash| verify_secret(hash, password))
+                })
+                .cloned(),
+            Credentials::Token { token } => split_token(token)
+                .and_then(|(id, _)| self.tokens.get(id))
+                .filter(|(digest, _)| constant_time_eq(&token_digest(token), digest))
+                .map(|(_, user)| Arc::clone(user)),
+        };
+        let mut failures = self.failures.lock().unwrap();
+        match found {
+            Some(user) => {
+                failures.remove(&peer);
+                Ok(user)
+            }
+            None => {
+                let now = Instant::now();
+                if failures.len() >= TRACKED_PEERS {
+                    failures.retain(|_, (_, since)| now - *since < FAILED_LOGIN_WINDOW);
+                }
+                let (count, since) = failures.entry(peer).or_insert((0, now));
+                if now - *since >= FAILED_LOGIN_WINDOW {
+                    *count = 0;
+                    *since = now;
+                }
+                *count += 1;
+                Err(MyError::Unauthorized("Invalid credentials".to_owned()))
+            }
+        }
+    }
+
+    /// Whether `peer` reached [`MAX_FAILED_LOGINS`] within the window.
+    fn refused(&self, peer: IpAddr) -> bool {
+        let failures = self.failures.lock().unwrap();
+        failures.get(&peer).is_some_and(|(count, since)| {
+            *count >= MAX_FAILED_LOGINS && since.elapsed() < FAILED_LOGIN_WINDOW
+        })
+    }
+}
+
+/// Generates a random token, `kvs_<id>_<secret>` in hex. Its hash for the
+/// credentials file comes from [`hash_token`].
+pub fn generate_token() -> String {
+    let mut id = [0u8; TOKEN_ID_LEN];
+    let mut secret = [0u8; TOKEN_SECRET_LEN];
+    getrandom::getrandom(&mut id).expect("the system random number generator failed");
+    getrandom::getrandom(&mut secret).expect("the system random number generator failed");
+    format!("{}_{}_{}", TOKEN_PREFIX, to_hex(&id), to_hex(&secret))
+}
+
+/// Hashes a token from [`generate_token`] as `sha256:<id>:<digest>`.
+pub fn hash_token(token: &str) -> Result<String> {
+    let (id, _) = split_token(token).ok_or_else(|| {
+        MyError::StringError("Not a token from kvs-admin generate-token".to_owned())
+    })?;
+    Ok(format!(
+        "{}:{}:{}",
+        TOKEN_HASH_SCHEME,
+        id,
+        to_hex(&token_digest(token))
+    ))
+}
+
+/// Splits `kvs_<id>_<secret>` into its id and secret, checking their length.
+fn split_token(token: &str) -> Option<(&str, &str)> {
+    let mut parts = token.split('_');
+    if parts.next()? != TOKEN_PREFIX {
+        return None;
+    }
+    let id = parts.next()?;
+    let secret = parts.next()?;
+    let valid = |part: &str, len| from_hex(part).is_some_and(|bytes| bytes.len() == len);
+    if parts.next().is_some() || !valid(id, TOKEN_ID_LEN) || !valid(secret, TOKEN_SECRET_LEN) {
+        return None;
+    }
+    Some((id, secret))
+}
+
+fn token_digest(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Splits `sha256:<id>:<digest>` into its parts.
+fn parse_token_hash(hash: &str) -> Option<(&str, Vec<u8>)> {
+    let mut parts = hash.split(':');
+    if parts.next()? != TOKEN_HASH_SCHEME {
+        return None;
+    }
+    let id = parts.next()?;
+    let digest = from_hex(parts.next()?)?;
+    let id_len = from_hex(id)?.len();
+    if parts.next().is_some() || id_len != TOKEN_ID_LEN || digest.len() != DIGEST_LEN {
+        return None;
+    }
+    Some((id, digest))
+}
+
+/// Compares two digests without stopping at the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Hashes a password with a random salt and [`DEFAULT_ITERATIONS`], for the
+/// credentials file.
+pub fn hash_secret(secret: &str) -> String {
+    let iterations = NonZeroU32::new(DEFAULT_ITERATIONS).expect("DEFAULT_ITERATIONS is not zero");
+    hash_secret_with_iterations(secret, iterations)
+}
+
+/// Hashes a password with a random salt and `iterations` rounds of
+/// PBKDF2, as `pbkdf2-sha256:<iterations>:<salt>:<digest>`.
+pub fn hash_secret_with_iterations(secret: &str, iterations: NonZeroU32) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    getrandom::getrandom(&mut salt).expect("the system random number generator failed");
+    let mut digest = [0u8; DIGEST_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        secret.as_bytes(),
+        &mut digest,
+    );
+    format!(
+        "{}:{}:{}:{}",
+        HASH_SCHEME,
+        iterations,
+        to_hex(&salt),
+        to_hex(&digest)
+    )
+}
+
+/// Checks `secret` against `hash`, in constant time.
+fn verify_secret(hash: &str, secret: &str) -> bool {
+    match parse_hash(hash) {
+        Some((iterations, salt, expected)) => pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            secret.as_bytes(),
+            &expected,
+        )
+        .is_ok(),
+        None => false,
+    }
+}
+
+/// Splits `pbkdf2-sha256:<iterations>:<salt>:<digest>` into its parts.
+fn parse_hash(hash: &str) -> Option<(NonZeroU32, Vec<u8>, Vec<u8>)> {
+    let mut parts = hash.split(':');
+    if parts.next()? != HASH_SCHEME {
+        return None;
+    }
+    let iterations = parts.next()?.parse().ok()?;
+    let salt = from_hex(parts.next()?)?;
+    let digest = from_hex(parts.next()?)?;
+    if parts.next().is_some() || salt.is_empty() || digest.len() != DIGEST_LEN {
+        return None;
+    }
+    Some((iterations, salt, digest))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,253 @@
+//! Online backup and offline restore of a store directory.
+//!
+//! A backup directory contains the files of the engine it was taken from, plus
+//! a `backup.json` manifest recording the engine, the number of keys and a
+//! checksum of every key/value pair. A backup of the default namespace holds
+//! every namespace, which the count and the checksum cover.
+use crate::engine::{KvsEngine, KvsSnapshot, LSM_DIR, MEMORY_FILE, NAMESPACES_DIR};
+use crate::migrate::write_engine_marker;
+use crate::{
+    KvStore, KvStoreOptions, LsmKvsEngine, MemoryKvsEngine, MyError, Result, SledKvsEngine,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+const MANIFEST_FILE: &str = "backup.json";
+const STAGING_DIR: &str = ".restore";
+
+/// Description of a backup, written next to the backed up data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Name of the engine which wrote the backup: `kvs`, `sled`, `memory` or `lsm`.
+    pub engine: String,
+    /// Version of kvs which wrote the backup.
+    pub version: String,
+    /// Number of keys in the backup.
+    pub keys: u64,
+    /// Checksum of every key/value pair, in key order.
+    pub checksum: u64,
+}
+
+impl BackupManifest {
+    /// Reads the manifest of the backup in `dir`.
+    pub fn read(dir: &Path) -> Result<BackupManifest> {
+        let file = fs::File::open(dir.join(MANIFEST_FILE))?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    pub(crate) fn new(engine: &str, keys: u64, checksum: u64) -> BackupManifest {
+        BackupManifest {
+            engine: engine.to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            keys,
+            checksum,
+        }
+    }
+
+    pub(crate) fn write(&self, dir: &Path) -> Result<()> {
+        let file = fs::File::create(dir.join(MANIFEST_FILE))?;
+        serde_json::to_writer_pretty(&file, self)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Order-dependent FNV-1a checksum of key/value pairs.
+///
+/// The value is stable across processes and platforms, so it can be recorded
+/// in a file and compared later.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Checksum {
+    hash: u64,
+    count: u64,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum {
+            hash: 0xcbf2_9ce4_8422_2325,
+            count: 0,
+        }
+    }
+
+    pub fn update(&mut self, key: &[u8], value: &[u8]) {
+        for part in &[key, value] {
+            self.write(&(part.len() as u64).to_le_bytes());
+            self.write(part);
+        }
+        self.count += 1;
+    }
+
+    /// Starts the pairs of the namespace `name`, so that moving pairs to
+    /// another namespace changes the checksum.
+    pub fn namespace(&mut self, name: &str) {
+        self.write(&u64::MAX.to_le_bytes());
+        self.write(name.as_bytes());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= u64::from(*byte);
+            self.hash = self.hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Computes the number of keys and the checksum of a snapshot.
+pub(crate) fn checksum<S: KvsSnapshot>(snapshot: &mut S) -> Result<Checksum> {
+    let mut checksum = Checksum::new();
+    add_pairs(&mut checksum, snapshot)?;
+    Ok(checksum)
+}
+
+/// Computes the number of keys and the checksum of every namespace of an
+/// engine, the default one first.
+pub(crate) fn checksum_all<E: KvsEngine>(engine: &mut E) -> Result<Checksum> {
+    let mut checksum = Checksum::new();
+    add_pairs(&mut checksum, &mut engine.snapshot()?)?;
+    for name in engine.namespaces()? {
+        checksum.namespace(&name);
+        add_pairs(&mut checksum, &mut engine.namespace(&name)?.snapshot()?)?;
+    }
+    Ok(checksum)
+}
+
+fn add_pairs<S: KvsSnapshot>(checksum: &mut Checksum, snapshot: &mut S) -> Result<()> {
+    for entry in snapshot.iter() {
+        let (key, value) = entry?;
+        checksum.update(&key, &value);
+    }
+    Ok(())
+}
+
+/// Checks that the backup in `dir` is complete and matches its manifest.
+pub fn verify(dir: &Path) -> Result<BackupManifest> {
+    verify_with(dir, KvStoreOptions::default())
+}
+
+/// Like `verify`, opening a kvs backup with `options`, which hold the keys of
+/// an encrypted backup.
+pub fn verify_with(dir: &Path, options: KvStoreOptions) -> Result<BackupManifest> {
+    let manifest = BackupManifest::read(dir)?;
+    let checksum = match manifest.engine.as_str() {
+        "kvs" => checksum_all(&mut KvStore::open_with(dir, options)?)?,
+        "sled" => checksum_all(&mut SledKvsEngine::open(dir)?)?,
+        "memory" => checksum(&mut MemoryKvsEngine::open(dir)?.snapshot()?)?,
+        "lsm" => checksum(&mut LsmKvsEngine::open(dir)?.snapshot()?)?,
+        engine => return Err(MyError::StringError(format!("Unknown engine {}", engine))),
+    };
+    if checksum.count() != manifest.keys || checksum.finish() != manifest.checksum {
+        return Err(MyError::StringError(format!(
+            "Backup in {} is corrupted: expected {} keys with checksum {:016x}, found {} keys with checksum {:016x}",
+            dir.display(),
+            manifest.keys,
+            manifest.checksum,
+            checksum.count(),
+            checksum.finish()
+        )));
+    }
+    Ok(manifest)
+}
+
+/// Validates the backup in `backup_dir` and installs it in `data_dir`.
+///
+/// The backup is copied to a staging directory inside `data_dir` and verified
+/// there before replacing the engine files, so a failed restore leaves the
+/// existing data untouched. The namespaces of `data_dir` are replaced by those
+/// of the backup, and its engine marker is set to the engine of the backup.
+/// The store must not be open while restoring.
+pub fn restore(backup_dir: &Path, data_dir: &Path) -> Result<BackupManifest> {
+    restore_with(backup_dir, data_dir, KvStoreOptions::default())
+}
+
+/// Like `restore`, verifying a kvs backup with `options`, which hold the keys
+/// of an encrypted backup.
+pub fn restore_with(
+    backup_dir: &Path,
+    data_dir: &Path,
+    options: KvStoreOptions,
+) -> Result<BackupManifest> {
+    fs::create_dir_all(data_dir)?;
+    let staging = data_dir.join(STAGING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    copy_dir(backup_dir, &staging)?;
+
+    let manifest = match verify_with(&staging, options) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            fs::remove_dir_all(&staging)?;
+            return Err(err);
+        }
+    };
+
+    match manifest.engine.as_str() {
+        "kvs" => fs::rename(staging.join("log.json"), data_dir.join("log.json"))?,
+        "memory" => fs::rename(staging.join(MEMORY_FILE), data_dir.join(MEMORY_FILE))?,
+        engine => {
+            let name = if engine == "lsm" { LSM_DIR } else { "sled-db" };
+            let current = data_dir.join(name);
+            let old = data_dir.join(format!("{}.old", name));
+            if current.exists() {
+                fs::rename(&current, &old)?;
+            }
+            fs::rename(staging.join(name), &current)?;
+            if old.exists() {
+                fs::remove_dir_all(&old)?;
+            }
+        }
+    }
+    let namespaces = data_dir.join(NAMESPACES_DIR);
+    if namespaces.exists() {
+        fs::remove_dir_all(&namespaces)?;
+    }
+    if staging.join(NAMESPACES_DIR).exists() {
+        fs::rename(staging.join(NAMESPACES_DIR), &namespaces)?;
+    }
+    fs::remove_dir_all(&staging)?;
+    write_engine_marker(data_dir, &manifest.engine)?;
+    info!(
+        "Restored {} keys from {} into {}",
+        manifest.keys,
+        backup_dir.display(),
+        data_dir.display()
+    );
+    Ok(manifest)
+}
+
+/// Creates an empty directory to hold a backup.
+pub(crate) fn create_backup_dir(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(MyError::StringError(format!(
+            "Backup directory {} is not empty",
+            dest.display()
+        )));
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
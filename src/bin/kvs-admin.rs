@@ -0,0 +1,295 @@
+use env_logger::{Env, Target};
+use kvs::auth::{generate_token, hash_secret, hash_secret_with_iterations, hash_token};
+use kvs::{
+    backup, dump, Credentials, DumpFormat, KvStore, KvsClient, KvsEngine, LsmKvsEngine,
+    MemoryKvsEngine, MyError, Result, SledKvsEngine,
+};
+use log::info;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use structopt::clap::arg_enum;
+use structopt::StructOpt;
+
+const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+const ADDRESS_FORMAT: &str = "IP:PORT";
+const DEFAULT_DUMP_FORMAT: &str = "jsonl";
+/// Environment variables holding the secrets, to keep them off the command line.
+const PASSWORD_VAR: &str = "KVS_PASSWORD";
+const TOKEN_VAR: &str = "KVS_TOKEN";
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-admin")]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "backup",
+        about = "Ask a running server to write a consistent backup to a directory on its host"
+    )]
+    Backup {
+        #[structopt(
+            name = "DEST",
+            help = "An empty or missing directory on the server host"
+        )]
+        dest: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "namespace",
+            help = "Sets the namespace to back up. Defaults to the server's default namespace",
+            value_name = "NAME"
+        )]
+        namespace: Option<String>,
+        #[structopt(flatten)]
+        login: Login,
+    },
+    #[structopt(
+        name = "hash-secret",
+        about = "Hash a password for the server credentials file"
+    )]
+    HashSecret {
+        #[structopt(
+            name = "SECRET",
+            help = "The password. Read from standard input if missing"
+        )]
+        secret: Option<String>,
+        #[structopt(long, help = "Rounds of PBKDF2, stored in the hash [default: 600000]")]
+        iterations: Option<NonZeroU32>,
+    },
+    #[structopt(
+        name = "generate-token",
+        about = "Print a new token, then its hash for the server credentials file"
+    )]
+    GenerateToken,
+    #[structopt(
+        name = "restore",
+        about = "Validate a backup and install it in a data directory. The server must be stopped"
+    )]
+    Restore {
+        #[structopt(name = "BACKUP", help = "The backup directory", parse(from_os_str))]
+        backup: PathBuf,
+        #[structopt(
+            name = "DATA-DIR",
+            help = "The server data directory",
+            parse(from_os_str)
+        )]
+        data_dir: PathBuf,
+    },
+    #[structopt(name = "verify", about = "Check a backup against its manifest")]
+    Verify {
+        #[structopt(name = "BACKUP", help = "The backup directory", parse(from_os_str))]
+        backup: PathBuf,
+    },
+    #[structopt(
+        name = "export",
+        about = "Write every key of a stopped store to an engine-independent dump"
+    )]
+    Export {
+        #[structopt(
+            name = "DATA-DIR",
+            help = "The store data directory",
+            parse(from_os_str)
+        )]
+        data_dir: PathBuf,
+        #[structopt(name = "FILE", help = "The dump file to write", parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(long, help = "Sets the storage engine of the store", value_name = "ENGINE-NAME",
+        possible_values = &Engine::variants(), case_insensitive = true)]
+        engine: Engine,
+        #[structopt(long, help = "Sets the dump format: jsonl or binary", value_name = "FORMAT",
+        default_value = DEFAULT_DUMP_FORMAT)]
+        format: DumpFormat,
+    },
+    #[structopt(name = "import", about = "Load a dump into a stopped store")]
+    Import {
+        #[structopt(name = "FILE", help = "The dump file to read", parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(
+            name = "DATA-DIR",
+            help = "The store data directory",
+            parse(from_os_str)
+        )]
+        data_dir: PathBuf,
+        #[structopt(long, help = "Sets the storage engine of the store", value_name = "ENGINE-NAME",
+        possible_values = &Engine::variants(), case_insensitive = true)]
+        engine: Engine,
+        #[structopt(long, help = "Sets the dump format: jsonl or binary", value_name = "FORMAT",
+        default_value = DEFAULT_DUMP_FORMAT)]
+        format: DumpFormat,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct Login {
+    #[structopt(
+        long = "user",
+        help = "Logs in as this user, with --password or the KVS_PASSWORD environment variable",
+        value_name = "NAME",
+        conflicts_with = "token"
+    )]
+    user: Option<String>,
+    #[structopt(
+        long = "password",
+        help = "Password of the user",
+        value_name = "PASSWORD",
+        requires = "user"
+    )]
+    password: Option<String>,
+    #[structopt(
+        long = "token",
+        help = "Logs in with this token. Defaults to the KVS_TOKEN environment variable, if set",
+        value_name = "TOKEN"
+    )]
+    token: Option<String>,
+}
+
+impl Login {
+    fn credentials(&self) -> Result<Option<Credentials>> {
+        if let Some(user) = &self.user {
+            let password = match &self.password {
+                Some(password) => password.clone(),
+                None => env::var(PASSWORD_VAR).map_err(|_| {
+                    MyError::StringError(format!("--user needs --password or {}", PASSWORD_VAR))
+                })?,
+            };
+            return Ok(Some(Credentials::password(user.clone(), password)));
+        }
+        let token = self.token.clone().or_else(|| env::var(TOKEN_VAR).ok());
+        Ok(token.map(Credentials::token))
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        memory,
+        lsm
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .target(Target::Stdout)
+        .init();
+
+    match opt.command {
+        Command::Backup {
+            dest,
+            addr,
+            namespace,
+            login,
+        } => {
+            let mut client = KvsClient::connect(addr)?;
+            if let Some(credentials) = login.credentials()? {
+                client.authenticate(credentials)?;
+            }
+            if let Some(namespace) = namespace {
+                client.select(namespace);
+            }
+            let manifest = client.backup(dest.clone())?;
+            info!(
+                "Backup of {} keys ({} engine) written to {}",
+                manifest.keys, manifest.engine, dest
+            );
+        }
+        Command::HashSecret { secret, iterations } => {
+            let secret = match secret {
+                Some(secret) => secret,
+                None => {
+                    let mut line = String::new();
+                    io::stdin().lock().read_line(&mut line)?;
+                    line.trim_end_matches(&['\r', '\n'][..]).to_owned()
+                }
+            };
+            let hash = match iterations {
+                Some(iterations) => hash_secret_with_iterations(&secret, iterations),
+                None => hash_secret(&secret),
+            };
+            println!("{}", hash);
+        }
+        Command::GenerateToken => {
+            let token = generate_token();
+            println!("{}", token);
+            println!("{}", hash_token(&token)?);
+        }
+        Command::Restore { backup, data_dir } => {
+            let manifest = backup::restore(&backup, &data_dir)?;
+            info!(
+                "Restored {} keys ({} engine) into {}",
+                manifest.keys,
+                manifest.engine,
+                data_dir.display()
+            );
+        }
+        Command::Verify { backup } => {
+            let manifest = backup::verify(&backup)?;
+            info!(
+                "Backup is valid: {} keys ({} engine), checksum {:016x}",
+                manifest.keys, manifest.engine, manifest.checksum
+            );
+        }
+        Command::Export {
+            data_dir,
+            file,
+            engine,
+            format,
+        } => {
+            let count = match engine {
+                Engine::kvs => export(KvStore::open(data_dir)?, &file, format)?,
+                Engine::sled => export(SledKvsEngine::open(data_dir)?, &file, format)?,
+                Engine::memory => export(MemoryKvsEngine::open(data_dir)?, &file, format)?,
+                Engine::lsm => export(LsmKvsEngine::open(data_dir)?, &file, format)?,
+            };
+            info!("Exported {} keys to {}", count, file.display());
+        }
+        Command::Import {
+            file,
+            data_dir,
+            engine,
+            format,
+        } => {
+            let count = match engine {
+                Engine::kvs => import(KvStore::open(data_dir)?, &file, format)?,
+                Engine::sled => import(SledKvsEngine::open(data_dir)?, &file, format)?,
+                Engine::memory => import(MemoryKvsEngine::open(data_dir)?, &file, format)?,
+                Engine::lsm => import(LsmKvsEngine::open(data_dir)?, &file, format)?,
+            };
+            info!("Imported {} keys from {}", count, file.display());
+        }
+    }
+    Ok(())
+}
+
+fn export<E: KvsEngine>(mut engine: E, file: &Path, format: DumpFormat) -> Result<u64> {
+    dump::export_all(&mut engine, File::create(file)?, format)
+}
+
+fn import<E: KvsEngine>(mut engine: E, file: &Path, format: DumpFormat) -> Result<u64> {
+    dump::import(&mut engine, File::open(file)?, format)
+}
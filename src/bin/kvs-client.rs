@@ -1,12 +1,17 @@
 use env_logger::{Env, Target};
-use kvs::{KvsClient, MyError, Result};
+use kvs::{Credentials, KvsClient, MyError, Result, TlsClientConfig};
 use log::{error, info};
+use std::env;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const ADDRESS_FORMAT: &str = "IP:PORT";
+/// Environment variables holding the secrets, to keep them off the command line.
+const PASSWORD_VAR: &str = "KVS_PASSWORD";
+const TOKEN_VAR: &str = "KVS_TOKEN";
 
 #[derive(StructOpt, Debug)]
 #[structopt(
@@ -20,20 +25,124 @@ struct Opt {
     command: Command,
 }
 
+#[derive(StructOpt, Debug)]
+struct Connection {
+    #[structopt(
+    long = "addr",
+    help = "Sets the server address",
+    value_name = ADDRESS_FORMAT,
+    default_value = DEFAULT_LISTENING_ADDRESS,
+    parse(try_from_str)
+    )]
+    addr: SocketAddr,
+    #[structopt(
+        long = "tls-ca",
+        help = "Connects over TLS, trusting the CA certificates of this PEM file",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    tls_ca: Option<PathBuf>,
+    #[structopt(
+        long = "tls-cert",
+        help = "Presents the certificate chain of this PEM file to the server",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires_all = &["tls-key", "tls-ca"]
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long = "tls-key",
+        help = "Private key of the client certificate, as a PEM file",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long = "tls-server-name",
+        help = "Name the server certificate must be valid for. Defaults to the server IP",
+        value_name = "NAME",
+        requires = "tls-ca"
+    )]
+    tls_server_name: Option<String>,
+    #[structopt(
+        long = "user",
+        help = "Logs in as this user, with --password or the KVS_PASSWORD environment variable",
+        value_name = "NAME",
+        conflicts_with = "token"
+    )]
+    user: Option<String>,
+    #[structopt(
+        long = "password",
+        help = "Password of the user",
+        value_name = "PASSWORD",
+        requires = "user"
+    )]
+    password: Option<String>,
+    #[structopt(
+        long = "token",
+        help = "Logs in with this token. Defaults to the KVS_TOKEN environment variable, if set",
+        value_name = "TOKEN"
+    )]
+    token: Option<String>,
+    #[structopt(
+        long = "namespace",
+        help = "Sets the namespace of the key. Defaults to the server's default namespace",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+}
+
+impl Connection {
+    fn connect(&self) -> Result<KvsClient> {
+        let mut client = self.open()?;
+        if let Some(credentials) = self.credentials()? {
+            client.authenticate(credentials)?;
+        }
+        if let Some(namespace) = &self.namespace {
+            client.select(namespace.clone());
+        }
+        Ok(client)
+    }
+
+    fn open(&self) -> Result<KvsClient> {
+        let ca = match &self.tls_ca {
+            Some(ca) => ca,
+            None => return KvsClient::connect(self.addr),
+        };
+        let mut config = TlsClientConfig::from_ca_pem_file(ca)?;
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            config = config.client_cert(cert, key)?;
+        }
+        if let Some(name) = &self.tls_server_name {
+            config = config.server_name(name.clone());
+        }
+        KvsClient::connect_tls(self.addr, &config)
+    }
+
+    fn credentials(&self) -> Result<Option<Credentials>> {
+        if let Some(user) = &self.user {
+            let password = match &self.password {
+                Some(password) => password.clone(),
+                None => env::var(PASSWORD_VAR).map_err(|_| {
+                    MyError::StringError(format!("--user needs --password or {}", PASSWORD_VAR))
+                })?,
+            };
+            return Ok(Some(Credentials::password(user.clone(), password)));
+        }
+        let token = self.token.clone().or_else(|| env::var(TOKEN_VAR).ok());
+        Ok(token.map(Credentials::token))
+    }
+}
+
 #[derive(StructOpt, Debug)]
 enum Command {
     #[structopt(name = "get", about = "Get the string value of a given string key")]
     Get {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
-        #[structopt(
-        long = "addr",
-        help = "Sets the server address",
-        value_name = ADDRESS_FORMAT,
-        default_value = DEFAULT_LISTENING_ADDRESS,
-        parse(try_from_str)
-        )]
-        addr: SocketAddr,
+        #[structopt(flatten)]
+        connection: Connection,
     },
     #[structopt(name = "set", about = "Set the value of a string key to a string")]
     Set {
@@ -41,27 +150,96 @@ enum Command {
         key: String,
         #[structopt(name = "VALUE", help = "The string value of the key")]
         value: String,
-        #[structopt(
-        long = "addr",
-        help = "Sets the server address",
-        value_name = ADDRESS_FORMAT,
-        default_value = DEFAULT_LISTENING_ADDRESS,
-        parse(try_from_str)
-        )]
-        addr: SocketAddr,
+        #[structopt(flatten)]
+        connection: Connection,
     },
     #[structopt(name = "rm", about = "Remove a given string key")]
     Remove {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
-        #[structopt(
-        long = "addr",
-        help = "Sets the server address",
-        value_name = ADDRESS_FORMAT,
-        default_value = DEFAULT_LISTENING_ADDRESS,
-        parse(try_from_str)
-        )]
-        addr: SocketAddr,
+        #[structopt(flatten)]
+        connection: Connection,
+    },
+    #[structopt(name = "admin", about = "Operate the server, with admin rights")]
+    Admin(Admin),
+}
+
+#[derive(StructOpt, Debug)]
+enum Admin {
+    #[structopt(
+        name = "info",
+        about = "Show the version, engine, uptime, key count and disk usage"
+    )]
+    Info {
+        #[structopt(flatten)]
+        connection: Connection,
+    },
+    #[structopt(name = "compact", about = "Compact the log now")]
+    Compact {
+        #[structopt(flatten)]
+        connection: Connection,
+    },
+    #[structopt(name = "flush", about = "Sync every write to disk")]
+    Flush {
+        #[structopt(flatten)]
+        connection: Connection,
+    },
+    #[structopt(name = "dbsize", about = "Count the keys")]
+    DbSize {
+        #[structopt(flatten)]
+        connection: Connection,
+    },
+    #[structopt(name = "config", about = "Read or change the parameters of the server")]
+    Config(Config),
+    #[structopt(
+        name = "promote",
+        about = "Make a follower the leader: it stops replicating and accepts writes"
+    )]
+    Promote {
+        #[structopt(flatten)]
+        connection: Connection,
+    },
+    #[structopt(
+        name = "add-node",
+        about = "Add a node to the Raft cluster, through its leader"
+    )]
+    AddNode {
+        #[structopt(name = "ID", help = "Id of the new node")]
+        id: u64,
+        #[structopt(name = "RAFT_ADDR", help = "Raft address of the new node, as IP:PORT")]
+        addr: String,
+        #[structopt(flatten)]
+        connection: Connection,
+    },
+    #[structopt(
+        name = "remove-node",
+        about = "Remove a node from the Raft cluster, through its leader"
+    )]
+    RemoveNode {
+        #[structopt(name = "ID", help = "Id of the node to remove")]
+        id: u64,
+        #[structopt(flatten)]
+        connection: Connection,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum Config {
+    #[structopt(name = "get", about = "Show a parameter, or all of them")]
+    Get {
+        #[structopt(name = "NAME", help = "A parameter name")]
+        name: Option<String>,
+        #[structopt(flatten)]
+        connection: Connection,
+    },
+    #[structopt(name = "set", about = "Change a parameter")]
+    Set {
+        #[structopt(name = "NAME", help = "A parameter name")]
+        name: String,
+        #[structopt(name = "VALUE", help = "The new value of the parameter")]
+        value: String,
+        #[structopt(flatten)]
+        connection: Connection,
     },
 }
 
@@ -80,8 +258,8 @@ fn run(opt: Opt) -> Result<()> {
     //let mut kvs = KvStore::open(current_dir()?)?;
 
     match opt.command {
-        Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+        Command::Get { key, connection } => {
+            let mut client = connection.connect()?;
 
             if let Some(value) = client.get(key.clone())? {
                 info!("{}", value);
@@ -89,14 +267,61 @@ fn run(opt: Opt) -> Result<()> {
                 error!("{}", MyError::KeyNotFound)
             }
         }
-        Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+        Command::Set {
+            key,
+            value,
+            connection,
+        } => {
+            let mut client = connection.connect()?;
             client.set(key, value)?;
         }
-        Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+        Command::Remove { key, connection } => {
+            let mut client = connection.connect()?;
             client.remove(key)?;
         }
+        Command::Admin(admin) => run_admin(admin)?,
+    }
+    Ok(())
+}
+
+fn run_admin(admin: Admin) -> Result<()> {
+    match admin {
+        Admin::Info { connection } => {
+            let info = connection.connect()?.info()?;
+            info!("version: {}", info.version);
+            info!("engine: {}", info.engine);
+            info!("uptime_secs: {}", info.uptime_secs);
+            info!("keys: {}", info.keys);
+            if let Some(bytes) = info.disk_bytes {
+                info!("disk_bytes: {}", bytes);
+            }
+            if let Some(bytes) = info.stale_bytes {
+                info!("stale_bytes: {}", bytes);
+            }
+            if let Some(role) = info.role {
+                info!("role: {}", role);
+            }
+        }
+        Admin::Compact { connection } => connection.connect()?.compact()?,
+        Admin::Flush { connection } => connection.connect()?.flush()?,
+        Admin::DbSize { connection } => info!("{}", connection.connect()?.dbsize()?),
+        Admin::Config(Config::Get { name, connection }) => {
+            for (name, value) in connection.connect()?.config_get(name)? {
+                info!("{} = {}", name, value);
+            }
+        }
+        Admin::Config(Config::Set {
+            name,
+            value,
+            connection,
+        }) => connection.connect()?.config_set(name, value)?,
+        Admin::Promote { connection } => connection.connect()?.promote()?,
+        Admin::AddNode {
+            id,
+            addr,
+            connection,
+        } => connection.connect()?.add_node(id, addr)?,
+        Admin::RemoveNode { id, connection } => connection.connect()?.remove_node(id)?,
     }
     Ok(())
 }
@@ -0,0 +1,80 @@
+use env_logger::{Env, Target};
+use kvs::fsck::{self, FsckReport};
+use kvs::{MyError, Result};
+use log::{info, warn};
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-fsck",
+    about = "Verify the log of a stopped kvs store and optionally salvage it"
+)]
+struct Opt {
+    #[structopt(long, help = "Rewrites every readable live record into a fresh log")]
+    repair: bool,
+    #[structopt(
+        name = "DATA-DIR",
+        help = "The store data directory",
+        parse(from_os_str)
+    )]
+    data_dir: PathBuf,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .target(Target::Stdout)
+        .init();
+
+    let report = if opt.repair {
+        fsck::repair(&opt.data_dir)?
+    } else {
+        fsck::check(&opt.data_dir)?
+    };
+    print_report(&report);
+
+    if report.is_clean() {
+        info!("No corruption found");
+    } else if opt.repair {
+        info!("Log repaired, the original is kept as log.json.corrupt");
+    } else {
+        return Err(MyError::StringError(
+            "Corruption found, run again with --repair to salvage readable records".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+fn print_report(report: &FsckReport) {
+    info!("Log size: {} bytes", report.log_bytes);
+    info!("Records: {} sets, {} removes", report.sets, report.removes);
+    if report.sealed > 0 {
+        info!(
+            "Encrypted records: {}, their content was not checked",
+            report.sealed
+        );
+    }
+    info!(
+        "Live keys: {} ({} bytes)",
+        report.live_keys, report.live_bytes
+    );
+    info!("Stale bytes: {}", report.stale_bytes);
+    for range in &report.corrupt_ranges {
+        warn!("Corrupt bytes {}..{}", range.start, range.end);
+    }
+    for key in &report.dangling_keys {
+        warn!(
+            "Dangling index entry for key {:?}",
+            String::from_utf8_lossy(key)
+        );
+    }
+}
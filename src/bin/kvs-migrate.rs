@@ -0,0 +1,66 @@
+use env_logger::{Env, Target};
+use kvs::{migrate, Result};
+use log::info;
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::clap::arg_enum;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-migrate",
+    about = "Copy every key of a stopped store to another engine and switch its engine marker"
+)]
+struct Opt {
+    #[structopt(long, help = "Sets the current storage engine", value_name = "ENGINE-NAME",
+    possible_values = &Engine::variants(), case_insensitive = true)]
+    from: Engine,
+    #[structopt(long, help = "Sets the new storage engine", value_name = "ENGINE-NAME",
+    possible_values = &Engine::variants(), case_insensitive = true)]
+    to: Engine,
+    #[structopt(long = "keep-source", help = "Keeps the files of the current engine")]
+    keep_source: bool,
+    #[structopt(
+        name = "DATA-DIR",
+        help = "The store data directory",
+        parse(from_os_str)
+    )]
+    data_dir: PathBuf,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        memory,
+        lsm
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .target(Target::Stdout)
+        .init();
+
+    let report = migrate::migrate(
+        &opt.data_dir,
+        &opt.from.to_string(),
+        &opt.to.to_string(),
+        opt.keep_source,
+    )?;
+    info!(
+        "Migration verified: {} keys, checksum {:016x}",
+        report.keys, report.checksum
+    );
+    Ok(())
+}
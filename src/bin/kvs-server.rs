@@ -1,14 +1,34 @@
 use env_logger::{Env, Target};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
-use kvs::{Result, Server};
-use log::info;
-use std::env::current_dir;
+use kvs::config::{check_data_dir, Config};
+use kvs::migrate::{detect_engine, write_engine_marker};
+use kvs::raft::{NodeId, RaftNode, RaftOptions, TcpTransport};
+use kvs::{
+    Acl, Credentials, MyError, Result, Server, TlsClientConfig, TlsPeerConfig, TlsServerConfig,
+};
+use kvs::{
+    Cipher, Compression, Durability, EncryptionKey, KvStore, KvStoreOptions, KvsEngine,
+    LsmKvsEngine, LsmOptions, MemoryKvsEngine, SledKvsEngine,
+};
+use log::{error, info, LevelFilter};
+use std::env;
+use std::fs;
+use std::mem;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 use structopt::clap::arg_enum;
 use structopt::StructOpt;
 
 //const DEFAULT_ENGINE: Engine = Engine::kvs;
+/// Environment variable holding the encryption key, as base64.
+const ENCRYPTION_KEY_VAR: &str = "KVS_ENCRYPTION_KEY";
+/// Environment variable holding the path of the configuration file.
+const CONFIG_VAR: &str = "KVS_CONFIG";
+/// Environment variable holding the log filter, read by `env_logger`.
+const LOG_FILTER_VAR: &str = "RUST_LOG";
+/// Environment variable holding the token a follower logs in to its leader with.
+const LEADER_TOKEN_VAR: &str = "KVS_LEADER_TOKEN";
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const ADDRESS_FORMAT: &str = "IP:PORT";
@@ -17,17 +37,359 @@ const DEFAULT_ENGINE: Engine = Engine::kvs;
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
 struct Opt {
+    #[structopt(
+        long = "config",
+        help = "Reads settings from this TOML file. Defaults to the file in the KVS_CONFIG \
+                environment variable, if set. Flags take precedence over KVS_* environment \
+                variables, which take precedence over the file",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    config: Option<PathBuf>,
+    #[structopt(
+        long = "data-dir",
+        help = "Keeps the store in this directory, created if needed. \
+                Required unless the engine is memory",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    data_dir: Option<PathBuf>,
     #[structopt(
     long = "addr",
-    help = "Sets the server address",
+    help = "Sets the server address. Defaults to 127.0.0.1:4000",
     value_name = ADDRESS_FORMAT,
-    default_value = DEFAULT_LISTENING_ADDRESS,
     parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
     #[structopt(long, help = "Sets the storage engine", value_name = "ENGINE-NAME",
     possible_values = &Engine::variants(), case_insensitive = true)]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets when writes are synced to disk: none, every-write, every-<N>ms or group-commit. \
+                Defaults to none for kvs and lsm, and every-write for sled",
+        value_name = "POLICY"
+    )]
+    sync: Option<Durability>,
+    #[structopt(
+        long = "memory-snapshot",
+        help = "With the memory engine, loads the data directory's snapshot on startup \
+                and writes it back on shutdown"
+    )]
+    memory_snapshot: bool,
+    #[structopt(
+        long = "index-memory",
+        help = "With the kvs engine, caps the memory used by the key index, \
+                spilling the least recently used keys to disk",
+        value_name = "BYTES"
+    )]
+    index_memory: Option<u64>,
+    #[structopt(
+        long = "cache-memory",
+        help = "With the kvs engine, keeps up to this many bytes of recently read values in memory",
+        value_name = "BYTES"
+    )]
+    cache_memory: Option<u64>,
+    #[structopt(
+        long = "no-mmap",
+        help = "With the kvs engine, reads the log with pread instead of memory-mapping it"
+    )]
+    no_mmap: bool,
+    #[structopt(
+        long,
+        help = "With the kvs engine, compresses new values: none, lz4 or zstd",
+        value_name = "CODEC"
+    )]
+    compression: Option<Compression>,
+    #[structopt(
+        long = "encryption-key-file",
+        help = "With the kvs engine, encrypts the log with the base64 key in this file. \
+                Defaults to the key in the KVS_ENCRYPTION_KEY environment variable, if set",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    encryption_key_file: Option<PathBuf>,
+    #[structopt(
+        long = "previous-key-file",
+        help = "With the kvs engine, a key the log was encrypted with before a rotation",
+        value_name = "PATH",
+        parse(from_os_str),
+        number_of_values = 1
+    )]
+    previous_key_files: Vec<PathBuf>,
+    #[structopt(
+        long,
+        help = "Sets the encryption cipher: aes-256-gcm or chacha20-poly1305",
+        value_name = "CIPHER",
+        default_value = "aes-256-gcm"
+    )]
+    cipher: Cipher,
+    #[structopt(
+        long = "encrypt-plaintext",
+        help = "With an encryption key, accepts the unencrypted records of the log \
+                so that compaction encrypts them"
+    )]
+    encrypt_plaintext: bool,
+    #[structopt(
+        long = "tls-cert",
+        help = "Serves over TLS with the certificate chain of this PEM file",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "tls-key"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long = "tls-key",
+        help = "Private key of the TLS certificate, as a PEM file",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long = "tls-client-ca",
+        help = "Requires clients to present a certificate signed by a CA of this PEM file",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "tls-cert"
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[structopt(
+        long = "credentials",
+        help = "Requires clients to log in as a user of this JSON credentials file, \
+                and checks their rights on each key",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    credentials: Option<PathBuf>,
+    #[structopt(
+        long = "metrics-addr",
+        help = "Serves Prometheus metrics on GET /metrics at this address",
+        value_name = ADDRESS_FORMAT,
+        parse(try_from_str)
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[structopt(
+        long,
+        help = "Serves connections with this many threads. Defaults to 64",
+        value_name = "N"
+    )]
+    threads: Option<usize>,
+    #[structopt(
+        long = "compaction-threshold",
+        help = "With the kvs engine, compacts a log once this many of its bytes are stale",
+        value_name = "BYTES"
+    )]
+    compaction_threshold: Option<u64>,
+    #[structopt(
+        long = "memtable-bytes",
+        help = "With the lsm engine, writes the memtable to disk once it holds this many bytes",
+        value_name = "BYTES"
+    )]
+    memtable_bytes: Option<u64>,
+    #[structopt(
+        long = "log-level",
+        help = "Sets the most verbose level logged: off, error, warn, info, debug or trace. \
+                Defaults to RUST_LOG, or info",
+        value_name = "LEVEL"
+    )]
+    log_level: Option<LevelFilter>,
+    #[structopt(
+        long = "max-connections",
+        help = "Closes new connections while this many are open",
+        value_name = "N"
+    )]
+    max_connections: Option<usize>,
+    #[structopt(
+        long = "idle-timeout",
+        help = "Closes connections idle for this many seconds. Defaults to 60",
+        value_name = "SECONDS"
+    )]
+    idle_timeout: Option<u64>,
+    #[structopt(
+        long = "max-key-bytes",
+        help = "Rejects requests with longer keys",
+        value_name = "BYTES"
+    )]
+    max_key_bytes: Option<usize>,
+    #[structopt(
+        long = "max-value-bytes",
+        help = "Rejects requests setting longer values",
+        value_name = "BYTES"
+    )]
+    max_value_bytes: Option<usize>,
+    #[structopt(
+        long,
+        help = "Accepts followers, which replicate the default namespace",
+        conflicts_with = "follow"
+    )]
+    leader: bool,
+    #[structopt(
+        long = "follower-backlog",
+        help = "Drops the followers lagging this many writes behind after their snapshot. \
+                They then resync [default: 10000]",
+        value_name = "WRITES",
+        requires = "leader"
+    )]
+    follower_backlog: Option<usize>,
+    #[structopt(
+        long,
+        help = "Replicates the default namespace of the leader at this address, \
+                serving reads only until promoted",
+        value_name = ADDRESS_FORMAT,
+        parse(try_from_str)
+    )]
+    follow: Option<SocketAddr>,
+    #[structopt(
+        long = "leader-token-file",
+        help = "Logs in to the leader with the token in this file, of a user with the admin \
+                right. Defaults to the KVS_LEADER_TOKEN environment variable, if set",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "follow"
+    )]
+    leader_token_file: Option<PathBuf>,
+    #[structopt(
+        long = "leader-tls-ca",
+        help = "Connects to the leader over TLS, trusting the CA certificates of this PEM file",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "follow"
+    )]
+    leader_tls_ca: Option<PathBuf>,
+    #[structopt(
+        long = "leader-tls-cert",
+        help = "Presents the certificate chain of this PEM file to the leader",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires_all = &["leader-tls-key", "leader-tls-ca"]
+    )]
+    leader_tls_cert: Option<PathBuf>,
+    #[structopt(
+        long = "leader-tls-key",
+        help = "Private key of the certificate presented to the leader, as a PEM file",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "leader-tls-cert"
+    )]
+    leader_tls_key: Option<PathBuf>,
+    #[structopt(
+        long = "leader-tls-server-name",
+        help = "Name the leader certificate must be valid for. Defaults to the leader IP",
+        value_name = "NAME",
+        requires = "leader-tls-ca"
+    )]
+    leader_tls_server_name: Option<String>,
+    #[structopt(
+        long = "raft-id",
+        help = "Runs as this node of a Raft cluster, which replicates the default namespace \
+                and elects its leader",
+        value_name = "ID",
+        requires_all = &["raft-addr", "raft-tls-cert", "raft-tls-key", "raft-tls-ca"],
+        conflicts_with_all = &["leader", "follow"]
+    )]
+    raft_id: Option<NodeId>,
+    #[structopt(
+        long = "raft-addr",
+        help = "Exchanges the messages of the Raft cluster on this address",
+        value_name = ADDRESS_FORMAT,
+        requires = "raft-id"
+    )]
+    raft_addr: Option<SocketAddr>,
+    #[structopt(
+        long = "raft-peer",
+        help = "Starts a new Raft cluster with this other node, given as ID=IP:PORT. \
+                Repeat it for each other node",
+        value_name = "ID=IP:PORT",
+        number_of_values = 1,
+        requires = "raft-id",
+        parse(try_from_str = parse_peer)
+    )]
+    raft_peers: Vec<(NodeId, SocketAddr)>,
+    #[structopt(
+        long = "raft-join",
+        help = "Waits to be added to an existing Raft cluster with kvs-client admin add-node",
+        requires = "raft-id",
+        conflicts_with = "raft-peers"
+    )]
+    raft_join: bool,
+    #[structopt(
+        long = "raft-tls-cert",
+        help = "Certificate chain of this PEM file, presented to the other Raft nodes. \
+                It must be valid for the IP of --raft-addr",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "raft-id"
+    )]
+    raft_tls_cert: Option<PathBuf>,
+    #[structopt(
+        long = "raft-tls-key",
+        help = "Private key of the certificate presented to the other Raft nodes, as a PEM file",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "raft-id"
+    )]
+    raft_tls_key: Option<PathBuf>,
+    #[structopt(
+        long = "raft-tls-ca",
+        help = "Only exchanges Raft messages with nodes presenting a certificate signed by \
+                a CA of this PEM file",
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "raft-id"
+    )]
+    raft_tls_ca: Option<PathBuf>,
+}
+
+/// Parses a Raft peer given as `ID=IP:PORT`.
+fn parse_peer(peer: &str) -> std::result::Result<(NodeId, SocketAddr), String> {
+    let invalid = || format!("Invalid Raft peer {}, expected ID=IP:PORT", peer);
+    let mut parts = peer.splitn(2, '=');
+    let id = parts
+        .next()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(invalid)?;
+    let addr = parts
+        .next()
+        .and_then(|addr| addr.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((id, addr))
+}
+
+impl Opt {
+    /// Returns the settings given as flags.
+    fn flags(&self) -> Config {
+        Config {
+            data_dir: self.data_dir.clone(),
+            addr: self.addr,
+            metrics_addr: self.metrics_addr,
+            engine: self.engine.map(|engine| engine.to_string()),
+            threads: self.threads,
+            sync: self.sync,
+            compaction_threshold: self.compaction_threshold,
+            memtable_bytes: self.memtable_bytes,
+            log_level: self.log_level,
+            max_connections: self.max_connections,
+            idle_timeout: self.idle_timeout,
+            max_key_bytes: self.max_key_bytes,
+            max_value_bytes: self.max_value_bytes,
+        }
+    }
+
+    /// Merges the flags, the environment and the configuration file.
+    fn settings(&self) -> Result<Config> {
+        let path = self
+            .config
+            .clone()
+            .or_else(|| env::var_os(CONFIG_VAR).map(PathBuf::from));
+        let file = match &path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        let settings = file.merge(Config::from_env()?).merge(self.flags());
+        settings.validate()?;
+        Ok(settings)
+    }
 }
 
 arg_enum! {
@@ -35,7 +397,9 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        memory,
+        lsm
     }
 }
 
@@ -48,25 +412,380 @@ fn main() {
 }
 
 fn run(opt: Opt) -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-        .target(Target::Stdout)
-        .init();
+    let settings = opt.settings()?;
+    // A single level is applied through `log::set_max_level` only, so that
+    // `CONFIG SET log-level` can raise it. The directives of RUST_LOG are
+    // applied by the logger, which drops the records above them.
+    let level = match settings.log_level {
+        Some(level) => Some(level),
+        None if env::var_os(LOG_FILTER_VAR).is_none() => Some(LevelFilter::Info),
+        None => None,
+    };
+    let mut logger = match level {
+        Some(_) => {
+            let mut logger = env_logger::Builder::new();
+            logger.filter_level(LevelFilter::Trace);
+            logger
+        }
+        None => env_logger::Builder::from_env(Env::default()),
+    };
+    logger.target(Target::Stdout).init();
+    if let Some(level) = level {
+        log::set_max_level(level);
+    }
 
     info!("Starting up");
     //let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     //info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
+    let addr = match settings.addr {
+        Some(addr) => addr,
+        None => DEFAULT_LISTENING_ADDRESS.parse().unwrap(),
+    };
+    info!("Listening on {}", addr);
 
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    let tls = tls_config(&opt)?;
+    let acl = match &opt.credentials {
+        Some(path) => {
+            info!("Authentication: users of {}", path.display());
+            Some(Acl::from_file(path)?)
+        }
+        None => None,
+    };
+    let mut frontend = Frontend {
+        tls,
+        acl,
+        settings: settings.clone(),
+        replication: replication(&opt)?,
+    };
+    let requested = settings
+        .engine
+        .as_deref()
+        .map(|name| name.to_ascii_lowercase().parse::<Engine>())
+        .transpose()
+        .map_err(MyError::StringError)?;
+    if requested == Some(Engine::memory) && !opt.memory_snapshot {
+        check_kvs_options(&opt, Engine::memory)?;
+        check_raft_engine(&opt, Engine::memory)?;
+        info!("Keeping data in memory only");
+        return run_engine(MemoryKvsEngine::new(), addr, frontend);
+    }
+    let dir = settings.data_dir.clone().ok_or_else(|| {
+        MyError::StringError(
+            "No data directory set: use --data-dir, KVS_DATA_DIR or data_dir in the \
+             configuration file"
+                .to_owned(),
+        )
+    })?;
+    check_data_dir(&dir)?;
+    info!("Data directory: {}", dir.display());
+    if let Some(Replication::Raft { options, .. }) = &mut frontend.replication {
+        *options = mem::take(options).dir(dir.join("raft"));
+    }
+    let engine = match (requested, detect_engine(&dir)?) {
+        (Some(engine), Some(existing)) if engine.to_string() != existing => {
+            return Err(MyError::StringError(format!(
+                "{} was written by the {} engine, use kvs-migrate to switch to {}",
+                dir.display(),
+                existing,
+                engine
+            )))
+        }
+        (Some(engine), _) => engine,
+        (None, Some(existing)) => existing.parse().map_err(MyError::StringError)?,
+        (None, None) => DEFAULT_ENGINE,
+    };
+    check_kvs_options(&opt, engine)?;
+    check_raft_engine(&opt, engine)?;
+    write_engine_marker(&dir, &engine.to_string())?;
 
     match engine {
-        Engine::kvs => run_engine(KvStore::open(current_dir()?)?, opt.addr),
-        Engine::sled => run_engine(SledKvsEngine::open(current_dir()?)?, opt.addr),
+        Engine::kvs => {
+            let mut options = KvStoreOptions::default();
+            if let Some(durability) = settings.sync {
+                options = options.durability(durability);
+            }
+            if let Some(bytes) = settings.compaction_threshold {
+                info!("Compaction threshold: {} bytes", bytes);
+                options = options.compaction_threshold(bytes);
+            }
+            if let Some(bytes) = opt.index_memory {
+                info!("Index memory: {} bytes", bytes);
+                options = options.index_memory(bytes);
+            }
+            if let Some(bytes) = opt.cache_memory {
+                info!("Value cache: {} bytes", bytes);
+                options = options.cache_bytes(bytes);
+            }
+            if opt.no_mmap {
+                options = options.mmap(false);
+            }
+            if let Some(compression) = opt.compression {
+                info!("Compression: {}", compression);
+                options = options.compression(compression);
+            }
+            let key = match &opt.encryption_key_file {
+                Some(path) => Some(EncryptionKey::from_file(opt.cipher, path)?),
+                None if env::var_os(ENCRYPTION_KEY_VAR).is_some() => {
+                    Some(EncryptionKey::from_env(opt.cipher, ENCRYPTION_KEY_VAR)?)
+                }
+                None => None,
+            };
+            if let Some(key) = key {
+                info!("Encryption: {} with key {}", key.cipher(), key.id());
+                options = options
+                    .encryption(key)
+                    .encrypt_plaintext(opt.encrypt_plaintext);
+            }
+            for path in &opt.previous_key_files {
+                options = options.previous_key(EncryptionKey::from_file(opt.cipher, path)?);
+            }
+            let store = KvStore::open_with(dir, options)?;
+            info!("Durability: {}", store.durability());
+            run_engine(store, addr, frontend)
+        }
+        Engine::sled => {
+            let db =
+                SledKvsEngine::open_with(dir, settings.sync.unwrap_or(Durability::EveryWrite))?;
+            info!("Durability: {}", db.durability());
+            run_engine(db, addr, frontend)
+        }
+        Engine::lsm => {
+            let mut options = LsmOptions::default();
+            if let Some(durability) = settings.sync {
+                options = options.durability(durability);
+            }
+            if let Some(bytes) = settings.memtable_bytes {
+                options = options.memtable_bytes(bytes);
+            }
+            let tree = LsmKvsEngine::open_with(dir, options)?;
+            info!("Durability: {}", tree.durability());
+            run_engine(tree, addr, frontend)
+        }
+        Engine::memory => {
+            let memory = MemoryKvsEngine::open(dir)?;
+            let on_shutdown = memory.clone();
+            ctrlc::set_handler(move || {
+                info!("Shutting down");
+                if let Err(e) = on_shutdown.persist() {
+                    error!("{}", e);
+                    exit(1);
+                }
+                exit(0);
+            })
+            .map_err(|e| MyError::StringError(e.to_string()))?;
+            run_engine(memory, addr, frontend)
+        }
     }
 }
 
-fn run_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = Server::new(engine);
+/// Fails if a Raft node would run on the memory engine. A node must keep its
+/// term, vote, log and applied state across restarts, or it may vote twice in
+/// a term and lose acknowledged writes.
+fn check_raft_engine(opt: &Opt, engine: Engine) -> Result<()> {
+    match opt.raft_id {
+        Some(_) if engine == Engine::memory => Err(MyError::StringError(
+            "--raft-id needs an engine which keeps its data on disk, not memory".to_owned(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Fails if options only the kvs engine understands are given for `engine`,
+/// rather than ignoring them: a key would otherwise leave the data unencrypted.
+fn check_kvs_options(opt: &Opt, engine: Engine) -> Result<()> {
+    if engine == Engine::kvs {
+        return Ok(());
+    }
+    let given = [
+        ("--encryption-key-file", opt.encryption_key_file.is_some()),
+        (
+            ENCRYPTION_KEY_VAR,
+            env::var_os(ENCRYPTION_KEY_VAR).is_some(),
+        ),
+        ("--previous-key-file", !opt.previous_key_files.is_empty()),
+        ("--encrypt-plaintext", opt.encrypt_plaintext),
+        ("--compression", opt.compression.is_some()),
+        ("--index-memory", opt.index_memory.is_some()),
+        ("--cache-memory", opt.cache_memory.is_some()),
+        ("--no-mmap", opt.no_mmap),
+    ];
+    match given.iter().find(|(_, given)| *given) {
+        Some((option, _)) => Err(MyError::StringError(format!(
+            "{} only applies to the kvs engine, not {}",
+            option, engine
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn tls_config(opt: &Opt) -> Result<Option<TlsServerConfig>> {
+    let (cert, key) = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+    let mut config = TlsServerConfig::from_pem_files(cert, key)?;
+    if let Some(ca) = &opt.tls_client_ca {
+        config = config.client_ca(ca)?;
+        info!("TLS: client certificates required");
+    } else {
+        info!("TLS: enabled");
+    }
+    Ok(Some(config))
+}
+
+/// Replication role given on the command line.
+enum Replication {
+    Leader {
+        backlog: Option<usize>,
+    },
+    Follower {
+        leader: SocketAddr,
+        credentials: Option<Credentials>,
+        tls: Option<TlsClientConfig>,
+    },
+    Raft {
+        id: NodeId,
+        addr: SocketAddr,
+        options: RaftOptions,
+        tls: TlsPeerConfig,
+    },
+}
+
+fn replication(opt: &Opt) -> Result<Option<Replication>> {
+    if let (Some(id), Some(addr)) = (opt.raft_id, opt.raft_addr) {
+        info!("Replication: Raft node {} on {}", id, addr);
+        let tls = match (&opt.raft_tls_cert, &opt.raft_tls_key, &opt.raft_tls_ca) {
+            (Some(cert), Some(key), Some(ca)) => TlsPeerConfig::from_pem_files(cert, key, ca)?,
+            _ => unreachable!("required by --raft-id"),
+        };
+        let mut options = RaftOptions::default();
+        if !opt.raft_join {
+            options = options.member(id, addr.to_string());
+            for (peer, peer_addr) in &opt.raft_peers {
+                options = options.member(*peer, peer_addr.to_string());
+            }
+        }
+        return Ok(Some(Replication::Raft {
+            id,
+            addr,
+            options,
+            tls,
+        }));
+    }
+    if opt.leader {
+        info!("Replication: leader");
+        return Ok(Some(Replication::Leader {
+            backlog: opt.follower_backlog,
+        }));
+    }
+    let leader = match opt.follow {
+        Some(leader) => leader,
+        None => return Ok(None),
+    };
+    info!("Replication: follower of {}", leader);
+    let token = match &opt.leader_token_file {
+        Some(path) => Some(
+            fs::read_to_string(path)?
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_owned(),
+        ),
+        None => env::var(LEADER_TOKEN_VAR).ok(),
+    };
+    Ok(Some(Replication::Follower {
+        leader,
+        credentials: token.map(Credentials::token),
+        tls: leader_tls_config(opt)?,
+    }))
+}
+
+fn leader_tls_config(opt: &Opt) -> Result<Option<TlsClientConfig>> {
+    let ca = match &opt.leader_tls_ca {
+        Some(ca) => ca,
+        None => return Ok(None),
+    };
+    let mut config = TlsClientConfig::from_ca_pem_file(ca)?;
+    if let (Some(cert), Some(key)) = (&opt.leader_tls_cert, &opt.leader_tls_key) {
+        config = config.client_cert(cert, key)?;
+    }
+    if let Some(name) = &opt.leader_tls_server_name {
+        config = config.server_name(name.clone());
+    }
+    info!("Replication: TLS to the leader");
+    Ok(Some(config))
+}
+
+/// How connections are encrypted, authenticated and limited, where metrics
+/// are served, and how the server replicates.
+struct Frontend {
+    tls: Option<TlsServerConfig>,
+    acl: Option<Acl>,
+    settings: Config,
+    replication: Option<Replication>,
+}
+
+fn run_engine<E: KvsEngine + Clone + Send + 'static>(
+    engine: E,
+    addr: SocketAddr,
+    frontend: Frontend,
+) -> Result<()> {
+    let mut server = Server::new(engine.clone());
+    if let Some(tls) = frontend.tls {
+        server = server.tls(tls)?;
+    }
+    if let Some(acl) = frontend.acl {
+        server = server.auth(acl);
+    }
+    match frontend.replication {
+        Some(Replication::Leader { backlog }) => {
+            server = server.leader();
+            if let Some(backlog) = backlog {
+                server = server.follower_backlog(backlog);
+            }
+        }
+        Some(Replication::Follower {
+            leader,
+            credentials,
+            tls,
+        }) => {
+            server = server.follow(leader);
+            if let Some(credentials) = credentials {
+                server = server.leader_credentials(credentials);
+            }
+            if let Some(tls) = tls {
+                server = server.leader_tls(tls);
+            }
+        }
+        Some(Replication::Raft {
+            id,
+            addr,
+            options,
+            tls,
+        }) => {
+            let transport = TcpTransport::bind(addr)?.tls(tls);
+            server = server.raft(RaftNode::start(id, engine, transport, options)?);
+        }
+        None => (),
+    }
+    let settings = frontend.settings;
+    if let Some(metrics_addr) = settings.metrics_addr {
+        server = server.metrics(metrics_addr)?;
+    }
+    if let Some(threads) = settings.threads {
+        info!("Threads: {}", threads);
+        server = server.threads(threads);
+    }
+    if let Some(max) = settings.max_connections {
+        server = server.max_connections(max);
+    }
+    if let Some(secs) = settings.idle_timeout {
+        server = server.idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(max) = settings.max_key_bytes {
+        server = server.max_key_bytes(max);
+    }
+    if let Some(max) = settings.max_value_bytes {
+        server = server.max_value_bytes(max);
+    }
     server.open(addr)
 }
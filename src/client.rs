@@ -1,15 +1,26 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::auth::Credentials;
+use crate::backup::BackupManifest;
+use crate::common::{
+    AdminResponse, AuthResponse, BackupResponse, ConfigResponse, DbSizeResponse, GetResponse,
+    InfoResponse, RemoveResponse, Request, ServerInfo, SetResponse,
+};
 use crate::errors::{MyError, Result};
+use crate::tls::TlsClientConfig;
+use crate::typed::Codec;
 use log::info;
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
+use std::collections::BTreeMap;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
 
 /// Key value store client
 pub struct KvsClient {
-    writer: BufWriter<TcpStream>,
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    reader: Deserializer<IoRead<BufReader<Box<dyn Read + Send>>>>,
+    /// Namespace of the requests, the server's default one if `None`.
+    namespace: Option<String>,
 }
 
 impl KvsClient {
@@ -21,42 +32,251 @@ impl KvsClient {
         let tcp_writer = tcp_reader.try_clone()?;
         info!("Connected to {:?}", tcp_reader.peer_addr()?);
 
-        Ok(KvsClient {
-            writer: BufWriter::new(tcp_writer),
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-        })
+        Ok(KvsClient::from_halves(tcp_reader, tcp_writer))
+    }
+
+    /// Connect to `addr` over TLS, verifying the server with `config`.
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, config: &TlsClientConfig) -> Result<Self> {
+        info!("Try to connect over TLS");
+
+        let tcp = TcpStream::connect(addr)?;
+        let peer_addr = tcp.peer_addr()?;
+        let stream = config.connect(tcp)?;
+        info!("Connected to {:?}", peer_addr);
+
+        Ok(KvsClient::from_halves(stream.clone(), stream))
+    }
+
+    fn from_halves(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> KvsClient {
+        let reader: Box<dyn Read + Send> = Box::new(reader);
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        KvsClient {
+            writer: BufWriter::new(writer),
+            reader: Deserializer::from_reader(BufReader::new(reader)),
+            namespace: None,
+        }
+    }
+
+    /// Sends the following requests to the namespace `name` of the server.
+    pub fn select(&mut self, name: impl Into<String>) {
+        self.namespace = Some(name.into());
+    }
+
+    /// Log in to a server which checks access rights. Later requests run with
+    /// the rights of the user the credentials belong to.
+    pub fn authenticate(&mut self, credentials: Credentials) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &Request::Auth { credentials })?;
+        self.writer.flush()?;
+        let resp = AuthResponse::deserialize(&mut self.reader)?;
+        match resp {
+            AuthResponse::Ok(()) => Ok(()),
+            AuthResponse::Unauthorized(msg) => Err(MyError::Unauthorized(msg)),
+        }
     }
 
     /// Get the value of a given key from the server.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        serde_json::to_writer(
+            &mut self.writer,
+            &Request::Get {
+                key,
+                namespace: self.namespace.clone(),
+            },
+        )?;
         self.writer.flush()?;
         let resp = GetResponse::deserialize(&mut self.reader)?;
         match resp {
             GetResponse::Ok(value) => Ok(value),
             GetResponse::Err(msg) => Err(MyError::StringError(msg)),
+            GetResponse::Unauthorized(msg) => Err(MyError::Unauthorized(msg)),
         }
     }
 
-    /// Set the value of a string key in the server.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
+    /// Set the value of a key in the server.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        serde_json::to_writer(
+            &mut self.writer,
+            &Request::Set {
+                key,
+                value,
+                namespace: self.namespace.clone(),
+            },
+        )?;
         self.writer.flush()?;
         let resp = SetResponse::deserialize(&mut self.reader)?;
         match resp {
             SetResponse::Ok(_value) => Ok(()),
             SetResponse::Err(msg) => Err(MyError::StringError(msg)),
+            SetResponse::Unauthorized(msg) => Err(MyError::Unauthorized(msg)),
         }
     }
 
-    /// Remove a string key in the server.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
+    /// Remove a key in the server.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        serde_json::to_writer(
+            &mut self.writer,
+            &Request::Remove {
+                key,
+                namespace: self.namespace.clone(),
+            },
+        )?;
         self.writer.flush()?;
         let resp = RemoveResponse::deserialize(&mut self.reader)?;
         match resp {
             RemoveResponse::Ok(_value) => Ok(()),
             RemoveResponse::Err(msg) => Err(MyError::StringError(msg)),
+            RemoveResponse::Unauthorized(msg) => Err(MyError::Unauthorized(msg)),
+        }
+    }
+
+    /// Ask the server to write a backup of the selected namespace to `dest`, a
+    /// directory on the server host.
+    pub fn backup(&mut self, dest: String) -> Result<BackupManifest> {
+        serde_json::to_writer(
+            &mut self.writer,
+            &Request::Backup {
+                dest,
+                namespace: self.namespace.clone(),
+            },
+        )?;
+        self.writer.flush()?;
+        let resp = BackupResponse::deserialize(&mut self.reader)?;
+        match resp {
+            BackupResponse::Ok(manifest) => Ok(manifest),
+            BackupResponse::Err(msg) => Err(MyError::StringError(msg)),
+            BackupResponse::Unauthorized(msg) => Err(MyError::Unauthorized(msg)),
+        }
+    }
+
+    /// Describe the server and the selected namespace. Needs admin rights.
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        let namespace = self.namespace.clone();
+        self.send(&Request::Info { namespace })?;
+        match InfoResponse::deserialize(&mut self.reader)? {
+            InfoResponse::Ok(info) => Ok(info),
+            InfoResponse::Err(msg) => Err(MyError::StringError(msg)),
+            InfoResponse::Unauthorized(msg) => Err(MyError::Unauthorized(msg)),
+        }
+    }
+
+    /// Ask the server to compact the selected namespace now. Needs admin rights.
+    pub fn compact(&mut self) -> Result<()> {
+        let namespace = self.namespace.clone();
+        self.send(&Request::Compact { namespace })?;
+        self.admin_response()
+    }
+
+    /// Ask the server to sync every write to the selected namespace to disk.
+    /// Needs admin rights.
+    pub fn flush(&mut self) -> Result<()> {
+        let namespace = self.namespace.clone();
+        self.send(&Request::Flush { namespace })?;
+        self.admin_response()
+    }
+
+    /// Count the keys of the selected namespace. Needs admin rights.
+    pub fn dbsize(&mut self) -> Result<u64> {
+        let namespace = self.namespace.clone();
+        self.send(&Request::DbSize { namespace })?;
+        match DbSizeResponse::deserialize(&mut self.reader)? {
+            DbSizeResponse::Ok(keys) => Ok(keys),
+            DbSizeResponse::Err(msg) => Err(MyError::StringError(msg)),
+            DbSizeResponse::Unauthorized(msg) => Err(MyError::Unauthorized(msg)),
         }
     }
+
+    /// Get the parameters of the server and its engine, or only `name`.
+    /// Needs admin rights.
+    pub fn config_get(&mut self, name: Option<String>) -> Result<BTreeMap<String, String>> {
+        self.send(&Request::ConfigGet { name })?;
+        match ConfigResponse::deserialize(&mut self.reader)? {
+            ConfigResponse::Ok(config) => Ok(config),
+            ConfigResponse::Err(msg) => Err(MyError::StringError(msg)),
+            ConfigResponse::Unauthorized(msg) => Err(MyError::Unauthorized(msg)),
+        }
+    }
+
+    /// Change a parameter of the server or its engine. Needs admin rights.
+    pub fn config_set(&mut self, name: String, value: String) -> Result<()> {
+        self.send(&Request::ConfigSet { name, value })?;
+        self.admin_response()
+    }
+
+    /// Make a follower the leader: it stops replicating and accepts writes.
+    /// Needs admin rights.
+    pub fn promote(&mut self) -> Result<()> {
+        self.send(&Request::Promote)?;
+        self.admin_response()
+    }
+
+    /// Add node `id`, whose Raft transport listens on `addr`, to the Raft
+    /// cluster of the server. Needs admin rights on the leader of the cluster.
+    pub fn add_node(&mut self, id: u64, addr: String) -> Result<()> {
+        self.send(&Request::AddNode { id, addr })?;
+        self.admin_response()
+    }
+
+    /// Remove node `id` from the Raft cluster of the server. Needs admin
+    /// rights on the leader of the cluster.
+    pub fn remove_node(&mut self, id: u64) -> Result<()> {
+        self.send(&Request::RemoveNode { id })?;
+        self.admin_response()
+    }
+
+    fn send(&mut self, request: &Request) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, request)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn admin_response(&mut self) -> Result<()> {
+        match AdminResponse::deserialize(&mut self.reader)? {
+            AdminResponse::Ok(()) => Ok(()),
+            AdminResponse::Err(msg) => Err(MyError::StringError(msg)),
+            AdminResponse::Unauthorized(msg) => Err(MyError::Unauthorized(msg)),
+        }
+    }
+
+    /// Get the string value of a given string key from the server.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Set the value of a string key in the server.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Remove a string key in the server.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Get the value of a given key from the server, decoded with `codec`.
+    pub fn get_typed<V: DeserializeOwned, C: Codec>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        codec: &C,
+    ) -> Result<Option<V>> {
+        self.get_bytes(key.into())?
+            .map(|bytes| codec.decode(&bytes))
+            .transpose()
+    }
+
+    /// Set the value of a key in the server, encoded with `codec`.
+    pub fn set_typed<V: Serialize, C: Codec>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: &V,
+        codec: &C,
+    ) -> Result<()> {
+        let bytes = codec.encode(value)?;
+        self.set_bytes(key.into(), bytes)
+    }
 }
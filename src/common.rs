@@ -1,26 +1,299 @@
+use crate::auth::Credentials;
+use crate::backup::BackupManifest;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        /// Namespace of the key, the default one if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Set {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        /// Namespace of the key, the default one if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Remove {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        /// Namespace of the key, the default one if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Backup {
+        dest: String,
+        /// Namespace to back up, the default one if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Auth {
+        credentials: Credentials,
+    },
+    Info {
+        /// Namespace to describe, the default one if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Compact {
+        /// Namespace to compact, the default one if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Flush {
+        /// Namespace to sync, the default one if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    DbSize {
+        /// Namespace to count the keys of, the default one if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    ConfigGet {
+        /// Parameter to read, every parameter if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    ConfigSet {
+        name: String,
+        value: String,
+    },
+    /// Sent by a follower: the connection then streams the default namespace.
+    Replicate,
+    /// Makes a follower the leader.
+    Promote,
+    /// Adds a node to the Raft cluster of the server, which must lead it.
+    AddNode {
+        id: u64,
+        addr: String,
+    },
+    /// Removes a node from the Raft cluster of the server, which must lead it.
+    RemoveNode {
+        id: u64,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
-    Ok(Option<String>),
+    Ok(#[serde(with = "option_bytes")] Option<Vec<u8>>),
     Err(String),
+    Unauthorized(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
     Err(String),
+    Unauthorized(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
     Err(String),
+    Unauthorized(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BackupResponse {
+    Ok(BackupManifest),
+    Err(String),
+    Unauthorized(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok(()),
+    Unauthorized(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum InfoResponse {
+    Ok(ServerInfo),
+    Err(String),
+    Unauthorized(String),
+}
+
+/// Response of the admin commands which return nothing.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminResponse {
+    Ok(()),
+    Err(String),
+    Unauthorized(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DbSizeResponse {
+    Ok(u64),
+    Err(String),
+    Unauthorized(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ConfigResponse {
+    Ok(BTreeMap<String, String>),
+    Err(String),
+    Unauthorized(String),
+}
+
+/// State of a server and of a namespace of its engine, returned by `KvsClient::info`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Version of the server.
+    pub version: String,
+    /// Name of the storage engine.
+    pub engine: String,
+    /// Seconds since the server started.
+    pub uptime_secs: u64,
+    /// Live keys of the namespace.
+    pub keys: u64,
+    /// Size of the log or data files, if the engine tracks it.
+    pub disk_bytes: Option<u64>,
+    /// Bytes of the log held by stale records, if the engine tracks them.
+    pub stale_bytes: Option<u64>,
+    /// Replication role, such as `leader` or `follower of 127.0.0.1:4000`, if
+    /// the server replicates.
+    #[serde(default)]
+    pub role: Option<String>,
+}
+
+/// Serde helpers for byte keys and values.
+///
+/// Bytes which are valid UTF-8 are written as a plain JSON string, so logs and
+/// requests produced by the String API keep their previous shape. Any other
+/// bytes are written in base64 under a tag, as `{"b64": "AP8K"}`. Both forms
+/// are accepted on read, and so are the arrays of numbers written before.
+pub(crate) mod bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use serde::de::{self, MapAccess, SeqAccess, Visitor};
+    use serde::ser::SerializeMap;
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    /// Key of the object holding bytes which are not valid UTF-8.
+    const BASE64_TAG: &str = "b64";
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(BASE64_TAG, &STANDARD.encode(bytes))?;
+                map.end()
+            }
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    pub(crate) struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string, {\"b64\": ...} or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            Ok(v.as_bytes().to_vec())
+        }
+
+        fn visit_string<E: de::Error>(self, v: String) -> Result<Vec<u8>, E> {
+            Ok(v.into_bytes())
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(b) = seq.next_element()? {
+                bytes.push(b);
+            }
+            Ok(bytes)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Vec<u8>, A::Error> {
+            let encoded: String = match map.next_key::<String>()? {
+                Some(tag) if tag == BASE64_TAG => map.next_value()?,
+                Some(tag) => return Err(de::Error::unknown_field(&tag, &[BASE64_TAG])),
+                None => return Err(de::Error::missing_field(BASE64_TAG)),
+            };
+            if let Some(tag) = map.next_key::<String>()? {
+                return Err(de::Error::unknown_field(&tag, &[BASE64_TAG]));
+            }
+            STANDARD.decode(&encoded).map_err(de::Error::custom)
+        }
+    }
+}
+
+/// Same as [`bytes`] for optional values.
+pub(crate) mod option_bytes {
+    use super::bytes::BytesVisitor;
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&Wrapper(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        deserializer.deserialize_option(OptionVisitor)
+    }
+
+    struct Wrapper<'a>(&'a [u8]);
+
+    impl serde::Serialize for Wrapper<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::bytes::serialize(self.0, serializer)
+        }
+    }
+
+    struct OptionVisitor;
+
+    impl<'de> Visitor<'de> for OptionVisitor {
+        type Value = Option<Vec<u8>>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("null, a string, {\"b64\": ...} or an array of bytes")
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+            d.deserialize_any(BytesVisitor).map(Some)
+        }
+    }
 }
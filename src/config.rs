@@ -0,0 +1,234 @@
+//! Settings of `kvs-server`
+//!
+//! Each setting can come from, in decreasing order of precedence:
+//!
+//! 1. the command line flag of the same name, e.g. `--max-connections`;
+//! 2. the environment variable `KVS_<NAME>`, e.g. `KVS_MAX_CONNECTIONS`;
+//! 3. the TOML file given with `--config` or `KVS_CONFIG`;
+//! 4. the built-in default.
+//!
+//! ```toml
+//! data_dir = "/var/lib/kvs"
+//! addr = "0.0.0.0:4000"
+//! metrics_addr = "127.0.0.1:9100"
+//! engine = "kvs"
+//! threads = 8
+//! sync = "every-100ms"
+//! compaction_threshold = 1048576
+//! memtable_bytes = 4194304
+//! log_level = "info"
+//! max_connections = 1024
+//! idle_timeout = 60
+//! max_key_bytes = 1024
+//! max_value_bytes = 1048576
+//! ```
+//!
+//! Unknown keys, invalid values and inconsistent settings are reported when
+//! the server starts.
+use crate::engine::Durability;
+use crate::errors::{MyError, Result};
+use log::LevelFilter;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Prefix of the environment variables holding settings.
+pub const ENV_PREFIX: &str = "KVS_";
+
+/// Names of the storage engines.
+pub const ENGINES: [&str; 4] = ["kvs", "sled", "memory", "lsm"];
+
+/// Settings of `kvs-server`. Unset settings are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Directory of the store. Required unless the engine is memory.
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    /// Address clients connect to.
+    #[serde(default)]
+    pub addr: Option<SocketAddr>,
+    /// Address of the Prometheus metrics endpoint, disabled if unset.
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Storage engine, one of `ENGINES`.
+    #[serde(default)]
+    pub engine: Option<String>,
+    /// Threads serving connections. 64 if unset.
+    #[serde(default)]
+    pub threads: Option<usize>,
+    /// When writes are synced to disk.
+    #[serde(default, deserialize_with = "parsed")]
+    pub sync: Option<Durability>,
+    /// Stale bytes in a `KvStore` log which trigger its compaction.
+    #[serde(default)]
+    pub compaction_threshold: Option<u64>,
+    /// Size of the memtable of the lsm engine, written to disk when full.
+    #[serde(default)]
+    pub memtable_bytes: Option<u64>,
+    /// Most verbose level of the server log.
+    #[serde(default, deserialize_with = "parsed")]
+    pub log_level: Option<LevelFilter>,
+    /// Connections served at once. Further connections are closed.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Seconds a connection may wait between requests before it is closed.
+    /// 60 if unset.
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+    /// Longest key accepted.
+    #[serde(default)]
+    pub max_key_bytes: Option<usize>,
+    /// Longest value accepted.
+    #[serde(default)]
+    pub max_value_bytes: Option<usize>,
+}
+
+impl Config {
+    /// Reads a TOML configuration file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| MyError::StringError(format!("{}: {}", path.display(), e)))?;
+        toml::from_str(&content)
+            .map_err(|e| MyError::StringError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Reads the `KVS_*` environment variables. Empty variables are ignored.
+    pub fn from_env() -> Result<Config> {
+        Config::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the settings from `lookup`, which returns the value of an
+    /// environment variable.
+    pub fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Config> {
+        let var = |name: &str| {
+            lookup(&format!("{}{}", ENV_PREFIX, name)).filter(|value| !value.is_empty())
+        };
+        Ok(Config {
+            data_dir: var("DATA_DIR").map(PathBuf::from),
+            addr: parse_var("ADDR", var("ADDR"))?,
+            metrics_addr: parse_var("METRICS_ADDR", var("METRICS_ADDR"))?,
+            engine: var("ENGINE"),
+            threads: parse_var("THREADS", var("THREADS"))?,
+            sync: parse_var("SYNC", var("SYNC"))?,
+            compaction_threshold: parse_var("COMPACTION_THRESHOLD", var("COMPACTION_THRESHOLD"))?,
+            memtable_bytes: parse_var("MEMTABLE_BYTES", var("MEMTABLE_BYTES"))?,
+            log_level: parse_var("LOG_LEVEL", var("LOG_LEVEL"))?,
+            max_connections: parse_var("MAX_CONNECTIONS", var("MAX_CONNECTIONS"))?,
+            idle_timeout: parse_var("IDLE_TIMEOUT", var("IDLE_TIMEOUT"))?,
+            max_key_bytes: parse_var("MAX_KEY_BYTES", var("MAX_KEY_BYTES"))?,
+            max_value_bytes: parse_var("MAX_VALUE_BYTES", var("MAX_VALUE_BYTES"))?,
+        })
+    }
+
+    /// Returns these settings, overridden by those set in `over`.
+    pub fn merge(self, over: Config) -> Config {
+        Config {
+            data_dir: over.data_dir.or(self.data_dir),
+            addr: over.addr.or(self.addr),
+            metrics_addr: over.metrics_addr.or(self.metrics_addr),
+            engine: over.engine.or(self.engine),
+            threads: over.threads.or(self.threads),
+            sync: over.sync.or(self.sync),
+            compaction_threshold: over.compaction_threshold.or(self.compaction_threshold),
+            memtable_bytes: over.memtable_bytes.or(self.memtable_bytes),
+            log_level: over.log_level.or(self.log_level),
+            max_connections: over.max_connections.or(self.max_connections),
+            idle_timeout: over.idle_timeout.or(self.idle_timeout),
+            max_key_bytes: over.max_key_bytes.or(self.max_key_bytes),
+            max_value_bytes: over.max_value_bytes.or(self.max_value_bytes),
+        }
+    }
+
+    /// Checks that the settings are consistent.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(engine) = &self.engine {
+            if !ENGINES.contains(&engine.to_ascii_lowercase().as_str()) {
+                return Err(MyError::StringError(format!(
+                    "Invalid engine {}, expected one of {}",
+                    engine,
+                    ENGINES.join(", ")
+                )));
+            }
+        }
+        let counts = [
+            ("threads", self.threads.map(|n| n as u64)),
+            ("compaction_threshold", self.compaction_threshold),
+            ("memtable_bytes", self.memtable_bytes),
+            ("max_connections", self.max_connections.map(|n| n as u64)),
+            ("idle_timeout", self.idle_timeout),
+            ("max_key_bytes", self.max_key_bytes.map(|n| n as u64)),
+            ("max_value_bytes", self.max_value_bytes.map(|n| n as u64)),
+        ];
+        for (name, value) in counts.iter() {
+            if *value == Some(0) {
+                return Err(MyError::StringError(format!("{} must be at least 1", name)));
+            }
+        }
+        if self.metrics_addr.is_some() && self.metrics_addr == self.addr {
+            return Err(MyError::StringError(
+                "metrics_addr must differ from addr".to_owned(),
+            ));
+        }
+        if let Some(dir) = &self.data_dir {
+            if dir.exists() && !dir.is_dir() {
+                return Err(MyError::StringError(format!(
+                    "data_dir {} is not a directory",
+                    dir.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates the data directory if needed, and checks that files can be
+/// created in it.
+pub fn check_data_dir(dir: &Path) -> Result<()> {
+    let not_writable = |e: std::io::Error| {
+        MyError::StringError(format!(
+            "Data directory {} is not writable: {}",
+            dir.display(),
+            e
+        ))
+    };
+    fs::create_dir_all(dir).map_err(not_writable)?;
+    tempfile::Builder::new()
+        .prefix(".write-check")
+        .tempfile_in(dir)
+        .map_err(not_writable)?;
+    Ok(())
+}
+
+fn parse_var<T>(name: &str, value: Option<String>) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match value {
+        None => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(|e| {
+            MyError::StringError(format!(
+                "{}{}: invalid value {:?}: {}",
+                ENV_PREFIX, name, value, e
+            ))
+        }),
+    }
+}
+
+/// Deserializes a setting written as a string, such as `sync = "every-write"`.
+fn parsed<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(de::Error::custom)
+}
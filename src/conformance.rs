@@ -0,0 +1,298 @@
+//! Behavioural contract every `KvsEngine` must honour.
+//!
+//! Each check takes a function opening the engine in a directory, runs in a
+//! fresh temporary directory and panics when the engine breaks the contract.
+//! Engine errors are returned as is. The `engine_conformance_tests!` macro
+//! turns every check into a `#[test]`:
+//!
+//! ```no_run
+//! mod kvs_engine {
+//!     kvs::engine_conformance_tests!(|path| kvs::KvStore::open(path));
+//! }
+//! ```
+//!
+//! An engine which keeps no files, such as `MemoryKvsEngine::new`, can run
+//! every check but `persistence_across_reopen` and `many_overwrites`.
+use crate::engine::{key_range, KvsEngine, KvsSnapshot};
+use crate::{MyError, Result};
+use std::path::Path;
+use std::thread;
+use tempfile::TempDir;
+
+/// Number of threads used by `concurrent_writers`.
+const WRITER_THREADS: usize = 8;
+/// Number of keys written by each thread of `concurrent_writers`.
+const KEYS_PER_WRITER: usize = 100;
+
+/// Runs every check of the contract against the engine opened by `open`.
+pub fn run_all<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine + Clone + Send + 'static,
+    F: Fn(&Path) -> Result<E>,
+{
+    get_stored_value(&open)?;
+    overwrite_value(&open)?;
+    get_non_existent_value(&open)?;
+    remove_key(&open)?;
+    remove_non_existent_key(&open)?;
+    binary_key_value(&open)?;
+    persistence_across_reopen(&open)?;
+    snapshot_isolation(&open)?;
+    scan_ranges(&open)?;
+    many_overwrites(&open)?;
+    concurrent_writers(&open)?;
+    Ok(())
+}
+
+fn temp_dir() -> TempDir {
+    TempDir::new().expect("unable to create temporary working directory")
+}
+
+/// Values which were set can be read back.
+pub fn get_stored_value<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Setting an existing key replaces its value.
+pub fn overwrite_value<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Getting a missing key returns `None`, not an error.
+pub fn get_non_existent_value<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+/// A removed key is gone, and can be set again.
+pub fn remove_key<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Removing a missing key, or a key removed already, fails with `KeyNotFound`.
+pub fn remove_non_existent_key<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    assert_key_not_found(engine.remove("key1".to_owned()));
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_key_not_found(engine.remove("key1".to_owned()));
+    Ok(())
+}
+
+fn assert_key_not_found(result: Result<()>) {
+    match result {
+        Err(MyError::KeyNotFound) => {}
+        Err(err) => panic!("expected KeyNotFound, got error {}", err),
+        Ok(()) => panic!("expected KeyNotFound, got Ok"),
+    }
+}
+
+/// Keys and values are arbitrary bytes, including invalid UTF-8 and empty values.
+pub fn binary_key_value<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    engine.set_bytes(vec![0, 159, 146, 150], vec![255, 0, 1])?;
+    engine.set_bytes(b"empty".to_vec(), Vec::new())?;
+    assert_eq!(
+        engine.get_bytes(&[0, 159, 146, 150])?,
+        Some(vec![255, 0, 1])
+    );
+    assert_eq!(engine.get_bytes(b"empty")?, Some(Vec::new()));
+    Ok(())
+}
+
+/// Every acknowledged set and remove survives dropping and reopening the engine.
+pub fn persistence_across_reopen<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    engine.set("key1".to_owned(), "value4".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    drop(engine);
+
+    let mut engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_key_not_found(engine.remove("key2".to_owned()));
+    Ok(())
+}
+
+/// A snapshot sees the data as of the moment it was taken.
+pub fn snapshot_isolation<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut snapshot = engine.snapshot()?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key4".to_owned())?, None);
+    assert_eq!(
+        snapshot.iter().collect::<Result<Vec<_>>>()?,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec())
+        ]
+    );
+    Ok(())
+}
+
+/// Scans return the live keys of a range in byte order, whatever the bounds.
+pub fn scan_ranges<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    for key_id in (0..10).rev() {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    engine.remove("key4".to_owned())?;
+
+    let mut snapshot = engine.snapshot()?;
+    let keys = |snapshot: &mut E::Snapshot, range| -> Result<Vec<String>> {
+        snapshot
+            .scan(range)
+            .map(|entry| entry.map(|(key, _)| String::from_utf8(key).unwrap()))
+            .collect()
+    };
+    assert_eq!(
+        keys(&mut snapshot, key_range(b"key3".to_vec()..b"key6".to_vec()))?,
+        vec!["key3", "key5"]
+    );
+    assert_eq!(
+        keys(
+            &mut snapshot,
+            key_range(b"key7".to_vec()..=b"key8".to_vec())
+        )?,
+        vec!["key7", "key8"]
+    );
+    assert_eq!(
+        keys(&mut snapshot, key_range(b"key8".to_vec()..))?,
+        vec!["key8", "key9"]
+    );
+    assert_eq!(
+        keys(&mut snapshot, key_range(..b"key2".to_vec()))?,
+        vec!["key0", "key1"]
+    );
+    assert!(keys(&mut snapshot, key_range(b"x".to_vec()..))?.is_empty());
+    assert_eq!(snapshot.iter().count(), 9);
+    Ok(())
+}
+
+/// Rewriting the same keys many times, which triggers compaction in
+/// log-structured engines, keeps the latest values, before and after reopening.
+pub fn many_overwrites<E: KvsEngine, F: Fn(&Path) -> Result<E>>(open: F) -> Result<()> {
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            engine.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 0..100 {
+        assert_eq!(engine.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    drop(engine);
+
+    let mut engine = open(dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(engine.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
+/// Clones of an engine can write from several threads at once without losing writes.
+pub fn concurrent_writers<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine + Clone + Send + 'static,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let mut engine = open(dir.path())?;
+    let writers: Vec<_> = (0..WRITER_THREADS)
+        .map(|thread_id| {
+            let mut engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..KEYS_PER_WRITER {
+                    engine.set(format!("key{}-{}", thread_id, i), format!("{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().expect("writer thread panicked")?;
+    }
+
+    for thread_id in 0..WRITER_THREADS {
+        for i in 0..KEYS_PER_WRITER {
+            assert_eq!(
+                engine.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("{}", i))
+            );
+        }
+    }
+    assert_eq!(
+        engine.snapshot()?.iter().count(),
+        WRITER_THREADS * KEYS_PER_WRITER
+    );
+    Ok(())
+}
+
+/// Generates one `#[test]` per check of `kvs::conformance`, opening the engine
+/// with the given closure from a directory path.
+#[macro_export]
+macro_rules! engine_conformance_tests {
+    ($open:expr) => {
+        $crate::engine_conformance_tests!(@tests $open;
+            get_stored_value,
+            overwrite_value,
+            get_non_existent_value,
+            remove_key,
+            remove_non_existent_key,
+            binary_key_value,
+            persistence_across_reopen,
+            snapshot_isolation,
+            scan_ranges,
+            many_overwrites,
+            concurrent_writers
+        );
+    };
+    (@tests $open:expr; $($check:ident),*) => {
+        $(
+            #[test]
+            fn $check() -> $crate::Result<()> {
+                $crate::conformance::$check($open)
+            }
+        )*
+    };
+}
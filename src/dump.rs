@@ -0,0 +1,312 @@
+//! Engine-independent dump format, used to move data between engines and machines.
+//!
+//! Two encodings of the same stream of key/value pairs are supported:
+//!
+//! - `JsonLines`: one `{"key": ..., "value": ...}` object per line. Keys and
+//!   values which are valid UTF-8 are written as strings, other bytes in
+//!   base64 as `{"b64": "AP8K"}`.
+//! - `Binary`: the magic bytes `KVSDUMP\x02`, then for each pair the key
+//!   length, the key, the value length and the value, lengths being
+//!   little-endian `u32`. The stream ends with a `u32::MAX` length so that
+//!   truncated dumps are detected. Dumps starting with `KVSDUMP\x01`, written
+//!   before namespaces, are still read.
+//!
+//! The pairs of the default namespace come first. Those of another namespace
+//! follow a `{"namespace": ...}` line, or a `u32::MAX - 1` length and the name
+//! of the namespace in binary dumps.
+use crate::common::bytes;
+use crate::engine::{KvsEngine, KvsSnapshot, DEFAULT_NAMESPACE};
+use crate::{MyError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::str::FromStr;
+
+const BINARY_MAGIC: &[u8; 8] = b"KVSDUMP\x02";
+/// Magic bytes of the binary dumps written before namespaces.
+const BINARY_MAGIC_V1: &[u8; 8] = b"KVSDUMP\x01";
+const BINARY_END: u32 = u32::MAX;
+/// Length introducing the name of a namespace in binary dumps.
+const BINARY_NAMESPACE: u32 = u32::MAX - 1;
+/// Number of pairs handed to `KvsEngine::set_batch` at once while importing.
+const IMPORT_BATCH: usize = 1024;
+
+/// Encoding of a dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// Compact length-prefixed binary records.
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<DumpFormat> {
+        match s.to_ascii_lowercase().as_str() {
+            "jsonl" | "json-lines" => Ok(DumpFormat::JsonLines),
+            "binary" | "bin" => Ok(DumpFormat::Binary),
+            _ => Err(MyError::StringError(format!("Unknown dump format {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DumpFormat::JsonLines => write!(f, "jsonl"),
+            DumpFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(with = "bytes")]
+    key: Vec<u8>,
+    #[serde(with = "bytes")]
+    value: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NamespaceRecord {
+    namespace: String,
+}
+
+/// A line of a JSON Lines dump.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Pair(Record),
+    Namespace(NamespaceRecord),
+}
+
+/// Writes every key/value pair of `snapshot` to `writer`. Returns the number of pairs written.
+pub fn export<S: KvsSnapshot, W: Write>(
+    snapshot: &mut S,
+    writer: W,
+    format: DumpFormat,
+) -> Result<u64> {
+    let mut dump = DumpWriter::new(writer, format)?;
+    dump.pairs(snapshot)?;
+    dump.finish()
+}
+
+/// Writes every key/value pair of every namespace of `engine` to `writer`.
+/// Returns the number of pairs written.
+pub fn export_all<E: KvsEngine, W: Write>(
+    engine: &mut E,
+    writer: W,
+    format: DumpFormat,
+) -> Result<u64> {
+    let mut dump = DumpWriter::new(writer, format)?;
+    dump.pairs(&mut engine.snapshot()?)?;
+    for name in engine.namespaces()? {
+        dump.namespace(&name)?;
+        dump.pairs(&mut engine.namespace(&name)?.snapshot()?)?;
+    }
+    dump.finish()
+}
+
+struct DumpWriter<W: Write> {
+    writer: BufWriter<W>,
+    format: DumpFormat,
+    count: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    fn new(writer: W, format: DumpFormat) -> Result<DumpWriter<W>> {
+        let mut writer = BufWriter::new(writer);
+        if format == DumpFormat::Binary {
+            writer.write_all(BINARY_MAGIC)?;
+        }
+        Ok(DumpWriter {
+            writer,
+            format,
+            count: 0,
+        })
+    }
+
+    /// Starts the pairs of the namespace `name`.
+    fn namespace(&mut self, name: &str) -> Result<()> {
+        match self.format {
+            DumpFormat::JsonLines => {
+                let record = NamespaceRecord {
+                    namespace: name.to_owned(),
+                };
+                serde_json::to_writer(&mut self.writer, &record)?;
+                self.writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                self.writer.write_all(&BINARY_NAMESPACE.to_le_bytes())?;
+                write_chunk(&mut self.writer, name.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn pairs<S: KvsSnapshot>(&mut self, snapshot: &mut S) -> Result<()> {
+        for entry in snapshot.iter() {
+            let (key, value) = entry?;
+            match self.format {
+                DumpFormat::JsonLines => {
+                    serde_json::to_writer(&mut self.writer, &Record { key, value })?;
+                    self.writer.write_all(b"\n")?;
+                }
+                DumpFormat::Binary => {
+                    write_chunk(&mut self.writer, &key)?;
+                    write_chunk(&mut self.writer, &value)?;
+                }
+            }
+            self.count += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<u64> {
+        if self.format == DumpFormat::Binary {
+            self.writer.write_all(&BINARY_END.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+/// Loads every key/value pair of a dump into `engine`, each in its namespace.
+/// Returns the number of pairs loaded.
+///
+/// Existing keys are overwritten; keys absent from the dump are left untouched.
+pub fn import<E: KvsEngine, R: Read>(engine: &mut E, reader: R, format: DumpFormat) -> Result<u64> {
+    let mut reader = BufReader::new(reader);
+    let mut loader = Loader {
+        engine,
+        namespace: None,
+        batch: Vec::with_capacity(IMPORT_BATCH),
+        count: 0,
+    };
+
+    match format {
+        DumpFormat::JsonLines => {
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line)? {
+                    Line::Pair(record) => loader.push(record.key, record.value)?,
+                    Line::Namespace(record) => loader.namespace(&record.namespace)?,
+                }
+            }
+        }
+        DumpFormat::Binary => {
+            let mut magic = [0; 8];
+            reader.read_exact(&mut magic)?;
+            let namespaces = match &magic {
+                BINARY_MAGIC => true,
+                BINARY_MAGIC_V1 => false,
+                _ => return Err(MyError::StringError("Not a binary kvs dump".to_owned())),
+            };
+            loop {
+                let key = match read_chunk(&mut reader)? {
+                    Chunk::Bytes(key) => key,
+                    Chunk::Namespace if namespaces => {
+                        let name = match read_chunk(&mut reader)? {
+                            Chunk::Bytes(name) => String::from_utf8(name)?,
+                            _ => {
+                                return Err(MyError::StringError(
+                                    "Dump ends after a namespace".to_owned(),
+                                ))
+                            }
+                        };
+                        loader.namespace(&name)?;
+                        continue;
+                    }
+                    Chunk::Namespace => {
+                        return Err(MyError::StringError(
+                            "Namespace in a dump without namespaces".to_owned(),
+                        ))
+                    }
+                    Chunk::End => break,
+                };
+                let value = match read_chunk(&mut reader)? {
+                    Chunk::Bytes(value) => value,
+                    _ => return Err(MyError::StringError("Dump ends after a key".to_owned())),
+                };
+                loader.push(key, value)?;
+            }
+        }
+    }
+    loader.flush()?;
+    Ok(loader.count)
+}
+
+/// Loads pairs in batches into the namespace of the dump being read.
+struct Loader<'a, E: KvsEngine> {
+    engine: &'a mut E,
+    /// Namespace the pairs go to, `None` for the default one.
+    namespace: Option<E>,
+    batch: Vec<(Vec<u8>, Vec<u8>)>,
+    count: u64,
+}
+
+impl<E: KvsEngine> Loader<'_, E> {
+    fn push(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.batch.push((key, value));
+        if self.batch.len() == IMPORT_BATCH {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn namespace(&mut self, name: &str) -> Result<()> {
+        self.flush()?;
+        self.namespace = if name == DEFAULT_NAMESPACE {
+            None
+        } else {
+            Some(self.engine.namespace(name)?)
+        };
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.count += self.batch.len() as u64;
+        let batch = std::mem::take(&mut self.batch);
+        match &mut self.namespace {
+            Some(namespace) => namespace.set_batch(batch),
+            None => self.engine.set_batch(batch),
+        }
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, chunk: &[u8]) -> Result<()> {
+    if chunk.len() >= BINARY_NAMESPACE as usize {
+        return Err(MyError::StringError(
+            "Keys and values must be smaller than 4 GiB".to_owned(),
+        ));
+    }
+    writer.write_all(&(chunk.len() as u32).to_le_bytes())?;
+    writer.write_all(chunk)?;
+    Ok(())
+}
+
+/// What a length read from a binary dump introduces.
+enum Chunk {
+    Bytes(Vec<u8>),
+    Namespace,
+    End,
+}
+
+/// Reads a length-prefixed chunk, or the marker read instead of a length.
+fn read_chunk<R: Read>(reader: &mut R) -> Result<Chunk> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = match u32::from_le_bytes(len) {
+        BINARY_END => return Ok(Chunk::End),
+        BINARY_NAMESPACE => return Ok(Chunk::Namespace),
+        len => len,
+    };
+    let mut chunk = vec![0; len as usize];
+    reader.read_exact(&mut chunk)?;
+    Ok(Chunk::Bytes(chunk))
+}
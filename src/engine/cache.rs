@@ -0,0 +1,161 @@
+//! Size-bounded cache of the values of a `KvStore`
+//!
+//! Eviction follows the CLOCK algorithm: entries sit in a ring in insertion
+//! order and the hand gives a second chance to the ones read since it last
+//! passed them. Writes to a key invalidate its entry, so the cache never
+//! returns a value older than the log.
+use std::collections::{HashMap, VecDeque};
+
+/// Estimated memory used by an entry besides its key and value.
+const ENTRY_BYTES: u64 = 64;
+
+/// Counters of a `KvStore` value cache, returned by `KvStore::cache_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Values held in the cache.
+    pub entries: u64,
+    /// Estimated memory used by the cache.
+    pub bytes: u64,
+    /// Reads answered from the cache.
+    pub hits: u64,
+    /// Reads of existing keys which had to go to the log.
+    pub misses: u64,
+    /// Values evicted to make room for others.
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// Share of the reads answered from the cache, `0.0` before any read.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+struct CacheEntry {
+    value: Vec<u8>,
+    /// Matches the ring slot which owns the entry.
+    generation: u64,
+    /// Read since the hand last passed it.
+    referenced: bool,
+}
+
+pub(crate) struct ValueCache {
+    capacity: u64,
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    /// Keys in the order the hand visits them. Slots whose generation does
+    /// not match the entry are left over by invalidations and skipped.
+    ring: VecDeque<(Vec<u8>, u64)>,
+    next_generation: u64,
+    bytes: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding at most about `capacity` bytes.
+    pub(crate) fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            capacity,
+            entries: HashMap::new(),
+            ring: VecDeque::new(),
+            next_generation: 0,
+            bytes: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Returns the cached value of `key`.
+    pub(crate) fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.referenced = true;
+                self.hits += 1;
+                Some(entry.value.clone())
+            }
+            None => None,
+        }
+    }
+
+    /// Counts a read which had to go to the log.
+    pub(crate) fn miss(&mut self) {
+        self.misses += 1;
+    }
+
+    /// Caches a value read from the log.
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        let size = entry_size(&key, &value);
+        if size > self.capacity {
+            return;
+        }
+        self.invalidate(&key);
+        while self.bytes + size > self.capacity {
+            self.evict();
+        }
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        self.ring.push_back((key.clone(), generation));
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                generation,
+                referenced: false,
+            },
+        );
+        self.bytes += size;
+    }
+
+    /// Drops the cached value of `key`, if any.
+    pub(crate) fn invalidate(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry_size(key, &entry.value);
+            // Left over slots are skipped by the hand, but a key written and
+            // read over and over would make the ring grow without bound.
+            if self.ring.len() > 2 * self.entries.len() + 16 {
+                let entries = &self.entries;
+                self.ring.retain(|(key, generation)| {
+                    entries.get(key).map(|entry| entry.generation) == Some(*generation)
+                });
+            }
+        }
+    }
+
+    /// Moves the hand until an entry is evicted.
+    fn evict(&mut self) {
+        while let Some((key, generation)) = self.ring.pop_front() {
+            let entry = match self.entries.get_mut(&key) {
+                Some(entry) if entry.generation == generation => entry,
+                _ => continue,
+            };
+            if entry.referenced {
+                entry.referenced = false;
+                self.ring.push_back((key, generation));
+                continue;
+            }
+            let entry = self.entries.remove(&key).unwrap();
+            self.bytes -= entry_size(&key, &entry.value);
+            self.evictions += 1;
+            return;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len() as u64,
+            bytes: self.bytes,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
+    }
+}
+
+fn entry_size(key: &[u8], value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64 + ENTRY_BYTES
+}
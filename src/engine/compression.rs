@@ -0,0 +1,129 @@
+//! Compression of the values written to a `KvStore` log
+//!
+//! A compressed record names its codec in a `codec` field next to the value,
+//! which holds the compressed bytes in base64. Records without the field are
+//! plain values, so logs written before compression was enabled stay readable
+//! and compression can be switched on or off between runs.
+use crate::{MyError, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Level used for zstd, its own default.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression of the values written by a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Values are written as they are.
+    None,
+    /// Fast compression, for values read often.
+    Lz4,
+    /// Smaller records at the cost of more CPU on writes.
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = MyError;
+
+    /// Parses `none`, `lz4` or `zstd`.
+    fn from_str(s: &str) -> Result<Compression> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(MyError::StringError(format!(
+                "Invalid compression {}, expected none, lz4 or zstd",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Codec of a compressed record, as written in the log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Codec {
+    Lz4,
+    Zstd,
+}
+
+/// Counters of the values written by a `KvStore` since it was opened,
+/// returned by `KvStore::compression_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Values written.
+    pub values: u64,
+    /// Values written compressed. The others did not get smaller.
+    pub compressed: u64,
+    /// Size of the values as given.
+    pub raw_bytes: u64,
+    /// Size of the values as written to the log.
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Stored size over raw size, `1.0` before any write.
+    pub fn ratio(&self) -> f64 {
+        match self.raw_bytes {
+            0 => 1.0,
+            raw => self.stored_bytes as f64 / raw as f64,
+        }
+    }
+
+    pub(crate) fn record(&mut self, raw: usize, stored: usize, codec: Option<Codec>) {
+        self.values += 1;
+        if codec.is_some() {
+            self.compressed += 1;
+        }
+        self.raw_bytes += raw as u64;
+        self.stored_bytes += stored as u64;
+    }
+}
+
+/// Returns the value to write and its codec. The value is kept as it is if
+/// compressing does not make it smaller.
+pub(crate) fn compress(
+    compression: Compression,
+    value: Vec<u8>,
+) -> Result<(Vec<u8>, Option<Codec>)> {
+    let (compressed, codec) = match compression {
+        Compression::None => return Ok((value, None)),
+        Compression::Lz4 => (lz4_flex::compress_prepend_size(&value), Codec::Lz4),
+        Compression::Zstd => (zstd::bulk::compress(&value, ZSTD_LEVEL)?, Codec::Zstd),
+    };
+    let encoded = STANDARD.encode(compressed).into_bytes();
+    if encoded.len() < value.len() {
+        Ok((encoded, Some(codec)))
+    } else {
+        Ok((value, None))
+    }
+}
+
+/// Returns the original value of a record.
+pub(crate) fn decompress(value: Vec<u8>, codec: Option<Codec>) -> Result<Vec<u8>> {
+    let codec = match codec {
+        Some(codec) => codec,
+        None => return Ok(value),
+    };
+    let compressed = STANDARD
+        .decode(&value)
+        .map_err(|e| MyError::StringError(format!("Corrupt compressed value: {}", e)))?;
+    match codec {
+        Codec::Lz4 => lz4_flex::decompress_size_prepended(&compressed)
+            .map_err(|e| MyError::StringError(format!("Corrupt lz4 value: {}", e))),
+        Codec::Zstd => Ok(zstd::stream::decode_all(&compressed[..])?),
+    }
+}
@@ -0,0 +1,176 @@
+//! When acknowledged writes reach stable storage.
+use crate::{MyError, Result};
+use log::error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// Durability policy shared by every engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes are handed to the operating system but never explicitly synced.
+    None,
+    /// Every write is synced before it is acknowledged.
+    EveryWrite,
+    /// Writes are synced in the background at this interval.
+    /// Up to one interval of acknowledged writes can be lost on power failure.
+    Interval(Duration),
+    /// Every write is synced before it is acknowledged, but concurrent writers
+    /// waiting for a sync share a single one.
+    GroupCommit,
+}
+
+impl FromStr for Durability {
+    type Err = MyError;
+
+    /// Parses `none`, `every-write`, `every-<N>ms` or `group-commit`.
+    fn from_str(s: &str) -> Result<Durability> {
+        let s = s.to_ascii_lowercase();
+        match s.as_str() {
+            "none" => Ok(Durability::None),
+            "every-write" => Ok(Durability::EveryWrite),
+            "group-commit" => Ok(Durability::GroupCommit),
+            _ => s
+                .strip_prefix("every-")
+                .and_then(|interval| interval.strip_suffix("ms"))
+                .and_then(|ms| ms.parse().ok())
+                .filter(|ms| *ms > 0)
+                .map(|ms| Durability::Interval(Duration::from_millis(ms)))
+                .ok_or_else(|| {
+                    MyError::StringError(format!(
+                        "Invalid durability {}, expected none, every-write, every-<N>ms or group-commit",
+                        s
+                    ))
+                }),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::None => write!(f, "none"),
+            Durability::EveryWrite => write!(f, "every-write"),
+            Durability::Interval(interval) => write!(f, "every-{}ms", interval.as_millis()),
+            Durability::GroupCommit => write!(f, "group-commit"),
+        }
+    }
+}
+
+type SyncFn = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+struct SyncState {
+    /// Every write with a ticket up to this one is on stable storage.
+    synced: u64,
+    /// A thread is running `sync`, on behalf of every waiting writer.
+    syncing: bool,
+}
+
+/// Applies a `Durability` policy to the writes of an engine.
+///
+/// Writers take a ticket once their write is handed to the operating system,
+/// then call `commit` with it before acknowledging the write.
+pub(crate) struct Syncer {
+    policy: Durability,
+    sync: SyncFn,
+    written: AtomicU64,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+impl Syncer {
+    /// Creates a `Syncer` calling `sync` to make every write so far durable.
+    ///
+    /// With `Durability::Interval`, a background thread syncs until the
+    /// `Syncer` is dropped.
+    pub fn new(policy: Durability, sync: SyncFn) -> Arc<Syncer> {
+        let syncer = Arc::new(Syncer {
+            policy,
+            sync,
+            written: AtomicU64::new(0),
+            state: Mutex::new(SyncState {
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        });
+        if let Durability::Interval(interval) = policy {
+            let weak = Arc::downgrade(&syncer);
+            thread::spawn(move || sync_periodically(weak, interval));
+        }
+        syncer
+    }
+
+    pub fn policy(&self) -> Durability {
+        self.policy
+    }
+
+    /// Returns the ticket of a write which was just handed to the operating system.
+    pub fn ticket(&self) -> u64 {
+        self.written.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Waits until the write with `ticket` is as durable as the policy requires.
+    pub fn commit(&self, ticket: u64) -> Result<()> {
+        match self.policy {
+            Durability::None | Durability::Interval(_) => Ok(()),
+            Durability::EveryWrite => (self.sync)(),
+            Durability::GroupCommit => self.sync_up_to(ticket),
+        }
+    }
+
+    /// Syncs every write made so far, whatever the policy.
+    pub fn sync_all(&self) -> Result<()> {
+        self.sync_up_to(self.written.load(Ordering::SeqCst))
+    }
+
+    /// Records that every write made so far is durable, after the engine made
+    /// it so by other means.
+    pub fn mark_synced(&self) {
+        let written = self.written.load(Ordering::SeqCst);
+        let mut state = self.state.lock().unwrap();
+        state.synced = state.synced.max(written);
+    }
+
+    fn sync_up_to(&self, ticket: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            // Become the leader: one sync covers every write made until now.
+            state.syncing = true;
+            let target = self.written.load(Ordering::SeqCst);
+            drop(state);
+            let result = (self.sync)();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+}
+
+fn sync_periodically(syncer: Weak<Syncer>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match syncer.upgrade() {
+            Some(syncer) => {
+                if let Err(err) = syncer.sync_all() {
+                    error!("Background sync failed: {}", err);
+                }
+            }
+            None => return,
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! Encryption at rest of the records of a `KvStore` log
+//!
+//! With a key, every record is sealed: the JSON of the command is encrypted
+//! with an AEAD cipher and written as a `Sealed` command naming the cipher, the
+//! key and the nonce. A sealed record which does not authenticate, or whose key
+//! is unknown, is an error: it is never skipped nor read as plaintext.
+//!
+//! Every record is bound to the cipher, the key id and its offset in the log
+//! as associated data: a sealed record copied or moved to another position,
+//! or relabelled with another cipher, does not authenticate. A record is only
+//! opened with the cipher of its key, never the one it names. The removal of
+//! whole records from the end of the log is still not detected.
+//!
+//! Keys are rotated by opening the store with the new key and the old ones
+//! as previous keys. Compaction moves records, so it re-seals every live
+//! record, with the new key.
+//!
+//! Key bytes are zeroed when an `EncryptionKey` is dropped.
+use crate::engine::Command;
+use crate::{MyError, Result};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Length of the keys of both ciphers.
+const KEY_BYTES: usize = 32;
+/// Length of the nonces of both ciphers.
+const NONCE_BYTES: usize = 12;
+
+/// AEAD cipher sealing the records of a `KvStore`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl FromStr for Cipher {
+    type Err = MyError;
+
+    /// Parses `aes-256-gcm` or `chacha20-poly1305`.
+    fn from_str(s: &str) -> Result<Cipher> {
+        match s.to_ascii_lowercase().as_str() {
+            "aes-256-gcm" => Ok(Cipher::Aes256Gcm),
+            "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            _ => Err(MyError::StringError(format!(
+                "Invalid cipher {}, expected aes-256-gcm or chacha20-poly1305",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cipher::Aes256Gcm => write!(f, "aes-256-gcm"),
+            Cipher::ChaCha20Poly1305 => write!(f, "chacha20-poly1305"),
+        }
+    }
+}
+
+/// A 256-bit key sealing the records of a `KvStore`.
+///
+/// Keys are exchanged as base64, in a file or an environment variable. The id
+/// of a key, derived from it, is written in every record it seals. The key
+/// bytes are zeroed on drop.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: Cipher,
+    key: [u8; KEY_BYTES],
+    id: String,
+}
+
+impl EncryptionKey {
+    /// Creates a key from its 32 bytes.
+    pub fn new(cipher: Cipher, key: &[u8]) -> Result<EncryptionKey> {
+        if key.len() != KEY_BYTES {
+            return Err(MyError::StringError(format!(
+                "Invalid encryption key: expected {} bytes, got {}",
+                KEY_BYTES,
+                key.len()
+            )));
+        }
+        let mut bytes = [0; KEY_BYTES];
+        bytes.copy_from_slice(key);
+        let digest = Sha256::new()
+            .chain_update(b"kvs encryption key")
+            .chain_update(key)
+            .finalize();
+        let id = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(EncryptionKey {
+            cipher,
+            key: bytes,
+            id,
+        })
+    }
+
+    /// Creates a random key.
+    pub fn generate(cipher: Cipher) -> EncryptionKey {
+        let mut key = Zeroizing::new([0; KEY_BYTES]);
+        OsRng.fill_bytes(&mut *key);
+        EncryptionKey::new(cipher, &*key).expect("generated keys have the right length")
+    }
+
+    /// Parses a key written in base64.
+    pub fn from_base64(cipher: Cipher, encoded: &str) -> Result<EncryptionKey> {
+        let key = STANDARD
+            .decode(encoded.trim())
+            .map(Zeroizing::new)
+            .map_err(|e| MyError::StringError(format!("Invalid encryption key: {}", e)))?;
+        EncryptionKey::new(cipher, &key)
+    }
+
+    /// Reads a base64 key from a file.
+    pub fn from_file(cipher: Cipher, path: &Path) -> Result<EncryptionKey> {
+        let encoded = Zeroizing::new(std::fs::read_to_string(path)?);
+        EncryptionKey::from_base64(cipher, &encoded)
+    }
+
+    /// Reads a base64 key from an environment variable.
+    pub fn from_env(cipher: Cipher, var: &str) -> Result<EncryptionKey> {
+        let encoded = std::env::var(var).map(Zeroizing::new).map_err(|_| {
+            MyError::StringError(format!("Environment variable {} is not set", var))
+        })?;
+        EncryptionKey::from_base64(cipher, &encoded)
+    }
+
+    /// Returns the key in base64, as read by `from_base64`.
+    pub fn to_base64(&self) -> String {
+        STANDARD.encode(self.key)
+    }
+
+    /// Returns the id written in the records sealed with this key.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the cipher this key seals and opens records with.
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// Seals `plaintext` into its nonce followed by the ciphertext, bound to
+    /// `aad`.
+    pub(crate) fn seal_bytes(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let (mut sealed, data) = self.seal(plaintext, aad)?;
+        sealed.extend(data);
+        Ok(sealed)
+    }
+
+    /// Opens bytes sealed by `seal_bytes` with the same `aad`.
+    pub(crate) fn open_bytes(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_BYTES {
+            return Err(MyError::StringError("Corrupt sealed data".to_owned()));
+        }
+        let (nonce, data) = sealed.split_at(NONCE_BYTES);
+        self.open(nonce, data, aad)
+    }
+
+    fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let sealed = match self.cipher {
+            Cipher::Aes256Gcm => {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                Aes256Gcm::new(&self.key.into())
+                    .encrypt(&nonce, payload)
+                    .map(|data| (nonce.to_vec(), data))
+            }
+            Cipher::ChaCha20Poly1305 => {
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                ChaCha20Poly1305::new(&self.key.into())
+                    .encrypt(&nonce, payload)
+                    .map(|data| (nonce.to_vec(), data))
+            }
+        };
+        sealed.map_err(|_| MyError::StringError("Cannot encrypt record".to_owned()))
+    }
+
+    fn open(&self, nonce: &[u8], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload { msg: data, aad };
+        let opened = match self.cipher {
+            _ if nonce.len() != NONCE_BYTES => Err(aes_gcm::aead::Error),
+            Cipher::Aes256Gcm => Aes256Gcm::new(&self.key.into()).decrypt(nonce.into(), payload),
+            Cipher::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(&self.key.into()).decrypt(nonce.into(), payload)
+            }
+        };
+        opened.map_err(|_| {
+            MyError::StringError(format!(
+                "Record sealed with key {} does not authenticate",
+                self.id
+            ))
+        })
+    }
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("cipher", &self.cipher)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+/// The keys a `KvStore` seals and opens its records with.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Keyring {
+    /// Key sealing new records, `None` to write plaintext.
+    pub(crate) current: Option<EncryptionKey>,
+    /// Keys only used to open records.
+    pub(crate) previous: Vec<EncryptionKey>,
+    /// Accept plaintext records while a key is set.
+    pub(crate) plaintext: bool,
+}
+
+impl Keyring {
+    /// Whether records may be sealed, in which case compaction reads them.
+    pub(crate) fn is_active(&self) -> bool {
+        self.current.is_some() || !self.previous.is_empty()
+    }
+
+    /// Seals a command with the current key, if any, for the record starting
+    /// at `offset` in the log.
+    pub(crate) fn seal(&self, command: Command, offset: u64) -> Result<Command> {
+        let key = match &self.current {
+            Some(key) => key,
+            None => return Ok(command),
+        };
+        let aad = record_aad(key, offset);
+        let (nonce, data) = key.seal(&serde_json::to_vec(&command)?, &aad)?;
+        Ok(Command::Sealed {
+            cipher: key.cipher,
+            key_id: key.id.clone(),
+            nonce: STANDARD.encode(nonce),
+            data: STANDARD.encode(data),
+        })
+    }
+
+    /// Returns the plaintext command of the record starting at `offset` in the
+    /// log.
+    pub(crate) fn open(&self, command: Command, offset: u64) -> Result<Command> {
+        let (cipher, key_id, nonce, data) = match command {
+            Command::Sealed {
+                cipher,
+                key_id,
+                nonce,
+                data,
+            } => (cipher, key_id, nonce, data),
+            _ if self.current.is_some() && !self.plaintext => {
+                return Err(MyError::StringError(
+                    "Log holds an unencrypted record while encryption is enabled".to_owned(),
+                ))
+            }
+            command => return Ok(command),
+        };
+        let key = self
+            .current
+            .iter()
+            .chain(&self.previous)
+            .find(|key| key.id == key_id)
+            .ok_or_else(|| {
+                MyError::StringError(format!(
+                    "Log holds a record sealed with key {}, which was not given",
+                    key_id
+                ))
+            })?;
+        if cipher != key.cipher {
+            return Err(MyError::StringError(format!(
+                "Record sealed with key {} names cipher {}, but the key is for {}",
+                key_id, cipher, key.cipher
+            )));
+        }
+        let decode = |field: &str| {
+            STANDARD
+                .decode(field)
+                .map_err(|e| MyError::StringError(format!("Corrupt sealed record: {}", e)))
+        };
+        let plaintext = key.open(&decode(&nonce)?, &decode(&data)?, &record_aad(key, offset))?;
+        match serde_json::from_slice(&plaintext)? {
+            Command::Sealed { .. } => Err(MyError::StringError(
+                "Corrupt sealed record: nested seal".to_owned(),
+            )),
+            command => Ok(command),
+        }
+    }
+
+    /// Whether compaction must re-seal a record it moves: sealed records are
+    /// bound to their offset, and plaintext ones are sealed once a key is set.
+    pub(crate) fn needs_reseal(&self, command: &Command) -> bool {
+        match command {
+            Command::Sealed { .. } => true,
+            _ => self.current.is_some(),
+        }
+    }
+}
+
+/// Associated data of the record starting at `offset`, sealed with `key`.
+fn record_aad(key: &EncryptionKey, offset: u64) -> Vec<u8> {
+    format!("kvs record {} {} {}", key.cipher, key.id, offset).into_bytes()
+}
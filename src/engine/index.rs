@@ -0,0 +1,702 @@
+//! Key index of a `KvStore`, optionally bounded in memory
+//!
+//! Without a bound every key lives in a `BTreeMap`. With a bound, the map
+//! only keeps the hot keys and the others are spilled to sorted files in the
+//! `index` directory of the store, of which one key in `SPARSE_INTERVAL` is
+//! kept in memory. Keys looked up from the files are brought back into memory.
+//!
+//! Each spill writes the changed keys to a new file rather than rewriting the
+//! previous ones. A file is merged with the one before it while it is not
+//! `SEGMENT_RATIO` times smaller, so there are few files and a key is only
+//! rewritten a few times.
+//!
+//! Spill files are only a cache of the log: they are deleted when the store is
+//! opened and the index is rebuilt from the log. When the store encrypts its
+//! records, each chunk of a spill file is sealed with the current key.
+use crate::engine::encryption::EncryptionKey;
+use crate::{MyError, Result};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::ops::{Bound, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+
+/// Directory of the spill files in the store directory.
+const INDEX_DIR: &str = "index";
+/// One key in `SPARSE_INTERVAL` of a spill file is kept in memory.
+const SPARSE_INTERVAL: usize = 64;
+/// Estimated memory used by an in-memory entry besides its key.
+const ENTRY_BYTES: u64 = 48;
+/// A spill file is merged with the previous one unless that one has at least
+/// `SEGMENT_RATIO` times as many entries.
+const SEGMENT_RATIO: u64 = 2;
+/// Position written in a spill file for a removed key.
+const TOMBSTONE: u64 = u64::MAX;
+
+/// Key and pointer of an index entry, `None` for a removed key.
+type Entry = (Vec<u8>, Option<Pointer>);
+
+/// Represents the position and length of a json-serialized command in the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Pointer {
+    pub(crate) pos: u64,
+    pub(crate) len: u64,
+}
+
+impl From<Range<u64>> for Pointer {
+    fn from(range: Range<u64>) -> Self {
+        Pointer {
+            pos: range.start,
+            len: range.end - range.start,
+        }
+    }
+}
+
+/// Counters of a `KvStore` index, returned by `KvStore::index_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexStats {
+    /// Keys held in memory, including removed keys still to be spilled.
+    pub hot_keys: u64,
+    /// Estimated memory used by the keys held in memory.
+    pub hot_bytes: u64,
+    /// Entries of the spill files, including removed keys and keys spilled
+    /// more than once.
+    pub cold_keys: u64,
+    /// Lookups answered from memory.
+    pub hits: u64,
+    /// Lookups which had to read the spill files.
+    pub misses: u64,
+    /// Number of times keys were evicted from memory.
+    pub spills: u64,
+}
+
+impl IndexStats {
+    /// Share of the lookups answered from memory, `1.0` before any lookup.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 1.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+struct HotEntry {
+    /// `None` for a removed key which may still be in a spill file.
+    pointer: Option<Pointer>,
+    /// Differs from the spill files.
+    dirty: bool,
+    /// Looked up since the last spill. Atomic so that a lookup sets it without
+    /// copying the map shared with the snapshots, which never read it.
+    referenced: AtomicBool,
+}
+
+impl HotEntry {
+    fn new(pointer: Option<Pointer>, dirty: bool, referenced: bool) -> HotEntry {
+        HotEntry {
+            pointer,
+            dirty,
+            referenced: AtomicBool::new(referenced),
+        }
+    }
+}
+
+impl Clone for HotEntry {
+    fn clone(&self) -> Self {
+        HotEntry::new(
+            self.pointer,
+            self.dirty,
+            self.referenced.load(AtomicOrdering::Relaxed),
+        )
+    }
+}
+
+pub(crate) struct Index {
+    dir: PathBuf,
+    max_bytes: Option<u64>,
+    /// Key sealing the spill files, if the store encrypts its records.
+    key: Option<EncryptionKey>,
+    /// Shared with the snapshots, and copied on the first change while one lives.
+    hot: Arc<BTreeMap<Vec<u8>, HotEntry>>,
+    hot_bytes: u64,
+    /// Spill files, oldest first: the newest file holding a key wins.
+    cold: Vec<Arc<ColdIndex>>,
+    next_cold_id: u64,
+    hits: u64,
+    misses: u64,
+    spills: u64,
+}
+
+impl Index {
+    /// Creates an empty index for the store in `dir`, holding at most about
+    /// `max_bytes` of keys in memory, and sealing its spill files with `key`.
+    ///
+    /// Deletes the `index` directory of `dir`: the spill files of a previous
+    /// run are stale, since the index is rebuilt from the log.
+    pub(crate) fn new(
+        dir: &Path,
+        max_bytes: Option<u64>,
+        key: Option<EncryptionKey>,
+    ) -> Result<Index> {
+        let dir = dir.join(INDEX_DIR);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        Ok(Index {
+            dir,
+            max_bytes,
+            key,
+            hot: Arc::new(BTreeMap::new()),
+            hot_bytes: 0,
+            cold: Vec::new(),
+            next_cold_id: 0,
+            hits: 0,
+            misses: 0,
+            spills: 0,
+        })
+    }
+
+    /// Returns the pointer of `key`, bringing it into memory if it was spilled.
+    pub(crate) fn get(&mut self, key: &[u8]) -> Result<Option<Pointer>> {
+        if let Some(entry) = self.hot.get(key) {
+            entry.referenced.store(true, AtomicOrdering::Relaxed);
+            self.hits += 1;
+            return Ok(entry.pointer);
+        }
+        let pointer = self.get_cold(key)?;
+        if let Some(pointer) = pointer {
+            self.put_hot(key.to_vec(), HotEntry::new(Some(pointer), false, true))?;
+        }
+        Ok(pointer)
+    }
+
+    /// Sets the pointer of `key`, returning the previous one.
+    pub(crate) fn insert(&mut self, key: Vec<u8>, pointer: Pointer) -> Result<Option<Pointer>> {
+        let previous = self.find(&key)?;
+        self.put_hot(key, HotEntry::new(Some(pointer), true, true))?;
+        Ok(previous)
+    }
+
+    /// Removes `key`, returning its pointer.
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Result<Option<Pointer>> {
+        let previous = self.find(key)?;
+        if previous.is_some() {
+            if !self.cold.is_empty() {
+                self.put_hot(key.to_vec(), HotEntry::new(None, true, false))?;
+            } else if Arc::make_mut(&mut self.hot).remove(key).is_some() {
+                self.hot_bytes -= entry_bytes(key);
+            }
+        }
+        Ok(previous)
+    }
+
+    /// Returns a read-only view of the index as of now. It shares the spill
+    /// files and the keys in memory, which the index copies on its next change
+    /// rather than now.
+    pub(crate) fn snapshot(&self) -> IndexSnapshot {
+        IndexSnapshot {
+            hot: Arc::clone(&self.hot),
+            cold: self.cold.clone(),
+        }
+    }
+
+    /// Replaces the pointer of every key, in key order, by the one returned by `relocate`.
+    ///
+    /// With spill files, every key is written to a single new spill file and
+    /// the keys in memory are kept as clean copies.
+    pub(crate) fn relocate<F>(&mut self, mut relocate: F) -> Result<()>
+    where
+        F: FnMut(&[u8], Pointer) -> Result<Pointer>,
+    {
+        if self.cold.is_empty() {
+            for (key, entry) in Arc::make_mut(&mut self.hot).iter_mut() {
+                if let Some(pointer) = entry.pointer {
+                    entry.pointer = Some(relocate(key, pointer)?);
+                }
+            }
+            return Ok(());
+        }
+
+        let path = self.next_cold_path()?;
+        let mut relocated = Vec::new();
+        let hot = self
+            .hot
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.pointer));
+        let entries = live(layered(hot, &self.cold, &Bound::Unbounded)).map(|entry| {
+            let (key, pointer) = entry?;
+            let pointer = relocate(&key, pointer)?;
+            if self.hot.contains_key(&key) {
+                relocated.push((key.clone(), pointer));
+            }
+            Ok((key, Some(pointer)))
+        });
+        let new_cold = ColdIndex::write(path, self.key.clone(), entries)?;
+
+        let hot = Arc::make_mut(&mut self.hot);
+        hot.retain(|_, entry| entry.pointer.is_some());
+        for (key, pointer) in relocated {
+            if let Some(entry) = hot.get_mut(&key) {
+                entry.pointer = Some(pointer);
+                entry.dirty = false;
+            }
+        }
+        self.hot_bytes = self.hot.keys().map(|key| entry_bytes(key)).sum();
+        self.cold = vec![Arc::new(new_cold)];
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> IndexStats {
+        IndexStats {
+            hot_keys: self.hot.len() as u64,
+            hot_bytes: self.hot_bytes,
+            cold_keys: self.cold.iter().map(|cold| cold.keys).sum(),
+            hits: self.hits,
+            misses: self.misses,
+            spills: self.spills,
+        }
+    }
+
+    /// Looks `key` up without bringing it into memory.
+    fn find(&mut self, key: &[u8]) -> Result<Option<Pointer>> {
+        if let Some(entry) = self.hot.get(key) {
+            self.hits += 1;
+            return Ok(entry.pointer);
+        }
+        self.get_cold(key)
+    }
+
+    fn get_cold(&mut self, key: &[u8]) -> Result<Option<Pointer>> {
+        if self.cold.is_empty() {
+            self.hits += 1;
+            return Ok(None);
+        }
+        self.misses += 1;
+        get_cold(&self.cold, key)
+    }
+
+    fn put_hot(&mut self, key: Vec<u8>, entry: HotEntry) -> Result<()> {
+        let bytes = entry_bytes(&key);
+        if Arc::make_mut(&mut self.hot).insert(key, entry).is_none() {
+            self.hot_bytes += bytes;
+        }
+        match self.max_bytes {
+            Some(max_bytes) if self.hot_bytes > max_bytes => self.spill(max_bytes / 2),
+            _ => Ok(()),
+        }
+    }
+
+    /// Evicts keys from memory until they use at most `target` bytes.
+    ///
+    /// Keys not looked up since the last spill go first; the others get a
+    /// second chance, as in the CLOCK algorithm. Changed keys are written to
+    /// a new spill file.
+    fn spill(&mut self, target: u64) -> Result<()> {
+        let mut evicted = Vec::new();
+        let mut remaining = self.hot_bytes;
+        for (key, entry) in self.hot.iter() {
+            if remaining <= target {
+                break;
+            }
+            if !entry.referenced.swap(false, AtomicOrdering::Relaxed) {
+                evicted.push(key.clone());
+                remaining -= entry_bytes(key);
+            }
+        }
+        let mut second_chance = Vec::new();
+        for key in self.hot.keys() {
+            if remaining <= target {
+                break;
+            }
+            if evicted.binary_search(key).is_err() {
+                second_chance.push(key.clone());
+                remaining -= entry_bytes(key);
+            }
+        }
+        evicted.extend(second_chance);
+        evicted.sort();
+
+        let mut changed = Vec::new();
+        for key in evicted {
+            if let Some(entry) = Arc::make_mut(&mut self.hot).remove(&key) {
+                self.hot_bytes -= entry_bytes(&key);
+                if entry.dirty {
+                    changed.push((key, entry.pointer));
+                }
+            }
+        }
+        self.spills += 1;
+        if changed.is_empty() {
+            return Ok(());
+        }
+        self.push_cold(changed)
+    }
+
+    /// Writes `entries` to a new spill file, then merges the newest file with
+    /// the previous one as long as that one is not `SEGMENT_RATIO` times larger.
+    ///
+    /// Removed keys are dropped from the oldest file, which has nothing left
+    /// to hide.
+    fn push_cold(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let oldest = self.cold.is_empty();
+        let entries = entries
+            .into_iter()
+            .filter(|(_, pointer)| !oldest || pointer.is_some())
+            .map(Ok);
+        let path = self.next_cold_path()?;
+        let cold = ColdIndex::write(path, self.key.clone(), entries)?;
+        self.cold.push(Arc::new(cold));
+
+        while let [.., older, newer] = self.cold.as_slice() {
+            if older.keys >= newer.keys * SEGMENT_RATIO {
+                break;
+            }
+            let (older, newer) = (Arc::clone(older), Arc::clone(newer));
+            let oldest = self.cold.len() == 2;
+            let entries = merge(
+                newer.iter_from(&Bound::Unbounded),
+                older.iter_from(&Bound::Unbounded),
+            )
+            .filter(|entry| !oldest || !matches!(entry, Ok((_, None))));
+            let path = self.next_cold_path()?;
+            let merged = ColdIndex::write(path, self.key.clone(), entries)?;
+            self.cold.truncate(self.cold.len() - 2);
+            self.cold.push(Arc::new(merged));
+        }
+        Ok(())
+    }
+
+    fn next_cold_path(&mut self) -> Result<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+        self.next_cold_id += 1;
+        Ok(self.dir.join(format!("{}.spill", self.next_cold_id)))
+    }
+}
+
+fn entry_bytes(key: &[u8]) -> u64 {
+    key.len() as u64 + ENTRY_BYTES
+}
+
+/// Looks `key` up in the spill files `cold`, from the newest one on.
+fn get_cold(cold: &[Arc<ColdIndex>], key: &[u8]) -> Result<Option<Pointer>> {
+    for segment in cold.iter().rev() {
+        if let Some(pointer) = segment.get(key)? {
+            return Ok(pointer);
+        }
+    }
+    Ok(None)
+}
+
+/// Read-only view of an `Index`.
+pub(crate) struct IndexSnapshot {
+    hot: Arc<BTreeMap<Vec<u8>, HotEntry>>,
+    cold: Vec<Arc<ColdIndex>>,
+}
+
+impl IndexSnapshot {
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Pointer>> {
+        match self.hot.get(key) {
+            Some(entry) => Ok(entry.pointer),
+            None => get_cold(&self.cold, key),
+        }
+    }
+
+    /// Iterates over the keys of `range` in order.
+    pub(crate) fn range(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Pointer)>> + '_> {
+        let hot = self
+            .hot
+            .range(range.clone())
+            .map(|(key, entry)| (key.clone(), entry.pointer));
+        let end = range.1;
+        Box::new(
+            live(layered(hot, &self.cold, &range.0)).take_while(move |entry| match entry {
+                Ok((key, _)) => !after_end(key, &end),
+                Err(_) => true,
+            }),
+        )
+    }
+}
+
+fn after_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(end) => key > end.as_slice(),
+        Bound::Excluded(end) => key >= end.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+/// Iterates from `start` on over the entries in memory merged with the spill
+/// files `cold`, keeping the removed keys.
+fn layered<'a, H>(
+    hot: H,
+    cold: &[Arc<ColdIndex>],
+    start: &Bound<Vec<u8>>,
+) -> Box<dyn Iterator<Item = Result<Entry>> + 'a>
+where
+    H: Iterator<Item = Entry> + 'a,
+{
+    let mut entries: Box<dyn Iterator<Item = Result<Entry>>> = Box::new(std::iter::empty());
+    for segment in cold {
+        entries = Box::new(merge(segment.iter_from(start), entries));
+    }
+    Box::new(merge(hot.map(Ok), entries))
+}
+
+/// Drops the removed keys of `entries`.
+fn live<I>(entries: I) -> impl Iterator<Item = Result<(Vec<u8>, Pointer)>>
+where
+    I: Iterator<Item = Result<Entry>>,
+{
+    entries.filter_map(|entry| match entry {
+        Ok((key, pointer)) => pointer.map(|pointer| Ok((key, pointer))),
+        Err(err) => Some(Err(err)),
+    })
+}
+
+/// Merges two sorted iterators of entries, those of `newer` winning.
+fn merge<N, O>(newer: N, older: O) -> Merge<N, O>
+where
+    N: Iterator<Item = Result<Entry>>,
+    O: Iterator<Item = Result<Entry>>,
+{
+    Merge {
+        newer: newer.peekable(),
+        older: older.peekable(),
+    }
+}
+
+struct Merge<N: Iterator, O: Iterator> {
+    newer: Peekable<N>,
+    older: Peekable<O>,
+}
+
+impl<N, O> Iterator for Merge<N, O>
+where
+    N: Iterator<Item = Result<Entry>>,
+    O: Iterator<Item = Result<Entry>>,
+{
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let order = match (self.newer.peek(), self.older.peek()) {
+            (None, None) => return None,
+            (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+            (_, Some(Err(_))) | (None, Some(_)) => Ordering::Greater,
+            (Some(Ok((newer_key, _))), Some(Ok((older_key, _)))) => newer_key.cmp(older_key),
+        };
+        match order {
+            Ordering::Greater => self.older.next(),
+            Ordering::Equal => {
+                self.older.next();
+                self.newer.next()
+            }
+            Ordering::Less => self.newer.next(),
+        }
+    }
+}
+
+/// Sorted spill file: for each key, its length as a little-endian `u32`, the
+/// key, then the position and length of its record as little-endian `u64`s,
+/// the position being `TOMBSTONE` for a removed key. With a key, each chunk of
+/// `SPARSE_INTERVAL` entries is sealed as a whole.
+struct ColdIndex {
+    path: PathBuf,
+    file: Mutex<File>,
+    key: Option<EncryptionKey>,
+    /// First key and offset of each chunk of `SPARSE_INTERVAL` entries.
+    sparse: Vec<(Vec<u8>, u64)>,
+    size: u64,
+    keys: u64,
+}
+
+impl ColdIndex {
+    fn write<I>(path: PathBuf, key: Option<EncryptionKey>, entries: I) -> Result<ColdIndex>
+    where
+        I: Iterator<Item = Result<Entry>>,
+    {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let mut writer = BufWriter::new(file);
+        let mut sparse = Vec::new();
+        let mut size = 0;
+        let mut keys = 0;
+        let mut chunk = Vec::new();
+        // Writes the entries of a chunk, sealed if the index has a key
+        let mut flush = |chunk: &mut Vec<u8>, size: &mut u64| -> Result<()> {
+            let bytes = match &key {
+                Some(key) => key.seal_bytes(chunk, &chunk_aad(&path, *size))?,
+                None => chunk.clone(),
+            };
+            writer.write_all(&bytes)?;
+            *size += bytes.len() as u64;
+            chunk.clear();
+            Ok(())
+        };
+        for entry in entries {
+            let (entry_key, pointer) = entry?;
+            if keys % SPARSE_INTERVAL as u64 == 0 {
+                if !chunk.is_empty() {
+                    flush(&mut chunk, &mut size)?;
+                }
+                sparse.push((entry_key.clone(), size));
+            }
+            chunk.extend_from_slice(&(entry_key.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(&entry_key);
+            let (pos, len) = pointer.map_or((TOMBSTONE, 0), |pointer| (pointer.pos, pointer.len));
+            chunk.extend_from_slice(&pos.to_le_bytes());
+            chunk.extend_from_slice(&len.to_le_bytes());
+            keys += 1;
+        }
+        if !chunk.is_empty() {
+            flush(&mut chunk, &mut size)?;
+        }
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        Ok(ColdIndex {
+            path,
+            file: Mutex::new(file),
+            key,
+            sparse,
+            size,
+            keys,
+        })
+    }
+
+    /// Returns the entry of `key`, `Some(None)` if the file holds its removal.
+    fn get(&self, key: &[u8]) -> Result<Option<Option<Pointer>>> {
+        let chunk = self
+            .sparse
+            .partition_point(|(first_key, _)| first_key.as_slice() <= key);
+        if chunk == 0 {
+            return Ok(None);
+        }
+        Ok(self
+            .read_chunk(chunk - 1)?
+            .into_iter()
+            .find(|(entry_key, _)| entry_key.as_slice() == key)
+            .map(|(_, pointer)| pointer))
+    }
+
+    fn read_chunk(&self, chunk: usize) -> Result<Vec<Entry>> {
+        let start = self.sparse[chunk].1;
+        let end = self
+            .sparse
+            .get(chunk + 1)
+            .map_or(self.size, |(_, offset)| *offset);
+        let mut buf = vec![0; (end - start) as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut buf)?;
+        }
+        if let Some(key) = &self.key {
+            buf = key.open_bytes(&buf, &chunk_aad(&self.path, start))?;
+        }
+        let mut entries = Vec::with_capacity(SPARSE_INTERVAL);
+        let mut buf = &buf[..];
+        while !buf.is_empty() {
+            let key_len = u32::from_le_bytes(take(&mut buf, 4)?.try_into().unwrap()) as usize;
+            let key = take(&mut buf, key_len)?.to_vec();
+            let pos = u64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap());
+            let len = u64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap());
+            let pointer = if pos == TOMBSTONE {
+                None
+            } else {
+                Some(Pointer { pos, len })
+            };
+            entries.push((key, pointer));
+        }
+        Ok(entries)
+    }
+
+    /// Iterates over the entries from `start` on.
+    fn iter_from(self: &Arc<Self>, start: &Bound<Vec<u8>>) -> ColdIter {
+        let chunk = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .sparse
+                .partition_point(|(first_key, _)| first_key <= key)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        ColdIter {
+            cold: Arc::clone(self),
+            next_chunk: chunk,
+            entries: Vec::new().into_iter(),
+            start: start.clone(),
+        }
+    }
+}
+
+impl Drop for ColdIndex {
+    fn drop(&mut self) {
+        // The file is a cache of the log, it is rebuilt on the next open.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Binds a sealed chunk to its spill file and offset.
+fn chunk_aad(path: &Path, offset: u64) -> Vec<u8> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    format!("kvs index {} {}", name, offset).into_bytes()
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(MyError::StringError("Corrupt index spill file".to_owned()));
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+/// Iterator over the entries of a spill file, reading one chunk at a time.
+struct ColdIter {
+    cold: Arc<ColdIndex>,
+    next_chunk: usize,
+    entries: std::vec::IntoIter<Entry>,
+    start: Bound<Vec<u8>>,
+}
+
+impl Iterator for ColdIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                let before_start = match &self.start {
+                    Bound::Included(start) => entry.0 < *start,
+                    Bound::Excluded(start) => entry.0 <= *start,
+                    Bound::Unbounded => false,
+                };
+                if before_start {
+                    continue;
+                }
+                self.start = Bound::Unbounded;
+                return Some(Ok(entry));
+            }
+            if self.next_chunk >= self.cold.sparse.len() {
+                return None;
+            }
+            match self.cold.read_chunk(self.next_chunk) {
+                Ok(entries) => {
+                    self.entries = entries.into_iter();
+                    self.next_chunk += 1;
+                }
+                Err(err) => {
+                    self.next_chunk = self.cold.sparse.len();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
@@ -1,31 +1,58 @@
-//! Simple in-memory key/value storee responds to command line arguments
-use crate::engine::KvsEngine;
+//! Log-structured key/value store persisted to disk
+use crate::backup::{self, BackupManifest, Checksum};
+use crate::common::bytes;
+use crate::engine::cache::{CacheStats, ValueCache};
+use crate::engine::compression::{self, Codec, Compression, CompressionStats};
+use crate::engine::durability::{Durability, Syncer};
+use crate::engine::encryption::{Cipher, EncryptionKey, Keyring};
+use crate::engine::index::{Index, IndexSnapshot, IndexStats, Pointer};
+use crate::engine::log_reader::LogReader;
+use crate::engine::{
+    check_namespace, read_only_parameter, unknown_parameter, EngineStats, KvsEngine, KvsSnapshot,
+    SnapshotIter, DEFAULT_NAMESPACE,
+};
 use crate::{MyError, Result};
+use log::info;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::{prelude::*, BufReader, BufWriter, SeekFrom, Write};
-use std::ops::Range;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufReader, BufWriter, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
-/// The size of the log file needed before compaction occurs
+/// The default amount of stale bytes in the log needed before compaction occurs
 const COMPACT_BYTES: u64 = 1024;
 
-/// The `KvStore` stores string key/value pairs.
+/// Parameter of `KvsEngine::config` holding the compaction threshold.
+const COMPACTION_THRESHOLD: &str = "compaction-threshold";
+
+/// Directory of the store holding one directory per namespace.
+pub(crate) const NAMESPACES_DIR: &str = "namespaces";
+
+/// Most namespaces kept open besides the default one. The least recently used
+/// of those without a handle is closed to open another.
+const MAX_OPEN_NAMESPACES: usize = 64;
+
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
 ///
-/// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
+/// Every write is appended as a record to `log.json` in the directory of the
+/// store, and an index maps each key to its latest record. The log is replayed
+/// to rebuild the index when the store is opened, and compacted once its stale
+/// records exceed the compaction threshold.
 ///
 /// Example:
 ///
 /// ```rust
 /// # use kvs::{MyError, Result, KvStore};
 /// # use kvs::KvsEngine;
-/// # use std::env::current_dir;
+/// # use tempfile::TempDir;
 /// # fn try_main() -> Result<()> {
+/// # let dir = TempDir::new()?;
 ///
-/// let mut store = KvStore::new()?;
+/// let mut store = KvStore::open(dir.path())?;
 /// store.set("key".to_owned(), "value".to_owned());
 /// let val = store.get("key".to_owned())?;
 /// assert_eq!(val, Some("value".to_owned()));
@@ -33,75 +60,422 @@ const COMPACT_BYTES: u64 = 1024;
 /// # Ok(())
 /// # }
 /// ```
+///
+/// A `KvStore` is a handle: clones share the same store and can be used from
+/// several threads.
+///
+/// Namespaces other than the default one are kept in `namespaces/<name>` under
+/// the store directory, each with its own log and index. Up to 64 of them are
+/// kept open at once.
+#[derive(Clone)]
 pub struct KvStore {
+    inner: Arc<Mutex<KvStoreInner>>,
+    syncer: Arc<Syncer>,
+    compression: Compression,
+    namespaces: Arc<Namespaces>,
+    /// Whether the handle is on the default namespace.
+    default: bool,
+}
+
+/// Log and syncer of one namespace.
+type Keyspace = (Arc<Mutex<KvStoreInner>>, Arc<Syncer>);
+
+/// Namespaces of a `KvStore`, opened on first use and shared by every handle.
+struct Namespaces {
+    dir: PathBuf,
+    options: KvStoreOptions,
+    /// Compaction threshold of every namespace, which can change while the store is open.
+    compact_bytes: Arc<AtomicU64>,
+    open: Mutex<OpenNamespaces>,
+}
+
+/// Keyspaces of the open namespaces, with when each was last used.
+struct OpenNamespaces {
+    keyspaces: HashMap<String, (Keyspace, u64)>,
+    clock: u64,
+}
+
+impl OpenNamespaces {
+    /// Closes the least recently used namespace no handle is on.
+    fn close_idle(&mut self) -> Result<()> {
+        let idle = self
+            .keyspaces
+            .iter()
+            .filter(|(name, ((inner, _), _))| {
+                *name != DEFAULT_NAMESPACE && Arc::strong_count(inner) == 1
+            })
+            .min_by_key(|(_, (_, used))| *used)
+            .map(|(name, _)| name.clone());
+        let name = idle.ok_or_else(|| {
+            MyError::StringError(format!(
+                "More than {} namespaces are in use",
+                MAX_OPEN_NAMESPACES
+            ))
+        })?;
+        let ((inner, syncer), _) = self.keyspaces.remove(&name).unwrap();
+        inner.lock().unwrap().writer.flush()?;
+        syncer.sync_all()?;
+        info!("Closed namespace {}", name);
+        Ok(())
+    }
+}
+
+/// Options of a `KvStore`, used by `KvStore::open_with`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    durability: Durability,
+    index_memory: Option<u64>,
+    cache_bytes: Option<u64>,
+    mmap: bool,
+    compression: Compression,
+    keyring: Keyring,
+    compact_bytes: u64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            durability: Durability::None,
+            index_memory: None,
+            cache_bytes: None,
+            mmap: true,
+            compression: Compression::None,
+            keyring: Keyring::default(),
+            compact_bytes: COMPACT_BYTES,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Sets when writes are synced to disk. Defaults to `Durability::None`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Caps the memory used by the key index to about `bytes`, the least
+    /// recently used keys being spilled to disk. Unbounded by default.
+    pub fn index_memory(mut self, bytes: u64) -> Self {
+        self.index_memory = Some(bytes);
+        self
+    }
+
+    /// Keeps up to about `bytes` of recently read values in memory, so that
+    /// reading them again skips the log. Disabled by default.
+    pub fn cache_bytes(mut self, bytes: u64) -> Self {
+        self.cache_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets whether records are read from a memory map of the log, or with
+    /// `pread` otherwise. Defaults to `true`; the store falls back to `pread`
+    /// if the log cannot be mapped.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Sets how new values are compressed. Defaults to `Compression::None`.
+    /// Records already in the log are read whatever their compression.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Seals every record written from now on with `key`. Once a key is set,
+    /// records which are not sealed with it or a previous key are rejected.
+    pub fn encryption(mut self, key: EncryptionKey) -> Self {
+        self.keyring.current = Some(key);
+        self
+    }
+
+    /// Adds a key records may be sealed with, from before a rotation. Live
+    /// records are re-sealed with the current key, or decrypted without one,
+    /// as compaction copies them.
+    pub fn previous_key(mut self, key: EncryptionKey) -> Self {
+        self.keyring.previous.push(key);
+        self
+    }
+
+    /// Accepts records written before encryption was enabled. Compaction
+    /// seals them, after which the option can be dropped.
+    pub fn encrypt_plaintext(mut self, plaintext: bool) -> Self {
+        self.keyring.plaintext = plaintext;
+        self
+    }
+
+    /// Sets the amount of stale bytes in a log which triggers its compaction.
+    /// Defaults to 1 KiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compact_bytes = bytes;
+        self
+    }
+}
+
+/// State of a `KvStore`, shared by all its clones.
+struct KvStoreInner {
     writer: BufWriter<File>,
-    reader: BufReader<File>,
-    index: BTreeMap<String, Pointer>,
+    /// Reader of the current log, shared with the lookups in progress and
+    /// the snapshots.
+    reader: Arc<LogReader>,
+    mmap: bool,
+    index: Index,
+    cache: Option<ValueCache>,
+    /// Bumped by every write, so that a value read without the lock is only
+    /// cached if the key was not written in the meantime.
+    writes: u64,
+    compression_stats: CompressionStats,
+    keyring: Arc<Keyring>,
     path: PathBuf,
+    /// Live keys.
+    keys: u64,
     uncompacted: u64,
+    compact_bytes: Arc<AtomicU64>,
+    /// Compactions run since the store was opened.
+    compactions: u64,
+    /// Handle on the current log used by the `Syncer`, replaced by compaction.
+    sync_file: Arc<Mutex<File>>,
+    syncer: Arc<Syncer>,
 }
 
 impl KvsEngine for KvStore {
-    /// Sets the value of a string key to a string.
+    type Snapshot = KvStoreSnapshot;
+
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Command::set(key.clone(), value.clone());
-        let initial_offset = self.writer.seek(SeekFrom::End(0))?;
-        self.writer.write_all(b"\r\n")?;
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
-        let new_offset = self.writer.seek(SeekFrom::End(0))?;
-        if let Some(pointer) = self
-            .index
-            .insert(key.clone(), (initial_offset..new_offset).into())
-        {
-            self.uncompacted += pointer.len;
-            //println!("Uncompacted {:?}", self.uncompacted);
-        }
-        if new_offset > COMPACT_BYTES {
-            self.compact()?;
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let value = StoredValue::new(self.compression, value)?;
+        let ticket = self.inner.lock().unwrap().set(key, value)?;
+        self.syncer.commit(ticket)
+    }
+
+    /// Appends every "set" command to the log with a single flush.
+    fn set_batch(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| Ok((key, StoredValue::new(self.compression, value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let ticket = self.inner.lock().unwrap().set_batch(entries)?;
+        self.syncer.commit(ticket)
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist. The record is read
+    /// without holding the store's lock, so reads run concurrently.
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (reader, keyring, pointer, writes) = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(value) = inner.cache.as_mut().and_then(|cache| cache.get(key)) {
+                return Ok(Some(value));
+            }
+            match inner.index.get(key)? {
+                Some(pointer) => (
+                    Arc::clone(&inner.reader),
+                    Arc::clone(&inner.keyring),
+                    pointer,
+                    inner.writes,
+                ),
+                None => return Ok(None),
+            }
+        };
+        let value = read_value(&reader, &keyring, &pointer)?;
+
+        let mut inner = self.inner.lock().unwrap();
+        let unchanged = inner.writes == writes;
+        if let Some(cache) = inner.cache.as_mut() {
+            cache.miss();
+            if unchanged {
+                cache.insert(key.to_vec(), value.clone());
+            }
         }
+        Ok(Some(value))
+    }
 
-        Ok(())
+    /// Remove a given key.
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        let ticket = self.inner.lock().unwrap().remove(key)?;
+        self.syncer.commit(ticket)
     }
 
-    /// Gets the string value of a given string key.
+    /// Returns a snapshot pinning the current log.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.reader.seek(SeekFrom::Start(0))?;
-        if let Some(pointer) = self.index.get(&key) {
-            self.reader.seek(SeekFrom::Start(pointer.pos))?;
-            let cmd_reader = (&mut self.reader).take(pointer.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(MyError::KeyNotFound)
+    /// The snapshot shares the index, which the store copies on its next write
+    /// rather than now, and the reader of the log.
+    /// Compaction goes on while it lives: the reader keeps the compacted log
+    /// open, and its disk space is reclaimed once the last snapshot reading it
+    /// is dropped.
+    fn snapshot(&mut self) -> Result<KvStoreSnapshot> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.writer.flush()?;
+        Ok(KvStoreSnapshot {
+            reader: Arc::clone(&inner.reader),
+            keyring: Arc::clone(&inner.keyring),
+            index: inner.index.snapshot(),
+        })
+    }
+
+    /// Writes every live record of a snapshot to a fresh log in `dest`, and
+    /// those of every namespace to `dest/namespaces/<name>` when the handle is
+    /// on the default namespace.
+    fn backup(&mut self, dest: &Path) -> Result<BackupManifest> {
+        backup::create_backup_dir(dest)?;
+        let mut checksum = Checksum::new();
+        write_backup_log(&mut self.snapshot()?, dest, &mut checksum)?;
+        if self.default {
+            for name in self.namespaces()? {
+                checksum.namespace(&name);
+                let dir = dest.join(NAMESPACES_DIR).join(&name);
+                fs::create_dir_all(&dir)?;
+                write_backup_log(&mut self.namespace(&name)?.snapshot()?, &dir, &mut checksum)?;
             }
+        }
+
+        let manifest = BackupManifest::new("kvs", checksum.count(), checksum.finish());
+        manifest.write(dest)?;
+        info!(
+            "Backup of {} keys written to {}",
+            manifest.keys,
+            dest.display()
+        );
+        Ok(manifest)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let inner = self.inner.lock().unwrap();
+        Ok(EngineStats {
+            keys: Some(inner.keys),
+            log_bytes: Some(inner.writer.get_ref().metadata()?.len()),
+            uncompacted_bytes: Some(inner.uncompacted),
+            compactions: Some(inner.compactions),
+        })
+    }
+
+    fn engine_name(&self) -> &'static str {
+        "kvs"
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        KvStore::compact(self)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        KvStore::sync(self)
+    }
+
+    fn config(&self) -> BTreeMap<String, String> {
+        let mut config = BTreeMap::new();
+        config.insert("durability".to_owned(), self.durability().to_string());
+        config.insert(
+            COMPACTION_THRESHOLD.to_owned(),
+            self.namespaces
+                .compact_bytes
+                .load(Ordering::Relaxed)
+                .to_string(),
+        );
+        config
+    }
+
+    /// The compaction threshold, shared by every namespace, can be changed.
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            COMPACTION_THRESHOLD => {
+                let bytes = value.parse().map_err(|_| {
+                    MyError::StringError(format!("Invalid {}: {}", COMPACTION_THRESHOLD, value))
+                })?;
+                self.namespaces
+                    .compact_bytes
+                    .store(bytes, Ordering::Relaxed);
+                Ok(())
+            }
+            "durability" => Err(read_only_parameter(name)),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+
+    /// Opens the namespace if it is open already or has a directory.
+    fn existing_namespace(&mut self, name: &str) -> Result<Option<KvStore>> {
+        check_namespace(name)?;
+        let exists = self
+            .namespaces
+            .open
+            .lock()
+            .unwrap()
+            .keyspaces
+            .contains_key(name)
+            || self.namespaces.dir.join(NAMESPACES_DIR).join(name).is_dir();
+        if exists {
+            self.namespace(name).map(Some)
         } else {
             Ok(None)
         }
     }
 
-    /// Remove a given key.
-    fn remove(&mut self, key: String) -> Result<()> {
-        self.writer.seek(SeekFrom::End(0))?;
-        let command = Command::remove(key.clone());
-        match self.index.remove(&key) {
-            Some(_x) => {
-                serde_json::to_writer(&mut self.writer, &command)?;
-                self.writer.write_all(b"\r\n")?;
-                self.writer.flush()?;
-                return Ok(());
+    /// Lists the directories of `namespaces/`.
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(self.namespaces.dir.join(NAMESPACES_DIR)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if check_namespace(name).is_ok() && name != DEFAULT_NAMESPACE {
+                    names.push(name.to_owned());
+                }
             }
-            None => return Err(MyError::KeyNotFound),
         }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Returns a handle on the namespace, opening its log on first use.
+    fn namespace(&mut self, name: &str) -> Result<KvStore> {
+        check_namespace(name)?;
+        let mut open = self.namespaces.open.lock().unwrap();
+        open.clock += 1;
+        let now = open.clock;
+        let (inner, syncer) = match open.keyspaces.get_mut(name) {
+            Some((keyspace, used)) => {
+                *used = now;
+                keyspace.clone()
+            }
+            None => {
+                if open.keyspaces.len() > MAX_OPEN_NAMESPACES {
+                    open.close_idle()?;
+                }
+                let path = self.namespaces.dir.join(NAMESPACES_DIR).join(name);
+                info!("Opening namespace {} in {}", name, path.display());
+                let keyspace = open_keyspace(
+                    path,
+                    &self.namespaces.options,
+                    &self.namespaces.compact_bytes,
+                )?;
+                open.keyspaces
+                    .insert(name.to_owned(), (keyspace.clone(), now));
+                keyspace
+            }
+        };
+        Ok(KvStore {
+            inner,
+            syncer,
+            compression: self.compression,
+            namespaces: Arc::clone(&self.namespaces),
+            default: name == DEFAULT_NAMESPACE,
+        })
     }
 }
 
 impl KvStore {
-    /// Creates a `KvStore`.
+    /// Creates a `KvStore` in the working directory.
+    #[deprecated(note = "the working directory depends on how the process is started, \
+                         use `KvStore::open` with an explicit directory")]
     pub fn new() -> Result<Self> {
         let cwd = std::env::current_dir()?;
         KvStore::open(cwd.as_path())
@@ -109,28 +483,236 @@ impl KvStore {
 
     /// Open the KvStore at a given path. Return the KvStore.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let mut path = path.into();
-        std::fs::create_dir_all(&path)?;
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
 
-        path.push("log");
-        path.set_extension("json");
+    /// Open the KvStore at a given path with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = path.into();
+        let compact_bytes = Arc::new(AtomicU64::new(options.compact_bytes));
+        let (inner, syncer) = open_keyspace(path.clone(), &options, &compact_bytes)?;
+        let mut keyspaces = HashMap::new();
+        keyspaces.insert(
+            DEFAULT_NAMESPACE.to_owned(),
+            ((Arc::clone(&inner), Arc::clone(&syncer)), 0),
+        );
+        let open = OpenNamespaces {
+            keyspaces,
+            clock: 0,
+        };
+        Ok(KvStore {
+            inner,
+            syncer,
+            compression: options.compression,
+            namespaces: Arc::new(Namespaces {
+                dir: path,
+                options,
+                compact_bytes,
+                open: Mutex::new(open),
+            }),
+            default: true,
+        })
+    }
 
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(&path)?;
+    /// Returns the durability policy of the store.
+    pub fn durability(&self) -> Durability {
+        self.syncer.policy()
+    }
 
-        let mut kv = KvStore {
-            writer: BufWriter::new(file),
-            reader: BufReader::new(OpenOptions::new().read(true).open(&path)?),
-            index: BTreeMap::new(),
-            path,
-            uncompacted: 0,
-        };
+    /// Syncs every write made so far to disk, whatever the durability policy.
+    pub fn sync(&self) -> Result<()> {
+        self.syncer.sync_all()
+    }
+
+    /// Compacts the log now rather than once enough of it is stale, which
+    /// also re-seals every live record after a key rotation.
+    pub fn compact(&self) -> Result<()> {
+        self.inner.lock().unwrap().compact()
+    }
+
+    /// Returns the counters of the key index.
+    pub fn index_stats(&self) -> IndexStats {
+        self.inner.lock().unwrap().index.stats()
+    }
+
+    /// Returns the counters of the values written since the store was opened.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.inner.lock().unwrap().compression_stats.clone()
+    }
+
+    /// Returns the counters of the value cache, all zero if it is disabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        match &self.inner.lock().unwrap().cache {
+            Some(cache) => cache.stats(),
+            None => CacheStats::default(),
+        }
+    }
+}
+
+/// Writes the pairs of a snapshot to a fresh log in `dir`, sealed with the
+/// keys of the store.
+fn write_backup_log(
+    snapshot: &mut KvStoreSnapshot,
+    dir: &Path,
+    checksum: &mut Checksum,
+) -> Result<()> {
+    let keyring = Arc::clone(&snapshot.keyring);
+    let file = File::create(dir.join("log.json"))?;
+    let mut writer = BufWriter::new(&file);
+    let mut offset = 0;
+    for entry in snapshot.iter() {
+        let (key, value) = entry?;
+        checksum.update(&key, &value);
+        let command = keyring.seal(Command::set(key, value), offset + 2)?;
+        let record = serde_json::to_vec(&command)?;
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&record)?;
+        offset += 2 + record.len() as u64;
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Opens the log of the store or namespace in `path` and loads its index.
+fn open_keyspace(
+    path: PathBuf,
+    options: &KvStoreOptions,
+    compact_bytes: &Arc<AtomicU64>,
+) -> Result<Keyspace> {
+    std::fs::create_dir_all(&path)?;
+    let log_path = path.join("log.json");
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .append(false)
+        .open(&log_path)?;
+
+    let sync_file = Arc::new(Mutex::new(file.try_clone()?));
+    let syncer = {
+        let sync_file = Arc::clone(&sync_file);
+        Syncer::new(
+            options.durability,
+            Box::new(move || Ok(sync_file.lock().unwrap().sync_data()?)),
+        )
+    };
+
+    let mut kv = KvStoreInner {
+        writer: BufWriter::new(file),
+        reader: Arc::new(LogReader::open(&log_path, options.mmap)?),
+        mmap: options.mmap,
+        index: Index::new(&path, options.index_memory, options.keyring.current.clone())?,
+        cache: options.cache_bytes.map(ValueCache::new),
+        writes: 0,
+        compression_stats: CompressionStats::default(),
+        keyring: Arc::new(options.keyring.clone()),
+        path: log_path,
+        keys: 0,
+        uncompacted: 0,
+        compact_bytes: Arc::clone(compact_bytes),
+        compactions: 0,
+        sync_file,
+        syncer: Arc::clone(&syncer),
+    };
+
+    kv.read_file()?;
+    Ok((Arc::new(Mutex::new(kv)), syncer))
+}
+
+impl KvStoreInner {
+    /// Drops the cached value of a key about to be written.
+    fn invalidate(&mut self, key: &[u8]) {
+        self.writes += 1;
+        if let Some(cache) = self.cache.as_mut() {
+            cache.invalidate(key);
+        }
+    }
+
+    /// Appends a "set" command to the log. Returns the ticket of the write.
+    fn set(&mut self, key: Vec<u8>, value: StoredValue) -> Result<u64> {
+        self.invalidate(&key);
+        self.compression_stats
+            .record(value.raw_len, value.value.len(), value.codec);
+        let initial_offset = self.writer.seek(SeekFrom::End(0))?;
+        let command = self
+            .keyring
+            .seal(Command::stored(key.clone(), value), initial_offset + 2)?;
+        self.writer.write_all(b"\r\n")?;
+        serde_json::to_writer(&mut self.writer, &command)?;
+        self.writer.flush()?;
+        let ticket = self.syncer.ticket();
+        let new_offset = self.writer.seek(SeekFrom::End(0))?;
+        match self
+            .index
+            .insert(key, (initial_offset..new_offset).into())?
+        {
+            Some(pointer) => self.uncompacted += pointer.len,
+            None => self.keys += 1,
+        }
+        if self.uncompacted > self.compact_bytes.load(Ordering::Relaxed) {
+            self.compact()?;
+        }
+
+        Ok(ticket)
+    }
+
+    /// Appends every "set" command to the log with a single flush.
+    fn set_batch(&mut self, entries: Vec<(Vec<u8>, StoredValue)>) -> Result<u64> {
+        let mut offset = self.writer.seek(SeekFrom::End(0))?;
+        let mut record = Vec::new();
+        for (key, value) in entries {
+            self.invalidate(&key);
+            self.compression_stats
+                .record(value.raw_len, value.value.len(), value.codec);
+            record.clear();
+            record.extend_from_slice(b"\r\n");
+            let command = self
+                .keyring
+                .seal(Command::stored(key.clone(), value), offset + 2)?;
+            serde_json::to_writer(&mut record, &command)?;
+            self.writer.write_all(&record)?;
+            let new_offset = offset + record.len() as u64;
+            match self.index.insert(key, (offset..new_offset).into())? {
+                Some(pointer) => self.uncompacted += pointer.len,
+                None => self.keys += 1,
+            }
+            offset = new_offset;
+        }
+        self.writer.flush()?;
+        let ticket = self.syncer.ticket();
+        if self.uncompacted > self.compact_bytes.load(Ordering::Relaxed) {
+            self.compact()?;
+        }
+        Ok(ticket)
+    }
 
-        kv.read_file()?;
-        Ok(kv)
+    /// Appends a "remove" command to the log. Returns the ticket of the write.
+    fn remove(&mut self, key: &[u8]) -> Result<u64> {
+        self.invalidate(key);
+        let initial_offset = self.writer.seek(SeekFrom::End(0))?;
+        match self.index.remove(key)? {
+            Some(pointer) => {
+                let command = self
+                    .keyring
+                    .seal(Command::remove(key.to_vec()), initial_offset)?;
+                serde_json::to_writer(&mut self.writer, &command)?;
+                self.writer.write_all(b"\r\n")?;
+                self.writer.flush()?;
+                let ticket = self.syncer.ticket();
+                let new_offset = self.writer.seek(SeekFrom::End(0))?;
+                // both the overwritten "set" and the "remove" itself are stale now.
+                self.uncompacted += pointer.len + new_offset - initial_offset;
+                self.keys -= 1;
+                if self.uncompacted > self.compact_bytes.load(Ordering::Relaxed) {
+                    self.compact()?;
+                }
+                Ok(ticket)
+            }
+            None => Err(MyError::KeyNotFound),
+        }
     }
 
     /// Read file and load history of command from the log
@@ -142,22 +724,32 @@ impl KvStore {
 
         while let Some(command) = stream.next() {
             let new_offset = stream.byte_offset() as u64;
-            match command? {
+            let command = command?;
+            let start = match &command {
+                // sealed records are written compact: they start their own
+                // length before the end of the record.
+                Command::Sealed { .. } => new_offset - serde_json::to_vec(&command)?.len() as u64,
+                _ => initial_offset,
+            };
+            match self.keyring.open(command, start)? {
                 Command::Set { key, .. } => {
-                    if let Some(pointer) = self
+                    match self
                         .index
-                        .insert(key.to_string(), (initial_offset..new_offset).into())
+                        .insert(key, (initial_offset..new_offset).into())?
                     {
-                        self.uncompacted += pointer.len;
+                        Some(pointer) => self.uncompacted += pointer.len,
+                        None => self.keys += 1,
                     }
                 }
                 Command::Remove { key } => {
-                    if let Some(_pointer) = self.index.remove(key.as_str()) {
+                    if let Some(_pointer) = self.index.remove(&key)? {
                         // the "remove" command itself can be deleted in the next compaction.
                         // so we add its length to `uncompacted`.
                         self.uncompacted += new_offset - initial_offset;
+                        self.keys -= 1;
                     }
                 }
+                Command::Sealed { .. } => unreachable!("opened by the keyring"),
             };
             initial_offset = new_offset;
         }
@@ -166,68 +758,178 @@ impl KvStore {
     }
 
     /// Compact file when when the size exceeds the configured one. Compact == remove remove the entries for identical keys
+    ///
+    /// The live snapshots keep reading the previous log, which stays open
+    /// through their reader once the compacted one is renamed over it.
     fn compact(&mut self) -> Result<()> {
-        let mut path = std::env::current_dir()?;
-        path.push("compacted_log");
-        path.set_extension("json");
-
-        let temp_file = OpenOptions::new().write(true).create(true).open(&path)?;
+        let path = self.path.with_file_name("compacted_log.json");
+        let temp_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
 
         let mut writer_temp_file = BufWriter::new(temp_file);
-        self.reader.seek(SeekFrom::Start(0))?;
-        for (_key, pointer) in &mut self.index {
-            self.reader.seek(SeekFrom::Start(pointer.pos))?;
-            let mut cmd_reader = (&mut self.reader).take(pointer.len);
-            let _len = std::io::copy(&mut cmd_reader, &mut writer_temp_file)?;
-        }
+        let mut new_offset = 0;
+        let reader = &self.reader;
+        let keyring = &self.keyring;
+        self.index.relocate(|_, pointer| {
+            let len = reader.with_record(&pointer, |record| {
+                if keyring.is_active() {
+                    let command = serde_json::from_slice(record)?;
+                    if keyring.needs_reseal(&command) {
+                        let command = keyring.open(command, json_start(&pointer, record))?;
+                        let mut resealed = b"\r\n".to_vec();
+                        serde_json::to_writer(
+                            &mut resealed,
+                            &keyring.seal(command, new_offset + 2)?,
+                        )?;
+                        writer_temp_file.write_all(&resealed)?;
+                        return Ok(resealed.len() as u64);
+                    }
+                }
+                writer_temp_file.write_all(record)?;
+                Ok(record.len() as u64)
+            })?;
+            let relocated = (new_offset..new_offset + len).into();
+            new_offset += len;
+            Ok(relocated)
+        })?;
         writer_temp_file.flush()?;
+        // The compacted log replaces every write made so far: it must be on
+        // disk before the rename, whatever the durability policy.
+        writer_temp_file.get_ref().sync_all()?;
+        drop(writer_temp_file);
 
-        //self.reader = BufReader::new(OpenOptions::new().read(true).open(&path)?);
-        //self.writer = writer_temp_file;
-
-        std::fs::remove_file(&self.path)?;
         std::fs::rename(&path, &self.path)?;
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        *self.sync_file.lock().unwrap() = file.try_clone()?;
+        self.syncer.mark_synced();
+        self.writer = BufWriter::new(file);
+        self.reader = Arc::new(LogReader::open(&self.path, self.mmap)?);
         self.uncompacted = 0;
-        //self.path = path;
+        self.compactions += 1;
         Ok(())
     }
 }
 
+/// Read-only view of a `KvStore` as of the moment `KvsEngine::snapshot` was called.
+pub struct KvStoreSnapshot {
+    reader: Arc<LogReader>,
+    keyring: Arc<Keyring>,
+    index: IndexSnapshot,
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key)? {
+            Some(pointer) => read_value(&self.reader, &self.keyring, &pointer).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(&mut self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> SnapshotIter<'_> {
+        let reader = &self.reader;
+        let keyring = &self.keyring;
+        Box::new(self.index.range(range).map(move |entry| {
+            let (key, pointer) = entry?;
+            let value = read_value(reader, keyring, &pointer)?;
+            Ok((key, value))
+        }))
+    }
+}
+
+/// Reads the value of the "set" command at `pointer`.
+fn read_value(reader: &LogReader, keyring: &Keyring, pointer: &Pointer) -> Result<Vec<u8>> {
+    reader.with_record(pointer, |record| {
+        let command = serde_json::from_slice(record)?;
+        match keyring.open(command, json_start(pointer, record))? {
+            Command::Set { value, codec, .. } => compression::decompress(value, codec),
+            _ => Err(MyError::KeyNotFound),
+        }
+    })
+}
+
+/// Offset in the log of the command of `record`, after its leading newline.
+fn json_start(pointer: &Pointer, record: &[u8]) -> u64 {
+    let blank = record
+        .iter()
+        .take_while(|b| b.is_ascii_whitespace())
+        .count();
+    pointer.pos + blank as u64
+}
+
+/// Value of a "set" command, compressed before the store is locked.
+struct StoredValue {
+    value: Vec<u8>,
+    codec: Option<Codec>,
+    raw_len: usize,
+}
+
+impl StoredValue {
+    fn new(compression: Compression, value: Vec<u8>) -> Result<StoredValue> {
+        let raw_len = value.len();
+        let (value, codec) = compression::compress(compression, value)?;
+        Ok(StoredValue {
+            value,
+            codec,
+            raw_len,
+        })
+    }
+}
+
 /// Command is an enum with each possible command of the database. Each enum
 /// command will be serialized to a log file and used as the basis for populating/
 /// updating an in-memory key/value store.
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// Plain `Set` and `Remove` commands are also the records a replication leader
+/// streams to its followers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        /// Codec of a compressed value, which is then base64.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        codec: Option<Codec>,
+    },
+    Remove {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+    },
+    /// Another command, encrypted. Nonce and data are base64.
+    Sealed {
+        cipher: Cipher,
+        key_id: String,
+        nonce: String,
+        data: String,
+    },
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    pub(crate) fn set(key: Vec<u8>, value: Vec<u8>) -> Command {
+        Command::Set {
+            key,
+            value,
+            codec: None,
+        }
+    }
+
+    fn stored(key: Vec<u8>, value: StoredValue) -> Command {
+        Command::Set {
+            key,
+            value: value.value,
+            codec: value.codec,
+        }
     }
 
     // fn get(key: String) -> Command {
     //     Command::Get { key }
     // }
 
-    fn remove(key: String) -> Command {
+    pub(crate) fn remove(key: Vec<u8>) -> Command {
         Command::Remove { key }
     }
 }
-
-/// Represents the position and length of a json-serialized command in the log.
-#[derive(Clone, Debug)]
-struct Pointer {
-    pos: u64,
-    len: u64,
-}
-
-impl From<Range<u64>> for Pointer {
-    fn from(range: Range<u64>) -> Self {
-        Pointer {
-            pos: range.start,
-            len: range.end - range.start,
-        }
-    }
-}
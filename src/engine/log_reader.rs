@@ -0,0 +1,104 @@
+//! Positional reads of the records of a `KvStore` log
+//!
+//! A `LogReader` is shared by the store and its snapshots and reads through
+//! `&self`, so lookups from several threads do not wait on each other. Records
+//! are read either from a memory map of the log, grown when a record lies past
+//! its end, or with `pread`.
+use crate::engine::index::Pointer;
+use crate::{MyError, Result};
+use log::warn;
+use memmap2::Mmap;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::RwLock;
+
+pub(crate) struct LogReader {
+    file: File,
+    /// `None` when reading with `pread`.
+    map: Option<RwLock<Mmap>>,
+}
+
+impl LogReader {
+    /// Opens the log at `path`, memory-mapping it if `mmap` is set.
+    pub(crate) fn open(path: &Path, mmap: bool) -> Result<LogReader> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let map = if mmap {
+            match map_file(&file) {
+                Ok(map) => Some(RwLock::new(map)),
+                Err(e) => {
+                    warn!("Cannot map {}, reading with pread: {}", path.display(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Ok(LogReader { file, map })
+    }
+
+    /// Calls `f` with the bytes of the record at `pointer`.
+    pub(crate) fn with_record<T>(
+        &self,
+        pointer: &Pointer,
+        f: impl FnOnce(&[u8]) -> Result<T>,
+    ) -> Result<T> {
+        let start = pointer.pos as usize;
+        let end = (pointer.pos + pointer.len) as usize;
+        let map = match &self.map {
+            Some(map) => map,
+            None => {
+                let mut buf = vec![0; pointer.len as usize];
+                read_exact_at(&self.file, &mut buf, pointer.pos)?;
+                return f(&buf);
+            }
+        };
+
+        {
+            let map = map.read().unwrap();
+            if end <= map.len() {
+                return f(&map[start..end]);
+            }
+        }
+        // The record was appended after the log was mapped
+        let mut map = map.write().unwrap();
+        if end > map.len() {
+            *map = map_file(&self.file)?;
+        }
+        match map.get(start..end) {
+            Some(record) => f(record),
+            None => Err(MyError::StringError(format!(
+                "Record at {}..{} is past the end of the log",
+                start, end
+            ))),
+        }
+    }
+}
+
+/// Maps the whole file as it is now.
+fn map_file(file: &File) -> std::io::Result<Mmap> {
+    // Safety: the log is only ever appended to while it is mapped, compaction
+    // writes a new file and renames it over the log, so the mapped bytes never
+    // change or go away.
+    unsafe { Mmap::map(file) }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Bloom filter of the keys of a table
+use crate::{MyError, Result};
+
+/// Bits of filter per key, giving about 1% false positives.
+const BITS_PER_KEY: usize = 10;
+/// Number of bit positions probed per key.
+const PROBES: u8 = 7;
+
+/// Answers "certainly absent" or "maybe present" for a key, so lookups skip
+/// the tables which cannot hold it.
+pub(super) struct BloomFilter {
+    bits: Vec<u8>,
+    probes: u8,
+}
+
+impl BloomFilter {
+    /// Builds a filter holding the keys whose `hash` is given.
+    pub(super) fn from_hashes(hashes: &[u64]) -> BloomFilter {
+        let bytes = (hashes.len() * BITS_PER_KEY).max(64) / 8 + 1;
+        let mut filter = BloomFilter {
+            bits: vec![0; bytes],
+            probes: PROBES,
+        };
+        for &hash in hashes {
+            for bit in filter.positions(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Returns `false` if `key` was certainly not added to the filter.
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Bit positions of a key, by double hashing.
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let delta = hash.rotate_left(32) | 1;
+        (0..u64::from(self.probes))
+            .map(move |probe| (hash.wrapping_add(probe.wrapping_mul(delta)) % len) as usize)
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.bits.len() + 1);
+        buf.push(self.probes);
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub(super) fn decode(buf: &[u8]) -> Result<BloomFilter> {
+        match buf.split_first() {
+            Some((&probes, bits)) if !bits.is_empty() => Ok(BloomFilter {
+                bits: bits.to_vec(),
+                probes,
+            }),
+            _ => Err(MyError::StringError("Corrupt bloom filter".to_owned())),
+        }
+    }
+}
+
+/// 64-bit FNV-1a of `key`, mixed so that every bit depends on every byte.
+pub(super) fn hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
@@ -0,0 +1,599 @@
+//! Log-structured merge tree engine
+//!
+//! Writes go to a write-ahead log and to the memtable, a sorted map in memory.
+//! When the memtable grows past `LsmOptions::memtable_bytes` it is written as
+//! a sorted table in level 0 and the log starts over. Tables of level 0 may
+//! overlap each other; once there are `L0_COMPACTION_TRIGGER` of them they are
+//! merged into level 1. Tables of deeper levels cover disjoint key ranges, and
+//! a level holding more than its budget, `LEVEL_MULTIPLIER` times the budget
+//! of the level above, has one table merged into the next level.
+//!
+//! The engine keeps its files in the `lsm` directory of the store: the log
+//! `wal.log`, the tables `<id>.sst`, and `MANIFEST` listing the tables of
+//! each level.
+use self::sstable::{
+    after_end, before_start, table_path, Entry, EntryIter, MergeIter, Table, TableBuilder,
+};
+use self::wal::Wal;
+use crate::backup::{self, BackupManifest, Checksum};
+use crate::engine::durability::{Durability, Syncer};
+use crate::engine::{read_only_parameter, unknown_parameter, KvsEngine, KvsSnapshot, SnapshotIter};
+use crate::{MyError, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+mod bloom;
+mod sstable;
+mod wal;
+
+/// Name of the directory holding the files of an `LsmKvsEngine` in its store.
+pub(crate) const LSM_DIR: &str = "lsm";
+const MANIFEST_FILE: &str = "MANIFEST";
+const WAL_FILE: &str = "wal.log";
+/// Number of level 0 tables which triggers their compaction into level 1.
+const L0_COMPACTION_TRIGGER: usize = 4;
+const MAX_LEVELS: usize = 7;
+/// Ratio between the budgets of two consecutive levels.
+const LEVEL_MULTIPLIER: u64 = 10;
+/// Parameter of `KvsEngine::config` holding the size of the memtable.
+const MEMTABLE_BYTES: &str = "memtable-bytes";
+/// Number of pairs copied at once by `backup`.
+const BACKUP_BATCH: usize = 1024;
+
+/// Keys and their values, `None` being a tombstone.
+type Memtable = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// The `LsmKvsEngine` stores key/value pairs in a log-structured merge tree,
+/// keeping in memory only the recent writes and the index and bloom filter of
+/// each table.
+///
+/// An `LsmKvsEngine` is a handle: clones share the same tree and can be used
+/// from several threads.
+#[derive(Clone)]
+pub struct LsmKvsEngine {
+    inner: Arc<Mutex<LsmInner>>,
+    syncer: Arc<Syncer>,
+}
+
+/// Options of an `LsmKvsEngine`, used by `LsmKvsEngine::open_with`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    durability: Durability,
+    memtable_bytes: u64,
+    table_bytes: u64,
+    level_base_bytes: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            durability: Durability::None,
+            memtable_bytes: 4 << 20,
+            table_bytes: 2 << 20,
+            level_base_bytes: 10 << 20,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// Sets when writes to the log are synced to disk. Defaults to `Durability::None`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the size of the memtable written to level 0 when full. Defaults to 4 MiB.
+    pub fn memtable_bytes(mut self, bytes: u64) -> Self {
+        self.memtable_bytes = bytes;
+        self
+    }
+
+    /// Sets the size at which compaction starts a new table. Defaults to 2 MiB.
+    pub fn table_bytes(mut self, bytes: u64) -> Self {
+        self.table_bytes = bytes;
+        self
+    }
+
+    /// Sets the budget of level 1, deeper levels getting ten times the budget
+    /// of the level above. Defaults to 10 MiB.
+    pub fn level_base_bytes(mut self, bytes: u64) -> Self {
+        self.level_base_bytes = bytes;
+        self
+    }
+}
+
+/// Tables of each level, as recorded on disk.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+/// State of an `LsmKvsEngine`, shared by all its clones.
+struct LsmInner {
+    dir: PathBuf,
+    options: LsmOptions,
+    wal: Wal,
+    /// Shared with the snapshots, copied on write while one is alive.
+    memtable: Arc<Memtable>,
+    memtable_bytes: u64,
+    /// Level 0 is ordered oldest first, deeper levels by key.
+    levels: Vec<Vec<Arc<Table>>>,
+    next_id: u64,
+    /// Last key compacted out of each level, so compactions go round the key space.
+    cursors: Vec<Vec<u8>>,
+    syncer: Arc<Syncer>,
+}
+
+impl KvsEngine for LsmKvsEngine {
+    type Snapshot = LsmSnapshot;
+
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let ticket = self.inner.lock().unwrap().write(vec![(key, Some(value))])?;
+        self.syncer.commit(ticket)
+    }
+
+    /// Appends every insertion to the log with a single flush.
+    fn set_batch(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (key, Some(value)))
+            .collect();
+        let ticket = self.inner.lock().unwrap().write(entries)?;
+        self.syncer.commit(ticket)
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let inner = self.inner.lock().unwrap();
+        lookup(&inner.memtable, &inner.levels, key)
+    }
+
+    /// Remove a given key, writing a tombstone.
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        let ticket = {
+            let mut inner = self.inner.lock().unwrap();
+            if lookup(&inner.memtable, &inner.levels, key)?.is_none() {
+                return Err(MyError::KeyNotFound);
+            }
+            inner.write(vec![(key.to_vec(), None)])?
+        };
+        self.syncer.commit(ticket)
+    }
+
+    /// Returns a snapshot sharing the memtable and tables of the engine.
+    ///
+    /// Tables compacted away while the snapshot lives are deleted when it is
+    /// dropped.
+    fn snapshot(&mut self) -> Result<LsmSnapshot> {
+        let inner = self.inner.lock().unwrap();
+        Ok(LsmSnapshot {
+            memtable: Arc::clone(&inner.memtable),
+            levels: inner.levels.clone(),
+        })
+    }
+
+    /// Copies a snapshot into a new engine in `dest`.
+    fn backup(&mut self, dest: &Path) -> Result<BackupManifest> {
+        backup::create_backup_dir(dest)?;
+        let mut snapshot = self.snapshot()?;
+        let mut target = LsmKvsEngine::open(dest)?;
+        let mut checksum = Checksum::new();
+        let mut batch = Vec::with_capacity(BACKUP_BATCH);
+        for entry in snapshot.iter() {
+            let (key, value) = entry?;
+            checksum.update(&key, &value);
+            batch.push((key, value));
+            if batch.len() == BACKUP_BATCH {
+                target.set_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        target.set_batch(batch)?;
+        target.flush()?;
+        drop(target);
+
+        let manifest = BackupManifest::new("lsm", checksum.count(), checksum.finish());
+        manifest.write(dest)?;
+        Ok(manifest)
+    }
+
+    fn engine_name(&self) -> &'static str {
+        "lsm"
+    }
+
+    /// Writes the memtable to level 0 and runs the compactions it calls for.
+    fn compact(&mut self) -> Result<()> {
+        LsmKvsEngine::flush(self)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        LsmKvsEngine::sync(self)
+    }
+
+    fn config(&self) -> BTreeMap<String, String> {
+        let mut config = BTreeMap::new();
+        config.insert("durability".to_owned(), self.durability().to_string());
+        config.insert(
+            MEMTABLE_BYTES.to_owned(),
+            self.inner
+                .lock()
+                .unwrap()
+                .options
+                .memtable_bytes
+                .to_string(),
+        );
+        config
+    }
+
+    /// The size of the memtable can be changed, and applies from the next write.
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            MEMTABLE_BYTES => {
+                let bytes = value.parse().map_err(|_| {
+                    MyError::StringError(format!("Invalid {}: {}", MEMTABLE_BYTES, value))
+                })?;
+                self.inner.lock().unwrap().options.memtable_bytes = bytes;
+                Ok(())
+            }
+            "durability" => Err(read_only_parameter(name)),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+}
+
+impl LsmKvsEngine {
+    /// Open the LsmKvsEngine at a given path with default options.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with(path, LsmOptions::default())
+    }
+
+    /// Open the LsmKvsEngine at a given path with the given options.
+    ///
+    /// Tables left over by an interrupted compaction are deleted, and the
+    /// log is replayed into the memtable.
+    pub fn open_with(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmKvsEngine> {
+        let dir = path.into().join(LSM_DIR);
+        fs::create_dir_all(&dir)?;
+
+        let manifest = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut levels = vec![Vec::new(); MAX_LEVELS];
+        let mut live = HashSet::new();
+        for (level, ids) in manifest.levels.iter().enumerate() {
+            for &id in ids {
+                levels[level].push(Arc::new(Table::open(id, table_path(&dir, id))?));
+                live.insert(table_path(&dir, id));
+            }
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension() == Some("sst".as_ref()) && !live.contains(&path) {
+                info!("Removing leftover table {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let (wal, entries) = Wal::open(&dir.join(WAL_FILE))?;
+        let syncer = {
+            let sync_file = wal.sync_file();
+            Syncer::new(
+                options.durability,
+                Box::new(move || Ok(sync_file.lock().unwrap().sync_data()?)),
+            )
+        };
+        let mut memtable = Memtable::new();
+        let mut memtable_bytes = 0;
+        for (key, value) in entries {
+            memtable_bytes += entry_bytes(&key, value.as_deref());
+            memtable.insert(key, value);
+        }
+
+        let mut inner = LsmInner {
+            dir,
+            options,
+            wal,
+            memtable: Arc::new(memtable),
+            memtable_bytes,
+            levels,
+            next_id: manifest.next_id,
+            cursors: vec![Vec::new(); MAX_LEVELS],
+            syncer: Arc::clone(&syncer),
+        };
+        inner.maybe_flush()?;
+        Ok(LsmKvsEngine {
+            inner: Arc::new(Mutex::new(inner)),
+            syncer,
+        })
+    }
+
+    /// Returns the durability policy of the engine.
+    pub fn durability(&self) -> Durability {
+        self.syncer.policy()
+    }
+
+    /// Syncs every write made so far to disk, whatever the durability policy.
+    pub fn sync(&self) -> Result<()> {
+        self.syncer.sync_all()
+    }
+
+    /// Writes the memtable to a level 0 table and runs the compactions it calls for.
+    pub fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.flush_memtable()?;
+        inner.compact()
+    }
+
+    /// Returns the number of tables in each level, level 0 first.
+    pub fn tables_per_level(&self) -> Vec<usize> {
+        let inner = self.inner.lock().unwrap();
+        inner.levels.iter().map(Vec::len).collect()
+    }
+}
+
+impl LsmInner {
+    /// Appends entries to the log and the memtable. Returns the ticket of the write.
+    fn write(&mut self, entries: Vec<Entry>) -> Result<u64> {
+        for (key, value) in &entries {
+            self.wal.append(key, value.as_deref())?;
+        }
+        self.wal.flush()?;
+        let ticket = self.syncer.ticket();
+        let memtable = Arc::make_mut(&mut self.memtable);
+        for (key, value) in entries {
+            self.memtable_bytes += entry_bytes(&key, value.as_deref());
+            memtable.insert(key, value);
+        }
+        self.maybe_flush()?;
+        Ok(ticket)
+    }
+
+    fn maybe_flush(&mut self) -> Result<()> {
+        if self.memtable_bytes < self.options.memtable_bytes {
+            return Ok(());
+        }
+        self.flush_memtable()?;
+        self.compact()
+    }
+
+    /// Writes the memtable to a new level 0 table and empties the log.
+    fn flush_memtable(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let mut builder = self.new_table()?;
+        for (key, value) in self.memtable.iter() {
+            builder.add(key, value.as_deref())?;
+        }
+        let table = builder.finish()?;
+        info!(
+            "Flushed {} entries of the memtable to table {}",
+            table.entries(),
+            table.id()
+        );
+        self.levels[0].push(Arc::new(table));
+        self.write_manifest()?;
+
+        // Every write so far is in a synced table now.
+        self.wal.reset()?;
+        self.syncer.mark_synced();
+        self.memtable = Arc::new(Memtable::new());
+        self.memtable_bytes = 0;
+        Ok(())
+    }
+
+    /// Runs compactions until level 0 is small enough and every level fits its budget.
+    fn compact(&mut self) -> Result<()> {
+        loop {
+            if self.levels[0].len() >= L0_COMPACTION_TRIGGER {
+                self.compact_level(0)?;
+                continue;
+            }
+            let over_budget = (1..MAX_LEVELS - 1).find(|&level| {
+                let bytes: u64 = self.levels[level].iter().map(|table| table.size()).sum();
+                bytes > self.level_budget(level)
+            });
+            match over_budget {
+                Some(level) => self.compact_level(level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn level_budget(&self, level: usize) -> u64 {
+        self.options.level_base_bytes * LEVEL_MULTIPLIER.pow(level as u32 - 1)
+    }
+
+    /// Merges every table of level 0, or one table of a deeper level, with the
+    /// overlapping tables of the next level.
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        let inputs: Vec<Arc<Table>> = if level == 0 {
+            self.levels[0].iter().rev().cloned().collect()
+        } else {
+            let cursor = &self.cursors[level];
+            let table = self.levels[level]
+                .iter()
+                .find(|table| table.first_key() > cursor.as_slice())
+                .unwrap_or(&self.levels[level][0]);
+            vec![Arc::clone(table)]
+        };
+        let smallest = inputs.iter().map(|table| table.first_key()).min().unwrap();
+        let largest = inputs.iter().map(|table| table.last_key()).max().unwrap();
+        let overlapping: Vec<Arc<Table>> = self.levels[level + 1]
+            .iter()
+            .filter(|table| table.overlaps(smallest, largest))
+            .cloned()
+            .collect();
+        let largest = largest.to_vec();
+
+        // Tombstones can go once no older table lies below the target level.
+        let drop_tombstones = self.levels[level + 2..].iter().all(Vec::is_empty);
+        let mut sources: Vec<EntryIter> = inputs
+            .iter()
+            .map(|table| Box::new(table.iter_from(&Bound::Unbounded)) as EntryIter)
+            .collect();
+        sources.push(level_iter(overlapping.clone(), &Bound::Unbounded));
+
+        let mut outputs = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for entry in MergeIter::new(sources) {
+            let (key, value) = entry?;
+            if value.is_none() && drop_tombstones {
+                continue;
+            }
+            let table = match builder.as_mut() {
+                Some(table) => table,
+                None => builder.get_or_insert(self.new_table()?),
+            };
+            table.add(&key, value.as_deref())?;
+            if table.size() >= self.options.table_bytes {
+                outputs.push(Arc::new(builder.take().unwrap().finish()?));
+            }
+        }
+        if let Some(table) = builder {
+            outputs.push(Arc::new(table.finish()?));
+        }
+
+        let removed: HashSet<u64> = inputs
+            .iter()
+            .chain(overlapping.iter())
+            .map(|table| table.id())
+            .collect();
+        info!(
+            "Compacted {} tables of level {} into {} tables of level {}",
+            removed.len(),
+            level,
+            outputs.len(),
+            level + 1
+        );
+        for tables in &mut self.levels[level..=level + 1] {
+            tables.retain(|table| !removed.contains(&table.id()));
+        }
+        self.levels[level + 1].extend(outputs);
+        self.levels[level + 1].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        if level > 0 {
+            self.cursors[level] = largest;
+        }
+        self.write_manifest()?;
+        for table in inputs.iter().chain(overlapping.iter()) {
+            table.mark_obsolete();
+        }
+        Ok(())
+    }
+
+    fn new_table(&mut self) -> Result<TableBuilder> {
+        let id = self.next_id;
+        self.next_id += 1;
+        TableBuilder::create(id, table_path(&self.dir, id))
+    }
+
+    /// Atomically records the tables of each level.
+    fn write_manifest(&self) -> Result<()> {
+        let manifest = Manifest {
+            next_id: self.next_id,
+            levels: self
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.id()).collect())
+                .collect(),
+        };
+        let tmp = self.dir.join("MANIFEST.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&manifest)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
+
+/// Approximate memory used by an entry of the memtable.
+fn entry_bytes(key: &[u8], value: Option<&[u8]>) -> u64 {
+    (key.len() + value.map_or(0, <[u8]>::len)) as u64
+}
+
+/// Looks `key` up from the newest data to the oldest.
+fn lookup(memtable: &Memtable, levels: &[Vec<Arc<Table>>], key: &[u8]) -> Result<Option<Vec<u8>>> {
+    if let Some(value) = memtable.get(key) {
+        return Ok(value.clone());
+    }
+    for table in levels[0].iter().rev() {
+        if let Some(value) = table.get(key)? {
+            return Ok(value);
+        }
+    }
+    for tables in &levels[1..] {
+        let candidate = tables.partition_point(|table| table.last_key() < key);
+        if let Some(table) = tables.get(candidate) {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Iterates over the entries of the disjoint, sorted tables of a level.
+fn level_iter<'a>(tables: Vec<Arc<Table>>, start: &Bound<Vec<u8>>) -> EntryIter<'a> {
+    let tables: Vec<Arc<Table>> = tables
+        .into_iter()
+        .filter(|table| !before_start(table.last_key(), start))
+        .collect();
+    let start = start.clone();
+    Box::new(
+        tables
+            .into_iter()
+            .flat_map(move |table| table.iter_from(&start)),
+    )
+}
+
+/// Read-only view of an `LsmKvsEngine` as of the moment `KvsEngine::snapshot` was called.
+pub struct LsmSnapshot {
+    memtable: Arc<Memtable>,
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl KvsSnapshot for LsmSnapshot {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        lookup(&self.memtable, &self.levels, key)
+    }
+
+    fn scan(&mut self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> SnapshotIter<'_> {
+        let (start, end) = range;
+        let mut sources: Vec<EntryIter> = vec![Box::new(
+            self.memtable
+                .range((start.clone(), Bound::Unbounded))
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        )];
+        for table in self.levels[0].iter().rev() {
+            sources.push(Box::new(table.iter_from(&start)));
+        }
+        for tables in &self.levels[1..] {
+            sources.push(level_iter(tables.clone(), &start));
+        }
+        Box::new(
+            MergeIter::new(sources)
+                .take_while(move |entry| match entry {
+                    Ok((key, _)) => !after_end(key, &end),
+                    Err(_) => true,
+                })
+                .filter_map(|entry| match entry {
+                    Ok((key, Some(value))) => Some(Ok((key, value))),
+                    Ok((_, None)) => None,
+                    Err(err) => Some(Err(err)),
+                }),
+        )
+    }
+}
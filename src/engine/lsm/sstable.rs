@@ -0,0 +1,499 @@
+//! Sorted string tables
+//!
+//! A table is an immutable file of entries sorted by key:
+//!
+//! - data blocks of about `BLOCK_BYTES` of entries, each followed by its CRC32;
+//! - the index: the first key of the table, the number of blocks, then for
+//!   each block its last key, offset and length;
+//! - the bloom filter of the keys;
+//! - a 32-byte footer: the offsets of the index and of the bloom filter, the
+//!   number of entries and `TABLE_MAGIC`, as little-endian `u64`s.
+//!
+//! An entry is the key length as a little-endian `u32`, the key, then `0` for a
+//! tombstone, or `1`, the value length and the value.
+use super::bloom::{self, BloomFilter};
+use crate::{MyError, Result};
+use std::cmp::Ordering;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+
+/// Target size of a data block.
+const BLOCK_BYTES: usize = 4096;
+const FOOTER_BYTES: u64 = 32;
+const TABLE_MAGIC: u64 = 0x4b56_5353_5441_4231;
+
+/// A key and its value, `None` being a tombstone.
+pub(super) type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// An iterator of entries in key order.
+pub(super) type EntryIter<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+pub(super) fn encode_entry(buf: &mut Vec<u8>, key: &[u8], value: Option<&[u8]>) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    match value {
+        Some(value) => {
+            buf.push(1);
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value);
+        }
+        None => buf.push(0),
+    }
+}
+
+/// Decodes the entry at the start of `buf` and advances it past the entry.
+pub(super) fn decode_entry(buf: &mut &[u8]) -> Result<Entry> {
+    let key = read_bytes(buf)?;
+    let value = match take(buf, 1)?[0] {
+        0 => None,
+        1 => Some(read_bytes(buf)?),
+        kind => return Err(corrupt(format!("unknown entry kind {}", kind))),
+    };
+    Ok((key, value))
+}
+
+fn read_bytes(buf: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = read_u32(buf)? as usize;
+    Ok(take(buf, len)?.to_vec())
+}
+
+fn read_u32(buf: &mut &[u8]) -> Result<u32> {
+    let bytes = take(buf, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(buf: &mut &[u8]) -> Result<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(buf, 8)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(corrupt("truncated entry".to_owned()));
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+fn corrupt(reason: String) -> MyError {
+    MyError::StringError(format!("Corrupt table: {}", reason))
+}
+
+/// Location of a data block in its table.
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+}
+
+/// Writes a table from entries added in key order.
+pub(super) struct TableBuilder {
+    id: u64,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    first_key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    pub(super) fn create(id: u64, path: PathBuf) -> Result<TableBuilder> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(TableBuilder {
+            id,
+            path,
+            writer: BufWriter::new(file),
+            offset: 0,
+            block: Vec::with_capacity(BLOCK_BYTES * 2),
+            first_key: None,
+            last_key: Vec::new(),
+            index: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    pub(super) fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+        encode_entry(&mut self.block, key, value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.hashes.push(bloom::hash(key));
+        if self.block.len() >= BLOCK_BYTES {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Number of bytes written so far.
+    pub(super) fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.writer
+            .write_all(&crc32fast::hash(&self.block).to_le_bytes())?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u32,
+        });
+        self.offset += self.block.len() as u64 + 4;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the index, bloom filter and footer, syncs the file and opens it.
+    ///
+    /// At least one entry must have been added.
+    pub(super) fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+        let first_key = self.first_key.take().unwrap_or_default();
+
+        let index_offset = self.offset;
+        let mut index = Vec::new();
+        index.extend_from_slice(&(first_key.len() as u32).to_le_bytes());
+        index.extend_from_slice(&first_key);
+        index.extend_from_slice(&(self.index.len() as u32).to_le_bytes());
+        for block in &self.index {
+            index.extend_from_slice(&(block.last_key.len() as u32).to_le_bytes());
+            index.extend_from_slice(&block.last_key);
+            index.extend_from_slice(&block.offset.to_le_bytes());
+            index.extend_from_slice(&block.len.to_le_bytes());
+        }
+        self.writer.write_all(&index)?;
+
+        let bloom_offset = index_offset + index.len() as u64;
+        self.writer
+            .write_all(&BloomFilter::from_hashes(&self.hashes).encode())?;
+
+        for field in &[
+            index_offset,
+            bloom_offset,
+            self.hashes.len() as u64,
+            TABLE_MAGIC,
+        ] {
+            self.writer.write_all(&field.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Table::open(self.id, self.path)
+    }
+}
+
+/// An open table. The index and bloom filter are kept in memory, data
+/// blocks are read on demand.
+pub(super) struct Table {
+    id: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    size: u64,
+    entries: u64,
+    first_key: Vec<u8>,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    /// Set once the table was compacted away: its file is deleted when the
+    /// last reference, possibly held by a snapshot, goes away.
+    obsolete: AtomicBool,
+}
+
+impl Table {
+    pub(super) fn open(id: u64, path: PathBuf) -> Result<Table> {
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_BYTES {
+            return Err(corrupt(format!("{} is too short", path.display())));
+        }
+        let mut footer = [0; FOOTER_BYTES as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_BYTES))?;
+        file.read_exact(&mut footer)?;
+        let mut footer = &footer[..];
+        let index_offset = read_u64(&mut footer)?;
+        let bloom_offset = read_u64(&mut footer)?;
+        let entries = read_u64(&mut footer)?;
+        if read_u64(&mut footer)? != TABLE_MAGIC
+            || index_offset > bloom_offset
+            || bloom_offset > size - FOOTER_BYTES
+        {
+            return Err(corrupt(format!("bad footer in {}", path.display())));
+        }
+
+        let mut meta = vec![0; (size - FOOTER_BYTES - index_offset) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut meta)?;
+        let (mut index_buf, bloom_buf) = meta.split_at((bloom_offset - index_offset) as usize);
+        let first_key = read_bytes(&mut index_buf)?;
+        let blocks = read_u32(&mut index_buf)?;
+        let mut index = Vec::with_capacity(blocks as usize);
+        for _ in 0..blocks {
+            index.push(BlockHandle {
+                last_key: read_bytes(&mut index_buf)?,
+                offset: read_u64(&mut index_buf)?,
+                len: read_u32(&mut index_buf)?,
+            });
+        }
+        if index.is_empty() {
+            return Err(corrupt(format!("{} has no data block", path.display())));
+        }
+
+        Ok(Table {
+            id,
+            path,
+            file: Mutex::new(file),
+            size,
+            entries,
+            first_key,
+            index,
+            bloom: BloomFilter::decode(bloom_buf)?,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Size of the table file in bytes.
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(super) fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    pub(super) fn first_key(&self) -> &[u8] {
+        &self.first_key
+    }
+
+    pub(super) fn last_key(&self) -> &[u8] {
+        &self.index[self.index.len() - 1].last_key
+    }
+
+    /// Returns `true` if the table may hold keys between `smallest` and `largest`.
+    pub(super) fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.first_key() <= largest && self.last_key() >= smallest
+    }
+
+    pub(super) fn mark_obsolete(&self) {
+        self.obsolete.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Looks `key` up. Returns `None` if the table holds no entry for it, and
+    /// `Some(None)` if it holds a tombstone.
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        if key < self.first_key() || key > self.last_key() || !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .partition_point(|block| block.last_key.as_slice() < key);
+        let entries = self.read_block(block)?;
+        Ok(entries
+            .binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key))
+            .ok()
+            .map(|position| entries[position].1.clone()))
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>> {
+        let handle = &self.index[block];
+        let mut data = vec![0; handle.len as usize + 4];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut data)?;
+        }
+        let (mut entries_buf, crc) = data.split_at(handle.len as usize);
+        if crc32fast::hash(entries_buf).to_le_bytes() != crc {
+            return Err(corrupt(format!(
+                "bad checksum for block {} of {}",
+                block,
+                self.path.display()
+            )));
+        }
+        let mut entries = Vec::new();
+        while !entries_buf.is_empty() {
+            entries.push(decode_entry(&mut entries_buf)?);
+        }
+        Ok(entries)
+    }
+
+    /// Iterates over the entries from `start` on.
+    pub(super) fn iter_from(self: &Arc<Self>, start: &Bound<Vec<u8>>) -> TableIter {
+        let next_block = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .index
+                .partition_point(|block| block.last_key.as_slice() < key.as_slice()),
+            Bound::Unbounded => 0,
+        };
+        TableIter {
+            table: Arc::clone(self),
+            next_block,
+            entries: Vec::new().into_iter(),
+            start: start.clone(),
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(AtomicOrdering::SeqCst) {
+            // A leftover file is removed the next time the engine is opened.
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Iterator over the entries of a table, reading one block at a time.
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    next_block: usize,
+    entries: std::vec::IntoIter<Entry>,
+    /// Entries before this bound are skipped.
+    start: Bound<Vec<u8>>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                if before_start(&entry.0, &self.start) {
+                    continue;
+                }
+                self.start = Bound::Unbounded;
+                return Some(Ok(entry));
+            }
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => {
+                    self.entries = entries.into_iter();
+                    self.next_block += 1;
+                }
+                Err(err) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Returns `true` if `key` comes before the `start` bound of a range.
+pub(super) fn before_start(key: &[u8], start: &Bound<Vec<u8>>) -> bool {
+    match start {
+        Bound::Included(start) => key < start.as_slice(),
+        Bound::Excluded(start) => key <= start.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+/// Returns `true` if `key` comes after the `end` bound of a range.
+pub(super) fn after_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(end) => key > end.as_slice(),
+        Bound::Excluded(end) => key >= end.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+/// Merges sorted sources into one sorted iterator. When several sources hold
+/// the same key, the entry of the first source wins: sources are given newest
+/// first.
+pub(super) struct MergeIter<'a> {
+    sources: Vec<EntryIter<'a>>,
+    heads: Vec<Option<Entry>>,
+    started: bool,
+}
+
+impl<'a> MergeIter<'a> {
+    pub(super) fn new(sources: Vec<EntryIter<'a>>) -> MergeIter<'a> {
+        MergeIter {
+            heads: sources.iter().map(|_| None).collect(),
+            sources,
+            started: false,
+        }
+    }
+
+    fn advance(&mut self, source: usize) -> Result<()> {
+        self.heads[source] = match self.sources[source].next() {
+            Some(entry) => Some(entry?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        if !self.started {
+            self.started = true;
+            for source in 0..self.sources.len() {
+                self.advance(source)?;
+            }
+        }
+        let newest = (0..self.heads.len())
+            .filter(|&source| self.heads[source].is_some())
+            .min_by(|&a, &b| compare_heads(&self.heads[a], &self.heads[b]));
+        let newest = match newest {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        let entry = self.heads[newest].take().unwrap();
+        self.advance(newest)?;
+        // Older versions of the key are shadowed.
+        for source in 0..self.heads.len() {
+            while matches!(&self.heads[source], Some((key, _)) if *key == entry.0) {
+                self.advance(source)?;
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+fn compare_heads(a: &Option<Entry>, b: &Option<Entry>) -> Ordering {
+    match (a, b) {
+        (Some((a, _)), Some((b, _))) => a.cmp(b),
+        _ => Ordering::Equal,
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(err) => {
+                // Stop after an error rather than yield entries out of order.
+                self.sources.clear();
+                self.heads.clear();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Path of the table `id` in `dir`.
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", id))
+}
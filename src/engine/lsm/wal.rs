@@ -0,0 +1,103 @@
+//! Write-ahead log of the memtable
+//!
+//! Each record is the length of its payload and the CRC32 of the payload, both
+//! little-endian `u32`, followed by the payload: an encoded table entry. A torn
+//! or corrupt record ends the log: it and everything after it are discarded
+//! when the log is replayed.
+use super::sstable::{decode_entry, encode_entry, Entry};
+use crate::Result;
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub(super) struct Wal {
+    writer: BufWriter<File>,
+    /// Handle on the log used by the `Syncer`.
+    sync_file: Arc<Mutex<File>>,
+}
+
+impl Wal {
+    /// Opens the log at `path`, returning it with the entries it holds, oldest first.
+    pub(super) fn open(path: &Path) -> Result<(Wal, Vec<Entry>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        let (entries, valid) = replay(&content);
+        if valid < content.len() {
+            warn!(
+                "Discarding {} bytes of torn records at the end of {}",
+                content.len() - valid,
+                path.display()
+            );
+            file.set_len(valid as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(valid as u64))?;
+
+        let wal = Wal {
+            sync_file: Arc::new(Mutex::new(file.try_clone()?)),
+            writer: BufWriter::new(file),
+        };
+        Ok((wal, entries))
+    }
+
+    /// Buffers an entry. Entries reach the file on `flush`.
+    pub(super) fn append(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let mut payload = Vec::with_capacity(key.len() + value.map_or(0, <[u8]>::len) + 9);
+        encode_entry(&mut payload, key, value);
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        self.writer.write_all(&payload)?;
+        Ok(())
+    }
+
+    pub(super) fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Empties the log once its entries are safely in a table.
+    pub(super) fn reset(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let file = self.writer.get_mut();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub(super) fn sync_file(&self) -> Arc<Mutex<File>> {
+        Arc::clone(&self.sync_file)
+    }
+}
+
+/// Decodes the records of `content`. Returns the entries and the length of
+/// the valid prefix of `content`.
+fn replay(content: &[u8]) -> (Vec<Entry>, usize) {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = content.get(offset..offset + 8) {
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let payload = match content.get(offset + 8..offset + 8 + len) {
+            Some(payload) if crc32fast::hash(payload) == crc => payload,
+            _ => break,
+        };
+        match decode_entry(&mut &payload[..]) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+        offset += 8 + len;
+    }
+    (entries, offset)
+}
@@ -0,0 +1,189 @@
+//! In-memory engine, for tests and caches
+use crate::backup::{self, BackupManifest, Checksum};
+use crate::dump::{self, DumpFormat};
+use crate::engine::{EngineStats, KvsEngine, KvsSnapshot, SnapshotIter};
+use crate::{MyError, Result};
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Name of the file holding the snapshot of a `MemoryKvsEngine` in its directory.
+pub(crate) const MEMORY_FILE: &str = "memory.bin";
+
+/// The `MemoryKvsEngine` keeps key/value pairs in an ordered map in memory.
+///
+/// Readers share a read lock while writers take it exclusively. A
+/// `MemoryKvsEngine` is a handle: clones share the same map and can be used
+/// from several threads.
+///
+/// An engine created with `new` is lost when dropped. An engine created with
+/// `open` loads the snapshot file of its directory and writes it back when the
+/// last handle is dropped, or when `persist` is called.
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    inner: Arc<MemoryInner>,
+}
+
+#[derive(Default)]
+struct MemoryInner {
+    map: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+    /// Snapshot file written on drop, if any.
+    path: Option<PathBuf>,
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    type Snapshot = MemorySnapshot;
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.inner.map.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn set_batch(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.inner.map.write().unwrap().extend(entries);
+        Ok(())
+    }
+
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.inner.map.read().unwrap().get(key).cloned())
+    }
+
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.inner
+            .map
+            .write()
+            .unwrap()
+            .remove(key)
+            .map(|_| ())
+            .ok_or(MyError::KeyNotFound)
+    }
+
+    /// Returns a copy of the map.
+    fn snapshot(&mut self) -> Result<MemorySnapshot> {
+        Ok(MemorySnapshot {
+            entries: self.inner.map.read().unwrap().clone(),
+        })
+    }
+
+    /// Writes the snapshot file of a copy of the map to `dest`.
+    fn backup(&mut self, dest: &Path) -> Result<BackupManifest> {
+        backup::create_backup_dir(dest)?;
+        let mut snapshot = self.snapshot()?;
+        let mut checksum = Checksum::new();
+        for (key, value) in &snapshot.entries {
+            checksum.update(key, value);
+        }
+        write_snapshot(&mut snapshot, &dest.join(MEMORY_FILE))?;
+
+        let manifest = BackupManifest::new("memory", checksum.count(), checksum.finish());
+        manifest.write(dest)?;
+        Ok(manifest)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: Some(self.inner.map.read().unwrap().len() as u64),
+            ..EngineStats::default()
+        })
+    }
+
+    fn engine_name(&self) -> &'static str {
+        "memory"
+    }
+
+    /// Writes the snapshot file, if the engine has one.
+    fn sync(&mut self) -> Result<()> {
+        self.persist()
+    }
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty engine which is never written to disk.
+    pub fn new() -> MemoryKvsEngine {
+        MemoryKvsEngine::default()
+    }
+
+    /// Opens an engine backed by the snapshot file of the directory `path`.
+    ///
+    /// The snapshot is loaded if it exists, and written back when the last
+    /// handle on the engine is dropped.
+    pub fn open(path: impl Into<PathBuf>) -> Result<MemoryKvsEngine> {
+        let dir = path.into();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(MEMORY_FILE);
+        let mut engine = MemoryKvsEngine::new();
+        if path.exists() {
+            let count = dump::import(&mut engine, File::open(&path)?, DumpFormat::Binary)?;
+            info!("Loaded {} keys from {}", count, path.display());
+        }
+        Ok(MemoryKvsEngine {
+            inner: Arc::new(MemoryInner {
+                map: RwLock::new(engine.snapshot()?.entries),
+                path: Some(path),
+            }),
+        })
+    }
+
+    /// Writes the snapshot file now. Does nothing for an engine created with `new`.
+    pub fn persist(&self) -> Result<()> {
+        self.inner.persist()
+    }
+}
+
+impl MemoryInner {
+    fn persist(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let mut snapshot = MemorySnapshot {
+                entries: self.map.read().unwrap().clone(),
+            };
+            write_snapshot(&mut snapshot, path)?;
+            info!(
+                "Saved {} keys to {}",
+                snapshot.entries.len(),
+                path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MemoryInner {
+    fn drop(&mut self) {
+        if let Err(err) = self.persist() {
+            error!("Failed to save the in-memory store: {}", err);
+        }
+    }
+}
+
+/// Writes a dump next to `path` then moves it in place, so a crash never
+/// leaves a truncated snapshot.
+fn write_snapshot(snapshot: &mut MemorySnapshot, path: &Path) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let file = File::create(&tmp)?;
+    dump::export(snapshot, &file, DumpFormat::Binary)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Read-only copy of a `MemoryKvsEngine` as of the moment `KvsEngine::snapshot` was called.
+pub struct MemorySnapshot {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KvsSnapshot for MemorySnapshot {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn scan(&mut self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> SnapshotIter<'_> {
+        Box::new(
+            self.entries
+                .range(range)
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        )
+    }
+}
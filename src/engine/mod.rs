@@ -1,28 +1,267 @@
 //! This module define key value storage engines.
 
-use crate::Result;
+use crate::backup::BackupManifest;
+use crate::{MyError, Result};
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+mod cache;
+mod compression;
+mod durability;
+mod encryption;
+mod index;
 mod kvs;
+mod log_reader;
+mod lsm;
+mod memory;
 mod sled;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::cache::CacheStats;
+pub use self::compression::{Compression, CompressionStats};
+pub use self::durability::Durability;
+pub use self::encryption::{Cipher, EncryptionKey};
+pub use self::index::IndexStats;
+pub(crate) use self::kvs::Command;
+pub(crate) use self::kvs::NAMESPACES_DIR;
+pub use self::kvs::{KvStore, KvStoreOptions, KvStoreSnapshot};
+pub(crate) use self::lsm::LSM_DIR;
+pub use self::lsm::{LsmKvsEngine, LsmOptions, LsmSnapshot};
+pub(crate) use self::memory::MEMORY_FILE;
+pub use self::memory::{MemoryKvsEngine, MemorySnapshot};
+pub use self::sled::{SledKvsEngine, SledSnapshot};
+
+/// Name of the namespace an engine is opened on.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Longest namespace name accepted.
+const MAX_NAMESPACE_LEN: usize = 64;
 
 /// Trait for a key value storage engine.
+///
+/// Engines store arbitrary bytes. The `String` methods are a convenience layer
+/// on top of the byte methods.
 pub trait KvsEngine {
+    /// Read-only view of the engine returned by `snapshot`. A Raft leader
+    /// reads it from its own thread while it sends it to a follower.
+    type Snapshot: KvsSnapshot + Send;
+
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `MyError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Sets the values of many keys at once.
+    ///
+    /// Engines override this to amortize flushes when bulk loading.
+    fn set_batch(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        for (key, value) in entries {
+            self.set_bytes(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a read-only handle which sees the store as of this moment.
+    ///
+    /// Writes made after the snapshot is taken are not visible through it.
+    fn snapshot(&mut self) -> Result<Self::Snapshot>;
+
+    /// Writes a consistent copy of the store to the empty directory `dest`.
+    ///
+    /// Writes may continue while the backup is taken; they are not part of it.
+    /// The backup can be installed with `kvs::backup::restore`.
+    fn backup(&mut self, dest: &Path) -> Result<BackupManifest>;
+
+    /// Returns the gauges and counters of the engine, for monitoring.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats::default())
+    }
+
+    /// Returns the name of the engine, as in backup manifests.
+    fn engine_name(&self) -> &'static str;
+
+    /// Compacts the store now rather than when the engine decides to.
+    ///
+    /// # Errors
+    ///
+    /// It returns `MyError::StringError` if the engine cannot compact on demand.
+    fn compact(&mut self) -> Result<()> {
+        Err(MyError::StringError(
+            "The engine does not compact on demand".to_owned(),
+        ))
+    }
+
+    /// Syncs every write made so far to disk, whatever the durability policy.
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the parameters of the engine, by name.
+    fn config(&self) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+
+    /// Changes a parameter of the engine while it is open.
+    ///
+    /// # Errors
+    ///
+    /// It returns `MyError::StringError` if the parameter is unknown, cannot
+    /// change while the engine is open, or if `value` is invalid.
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        let _ = value;
+        Err(unknown_parameter(name))
+    }
+
+    /// Returns a handle on the namespace `name`, a keyspace of its own which is
+    /// created on first use. `DEFAULT_NAMESPACE` is the keyspace the engine
+    /// was opened on. Snapshots cover a single namespace, and so do backups
+    /// except those of the default namespace, which cover every one.
+    ///
+    /// # Errors
+    ///
+    /// It returns `MyError::StringError` if the engine has no namespaces, or
+    /// if `name` is not a valid namespace name.
+    fn namespace(&mut self, name: &str) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Err(MyError::StringError(format!(
+            "Namespace {}: the engine does not support namespaces",
+            name
+        )))
+    }
+
+    /// Returns a handle on the namespace `name` if it exists, without
+    /// creating it otherwise. Engines which cannot tell create it.
+    ///
+    /// # Errors
+    ///
+    /// The same as `namespace`.
+    fn existing_namespace(&mut self, name: &str) -> Result<Option<Self>>
+    where
+        Self: Sized,
+    {
+        self.namespace(name).map(Some)
+    }
+
+    /// Returns the names of the namespaces other than the default one, in
+    /// order. Engines without namespaces have none.
+    fn namespaces(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    ///
+    /// # Errors
+    ///
+    /// It returns `MyError::Utf8` if the stored value is not valid UTF-8.
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
 
-    /// Removes a given key.
+    /// Removes a given string key.
     ///
     /// # Errors
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&mut self, key: String) -> Result<()>;
+    /// It returns `MyError::KeyNotFound` if the given key is not found.
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
+
+/// Gauges and counters of an engine, returned by `KvsEngine::stats`. What an
+/// engine does not track is left `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    /// Live keys.
+    pub keys: Option<u64>,
+    /// Size of the log, or of the data files for engines without one.
+    pub log_bytes: Option<u64>,
+    /// Bytes of the log held by overwritten or removed keys.
+    pub uncompacted_bytes: Option<u64>,
+    /// Compactions run since the engine was opened.
+    pub compactions: Option<u64>,
+}
+
+/// Iterator over the key/value pairs of a snapshot, in key order.
+pub type SnapshotIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Trait for a point-in-time, read-only view of a storage engine.
+pub trait KvsSnapshot {
+    /// Gets the value of a given key as of the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist.
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Iterates over the key/value pairs whose key falls in `range`, in key order.
+    fn scan(&mut self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> SnapshotIter<'_>;
+
+    /// Iterates over every key/value pair of the snapshot, in key order.
+    fn iter(&mut self) -> SnapshotIter<'_> {
+        self.scan((Bound::Unbounded, Bound::Unbounded))
+    }
+
+    /// Gets the string value of a given string key as of the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist.
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+}
+
+/// Checks that `name` can name a namespace: 1 to 64 ASCII letters, digits,
+/// `-` or `_`, starting with a letter or a digit.
+pub(crate) fn check_namespace(name: &str) -> Result<()> {
+    let valid = name.len() <= MAX_NAMESPACE_LEN
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(MyError::StringError(format!(
+            "Invalid namespace {:?}: expected up to {} letters, digits, - or _",
+            name, MAX_NAMESPACE_LEN
+        )))
+    }
+}
+
+pub(crate) fn unknown_parameter(name: &str) -> MyError {
+    MyError::StringError(format!("Unknown parameter {}", name))
+}
+
+pub(crate) fn read_only_parameter(name: &str) -> MyError {
+    MyError::StringError(format!("Parameter {} cannot change while running", name))
+}
+
+/// Converts any range of keys to the owned bounds taken by `KvsSnapshot::scan`.
+pub fn key_range(range: impl RangeBounds<Vec<u8>>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (range.start_bound().cloned(), range.end_bound().cloned())
 }
@@ -1,55 +1,404 @@
 //! Map sled crate
-use crate::engine::KvsEngine;
+use crate::backup::{self, BackupManifest, Checksum};
+use crate::engine::durability::{Durability, Syncer};
+use crate::engine::{
+    check_namespace, read_only_parameter, unknown_parameter, EngineStats, KvsEngine, KvsSnapshot,
+    SnapshotIter, DEFAULT_NAMESPACE,
+};
 use crate::{MyError, Result};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
 
+/// Number of times `open` retries while the database lock is held.
+const LOCK_ATTEMPTS: u32 = 50;
+
+/// A `SledKvsEngine` is a handle: clones share the same database and can be
+/// used from several threads.
+///
+/// Each namespace is a sled tree of the database.
+#[derive(Clone)]
 pub struct SledKvsEngine {
-    store: sled::Db,
+    db: sled::Db,
+    /// Tree of the namespace the handle is on.
+    store: sled::Tree,
+    syncer: Arc<Syncer>,
+    snapshots: Arc<Snapshots>,
 }
 
+/// Values the keys of a tree had when a snapshot was taken, recorded by the
+/// first write of each key since. `None` for the keys which did not exist.
+type Undo = Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>;
+
+/// Live snapshots of each tree of a database, by tree name.
+///
+/// sled has no point-in-time view of a tree, so a snapshot reads the live
+/// tree and its `Undo` for the keys written since. Writes record the previous
+/// values under the write lock while a snapshot of their tree is live, and
+/// under the read lock otherwise.
+#[derive(Default)]
+struct Snapshots(RwLock<HashMap<Vec<u8>, Vec<Weak<Undo>>>>);
+
 impl KvsEngine for SledKvsEngine {
-    /// Sets the value of a string key to a string.
+    type Snapshot = SledSnapshot;
+
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.store.insert(key, value.as_bytes())?;
-        self.store.flush()?;
-        Ok(())
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(&[&key], |store| Ok(store.insert(&key, value)?))?;
+        self.syncer.commit(self.syncer.ticket())
     }
 
-    /// Gets the string value of a given string key.
+    /// Applies every insertion as one sled batch, with a single flush.
+    fn set_batch(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let keys: Vec<&[u8]> = entries.iter().map(|(key, _)| key.as_slice()).collect();
+        let mut batch = sled::Batch::default();
+        for (key, value) in &entries {
+            batch.insert(key.as_slice(), value.as_slice());
+        }
+        self.write(&keys, |store| Ok(store.apply_batch(batch)?))?;
+        self.syncer.commit(self.syncer.ticket())
+    }
+
+    /// Gets the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .store
-            .get(key)?
-            .map(|v| v.to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.store.get(key)?.map(|v| v.to_vec()))
     }
     /// Remove a given key.
-    fn remove(&mut self, key: String) -> Result<()> {
-        self.store.remove(key)?.ok_or(MyError::KeyNotFound)?;
-        self.store.flush()?;
-        Ok(())
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.write(&[key], |store| Ok(store.remove(key)?))?
+            .ok_or(MyError::KeyNotFound)?;
+        self.syncer.commit(self.syncer.ticket())
+    }
+
+    /// Returns a snapshot reading the live tree, which keeps the previous
+    /// values of the keys written while it is alive. Nothing is copied when
+    /// it is taken, and it holds as many values as keys written since.
+    fn snapshot(&mut self) -> Result<SledSnapshot> {
+        let undo = Arc::new(Mutex::new(BTreeMap::new()));
+        let mut trees = self.snapshots.0.write().unwrap();
+        let live = trees.entry(self.store.name().to_vec()).or_default();
+        live.retain(|undo| undo.strong_count() > 0);
+        live.push(Arc::downgrade(&undo));
+        Ok(SledSnapshot {
+            store: self.store.clone(),
+            undo,
+        })
+    }
+
+    /// Copies a snapshot into a new sled database in `dest`, with a snapshot
+    /// of every tree when the handle is on the default namespace.
+    fn backup(&mut self, dest: &Path) -> Result<BackupManifest> {
+        backup::create_backup_dir(dest)?;
+        let target = SledKvsEngine::open_with(dest, Durability::None)?;
+        let mut checksum = Checksum::new();
+        copy_tree(&mut self.snapshot()?, &target.store, &mut checksum)?;
+        if self.store.name() == self.db.name() {
+            for name in self.namespaces()? {
+                checksum.namespace(&name);
+                let tree = target.db.open_tree(&name)?;
+                copy_tree(
+                    &mut self.namespace(&name)?.snapshot()?,
+                    &tree,
+                    &mut checksum,
+                )?;
+            }
+        }
+        target.db.flush()?;
+        drop(target);
+
+        let manifest = BackupManifest::new("sled", checksum.count(), checksum.finish());
+        manifest.write(dest)?;
+        Ok(manifest)
+    }
+
+    /// Counts the keys of the namespace and sizes the whole database.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: Some(self.store.len() as u64),
+            log_bytes: Some(self.db.size_on_disk()?),
+            ..EngineStats::default()
+        })
+    }
+
+    fn engine_name(&self) -> &'static str {
+        "sled"
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        SledKvsEngine::sync(self)
+    }
+
+    fn config(&self) -> BTreeMap<String, String> {
+        let mut config = BTreeMap::new();
+        config.insert("durability".to_owned(), self.durability().to_string());
+        config
+    }
+
+    fn set_config(&mut self, name: &str, _value: &str) -> Result<()> {
+        match name {
+            "durability" => Err(read_only_parameter(name)),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+
+    /// Opens the tree of the namespace if the database has it.
+    fn existing_namespace(&mut self, name: &str) -> Result<Option<SledKvsEngine>> {
+        if name != DEFAULT_NAMESPACE {
+            check_namespace(name)?;
+            let exists = self
+                .db
+                .tree_names()
+                .iter()
+                .any(|tree| tree.as_ref() == name.as_bytes());
+            if !exists {
+                return Ok(None);
+            }
+        }
+        self.namespace(name).map(Some)
+    }
+
+    /// Lists the trees of the database but the default one.
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let default = self.db.name();
+        let mut names: Vec<String> = self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter(|name| *name != default)
+            .filter_map(|name| String::from_utf8(name.to_vec()).ok())
+            .filter(|name| check_namespace(name).is_ok())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Returns a handle on the sled tree named after the namespace.
+    fn namespace(&mut self, name: &str) -> Result<SledKvsEngine> {
+        let store = if name == DEFAULT_NAMESPACE {
+            (*self.db).clone()
+        } else {
+            check_namespace(name)?;
+            self.db.open_tree(name)?
+        };
+        Ok(SledKvsEngine {
+            db: self.db.clone(),
+            store,
+            syncer: Arc::clone(&self.syncer),
+            snapshots: Arc::clone(&self.snapshots),
+        })
+    }
+}
+
+/// Inserts the pairs of a snapshot into `tree`.
+fn copy_tree(
+    snapshot: &mut SledSnapshot,
+    tree: &sled::Tree,
+    checksum: &mut Checksum,
+) -> Result<()> {
+    for entry in snapshot.iter() {
+        let (key, value) = entry?;
+        checksum.update(&key, &value);
+        tree.insert(key, value)?;
     }
+    Ok(())
 }
 
 impl SledKvsEngine {
-    /// Creates a `SledKvsEngine` from `sled::Db`.
+    /// Creates a `SledKvsEngine` in the working directory.
+    #[deprecated(note = "the working directory depends on how the process is started, \
+                         use `SledKvsEngine::open` with an explicit directory")]
     pub fn new() -> Result<Self> {
         let cwd = std::env::current_dir()?;
         SledKvsEngine::open(cwd.as_path())
     }
 
     /// Open the SledKvsEngine at a given path. Return the `SledKvsEngine`.
+    ///
+    /// Every write is flushed before it is acknowledged.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        SledKvsEngine::open_with(path, Durability::EveryWrite)
+    }
+
+    /// Open the SledKvsEngine at a given path, flushing writes according to `durability`.
+    pub fn open_with(path: impl Into<PathBuf>, durability: Durability) -> Result<SledKvsEngine> {
         let mut path = path.into();
         std::fs::create_dir_all(&path)?;
         path.push("sled-db");
+        // Flushing is driven by `durability` only.
+        let config = sled::Config::new().path(path).flush_every_ms(None);
+        let db = open_db(&config)?;
+        let syncer = {
+            let db = db.clone();
+            Syncer::new(
+                durability,
+                Box::new(move || {
+                    db.flush()?;
+                    Ok(())
+                }),
+            )
+        };
+        let store = (*db).clone();
         Ok(SledKvsEngine {
-            store: sled::open(path)?,
+            db,
+            store,
+            syncer,
+            snapshots: Arc::default(),
+        })
+    }
+
+    /// Returns the durability policy of the engine.
+    pub fn durability(&self) -> Durability {
+        self.syncer.policy()
+    }
+
+    /// Flushes every write made so far to disk, whatever the durability policy.
+    pub fn sync(&self) -> Result<()> {
+        self.syncer.sync_all()
+    }
+
+    /// Runs a write of `keys`, after recording their values in the live
+    /// snapshots of the tree which have none yet.
+    fn write<T>(&self, keys: &[&[u8]], write: impl FnOnce(&sled::Tree) -> Result<T>) -> Result<T> {
+        let name = self.store.name();
+        {
+            let trees = self.snapshots.0.read().unwrap();
+            let live = trees
+                .get(name.as_ref())
+                .is_some_and(|undos| undos.iter().any(|undo| undo.strong_count() > 0));
+            if !live {
+                return write(&self.store);
+            }
+        }
+        let mut trees = self.snapshots.0.write().unwrap();
+        let mut undos = Vec::new();
+        if let Some(live) = trees.get_mut(name.as_ref()) {
+            live.retain(|undo| undo.strong_count() > 0);
+            undos.extend(live.iter().filter_map(Weak::upgrade));
+        }
+        for key in keys {
+            let missing: Vec<_> = undos
+                .iter()
+                .filter(|undo| !undo.lock().unwrap().contains_key(*key))
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            let previous = self.store.get(key)?.map(|value| value.to_vec());
+            for undo in missing {
+                undo.lock().unwrap().insert(key.to_vec(), previous.clone());
+            }
+        }
+        write(&self.store)
+    }
+}
+
+/// Opens the database, waiting for a handle which was just dropped to release it.
+///
+/// sled's I/O threads may hold the file lock for a moment after the last
+/// handle on the database is dropped.
+fn open_db(config: &sled::Config) -> Result<sled::Db> {
+    let mut attempts = 0;
+    loop {
+        match config.open() {
+            Err(sled::Error::Io(ref err))
+                if attempts < LOCK_ATTEMPTS
+                    && err.to_string().contains("could not acquire lock") =>
+            {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Read-only view of a `SledKvsEngine` as of the moment `KvsEngine::snapshot` was called.
+pub struct SledSnapshot {
+    store: sled::Tree,
+    undo: Arc<Undo>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // Read first: a write recording the key in between is then seen in `undo`
+        let live = self.store.get(key)?.map(|value| value.to_vec());
+        match self.undo.lock().unwrap().get(key) {
+            Some(previous) => Ok(previous.clone()),
+            None => Ok(live),
+        }
+    }
+
+    fn scan(&mut self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> SnapshotIter<'_> {
+        Box::new(Scan {
+            live: self.store.range(range.clone()).peekable(),
+            undo: &self.undo,
+            after: range.0,
+            end: range.1,
         })
     }
 }
+
+/// Merges the live tree with the values a snapshot recorded, in key order.
+///
+/// As in `get_bytes`, the live key is read before `undo` is checked, and a
+/// key recorded later was returned with its value from before the write.
+struct Scan<'a> {
+    live: Peekable<sled::Iter>,
+    undo: &'a Undo,
+    /// Bound of the keys not returned yet.
+    after: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let live = match self.live.peek() {
+                Some(Ok((key, _))) => Some(key.to_vec()),
+                Some(Err(_)) => {
+                    return self
+                        .live
+                        .next()
+                        .and_then(|entry| entry.err())
+                        .map(|e| Err(e.into()))
+                }
+                None => None,
+            };
+            let recorded = self
+                .undo
+                .lock()
+                .unwrap()
+                .range((self.after.clone(), self.end.clone()))
+                .next()
+                .map(|(key, value)| (key.clone(), value.clone()));
+            let (key, value) = match (live, recorded) {
+                (None, None) => return None,
+                (Some(live), Some((key, value))) if key <= live => {
+                    if key == live {
+                        self.live.next();
+                    }
+                    (key, value)
+                }
+                (None, Some((key, value))) => (key, value),
+                (Some(_), _) => match self.live.next()? {
+                    Ok((key, value)) => (key.to_vec(), Some(value.to_vec())),
+                    Err(e) => return Some(Err(e.into())),
+                },
+            };
+            self.after = Bound::Excluded(key.clone());
+            if let Some(value) = value {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
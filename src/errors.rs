@@ -1,3 +1,6 @@
+// `failure_derive` expands to impl blocks nested in a const item.
+#![allow(non_local_definitions)]
+
 use std::io::{self};
 use std::string;
 
@@ -17,6 +20,18 @@ pub enum MyError {
     Sled(#[cause] sled::Error),
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[fail(cause)] string::FromUtf8Error),
+    /// A value could not be encoded or decoded by a `Codec`
+    #[fail(display = "Codec error: {}", _0)]
+    Codec(String),
+    /// TLS configuration or handshake failure
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(String),
+    /// The server refused the credentials or the request
+    #[fail(display = "Unauthorized: {}", _0)]
+    Unauthorized(String),
+    /// A node of a Raft cluster which does not lead refused the request
+    #[fail(display = "Not the leader: {}", _0)]
+    NotLeader(String),
 }
 
 impl From<io::Error> for MyError {
@@ -35,6 +50,11 @@ impl From<sled::Error> for MyError {
         MyError::Sled(err)
     }
 }
+impl From<rustls::Error> for MyError {
+    fn from(err: rustls::Error) -> MyError {
+        MyError::Tls(err.to_string())
+    }
+}
 impl From<string::FromUtf8Error> for MyError {
     fn from(err: string::FromUtf8Error) -> MyError {
         MyError::Utf8(err)
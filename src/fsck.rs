@@ -0,0 +1,193 @@
+//! Integrity verification and repair of a `KvStore` log.
+//!
+//! `KvStore::open` stops at the first record it cannot read. `check` instead
+//! scans every record of `log.json`, resynchronising on line boundaries, and
+//! reports what it found. `repair` rewrites every readable live record into a
+//! fresh log.
+use crate::engine::Command;
+use crate::{MyError, Result};
+use log::{info, warn};
+use serde_json::Deserializer;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// Latest "set" record of every live key.
+type RecordIndex = BTreeMap<Vec<u8>, Range<u64>>;
+
+/// Result of a log scan.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Size of the log in bytes.
+    pub log_bytes: u64,
+    /// Number of readable "set" records.
+    pub sets: u64,
+    /// Number of readable "remove" records.
+    pub removes: u64,
+    /// Number of encrypted records, whose command is not checked.
+    pub sealed: u64,
+    /// Number of keys whose latest readable record is a "set".
+    pub live_keys: u64,
+    /// Bytes taken by the latest "set" record of every live key.
+    pub live_bytes: u64,
+    /// Bytes of readable records which compaction would reclaim.
+    pub stale_bytes: u64,
+    /// Byte ranges of the log which could not be read.
+    pub corrupt_ranges: Vec<Range<u64>>,
+    /// Keys of index entries which do not point to a readable "set" for that key.
+    pub dangling_keys: Vec<Vec<u8>>,
+}
+
+impl FsckReport {
+    /// Returns `true` if no corruption was found.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_ranges.is_empty() && self.dangling_keys.is_empty()
+    }
+
+    /// Total number of corrupt bytes.
+    pub fn corrupt_bytes(&self) -> u64 {
+        self.corrupt_ranges.iter().map(|r| r.end - r.start).sum()
+    }
+}
+
+/// Scans the log of the store in `dir` without modifying it.
+pub fn check(dir: &Path) -> Result<FsckReport> {
+    scan(dir).map(|(report, _)| report)
+}
+
+/// Salvages every readable live record of the store in `dir` into a fresh log.
+///
+/// The original log is kept as `log.json.corrupt` when corruption was found.
+/// The store must not be open while repairing. Returns the report of the scan
+/// made before the repair.
+pub fn repair(dir: &Path) -> Result<FsckReport> {
+    let (report, index) = scan(dir)?;
+    if report.sealed > 0 {
+        return Err(MyError::StringError(format!(
+            "{} is encrypted, repair would drop its records",
+            dir.display()
+        )));
+    }
+    if report.is_clean() {
+        info!("{} is clean, nothing to repair", dir.display());
+        return Ok(report);
+    }
+
+    let log_path = dir.join("log.json");
+    let repaired_path = dir.join("log.json.repair");
+    let mut reader = BufReader::new(File::open(&log_path)?);
+    let repaired = File::create(&repaired_path)?;
+    let mut writer = BufWriter::new(&repaired);
+    for (key, range) in index {
+        match read_set(&mut reader, &range)? {
+            Some(command) if set_key(&command) == Some(&key) => {
+                writer.write_all(b"\r\n")?;
+                serde_json::to_writer(&mut writer, &command)?;
+            }
+            _ => warn!("Dropping unreadable record of key {:?}", key),
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    repaired.sync_all()?;
+
+    fs::rename(&log_path, dir.join("log.json.corrupt"))?;
+    fs::rename(&repaired_path, &log_path)?;
+    info!(
+        "Repaired {}: kept {} keys, dropped {} corrupt bytes",
+        dir.display(),
+        report.live_keys,
+        report.corrupt_bytes()
+    );
+    Ok(report)
+}
+
+/// Scans the log and returns the report with the index of live "set" records.
+fn scan(dir: &Path) -> Result<(FsckReport, RecordIndex)> {
+    let log_path = dir.join("log.json");
+    let file = OpenOptions::new().read(true).open(&log_path)?;
+    let mut report = FsckReport {
+        log_bytes: file.metadata()?.len(),
+        ..FsckReport::default()
+    };
+    let mut index = RecordIndex::new();
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    let mut line_start = 0;
+
+    // Records never contain raw newlines: serde_json escapes them. A record
+    // which cannot be read only spoils the rest of its line.
+    loop {
+        line.clear();
+        let len = reader.read_until(b'\n', &mut line)? as u64;
+        if len == 0 {
+            break;
+        }
+        let mut stream = Deserializer::from_slice(&line).into_iter::<Command>();
+        let mut record_start = 0;
+        loop {
+            match stream.next() {
+                None => break,
+                Some(Ok(command)) => {
+                    let record_end = stream.byte_offset() as u64;
+                    let range = line_start + record_start..line_start + record_end;
+                    match command {
+                        Command::Set { key, .. } => {
+                            report.sets += 1;
+                            if let Some(old) = index.insert(key, range) {
+                                report.stale_bytes += old.end - old.start;
+                            }
+                        }
+                        Command::Remove { key } => {
+                            report.removes += 1;
+                            report.stale_bytes += range.end - range.start;
+                            if let Some(old) = index.remove(&key) {
+                                report.stale_bytes += old.end - old.start;
+                            }
+                        }
+                        Command::Sealed { .. } => report.sealed += 1,
+                    }
+                    record_start = record_end;
+                }
+                Some(Err(_)) => {
+                    let start = line_start + record_start;
+                    let end = line_start + len;
+                    match report.corrupt_ranges.last_mut() {
+                        Some(last) if last.end == start => last.end = end,
+                        _ => report.corrupt_ranges.push(start..end),
+                    }
+                    break;
+                }
+            }
+        }
+        line_start += len;
+    }
+
+    // Every index entry must point back to a readable "set" of its key.
+    let mut reader = BufReader::new(File::open(&log_path)?);
+    for (key, range) in &index {
+        match read_set(&mut reader, range)? {
+            Some(ref command) if set_key(command) == Some(key) => {
+                report.live_keys += 1;
+                report.live_bytes += range.end - range.start;
+            }
+            _ => report.dangling_keys.push(key.clone()),
+        }
+    }
+    Ok((report, index))
+}
+
+fn read_set(reader: &mut BufReader<File>, range: &Range<u64>) -> Result<Option<Command>> {
+    reader.seek(SeekFrom::Start(range.start))?;
+    let record = reader.take(range.end - range.start);
+    Ok(serde_json::from_reader(record).ok())
+}
+
+fn set_key(command: &Command) -> Option<&Vec<u8>> {
+    match command {
+        Command::Set { key, .. } => Some(key),
+        _ => None,
+    }
+}
@@ -1,19 +1,42 @@
 //#![deny(missing_docs)]
 
+pub mod auth;
+pub mod backup;
 mod client;
 mod common;
+pub mod config;
+pub mod conformance;
+pub mod dump;
 mod engine;
 mod errors;
+pub mod fsck;
+mod metrics;
+pub mod migrate;
+pub mod raft;
+mod replication;
 mod server;
+mod tls;
+mod typed;
 
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
 
+pub use auth::{Acl, Credentials, Right};
+pub use backup::BackupManifest;
 pub use client::KvsClient;
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
+pub use common::ServerInfo;
+pub use dump::DumpFormat;
+pub use engine::{
+    key_range, CacheStats, Cipher, Compression, CompressionStats, Durability, EncryptionKey,
+    EngineStats, IndexStats, KvStore, KvStoreOptions, KvStoreSnapshot, KvsEngine, KvsSnapshot,
+    LsmKvsEngine, LsmOptions, LsmSnapshot, MemoryKvsEngine, MemorySnapshot, SledKvsEngine,
+    SledSnapshot, SnapshotIter, DEFAULT_NAMESPACE,
+};
 pub use errors::{MyError, Result};
 pub use server::Server;
+pub use tls::{TlsClientConfig, TlsPeerConfig, TlsServerConfig};
+pub use typed::{Bincode, Codec, Json, MessagePack, TypedStore};
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,298 @@
+//! Prometheus metrics of a `Server`
+//!
+//! Counters are updated by the connection threads and rendered in the
+//! Prometheus text format by a small HTTP listener answering `GET /metrics`.
+use crate::engine::{EngineStats, KvsEngine};
+use crate::errors::{MyError, Result};
+use log::warn;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Requests the server answers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Op {
+    Get,
+    Set,
+    Remove,
+    Backup,
+    Auth,
+    /// Any admin command
+    Admin,
+}
+
+impl Op {
+    const ALL: [Op; 6] = [
+        Op::Get,
+        Op::Set,
+        Op::Remove,
+        Op::Backup,
+        Op::Auth,
+        Op::Admin,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Get => "get",
+            Op::Set => "set",
+            Op::Remove => "remove",
+            Op::Backup => "backup",
+            Op::Auth => "auth",
+            Op::Admin => "admin",
+        }
+    }
+}
+
+/// Latency histogram of one operation.
+#[derive(Default)]
+struct Histogram {
+    /// Requests in each bucket, not cumulated. The last one is `+Inf`.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters of a `Server`, shared by its connection threads.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    latencies: [Histogram; Op::ALL.len()],
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    /// Records a request answered after `elapsed`, and its error if it failed.
+    pub(crate) fn request(&self, op: Op, elapsed: Duration, error: Option<&MyError>) {
+        self.latencies[op as usize].observe(elapsed);
+        if let Some(err) = error {
+            self.error(err);
+        }
+    }
+
+    /// Records an error, by kind.
+    pub(crate) fn error(&self, err: &MyError) {
+        *self
+            .errors
+            .lock()
+            .unwrap()
+            .entry(error_kind(err))
+            .or_insert(0) += 1;
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub(crate) fn render(&self, engine: &EngineStats) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "kvs_requests_total",
+            "counter",
+            "Requests answered.",
+        );
+        for op in Op::ALL.iter() {
+            let count = self.latencies[*op as usize].count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "kvs_requests_total{{op=\"{}\"}} {}", op.name(), count);
+        }
+
+        header(
+            &mut out,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "Time to answer a request.",
+        );
+        for op in Op::ALL.iter() {
+            let histogram = &self.latencies[*op as usize];
+            let mut cumulated = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulated += bucket.load(Ordering::Relaxed);
+                let bound = match BUCKETS.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_owned(),
+                };
+                let _ = writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                    op.name(),
+                    bound,
+                    cumulated
+                );
+            }
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_sum{{op=\"{}\"}} {}",
+                op.name(),
+                sum
+            );
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_count{{op=\"{}\"}} {}",
+                op.name(),
+                histogram.count.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "kvs_connections_total",
+            "counter",
+            "Connections accepted.",
+        );
+        let _ = writeln!(
+            out,
+            "kvs_connections_total {}",
+            self.connections.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "kvs_connections_active",
+            "gauge",
+            "Connections open.",
+        );
+        let _ = writeln!(
+            out,
+            "kvs_connections_active {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+
+        header(&mut out, "kvs_errors_total", "counter", "Errors, by kind.");
+        for (kind, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "kvs_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        let gauges = [
+            ("kvs_engine_keys", "gauge", "Live keys.", engine.keys),
+            (
+                "kvs_engine_log_bytes",
+                "gauge",
+                "Size of the log or data files.",
+                engine.log_bytes,
+            ),
+            (
+                "kvs_engine_uncompacted_bytes",
+                "gauge",
+                "Bytes of the log held by stale records.",
+                engine.uncompacted_bytes,
+            ),
+            (
+                "kvs_engine_compactions_total",
+                "counter",
+                "Compactions run since the engine was opened.",
+                engine.compactions,
+            ),
+        ];
+        for (name, kind, help, value) in gauges.iter() {
+            if let Some(value) = value {
+                header(&mut out, name, kind, help);
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Label of an error in `kvs_errors_total`.
+fn error_kind(err: &MyError) -> &'static str {
+    match err {
+        MyError::KeyNotFound => "key_not_found",
+        MyError::Io(_) => "io",
+        MyError::DeserializeError(_) => "protocol",
+        MyError::StringError(_) => "engine",
+        MyError::Sled(_) => "sled",
+        MyError::Utf8(_) => "utf8",
+        MyError::Codec(_) => "codec",
+        MyError::Tls(_) => "tls",
+        MyError::Unauthorized(_) => "unauthorized",
+        MyError::NotLeader(_) => "not_leader",
+    }
+}
+
+/// Answers `GET /metrics` on `listener` with the metrics and the stats of
+/// `engine`.
+pub(crate) fn serve<E: KvsEngine>(listener: TcpListener, metrics: &Metrics, engine: E) {
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(MyError::from)
+            .and_then(|stream| scrape(stream, metrics, &engine));
+        if let Err(e) = result {
+            warn!("Error on serving metrics: {}", e);
+        }
+    }
+}
+
+fn scrape<E: KvsEngine>(stream: TcpStream, metrics: &Metrics, engine: &E) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, the request has no body
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match engine.stats() {
+            Ok(stats) => (
+                "200 OK",
+                "text/plain; version=0.0.4",
+                metrics.render(&stats),
+            ),
+            Err(e) => (
+                "500 Internal Server Error",
+                "text/plain",
+                format!("{}\n", e),
+            ),
+        },
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_owned()),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! Engine marker of a data directory and offline migration between engines.
+//!
+//! The marker is a file named `engine` holding the name of the engine which
+//! owns the directory: `kvs`, `sled`, `lsm`, or `memory` for a persisted
+//! in-memory engine. `kvs-server` refuses to open a directory with another engine, and
+//! `migrate` switches it atomically once every key has been copied and
+//! verified.
+use crate::backup::{checksum_all, Checksum};
+use crate::engine::{KvsEngine, KvsSnapshot, LSM_DIR, MEMORY_FILE, NAMESPACES_DIR};
+use crate::{KvStore, LsmKvsEngine, MemoryKvsEngine, MyError, Result, SledKvsEngine};
+use log::info;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the engine marker file in a data directory.
+pub const ENGINE_MARKER: &str = "engine";
+const STAGING_DIR: &str = ".migrate";
+/// Number of pairs copied to the target engine at once.
+const MIGRATE_BATCH: usize = 1024;
+
+/// Summary of a successful migration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Number of keys copied, in every namespace.
+    pub keys: u64,
+    /// Checksum of every key/value pair, identical in both engines.
+    pub checksum: u64,
+}
+
+/// Returns the engine owning `dir`.
+///
+/// The marker is used when present. Directories written before markers
+/// existed are recognised by their files. Returns `None` for an empty directory.
+pub fn detect_engine(dir: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(dir.join(ENGINE_MARKER)) {
+        Ok(engine) => return Ok(Some(engine.trim().to_owned())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    let found: Vec<&str> = ["kvs", "sled", "memory", "lsm"]
+        .iter()
+        .copied()
+        .filter(|engine| engine_files(dir, engine).exists())
+        .collect();
+    match found.as_slice() {
+        [] => Ok(None),
+        [engine] => Ok(Some((*engine).to_owned())),
+        _ => Err(MyError::StringError(format!(
+            "{} holds data for several engines and has no engine marker",
+            dir.display()
+        ))),
+    }
+}
+
+/// Atomically records `engine` as the owner of `dir`.
+pub fn write_engine_marker(dir: &Path, engine: &str) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", ENGINE_MARKER));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(engine.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(ENGINE_MARKER))?;
+    Ok(())
+}
+
+/// Copies every live key of every namespace of the `from` engine in `dir` into
+/// the `to` engine and switches the directory's engine marker.
+///
+/// The target is built in a staging directory and verified against the source
+/// (key count and checksum) before it is moved in place. The marker is switched
+/// last, so an interrupted migration leaves the source engine in charge. Unless
+/// `keep_source` is set, the source files are removed afterwards.
+/// The store must not be open while migrating.
+pub fn migrate(dir: &Path, from: &str, to: &str, keep_source: bool) -> Result<MigrationReport> {
+    if from == to {
+        return Err(MyError::StringError(format!(
+            "{} already uses the {} engine",
+            dir.display(),
+            to
+        )));
+    }
+    match detect_engine(dir)? {
+        Some(ref engine) if engine == from => {}
+        Some(engine) => {
+            return Err(MyError::StringError(format!(
+                "{} uses the {} engine, not {}",
+                dir.display(),
+                engine,
+                from
+            )))
+        }
+        None => {
+            return Err(MyError::StringError(format!(
+                "{} holds no data",
+                dir.display()
+            )))
+        }
+    }
+
+    let staging = dir.join(STAGING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let copied = match from {
+        "kvs" => copy_to(&mut KvStore::open(dir)?, &staging, to)?,
+        "sled" => copy_to(&mut SledKvsEngine::open(dir)?, &staging, to)?,
+        "memory" => copy_to(&mut MemoryKvsEngine::open(dir)?, &staging, to)?,
+        "lsm" => copy_to(&mut LsmKvsEngine::open(dir)?, &staging, to)?,
+        engine => return Err(unknown_engine(engine)),
+    };
+    let verified = match to {
+        "kvs" => checksum_all(&mut KvStore::open(&staging)?)?,
+        "memory" => checksum_all(&mut MemoryKvsEngine::open(&staging)?)?,
+        "lsm" => checksum_all(&mut LsmKvsEngine::open(&staging)?)?,
+        _ => checksum_all(&mut SledKvsEngine::open(&staging)?)?,
+    };
+    if copied.count() != verified.count() || copied.finish() != verified.finish() {
+        fs::remove_dir_all(&staging)?;
+        return Err(MyError::StringError(format!(
+            "Migration check failed: copied {} keys with checksum {:016x}, found {} keys with checksum {:016x}",
+            copied.count(),
+            copied.finish(),
+            verified.count(),
+            verified.finish()
+        )));
+    }
+
+    // Leftovers of an interrupted migration are replaced.
+    let target = engine_files(dir, to);
+    if target.is_dir() {
+        fs::remove_dir_all(&target)?;
+    }
+    fs::rename(engine_files(&staging, to), &target)?;
+    let namespaces = dir.join(NAMESPACES_DIR);
+    if to == "kvs" {
+        if namespaces.exists() {
+            fs::remove_dir_all(&namespaces)?;
+        }
+        if staging.join(NAMESPACES_DIR).exists() {
+            fs::rename(staging.join(NAMESPACES_DIR), &namespaces)?;
+        }
+    }
+    write_engine_marker(dir, to)?;
+    fs::remove_dir_all(&staging)?;
+
+    if !keep_source {
+        let source = engine_files(dir, from);
+        if source.is_dir() {
+            fs::remove_dir_all(&source)?;
+        } else {
+            fs::remove_file(&source)?;
+        }
+        if from == "kvs" && namespaces.exists() {
+            fs::remove_dir_all(&namespaces)?;
+        }
+    }
+    info!(
+        "Migrated {} keys of {} from {} to {}",
+        copied.count(),
+        dir.display(),
+        from,
+        to
+    );
+    Ok(MigrationReport {
+        keys: copied.count(),
+        checksum: copied.finish(),
+    })
+}
+
+/// Streams a snapshot of every namespace of `source` into a new `to` engine
+/// in `staging`.
+fn copy_to<E: KvsEngine>(source: &mut E, staging: &Path, to: &str) -> Result<Checksum> {
+    match to {
+        "kvs" => copy_engine(source, &mut KvStore::open(staging)?),
+        "sled" => copy_engine(source, &mut SledKvsEngine::open(staging)?),
+        "memory" => copy_engine(source, &mut MemoryKvsEngine::open(staging)?),
+        "lsm" => copy_engine(source, &mut LsmKvsEngine::open(staging)?),
+        engine => Err(unknown_engine(engine)),
+    }
+}
+
+/// Copies the default namespace, then the others. Fails if `target` has no
+/// namespaces while `source` has some.
+fn copy_engine<E: KvsEngine, T: KvsEngine>(source: &mut E, target: &mut T) -> Result<Checksum> {
+    let mut checksum = Checksum::new();
+    copy_snapshot(&mut source.snapshot()?, target, &mut checksum)?;
+    for name in source.namespaces()? {
+        checksum.namespace(&name);
+        let mut snapshot = source.namespace(&name)?.snapshot()?;
+        copy_snapshot(&mut snapshot, &mut target.namespace(&name)?, &mut checksum)?;
+    }
+    Ok(checksum)
+}
+
+fn copy_snapshot<S: KvsSnapshot, E: KvsEngine>(
+    snapshot: &mut S,
+    target: &mut E,
+    checksum: &mut Checksum,
+) -> Result<()> {
+    let mut batch = Vec::with_capacity(MIGRATE_BATCH);
+    for entry in snapshot.iter() {
+        let (key, value) = entry?;
+        checksum.update(&key, &value);
+        batch.push((key, value));
+        if batch.len() == MIGRATE_BATCH {
+            target.set_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    target.set_batch(batch)
+}
+
+/// Path of the files an engine keeps in `dir`.
+fn engine_files(dir: &Path, engine: &str) -> PathBuf {
+    match engine {
+        "kvs" => dir.join("log.json"),
+        "memory" => dir.join(MEMORY_FILE),
+        "lsm" => dir.join(LSM_DIR),
+        _ => dir.join("sled-db"),
+    }
+}
+
+fn unknown_engine(engine: &str) -> MyError {
+    MyError::StringError(format!("Unknown engine {}", engine))
+}
@@ -0,0 +1,1127 @@
+//! The Raft algorithm, driven by `tick`, `step` and the requests of clients.
+//!
+//! `Raft` does no I/O besides its storage and its engine: the messages it
+//! sends are queued in an outbox, which `RaftNode` hands to the transport.
+use super::storage::{Staging, Storage};
+use super::{Body, Entry, EntryData, Members, Message, NodeId, RaftOptions, RaftRole, RaftStatus};
+use crate::common::Request;
+use crate::engine::{KvsEngine, KvsSnapshot};
+use crate::errors::{MyError, Result};
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::Peekable;
+use std::mem;
+use std::ops::Bound;
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Entries sent in one `Append` at most.
+const MAX_APPEND_ENTRIES: usize = 100;
+
+/// What the leader knows of a follower.
+struct Progress<S> {
+    /// Next entry to send.
+    next: u64,
+    /// Last entry known to match the leader's log.
+    matched: u64,
+    /// Last heartbeat round the follower answered.
+    acked_seq: u64,
+    /// Whether the follower answered since the last quorum check.
+    active: bool,
+    /// Snapshot being sent to the follower, while it misses compacted entries.
+    snapshot: Option<OutgoingSnapshot<S>>,
+}
+
+/// Snapshot sent to a follower one chunk at a time, read from a view of the
+/// engine as of entry `index`.
+struct OutgoingSnapshot<S> {
+    index: u64,
+    term: u64,
+    members: Members,
+    view: S,
+    /// Pairs the follower staged, and the last of their keys.
+    acked: u64,
+    acked_key: Option<Vec<u8>>,
+    /// End of the chunk sent last, and its last key.
+    sent: u64,
+    sent_key: Option<Vec<u8>>,
+}
+
+/// Snapshot a follower receives, staged until its last chunk.
+struct IncomingSnapshot {
+    index: u64,
+    term: u64,
+    members: Members,
+    /// Pairs staged so far.
+    offset: u64,
+    staging: Staging,
+}
+
+/// A read waiting for the leader to confirm that it still leads, then for
+/// the entries before it to be applied.
+struct PendingRead {
+    seq: u64,
+    index: u64,
+    confirmed: bool,
+    reply: Sender<Result<()>>,
+}
+
+pub(crate) struct Raft<E: KvsEngine> {
+    id: NodeId,
+    engine: E,
+    options: RaftOptions,
+    storage: Option<Storage>,
+
+    term: u64,
+    voted_for: Option<NodeId>,
+    /// Entries after the snapshot: the first one has index `snapshot_index + 1`.
+    entries: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// Members as of the snapshot.
+    snapshot_members: Members,
+    /// Members as of the last entry of the log.
+    members: Members,
+    /// Address of every node ever a member, to answer removed nodes.
+    addresses: Members,
+
+    role: RaftRole,
+    leader: Option<NodeId>,
+    commit: u64,
+    applied: u64,
+    election_elapsed: u32,
+    election_timeout: u32,
+    heartbeat_elapsed: u32,
+    votes: BTreeSet<NodeId>,
+    progress: BTreeMap<NodeId, Progress<E::Snapshot>>,
+    incoming: Option<IncomingSnapshot>,
+    /// Heartbeat round of the leader.
+    seq: u64,
+    /// Term and client of each entry proposed by this leader.
+    proposals: BTreeMap<u64, (u64, Sender<Result<()>>)>,
+    reads: Vec<PendingRead>,
+    outbox: Vec<(String, Message)>,
+    rng: u64,
+}
+
+impl<E: KvsEngine> Raft<E> {
+    pub(crate) fn new(id: NodeId, engine: E, options: RaftOptions) -> Result<Raft<E>> {
+        let (storage, stored) = match &options.dir {
+            Some(dir) => {
+                let (storage, stored) = Storage::open(dir, &options.members)?;
+                (Some(storage), Some(stored))
+            }
+            None => (None, None),
+        };
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        let mut raft = Raft {
+            id,
+            engine,
+            storage,
+            term: 0,
+            voted_for: None,
+            entries: Vec::new(),
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot_members: options.members.clone(),
+            members: Members::new(),
+            addresses: Members::new(),
+            role: RaftRole::Follower,
+            leader: None,
+            commit: 0,
+            applied: 0,
+            election_elapsed: 0,
+            election_timeout: options.election_ticks,
+            heartbeat_elapsed: 0,
+            votes: BTreeSet::new(),
+            progress: BTreeMap::new(),
+            incoming: None,
+            seq: 0,
+            proposals: BTreeMap::new(),
+            reads: Vec::new(),
+            outbox: Vec::new(),
+            rng: (seed ^ id.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1,
+            options,
+        };
+        if let Some(stored) = stored {
+            raft.term = stored.term;
+            raft.voted_for = stored.voted_for;
+            raft.snapshot_index = stored.snapshot_index;
+            raft.snapshot_term = stored.snapshot_term;
+            raft.snapshot_members = stored.members;
+            raft.entries = stored.entries;
+            // The engine holds at least the state of the snapshot
+            raft.commit = stored.snapshot_index;
+            raft.applied = stored.snapshot_index;
+        }
+        raft.refresh_members();
+        raft.reset_election_timer();
+        Ok(raft)
+    }
+
+    pub(crate) fn status(&self) -> RaftStatus {
+        RaftStatus {
+            id: self.id,
+            role: self.role,
+            term: self.term,
+            voted_for: self.voted_for,
+            leader: self.leader,
+            commit_index: self.commit,
+            applied_index: self.applied,
+            snapshot_index: self.snapshot_index,
+            members: self.members.clone(),
+        }
+    }
+
+    /// Takes the messages to send, with the address of their destination.
+    pub(crate) fn take_outbox(&mut self) -> Vec<(String, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Advances the clock of the node by one tick.
+    pub(crate) fn tick(&mut self) -> Result<()> {
+        self.election_elapsed += 1;
+        if self.role != RaftRole::Leader {
+            if self.election_elapsed >= self.election_timeout && self.members.contains_key(&self.id)
+            {
+                self.campaign()?;
+            }
+            return Ok(());
+        }
+        if self.election_elapsed >= self.options.election_ticks {
+            self.election_elapsed = 0;
+            // A leader cut off from the majority stops taking requests it cannot commit
+            if !self.check_quorum() {
+                warn!(
+                    "Node {} lost contact with the majority, stepping down",
+                    self.id
+                );
+                return self.become_follower(self.term, None);
+            }
+        }
+        self.heartbeat_elapsed += 1;
+        if self.heartbeat_elapsed >= self.options.heartbeat_ticks {
+            self.broadcast_append()?;
+        }
+        Ok(())
+    }
+
+    /// Handles a message of another node.
+    pub(crate) fn step(&mut self, message: Message) -> Result<()> {
+        if message.to != self.id {
+            return Ok(());
+        }
+        if message.term > self.term {
+            if let Body::RequestVote { .. } = message.body {
+                // A node cut off from the cluster, or removed from it, keeps
+                // campaigning: ignore it while the leader is known to be alive
+                if self.leader.is_some() && self.election_elapsed < self.options.election_ticks {
+                    return Ok(());
+                }
+            }
+            let leader = match message.body {
+                Body::Append { .. } | Body::InstallSnapshot { .. } => Some(message.from),
+                _ => None,
+            };
+            self.become_follower(message.term, leader)?;
+        }
+        let from = message.from;
+        let current = message.term == self.term;
+        match message.body {
+            Body::RequestVote {
+                last_index,
+                last_term,
+            } => {
+                let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+                let granted = current
+                    && up_to_date
+                    && self.voted_for.is_none_or(|voted_for| voted_for == from);
+                if granted {
+                    self.voted_for = Some(from);
+                    self.save_state()?;
+                    self.election_elapsed = 0;
+                }
+                self.send(from, Body::Vote { granted });
+            }
+            Body::Vote { granted } => {
+                if current && granted && self.role == RaftRole::Candidate {
+                    self.votes.insert(from);
+                    if self.has_quorum(|id| self.votes.contains(&id)) {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            Body::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                seq,
+            } => {
+                if !current {
+                    // Tells the stale leader about the new term
+                    let match_index = self.last_index();
+                    self.send(
+                        from,
+                        Body::AppendReply {
+                            success: false,
+                            match_index,
+                            seq,
+                        },
+                    );
+                    return Ok(());
+                }
+                self.accept_leader(from);
+                let (success, match_index) =
+                    self.append_entries(prev_index, prev_term, entries, commit)?;
+                self.send(
+                    from,
+                    Body::AppendReply {
+                        success,
+                        match_index,
+                        seq,
+                    },
+                );
+            }
+            Body::AppendReply {
+                success,
+                match_index,
+                seq,
+            } => {
+                if current && self.role == RaftRole::Leader {
+                    self.handle_append_reply(from, success, match_index, seq)?;
+                }
+            }
+            Body::InstallSnapshot {
+                index,
+                snapshot_term,
+                members,
+                offset,
+                pairs,
+                done,
+            } => {
+                if !current {
+                    // Tells the stale leader about the new term, or it would
+                    // keep sending chunks while this node campaigns
+                    let match_index = self.last_index();
+                    self.send(
+                        from,
+                        Body::AppendReply {
+                            success: false,
+                            match_index,
+                            seq: 0,
+                        },
+                    );
+                    return Ok(());
+                }
+                self.accept_leader(from);
+                let staged = if index > self.commit {
+                    self.receive_snapshot(index, snapshot_term, members, offset, pairs, done)?
+                } else {
+                    self.drop_incoming()?;
+                    None
+                };
+                let body = match staged {
+                    Some(offset) => Body::SnapshotReply { index, offset },
+                    None => Body::AppendReply {
+                        success: true,
+                        match_index: index,
+                        seq: 0,
+                    },
+                };
+                self.send(from, body);
+            }
+            Body::SnapshotReply { index, offset } => {
+                if current && self.role == RaftRole::Leader {
+                    self.handle_snapshot_reply(from, index, offset)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends an entry if this node leads. `reply` gets the result of its
+    /// application, or an error if it may not be applied.
+    pub(crate) fn propose(&mut self, data: EntryData, reply: Sender<Result<()>>) -> Result<()> {
+        if let Err(e) = self.check_proposal(&data) {
+            let _ = reply.send(Err(e));
+            return Ok(());
+        }
+        let index = self.append(data)?;
+        self.proposals.insert(index, (self.term, reply));
+        self.advance_commit()?;
+        self.broadcast_append()
+    }
+
+    /// Registers a read, answered on `reply` once the engine can serve it
+    /// linearizably.
+    pub(crate) fn read(&mut self, reply: Sender<Result<()>>) -> Result<()> {
+        if self.role != RaftRole::Leader {
+            let _ = reply.send(Err(self.not_leader()));
+            return Ok(());
+        }
+        // Every entry committed when the read arrives is at most the last one
+        self.reads.push(PendingRead {
+            seq: self.seq + 1,
+            index: self.last_index(),
+            confirmed: false,
+            reply,
+        });
+        self.broadcast_append()?;
+        self.confirm_reads();
+        Ok(())
+    }
+
+    fn check_proposal(&self, data: &EntryData) -> Result<()> {
+        if self.role != RaftRole::Leader {
+            return Err(self.not_leader());
+        }
+        let pending_change = self
+            .entries
+            .iter()
+            .filter(|entry| entry.index > self.commit)
+            .any(|entry| is_membership_change(&entry.data));
+        match data {
+            EntryData::AddNode { .. } | EntryData::RemoveNode { .. } if pending_change => Err(
+                MyError::StringError("A membership change is in progress, retry".to_owned()),
+            ),
+            EntryData::AddNode { id, .. } if self.members.contains_key(id) => Err(
+                MyError::StringError(format!("Node {} is already a member", id)),
+            ),
+            EntryData::RemoveNode { id } if !self.members.contains_key(id) => {
+                Err(MyError::StringError(format!("Node {} is not a member", id)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Error of a request sent to a node which does not lead.
+    pub(crate) fn not_leader(&self) -> MyError {
+        let leader = self
+            .leader
+            .filter(|leader| *leader != self.id)
+            .and_then(|leader| Some((leader, self.addresses.get(&leader)?)));
+        MyError::NotLeader(match leader {
+            Some((leader, addr)) => format!("node {} at {} leads", leader, addr),
+            None => "no leader is known".to_owned(),
+        })
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.save_state()?;
+        self.role = RaftRole::Candidate;
+        self.leader = None;
+        self.votes = Some(self.id).into_iter().collect();
+        self.reset_election_timer();
+        info!("Node {} campaigns for term {}", self.id, self.term);
+        if self.has_quorum(|id| self.votes.contains(&id)) {
+            return self.become_leader();
+        }
+        let (last_index, last_term) = (self.last_index(), self.last_term());
+        for id in self.peers() {
+            self.send(
+                id,
+                Body::RequestVote {
+                    last_index,
+                    last_term,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!("Node {} leads term {}", self.id, self.term);
+        self.role = RaftRole::Leader;
+        self.leader = Some(self.id);
+        self.election_elapsed = 0;
+        self.progress.clear();
+        self.refresh_progress(self.last_index() + 1);
+        self.append(EntryData::Noop)?;
+        self.advance_commit()?;
+        self.broadcast_append()
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_state()?;
+        }
+        if self.role == RaftRole::Leader {
+            info!("Node {} no longer leads", self.id);
+        }
+        self.role = RaftRole::Follower;
+        self.leader = leader;
+        self.reset_election_timer();
+        self.progress.clear();
+        let lost = || {
+            MyError::NotLeader(
+                "leadership was lost, the request may or may not be applied".to_owned(),
+            )
+        };
+        for (_, (_, reply)) in std::mem::take(&mut self.proposals) {
+            let _ = reply.send(Err(lost()));
+        }
+        for read in self.reads.drain(..) {
+            let _ = read.reply.send(Err(lost()));
+        }
+        Ok(())
+    }
+
+    fn accept_leader(&mut self, leader: NodeId) {
+        if self.role != RaftRole::Follower {
+            self.role = RaftRole::Follower;
+            self.progress.clear();
+        }
+        self.leader = Some(leader);
+        self.election_elapsed = 0;
+    }
+
+    /// Appends the entries of the leader after `prev_index`, if the log
+    /// holds it. Returns whether it did, and the last entry known to match.
+    fn append_entries(
+        &mut self,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<(bool, u64)> {
+        if prev_index > self.last_index() {
+            return Ok((false, self.last_index()));
+        }
+        if prev_index > self.snapshot_index && self.term_at(prev_index) != Some(prev_term) {
+            return Ok((false, prev_index - 1));
+        }
+        let last_new = prev_index + entries.len() as u64;
+        let mut appended = Vec::new();
+        for entry in entries {
+            if entry.index <= self.snapshot_index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.entries
+                        .truncate((entry.index - self.snapshot_index - 1) as usize);
+                    if let Some(storage) = &mut self.storage {
+                        storage.truncate(entry.index)?;
+                    }
+                }
+                None => (),
+            }
+            self.entries.push(entry.clone());
+            appended.push(entry);
+        }
+        if !appended.is_empty() {
+            if let Some(storage) = &mut self.storage {
+                storage.append(&appended)?;
+            }
+            self.refresh_members();
+        }
+        let commit = commit.min(last_new);
+        if commit > self.commit {
+            self.commit = commit;
+            self.apply()?;
+        }
+        Ok((true, last_new.max(self.snapshot_index)))
+    }
+
+    fn handle_append_reply(
+        &mut self,
+        from: NodeId,
+        success: bool,
+        match_index: u64,
+        seq: u64,
+    ) -> Result<()> {
+        let last_index = self.last_index();
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+        progress.active = true;
+        progress.acked_seq = progress.acked_seq.max(seq);
+        let behind = if success {
+            progress.matched = progress.matched.max(match_index);
+            progress.next = progress.next.max(progress.matched + 1);
+            let installed = progress.matched;
+            if progress
+                .snapshot
+                .as_ref()
+                .is_some_and(|snapshot| snapshot.index <= installed)
+            {
+                progress.snapshot = None;
+            }
+            progress.next <= last_index
+        } else {
+            progress.next = progress
+                .next
+                .saturating_sub(1)
+                .min(match_index + 1)
+                .max(progress.matched + 1);
+            true
+        };
+        if success {
+            self.advance_commit()?;
+        }
+        self.confirm_reads();
+        if behind && self.role == RaftRole::Leader {
+            self.send_append(from)?;
+        }
+        Ok(())
+    }
+
+    /// Commits the last entry of the current term held by a majority.
+    fn advance_commit(&mut self) -> Result<()> {
+        if self.role != RaftRole::Leader {
+            return Ok(());
+        }
+        let last_index = self.last_index();
+        for index in (self.commit + 1..=last_index).rev() {
+            // Only entries of the current term are committed by counting
+            if self.term_at(index) != Some(self.term) {
+                break;
+            }
+            let held = |id: NodeId| {
+                if id == self.id {
+                    true
+                } else {
+                    self.progress
+                        .get(&id)
+                        .is_some_and(|progress| progress.matched >= index)
+                }
+            };
+            if self.has_quorum(held) {
+                self.commit = index;
+                break;
+            }
+        }
+        self.apply()
+    }
+
+    /// Applies the committed entries to the engine.
+    fn apply(&mut self) -> Result<()> {
+        while self.applied < self.commit {
+            let index = self.applied + 1;
+            let entry = self.entry(index).clone();
+            let result = match &entry.data {
+                EntryData::Request(request) => apply_request(&mut self.engine, request.clone()),
+                _ => Ok(()),
+            };
+            self.applied = index;
+            match self.proposals.remove(&index) {
+                Some((term, reply)) if term == entry.term => {
+                    let _ = reply.send(result);
+                }
+                Some((_, reply)) => {
+                    let _ = reply.send(Err(MyError::NotLeader(
+                        "the request was overwritten by another leader".to_owned(),
+                    )));
+                }
+                None => match result {
+                    Ok(()) | Err(MyError::KeyNotFound) => (),
+                    Err(e) => warn!("Node {} failed to apply entry {}: {}", self.id, index, e),
+                },
+            }
+            if let EntryData::RemoveNode { id } = entry.data {
+                if id == self.id && self.role == RaftRole::Leader {
+                    info!("Node {} was removed from the cluster", self.id);
+                    self.become_follower(self.term, None)?;
+                }
+            }
+        }
+        self.answer_reads();
+        self.maybe_snapshot()
+    }
+
+    /// Compacts the applied entries once there are enough of them.
+    fn maybe_snapshot(&mut self) -> Result<()> {
+        if self.applied - self.snapshot_index < self.options.snapshot_entries {
+            return Ok(());
+        }
+        // The engine must hold the applied entries before they leave the log
+        self.engine.sync()?;
+        let index = self.applied;
+        let term = self.term_at(index).unwrap_or(self.snapshot_term);
+        let members = self.members_at(index);
+        self.entries = self
+            .entries
+            .split_off((index - self.snapshot_index) as usize);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.snapshot_members = members;
+        if let Some(storage) = &mut self.storage {
+            storage.compact(index, term, &self.snapshot_members, &self.entries)?;
+        }
+        info!("Node {} compacted its log up to {}", self.id, index);
+        Ok(())
+    }
+
+    /// Stages a chunk of the snapshot up to `index`. Returns the pairs staged
+    /// so far, or `None` once the snapshot is restored.
+    fn receive_snapshot(
+        &mut self,
+        index: u64,
+        term: u64,
+        members: Members,
+        offset: u64,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        done: bool,
+    ) -> Result<Option<u64>> {
+        if offset == 0 {
+            // The leader starts sending over
+            self.drop_incoming()?;
+            self.incoming = Some(IncomingSnapshot {
+                index,
+                term,
+                members,
+                offset,
+                staging: Staging::open(self.storage.as_ref())?,
+            });
+        }
+        let incoming = match &mut self.incoming {
+            Some(incoming) if incoming.index == index => incoming,
+            _ => return Ok(Some(0)),
+        };
+        if offset != incoming.offset {
+            return Ok(Some(incoming.offset));
+        }
+        incoming.offset += pairs.len() as u64;
+        incoming.staging.write(pairs)?;
+        if !done {
+            return Ok(Some(incoming.offset));
+        }
+        if let Some(incoming) = self.incoming.take() {
+            self.restore(incoming)?;
+        }
+        Ok(None)
+    }
+
+    /// Discards the snapshot being received.
+    fn drop_incoming(&mut self) -> Result<()> {
+        match self.incoming.take() {
+            Some(incoming) => incoming.staging.discard(),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the engine and the log with a snapshot of the leader.
+    fn restore(&mut self, incoming: IncomingSnapshot) -> Result<()> {
+        let IncomingSnapshot {
+            index,
+            term,
+            members,
+            mut staging,
+            ..
+        } = incoming;
+        info!("Node {} restores a snapshot up to {}", self.id, index);
+        {
+            // Both iterate in key order: the keys of the engine missing from
+            // the snapshot are removed as they are passed
+            let mut staged = staging.snapshot()?;
+            let mut current = self.engine.snapshot()?;
+            let mut current = current.iter().peekable();
+            let mut batch = Vec::new();
+            let mut bytes = 0;
+            for pair in staged.iter() {
+                let (key, value) = pair?;
+                remove_stale(&mut self.engine, &mut current, Some(&key))?;
+                bytes += key.len() + value.len();
+                batch.push((key, value));
+                if bytes >= self.options.snapshot_chunk_bytes {
+                    self.engine.set_batch(mem::take(&mut batch))?;
+                    bytes = 0;
+                }
+            }
+            self.engine.set_batch(batch)?;
+            remove_stale(&mut self.engine, &mut current, None)?;
+        }
+        staging.discard()?;
+        self.engine.sync()?;
+
+        // Entries after the snapshot are kept if the log agrees with it
+        if self.term_at(index) == Some(term) {
+            self.entries = self
+                .entries
+                .split_off((index - self.snapshot_index) as usize);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.snapshot_members = members;
+        self.commit = self.commit.max(index);
+        self.applied = index;
+        if let Some(storage) = &mut self.storage {
+            storage.compact(index, term, &self.snapshot_members, &self.entries)?;
+        }
+        self.refresh_members();
+        self.apply()
+    }
+
+    /// Appends an entry to the log of this leader.
+    fn append(&mut self, data: EntryData) -> Result<u64> {
+        let entry = Entry {
+            index: self.last_index() + 1,
+            term: self.term,
+            data,
+        };
+        if let Some(storage) = &mut self.storage {
+            storage.append(std::slice::from_ref(&entry))?;
+        }
+        let (index, change) = (entry.index, is_membership_change(&entry.data));
+        self.entries.push(entry);
+        if change {
+            self.refresh_members();
+        }
+        Ok(index)
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        self.heartbeat_elapsed = 0;
+        self.seq += 1;
+        for id in self.progress.keys().copied().collect::<Vec<_>>() {
+            self.send_append(id)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a follower the entries it misses, or a snapshot if they were
+    /// compacted.
+    fn send_append(&mut self, to: NodeId) -> Result<()> {
+        let next = match self.progress.get(&to) {
+            Some(progress) => progress.next,
+            None => return Ok(()),
+        };
+        if next <= self.snapshot_index {
+            return self.send_snapshot(to);
+        }
+        let prev_index = next - 1;
+        let prev_term = self.term_at(prev_index).unwrap_or(self.snapshot_term);
+        let start = (next - self.snapshot_index - 1) as usize;
+        let entries = self.entries[start.min(self.entries.len())..]
+            .iter()
+            .take(MAX_APPEND_ENTRIES)
+            .cloned()
+            .collect();
+        let body = Body::Append {
+            prev_index,
+            prev_term,
+            entries,
+            commit: self.commit,
+            seq: self.seq,
+        };
+        self.send(to, body);
+        Ok(())
+    }
+
+    /// Sends the next chunk of the state of the engine as of the last applied
+    /// entry, again until the follower acknowledges it.
+    fn send_snapshot(&mut self, to: NodeId) -> Result<()> {
+        if self
+            .progress
+            .get(&to)
+            .is_some_and(|progress| progress.snapshot.is_none())
+        {
+            let index = self.applied;
+            let snapshot = OutgoingSnapshot {
+                index,
+                term: self.term_at(index).unwrap_or(self.snapshot_term),
+                members: self.members_at(index),
+                view: self.engine.snapshot()?,
+                acked: 0,
+                acked_key: None,
+                sent: 0,
+                sent_key: None,
+            };
+            info!(
+                "Node {} sends a snapshot up to {} to node {}",
+                self.id, index, to
+            );
+            if let Some(progress) = self.progress.get_mut(&to) {
+                progress.snapshot = Some(snapshot);
+            }
+        }
+        let limit = self.options.snapshot_chunk_bytes;
+        let snapshot = match self.progress.get_mut(&to) {
+            Some(Progress {
+                snapshot: Some(snapshot),
+                ..
+            }) => snapshot,
+            _ => return Ok(()),
+        };
+        let start = match &snapshot.acked_key {
+            Some(key) => Bound::Excluded(key.clone()),
+            None => Bound::Unbounded,
+        };
+        let mut pairs = Vec::new();
+        let mut bytes = 0;
+        let mut done = true;
+        for pair in snapshot.view.scan((start, Bound::Unbounded)) {
+            if !pairs.is_empty() && bytes >= limit {
+                done = false;
+                break;
+            }
+            let (key, value) = pair?;
+            bytes += key.len() + value.len();
+            pairs.push((key, value));
+        }
+        snapshot.sent = snapshot.acked + pairs.len() as u64;
+        snapshot.sent_key = match pairs.last() {
+            Some((key, _)) => Some(key.clone()),
+            None => snapshot.acked_key.clone(),
+        };
+        let body = Body::InstallSnapshot {
+            index: snapshot.index,
+            snapshot_term: snapshot.term,
+            members: snapshot.members.clone(),
+            offset: snapshot.acked,
+            pairs,
+            done,
+        };
+        self.send(to, body);
+        Ok(())
+    }
+
+    /// Moves on to the next chunk once the follower staged the one sent, or
+    /// starts over if it lost the snapshot.
+    fn handle_snapshot_reply(&mut self, from: NodeId, index: u64, offset: u64) -> Result<()> {
+        let snapshot = match self.progress.get_mut(&from) {
+            Some(progress) => {
+                progress.active = true;
+                match &mut progress.snapshot {
+                    Some(snapshot) if snapshot.index == index => snapshot,
+                    _ => return Ok(()),
+                }
+            }
+            None => return Ok(()),
+        };
+        if offset == snapshot.sent {
+            snapshot.acked = offset;
+            snapshot.acked_key = snapshot.sent_key.clone();
+        } else if offset == 0 {
+            snapshot.acked = 0;
+            snapshot.acked_key = None;
+        } else {
+            // Answers a chunk sent again
+            return Ok(());
+        }
+        self.send_snapshot(from)
+    }
+
+    /// Marks the reads confirmed by a majority of the current heartbeat round.
+    fn confirm_reads(&mut self) {
+        if self.reads.is_empty() {
+            return;
+        }
+        let mut acked: Vec<u64> = self
+            .members
+            .keys()
+            .map(|id| match self.progress.get(id) {
+                _ if *id == self.id => self.seq,
+                Some(progress) => progress.acked_seq,
+                None => 0,
+            })
+            .collect();
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        let confirmed = match acked.get(self.members.len() / 2) {
+            Some(seq) => *seq,
+            None => return,
+        };
+        for read in &mut self.reads {
+            if read.seq <= confirmed {
+                read.confirmed = true;
+            }
+        }
+        self.answer_reads();
+    }
+
+    fn answer_reads(&mut self) {
+        let applied = self.applied;
+        self.reads.retain(|read| {
+            if read.confirmed && read.index <= applied {
+                let _ = read.reply.send(Ok(()));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Whether most followers answered since the last check.
+    fn check_quorum(&mut self) -> bool {
+        let id = self.id;
+        let progress = &self.progress;
+        let active = self
+            .members
+            .keys()
+            .filter(|member| **member == id || progress.get(member).is_some_and(|p| p.active))
+            .count();
+        for progress in self.progress.values_mut() {
+            progress.active = false;
+        }
+        active > self.members.len() / 2
+    }
+
+    fn has_quorum(&self, holds: impl Fn(NodeId) -> bool) -> bool {
+        let count = self.members.keys().filter(|id| holds(**id)).count();
+        count > self.members.len() / 2
+    }
+
+    /// Recomputes the members from the log, and the followers of a leader.
+    fn refresh_members(&mut self) {
+        self.members = self.members_at(self.last_index());
+        for (id, addr) in &self.members {
+            self.addresses.insert(*id, addr.clone());
+        }
+        if self.role == RaftRole::Leader {
+            // A new member knows no other node until it receives the members
+            // with a snapshot, so it could not reply to entries
+            self.refresh_progress(0);
+        }
+    }
+
+    /// Tracks the followers among the members, new ones from entry `next`.
+    fn refresh_progress(&mut self, next: u64) {
+        let members = &self.members;
+        self.progress.retain(|id, _| members.contains_key(id));
+        for id in self.peers() {
+            self.progress.entry(id).or_insert(Progress {
+                next,
+                matched: 0,
+                acked_seq: 0,
+                active: true,
+                snapshot: None,
+            });
+        }
+    }
+
+    /// Members as of the entry at `index`.
+    fn members_at(&self, index: u64) -> Members {
+        let mut members = self.snapshot_members.clone();
+        for entry in self.entries.iter().take_while(|entry| entry.index <= index) {
+            match &entry.data {
+                EntryData::AddNode { id, addr } => {
+                    members.insert(*id, addr.clone());
+                }
+                EntryData::RemoveNode { id } => {
+                    members.remove(id);
+                }
+                _ => (),
+            }
+        }
+        members
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.members
+            .keys()
+            .copied()
+            .filter(|id| *id != self.id)
+            .collect()
+    }
+
+    fn send(&mut self, to: NodeId, body: Body) {
+        if let Some(addr) = self.addresses.get(&to) {
+            let message = Message {
+                from: self.id,
+                to,
+                term: self.term,
+                body,
+            };
+            self.outbox.push((addr.clone(), message));
+        }
+    }
+
+    fn save_state(&mut self) -> Result<()> {
+        match &mut self.storage {
+            Some(storage) => storage.save_state(self.term, self.voted_for),
+            None => Ok(()),
+        }
+    }
+
+    /// Restarts the election timer, with a new random timeout.
+    fn reset_election_timer(&mut self) {
+        self.election_elapsed = 0;
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let ticks = self.options.election_ticks.max(1);
+        self.election_timeout = ticks + (self.rng % u64::from(ticks)) as u32;
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index {
+            return None;
+        }
+        self.entries
+            .get((index - self.snapshot_index - 1) as usize)
+            .map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> &Entry {
+        &self.entries[(index - self.snapshot_index - 1) as usize]
+    }
+}
+
+fn is_membership_change(data: &EntryData) -> bool {
+    matches!(
+        data,
+        EntryData::AddNode { .. } | EntryData::RemoveNode { .. }
+    )
+}
+
+/// Removes the keys of `current` before `until`, or all of them, and skips
+/// `until` itself.
+fn remove_stale<E: KvsEngine>(
+    engine: &mut E,
+    current: &mut Peekable<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>,
+    until: Option<&[u8]>,
+) -> Result<()> {
+    let before = |entry: &Result<(Vec<u8>, Vec<u8>)>| match entry {
+        Ok((key, _)) => until.is_none_or(|until| key.as_slice() < until),
+        Err(_) => true,
+    };
+    while let Some(entry) = current.next_if(before) {
+        let (key, _) = entry?;
+        match engine.remove_bytes(&key) {
+            Ok(()) | Err(MyError::KeyNotFound) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(until) = until {
+        current.next_if(|entry| matches!(entry, Ok((key, _)) if key.as_slice() == until));
+    }
+    Ok(())
+}
+
+/// Applies a replicated request to the engine.
+fn apply_request<E: KvsEngine>(engine: &mut E, request: Request) -> Result<()> {
+    match request {
+        Request::Set { key, value, .. } => engine.set_bytes(key, value),
+        Request::Remove { key, .. } => engine.remove_bytes(&key),
+        other => Err(MyError::StringError(format!(
+            "Unexpected replicated request {:?}",
+            other
+        ))),
+    }
+}
@@ -0,0 +1,280 @@
+//! Raft consensus cluster mode
+//!
+//! The nodes of a cluster replicate a log of `Set` and `Remove` requests with
+//! the Raft algorithm, and each node applies the committed requests to its own
+//! engine. Requests go through the leader: a write is acknowledged once a
+//! majority of the nodes hold it, and a read is answered once the leader has
+//! checked with a majority that it still leads, so reads are linearizable.
+//!
+//! A node compacts its log once `RaftOptions::snapshot_entries` entries are
+//! applied, since its engine holds the state they built. A node too far behind
+//! the leader receives a snapshot of the leader's engine instead of the
+//! entries it missed, in chunks of `RaftOptions::snapshot_chunk_bytes`. It
+//! stages them apart from its engine, which it updates once the last one
+//! arrives.
+//!
+//! Members are added and removed one at a time. A new node starts with no
+//! members, and waits for the leader to contact it once it is added.
+//!
+//! Nodes exchange `Message`s through a `Transport`: `TcpTransport` between
+//! processes, authenticated with mutual TLS, or `SimNetwork` within a
+//! process, which drops, delays and partitions messages for tests. Only the
+//! default namespace is replicated.
+mod core;
+mod network;
+mod node;
+mod storage;
+mod tcp;
+
+pub use self::network::SimNetwork;
+pub use self::node::RaftNode;
+pub use self::tcp::TcpTransport;
+
+use crate::common::Request;
+use crate::errors::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Identifier of a node, unique in its cluster.
+pub type NodeId = u64;
+
+/// Address of each member of a cluster.
+pub type Members = BTreeMap<NodeId, String>;
+
+/// Message between two nodes of a cluster. Transports route it without
+/// looking into it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub(crate) from: NodeId,
+    pub(crate) to: NodeId,
+    pub(crate) term: u64,
+    pub(crate) body: Body,
+}
+
+impl Message {
+    /// Node which sent the message.
+    pub fn from(&self) -> NodeId {
+        self.from
+    }
+
+    /// Node the message is addressed to.
+    pub fn to(&self) -> NodeId {
+        self.to
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Body {
+    RequestVote {
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        granted: bool,
+    },
+    /// Entries following `prev_index`, none for a heartbeat.
+    Append {
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+        /// Heartbeat round of the leader, echoed to confirm reads.
+        seq: u64,
+    },
+    /// On success, the follower holds the log of the leader up to
+    /// `match_index`. Otherwise the leader should resend from there.
+    AppendReply {
+        success: bool,
+        match_index: u64,
+        seq: u64,
+    },
+    /// Chunk of the engine of the leader as of entry `index`: `pairs` follow
+    /// the `offset` pairs sent before, in key order. Once `done`, the
+    /// snapshot replaces the engine of the follower, and its log up to `index`.
+    InstallSnapshot {
+        index: u64,
+        snapshot_term: u64,
+        members: Members,
+        offset: u64,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        done: bool,
+    },
+    /// The follower staged the first `offset` pairs of the snapshot up to
+    /// `index`, and waits for the next ones.
+    SnapshotReply {
+        index: u64,
+        offset: u64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) index: u64,
+    pub(crate) term: u64,
+    pub(crate) data: EntryData,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum EntryData {
+    /// Appended by a new leader, to commit the entries of earlier terms.
+    Noop,
+    /// `Request::Set` or `Request::Remove` on the default namespace.
+    Request(Request),
+    /// Membership changes, effective as soon as they are in the log.
+    AddNode {
+        id: NodeId,
+        addr: String,
+    },
+    RemoveNode {
+        id: NodeId,
+    },
+}
+
+/// Carries messages between the nodes of a cluster. Messages may be lost:
+/// Raft sends them again.
+pub trait Transport: Send + Sync + 'static {
+    /// Delivers the messages addressed to node `id` to `mailbox` from now on.
+    fn listen(&self, id: NodeId, mailbox: Mailbox) -> Result<()>;
+
+    /// Sends a message to the node at `addr`, without waiting for delivery.
+    fn send(&self, addr: &str, message: Message);
+}
+
+/// Receiving end of a node, handed to its transport.
+#[derive(Clone)]
+pub struct Mailbox(mpsc::Sender<Message>);
+
+impl Mailbox {
+    /// Delivers a message to the node. Returns `false` once the node stopped.
+    pub fn deliver(&self, message: Message) -> bool {
+        self.0.send(message).is_ok()
+    }
+}
+
+/// Settings of a `RaftNode`.
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    members: Members,
+    dir: Option<PathBuf>,
+    tick: Duration,
+    election_ticks: u32,
+    heartbeat_ticks: u32,
+    snapshot_entries: u64,
+    snapshot_chunk_bytes: usize,
+    request_timeout: Duration,
+}
+
+impl Default for RaftOptions {
+    fn default() -> RaftOptions {
+        RaftOptions {
+            members: Members::new(),
+            dir: None,
+            tick: Duration::from_millis(10),
+            election_ticks: 15,
+            heartbeat_ticks: 3,
+            snapshot_entries: 1000,
+            snapshot_chunk_bytes: 1024 * 1024,
+            request_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RaftOptions {
+    /// Adds a member to the initial cluster, which is bootstrapped when it
+    /// includes the node itself. Ignored once the node has stored state.
+    pub fn member(mut self, id: NodeId, addr: impl Into<String>) -> Self {
+        self.members.insert(id, addr.into());
+        self
+    }
+
+    /// Keeps the term, vote and log of the node in `dir`, so that it can
+    /// restart. Without it the node forgets them when it stops, and must never
+    /// rejoin its cluster: it could vote twice in a term or drop acknowledged
+    /// entries.
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the period of the node's clock.
+    pub fn tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    /// Sets the ticks without a leader after which a follower campaigns, at
+    /// random between `ticks` and twice as many.
+    pub fn election_ticks(mut self, ticks: u32) -> Self {
+        self.election_ticks = ticks;
+        self
+    }
+
+    /// Sets the ticks between the heartbeats of a leader.
+    pub fn heartbeat_ticks(mut self, ticks: u32) -> Self {
+        self.heartbeat_ticks = ticks;
+        self
+    }
+
+    /// Compacts the log once this many entries are applied since the last
+    /// snapshot.
+    pub fn snapshot_entries(mut self, entries: u64) -> Self {
+        self.snapshot_entries = entries;
+        self
+    }
+
+    /// Sends snapshots in chunks of about `bytes` of keys and values.
+    pub fn snapshot_chunk_bytes(mut self, bytes: usize) -> Self {
+        self.snapshot_chunk_bytes = bytes;
+        self
+    }
+
+    /// Sets how long a request waits for the cluster before failing.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+}
+
+/// Role of a node in its term.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// State of a node, returned by `RaftNode::status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftStatus {
+    pub id: NodeId,
+    pub role: RaftRole,
+    pub term: u64,
+    /// Node voted for in the term, if any.
+    pub voted_for: Option<NodeId>,
+    /// Leader of the term, if known.
+    pub leader: Option<NodeId>,
+    /// Last entry known to be held by a majority.
+    pub commit_index: u64,
+    /// Last entry applied to the engine.
+    pub applied_index: u64,
+    /// Last entry compacted into the snapshot.
+    pub snapshot_index: u64,
+    pub members: Members,
+}
+
+impl fmt::Display for RaftStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.role, self.leader) {
+            (RaftRole::Leader, _) => write!(f, "raft leader, term {}", self.term),
+            (RaftRole::Candidate, _) => write!(f, "raft candidate, term {}", self.term),
+            (RaftRole::Follower, Some(leader)) => {
+                write!(f, "raft follower of node {}, term {}", leader, self.term)
+            }
+            (RaftRole::Follower, None) => write!(f, "raft follower, term {}", self.term),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! In-process network for testing clusters.
+use super::{Mailbox, Message, NodeId, Transport};
+use crate::errors::Result;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest wait of the delivery thread, which exits once the network is dropped.
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
+/// Network between the nodes of a cluster running in one process. It can
+/// drop, delay and partition messages. Clones share the same network.
+///
+/// Nodes are told apart by their id: the addresses of members are ignored.
+#[derive(Clone, Default)]
+pub struct SimNetwork {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: Mutex<State>,
+    /// Wakes the delivery thread when a delayed message is queued.
+    queued: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    mailboxes: HashMap<NodeId, Mailbox>,
+    drop_rate: f64,
+    min_delay: Duration,
+    max_delay: Duration,
+    /// Group of each node while the network is partitioned.
+    groups: Option<HashMap<NodeId, usize>>,
+    delayed: BinaryHeap<Delayed>,
+    sent: u64,
+    rng: u64,
+    delivering: bool,
+}
+
+/// A message held back until `at`.
+struct Delayed {
+    at: Instant,
+    seq: u64,
+    message: Message,
+}
+
+impl SimNetwork {
+    pub fn new() -> SimNetwork {
+        SimNetwork::default()
+    }
+
+    /// Drops each message with probability `rate`, between 0 and 1.
+    pub fn set_drop_rate(&self, rate: f64) {
+        self.inner.state.lock().unwrap().drop_rate = rate;
+    }
+
+    /// Delays each message by a random duration between `min` and `max`,
+    /// which reorders them.
+    pub fn set_delay(&self, min: Duration, max: Duration) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.min_delay = min;
+        state.max_delay = max.max(min);
+    }
+
+    /// Splits the network into `groups`: nodes of different groups cannot
+    /// communicate. A node in no group is cut off from every other node.
+    pub fn partition(&self, groups: &[&[NodeId]]) {
+        let groups = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, ids)| ids.iter().map(move |id| (*id, group)))
+            .collect();
+        self.inner.state.lock().unwrap().groups = Some(groups);
+    }
+
+    /// Cuts node `id` off from every other node.
+    pub fn isolate(&self, id: NodeId) {
+        let mut state = self.inner.state.lock().unwrap();
+        let mut groups = state.groups.take().unwrap_or_else(|| {
+            state
+                .mailboxes
+                .keys()
+                .map(|node| (*node, 0))
+                .collect::<HashMap<_, _>>()
+        });
+        groups.remove(&id);
+        state.groups = Some(groups);
+    }
+
+    /// Reconnects every node. Dropping and delaying are left unchanged.
+    pub fn heal(&self) {
+        self.inner.state.lock().unwrap().groups = None;
+    }
+
+    /// Delivers the delayed messages when they are due, until the network
+    /// is dropped.
+    fn deliver_delayed(inner: Weak<Inner>) {
+        while let Some(inner) = inner.upgrade() {
+            let mut state = inner.state.lock().unwrap();
+            let now = Instant::now();
+            while state
+                .delayed
+                .peek()
+                .is_some_and(|delayed| delayed.at <= now)
+            {
+                let delayed = state.delayed.pop().unwrap();
+                state.deliver(delayed.message);
+            }
+            let wait = match state.delayed.peek() {
+                Some(delayed) => (delayed.at - now).min(IDLE_WAIT),
+                None => IDLE_WAIT,
+            };
+            drop(inner.queued.wait_timeout(state, wait).unwrap());
+        }
+    }
+}
+
+impl Transport for SimNetwork {
+    fn listen(&self, id: NodeId, mailbox: Mailbox) -> Result<()> {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .mailboxes
+            .insert(id, mailbox);
+        Ok(())
+    }
+
+    fn send(&self, _addr: &str, message: Message) {
+        let mut state = self.inner.state.lock().unwrap();
+        if !state.connected(message.from, message.to) || state.random() < state.drop_rate {
+            return;
+        }
+        let spread = state.max_delay - state.min_delay;
+        let delay = state.min_delay + spread.mul_f64(state.random());
+        if delay.is_zero() {
+            state.deliver(message);
+            return;
+        }
+        state.sent += 1;
+        let delayed = Delayed {
+            at: Instant::now() + delay,
+            seq: state.sent,
+            message,
+        };
+        state.delayed.push(delayed);
+        if !state.delivering {
+            state.delivering = true;
+            let inner = Arc::downgrade(&self.inner);
+            thread::spawn(move || SimNetwork::deliver_delayed(inner));
+        }
+        self.inner.queued.notify_one();
+    }
+}
+
+impl State {
+    fn connected(&self, from: NodeId, to: NodeId) -> bool {
+        match &self.groups {
+            None => true,
+            Some(groups) => match (groups.get(&from), groups.get(&to)) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+
+    /// Hands a message to its node, unless a partition cut them off since
+    /// it was sent.
+    fn deliver(&mut self, message: Message) {
+        if !self.connected(message.from, message.to) {
+            return;
+        }
+        let to = message.to;
+        if let Some(mailbox) = self.mailboxes.get(&to) {
+            if !mailbox.deliver(message) {
+                self.mailboxes.remove(&to);
+            }
+        }
+    }
+
+    /// Returns a pseudo-random number in [0, 1).
+    fn random(&mut self) -> f64 {
+        if self.rng == 0 {
+            self.rng = 0x2545_F491_4F6C_DD1D;
+        }
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    /// The earliest message is the greatest, on top of the heap.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
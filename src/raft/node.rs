@@ -0,0 +1,194 @@
+use super::core::Raft;
+use super::{EntryData, Mailbox, Message, NodeId, RaftOptions, RaftStatus, Transport};
+use crate::common::Request;
+use crate::engine::{Command, KvsEngine};
+use crate::errors::{MyError, Result};
+use log::error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A node of a Raft cluster, replicating writes to the default namespace of
+/// its engine. Clones are handles on the same node.
+pub struct RaftNode<E: KvsEngine> {
+    shared: Arc<Shared<E>>,
+}
+
+impl<E: KvsEngine> Clone for RaftNode<E> {
+    fn clone(&self) -> Self {
+        RaftNode {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+struct Shared<E: KvsEngine> {
+    id: NodeId,
+    raft: Mutex<Raft<E>>,
+    transport: Box<dyn Transport>,
+    /// Handle reads are served from, once the leader confirmed them.
+    engine: Mutex<E>,
+    request_timeout: Duration,
+    stopped: AtomicBool,
+}
+
+impl<E: KvsEngine + Clone + Send + 'static> RaftNode<E> {
+    /// Starts node `id` on `engine`, exchanging messages through `transport`.
+    pub fn start(
+        id: NodeId,
+        engine: E,
+        transport: impl Transport,
+        options: RaftOptions,
+    ) -> Result<RaftNode<E>> {
+        let tick = options.tick;
+        let request_timeout = options.request_timeout;
+        let raft = Raft::new(id, engine.clone(), options)?;
+        let (sender, inbox) = mpsc::channel();
+        let node = RaftNode {
+            shared: Arc::new(Shared {
+                id,
+                raft: Mutex::new(raft),
+                transport: Box::new(transport),
+                engine: Mutex::new(engine),
+                request_timeout,
+                stopped: AtomicBool::new(false),
+            }),
+        };
+        node.shared.transport.listen(id, Mailbox(sender))?;
+        let driver = node.clone();
+        thread::spawn(move || driver.run(inbox, tick));
+        Ok(node)
+    }
+
+    /// Ticks the clock and handles the messages of other nodes until the
+    /// node stops.
+    fn run(&self, inbox: Receiver<Message>, tick: Duration) {
+        let mut next_tick = Instant::now() + tick;
+        while !self.is_stopped() {
+            let now = Instant::now();
+            let result = if now >= next_tick {
+                next_tick += tick;
+                self.with_raft(|raft| raft.tick())
+            } else {
+                match inbox.recv_timeout(next_tick - now) {
+                    Ok(message) => self.with_raft(|raft| raft.step(message)),
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            };
+            if let Err(e) = result {
+                error!("Raft node {} stopped: {}", self.shared.id, e);
+                self.stop();
+            }
+        }
+    }
+
+    /// Runs `f` on the state of the node, then sends the messages it queued.
+    fn with_raft<T>(&self, f: impl FnOnce(&mut Raft<E>) -> Result<T>) -> Result<T> {
+        let (result, outbox) = {
+            let mut raft = self.shared.raft.lock().unwrap();
+            let result = f(&mut raft);
+            (result, raft.take_outbox())
+        };
+        for (addr, message) in outbox {
+            self.shared.transport.send(&addr, message);
+        }
+        result
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.shared.id
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        self.shared.raft.lock().unwrap().status()
+    }
+
+    /// Sets the value of a key once a majority of the cluster holds the write.
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(Command::set(key, value))
+    }
+
+    /// Removes a key once a majority of the cluster holds the removal.
+    ///
+    /// It returns `MyError::KeyNotFound` if the key does not exist when the
+    /// removal is applied.
+    pub fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.write(Command::remove(key))
+    }
+
+    /// Gets the value of a key, as of a moment between the call and its
+    /// return: the read reflects every write acknowledged before it.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.request(|raft, reply| raft.read(reply))?;
+        let mut engine = self.shared.engine.lock().unwrap().clone();
+        engine.get_bytes(key)
+    }
+
+    /// Adds node `id`, reachable at `addr`, to the cluster. The node should
+    /// be started with no members.
+    pub fn add_node(&self, id: NodeId, addr: impl Into<String>) -> Result<()> {
+        let addr = addr.into();
+        self.request(|raft, reply| raft.propose(EntryData::AddNode { id, addr }, reply))
+    }
+
+    /// Removes node `id` from the cluster. A leader removing itself steps
+    /// down once the removal is committed.
+    pub fn remove_node(&self, id: NodeId) -> Result<()> {
+        self.request(|raft, reply| raft.propose(EntryData::RemoveNode { id }, reply))
+    }
+
+    /// Stops the node, as if its process had crashed. Its engine and storage
+    /// are left as they are, so a new node can start on them.
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.shared.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Replicates a plain `Set` or `Remove` of the default namespace.
+    pub(crate) fn write(&self, command: Command) -> Result<()> {
+        let request = match command {
+            Command::Set { key, value, .. } => Request::Set {
+                key,
+                value,
+                namespace: None,
+            },
+            Command::Remove { key } => Request::Remove {
+                key,
+                namespace: None,
+            },
+            Command::Sealed { .. } => {
+                return Err(MyError::StringError(
+                    "Sealed records are not replicated".to_owned(),
+                ))
+            }
+        };
+        self.request(|raft, reply| raft.propose(EntryData::Request(request), reply))
+    }
+
+    /// Submits a request to the node and waits for its outcome.
+    fn request(
+        &self,
+        submit: impl FnOnce(&mut Raft<E>, mpsc::Sender<Result<()>>) -> Result<()>,
+    ) -> Result<()> {
+        if self.is_stopped() {
+            return Err(MyError::StringError(format!(
+                "Raft node {} is stopped",
+                self.shared.id
+            )));
+        }
+        let (reply, outcome) = mpsc::channel();
+        self.with_raft(|raft| submit(raft, reply))?;
+        match outcome.recv_timeout(self.shared.request_timeout) {
+            Ok(result) => result,
+            Err(_) => Err(MyError::StringError(
+                "Timed out waiting for the cluster".to_owned(),
+            )),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Durable state of a Raft node: its term, its vote and its log, and the
+//! snapshot it is receiving.
+//!
+//! A torn last record of the log, left by a crash in the middle of an append,
+//! is truncated on open. Any other unreadable record is an error.
+use super::{Entry, Members, NodeId};
+use crate::engine::{KvStore, KvsEngine, KvsSnapshot, MemoryKvsEngine};
+use crate::errors::{MyError, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "state.json";
+const LOG_FILE: &str = "log.json";
+const STAGING_DIR: &str = "snapshot";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<NodeId>,
+}
+
+/// Record of the log file, replayed in order on open.
+#[derive(Debug, Serialize, Deserialize)]
+enum LogRecord {
+    /// Entries up to `index` are compacted; the engine holds their state.
+    Snapshot {
+        index: u64,
+        term: u64,
+        members: Members,
+    },
+    Append(Entry),
+    /// Entries from `from` on were overwritten by a new leader.
+    Truncate {
+        from: u64,
+    },
+}
+
+/// State read back by `Storage::open`.
+pub(crate) struct Stored {
+    pub(crate) term: u64,
+    pub(crate) voted_for: Option<NodeId>,
+    pub(crate) snapshot_index: u64,
+    pub(crate) snapshot_term: u64,
+    pub(crate) members: Members,
+    pub(crate) entries: Vec<Entry>,
+}
+
+pub(crate) struct Storage {
+    dir: PathBuf,
+    log: BufWriter<File>,
+}
+
+impl Storage {
+    /// Opens the state kept in `dir`. A new node starts with `members`.
+    pub(crate) fn open(dir: &Path, members: &Members) -> Result<(Storage, Stored)> {
+        fs::create_dir_all(dir)?;
+        let state: HardState = match File::open(dir.join(STATE_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut stored = Stored {
+            term: state.term,
+            voted_for: state.voted_for,
+            snapshot_index: 0,
+            snapshot_term: 0,
+            members: members.clone(),
+            entries: Vec::new(),
+        };
+        let log_path = dir.join(LOG_FILE);
+        let fresh = !log_path.exists();
+        if !fresh {
+            let reader = BufReader::new(File::open(&log_path)?);
+            let mut stream = Deserializer::from_reader(reader).into_iter::<LogRecord>();
+            let mut end = 0;
+            while let Some(record) = stream.next() {
+                let record = match record {
+                    Ok(record) => record,
+                    // A crash in the middle of an append cuts the last record
+                    // short. It was never acknowledged: drop it.
+                    Err(e) if e.is_eof() => {
+                        warn!(
+                            "Truncating the torn last record of {} at byte {}",
+                            log_path.display(),
+                            end
+                        );
+                        let file = OpenOptions::new().write(true).open(&log_path)?;
+                        file.set_len(end)?;
+                        file.sync_all()?;
+                        break;
+                    }
+                    Err(e) => {
+                        return Err(MyError::StringError(format!(
+                            "{}: {}",
+                            log_path.display(),
+                            e
+                        )))
+                    }
+                };
+                end = stream.byte_offset() as u64;
+                match record {
+                    LogRecord::Snapshot {
+                        index,
+                        term,
+                        members,
+                    } => {
+                        stored.snapshot_index = index;
+                        stored.snapshot_term = term;
+                        stored.members = members;
+                        stored.entries.retain(|entry| entry.index > index);
+                    }
+                    LogRecord::Append(entry) => {
+                        if entry.index > stored.snapshot_index {
+                            stored.entries.push(entry);
+                        }
+                    }
+                    LogRecord::Truncate { from } => {
+                        stored.entries.retain(|entry| entry.index < from);
+                    }
+                }
+            }
+        }
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        let mut storage = Storage {
+            dir: dir.to_owned(),
+            log: BufWriter::new(log),
+        };
+        if fresh {
+            storage.write(&[LogRecord::Snapshot {
+                index: 0,
+                term: 0,
+                members: members.clone(),
+            }])?;
+        }
+        Ok((storage, stored))
+    }
+
+    /// Durably records the term and vote, replacing the previous ones.
+    pub(crate) fn save_state(&mut self, term: u64, voted_for: Option<NodeId>) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, &HardState { term, voted_for })?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(STATE_FILE))?;
+        Ok(())
+    }
+
+    pub(crate) fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let records: Vec<_> = entries.iter().cloned().map(LogRecord::Append).collect();
+        self.write(&records)
+    }
+
+    pub(crate) fn truncate(&mut self, from: u64) -> Result<()> {
+        self.write(&[LogRecord::Truncate { from }])
+    }
+
+    /// Rewrites the log as a snapshot up to `index` followed by `entries`.
+    pub(crate) fn compact(
+        &mut self,
+        index: u64,
+        term: u64,
+        members: &Members,
+        entries: &[Entry],
+    ) -> Result<()> {
+        let path = self.dir.join(LOG_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", LOG_FILE));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let snapshot = LogRecord::Snapshot {
+            index,
+            term,
+            members: members.clone(),
+        };
+        serde_json::to_writer(&mut writer, &snapshot)?;
+        for entry in entries {
+            serde_json::to_writer(&mut writer, &LogRecord::Append(entry.clone()))?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, &path)?;
+        self.log = BufWriter::new(OpenOptions::new().append(true).open(&path)?);
+        Ok(())
+    }
+
+    fn write(&mut self, records: &[LogRecord]) -> Result<()> {
+        for record in records {
+            serde_json::to_writer(&mut self.log, record)?;
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Engine the chunks of a snapshot are staged in until the last one arrives:
+/// a `KvStore` next to the log, or memory for a node without a directory.
+pub(crate) enum Staging {
+    Disk(KvStore, PathBuf),
+    Memory(MemoryKvsEngine),
+}
+
+impl Staging {
+    /// Starts an empty staging engine, dropping what a previous one left.
+    pub(crate) fn open(storage: Option<&Storage>) -> Result<Staging> {
+        match storage {
+            Some(storage) => {
+                let dir = storage.dir.join(STAGING_DIR);
+                if dir.exists() {
+                    fs::remove_dir_all(&dir)?;
+                }
+                Ok(Staging::Disk(KvStore::open(&dir)?, dir))
+            }
+            None => Ok(Staging::Memory(MemoryKvsEngine::new())),
+        }
+    }
+
+    pub(crate) fn write(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        match self {
+            Staging::Disk(store, _) => store.set_batch(pairs),
+            Staging::Memory(engine) => engine.set_batch(pairs),
+        }
+    }
+
+    /// Returns a view of the staged pairs, to iterate over in key order.
+    pub(crate) fn snapshot(&mut self) -> Result<Box<dyn KvsSnapshot>> {
+        Ok(match self {
+            Staging::Disk(store, _) => Box::new(store.snapshot()?),
+            Staging::Memory(engine) => Box::new(engine.snapshot()?),
+        })
+    }
+
+    /// Deletes the staged pairs.
+    pub(crate) fn discard(self) -> Result<()> {
+        if let Staging::Disk(store, dir) = self {
+            drop(store);
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+}
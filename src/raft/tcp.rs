@@ -0,0 +1,166 @@
+//! Transport between processes.
+use super::{Mailbox, Message, NodeId, Transport};
+use crate::errors::{MyError, Result};
+use crate::tls::TlsPeerConfig;
+use log::{debug, info, warn};
+use serde_json::Deserializer;
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Longest wait for a connection to another node, or for its TLS handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Carries messages between processes as JSON over TCP.
+///
+/// A connection to each node is opened on first use and reopened after a
+/// failure. Messages sent while a node is unreachable are lost.
+///
+/// With `tls`, nodes authenticate each other with mutual TLS and the messages
+/// of a peer without a certificate of the cluster are never read. Without
+/// it, anyone reaching the Raft address can write to the engine: plain TCP is
+/// only meant for tests.
+pub struct TcpTransport {
+    listener: Mutex<Option<TcpListener>>,
+    local_addr: SocketAddr,
+    tls: Option<Arc<TlsPeerConfig>>,
+    /// Queue of the thread writing to each node, by address.
+    peers: Mutex<HashMap<String, Sender<Message>>>,
+}
+
+impl TcpTransport {
+    /// Listens for the messages of the other nodes on `addr`.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<TcpTransport> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(TcpTransport {
+            local_addr: listener.local_addr()?,
+            listener: Mutex::new(Some(listener)),
+            tls: None,
+            peers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Authenticates the other nodes, and this one to them, with mutual TLS.
+    pub fn tls(mut self, config: TlsPeerConfig) -> Self {
+        self.tls = Some(Arc::new(config));
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Transport for TcpTransport {
+    fn listen(&self, id: NodeId, mailbox: Mailbox) -> Result<()> {
+        let listener = self.listener.lock().unwrap().take().ok_or_else(|| {
+            MyError::StringError("The transport already serves a node".to_owned())
+        })?;
+        info!("Raft node {} listening on {}", id, self.local_addr);
+        let tls = self.tls.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mailbox = mailbox.clone();
+                let tls = tls.clone();
+                thread::spawn(move || match accept(stream, tls.as_deref()) {
+                    Ok(stream) => receive(id, stream, mailbox),
+                    Err(e) => warn!("Rejected a Raft connection: {}", e),
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn send(&self, addr: &str, message: Message) {
+        let mut peers = self.peers.lock().unwrap();
+        let message = match peers.get(addr) {
+            Some(queue) => match queue.send(message) {
+                Ok(()) => return,
+                Err(mpsc::SendError(message)) => message,
+            },
+            None => message,
+        };
+        let (queue, messages) = mpsc::channel();
+        let peer = addr.to_owned();
+        let tls = self.tls.clone();
+        thread::spawn(move || forward(peer, tls, messages));
+        let _ = queue.send(message);
+        peers.insert(addr.to_owned(), queue);
+    }
+}
+
+/// Returns the stream messages are read from, once the peer authenticated.
+fn accept(stream: TcpStream, tls: Option<&TlsPeerConfig>) -> Result<Box<dyn Read + Send>> {
+    match tls {
+        Some(tls) => {
+            stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+            let handshake = stream.try_clone()?;
+            let stream = tls.accept(stream)?;
+            handshake.set_read_timeout(None)?;
+            Ok(Box::new(stream))
+        }
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Delivers the messages read from a connection to node `id`.
+fn receive(id: NodeId, stream: impl Read, mailbox: Mailbox) {
+    let messages = Deserializer::from_reader(BufReader::new(stream)).into_iter::<Message>();
+    for message in messages {
+        match message {
+            Ok(message) if message.to == id => {
+                if !mailbox.deliver(message) {
+                    return;
+                }
+            }
+            Ok(_) => (),
+            Err(e) => {
+                debug!("Raft connection closed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Writes the queued messages to the node at `addr`, reconnecting as needed.
+fn forward(addr: String, tls: Option<Arc<TlsPeerConfig>>, messages: Receiver<Message>) {
+    let mut connection: Option<BufWriter<Box<dyn Write + Send>>> = None;
+    for message in messages {
+        if connection.is_none() {
+            connection = connect(&addr, tls.as_deref()).map(BufWriter::new);
+        }
+        if let Some(writer) = &mut connection {
+            let sent = serde_json::to_writer(&mut *writer, &message)
+                .map_err(MyError::from)
+                .and_then(|()| Ok(writer.flush()?));
+            if let Err(e) = sent {
+                debug!("Lost a message to {}: {}", addr, e);
+                connection = None;
+            }
+        }
+    }
+}
+
+fn connect(addr: &str, tls: Option<&TlsPeerConfig>) -> Option<Box<dyn Write + Send>> {
+    let addr = addr.to_socket_addrs().ok()?.next()?;
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).ok()?;
+    stream.set_nodelay(true).ok()?;
+    match tls {
+        Some(tls) => {
+            // Only the handshake reads from the peer.
+            stream.set_read_timeout(Some(CONNECT_TIMEOUT)).ok()?;
+            match tls.connect(stream) {
+                Ok(stream) => Some(Box::new(stream)),
+                Err(e) => {
+                    warn!("TLS handshake with Raft node {} failed: {}", addr, e);
+                    None
+                }
+            }
+        }
+        None => Some(Box::new(stream)),
+    }
+}
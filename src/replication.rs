@@ -0,0 +1,399 @@
+//! Asynchronous leader–follower replication
+//!
+//! A follower connects to its leader like a client and sends
+//! `Request::Replicate`. The leader answers with a snapshot of its default
+//! namespace, then streams the `Command` of every write it applies, in the
+//! order it applied them. The follower applies the records to its own engine
+//! and refuses the writes of clients until it is promoted. It connects over
+//! TLS when given a `TlsClientConfig`, as a `KvsClient` does.
+//!
+//! The leader acknowledges a write without waiting for its followers, so a
+//! promoted follower may lack the last writes of its former leader.
+use crate::auth::Credentials;
+use crate::common::{AuthResponse, Request};
+use crate::engine::{Command, KvsEngine, KvsSnapshot};
+use crate::errors::{MyError, Result};
+use crate::tls::TlsClientConfig;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Writes a follower may lag behind before its leader drops it, unless set
+/// with `Server::follower_backlog`. The follower then reconnects and resyncs
+/// from a new snapshot.
+pub(crate) const DEFAULT_FOLLOWER_BACKLOG: usize = 10_000;
+/// Delay before a follower reconnects to its leader.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// What a leader streams to a follower.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ReplicationMessage {
+    /// The pairs of the snapshot follow, as `Command::Set`.
+    SnapshotStart,
+    /// The snapshot is complete. The writes applied since follow.
+    SnapshotEnd,
+    Command(Command),
+    /// The server does not accept this follower.
+    Refused(String),
+}
+
+/// Role of a replicating server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Leader,
+    /// Replicates the leader at this address and refuses writes.
+    Follower(SocketAddr),
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Leader => f.write_str("leader"),
+            Role::Follower(leader) => write!(f, "follower of {}", leader),
+        }
+    }
+}
+
+/// Replication state shared by the connections of a `Server`.
+pub(crate) struct Replication {
+    state: Mutex<State>,
+    /// Held by each write from before it is applied until it is queued, and
+    /// exclusively by `subscribe`, so a write is either in the snapshot of a
+    /// new follower or in its queue.
+    writes: RwLock<()>,
+    /// Held by each write while it is applied and queued, so the followers
+    /// receive the writes in the order the leader applied them.
+    applying: Mutex<()>,
+}
+
+struct State {
+    role: Role,
+    /// Writes not sent yet to each follower of this leader.
+    followers: Vec<Arc<Backlog>>,
+    /// Connection to the leader, shut down on promotion.
+    upstream: Option<TcpStream>,
+}
+
+impl Replication {
+    pub(crate) fn new(role: Role) -> Replication {
+        Replication {
+            state: Mutex::new(State {
+                role,
+                followers: Vec::new(),
+                upstream: None,
+            }),
+            writes: RwLock::new(()),
+            applying: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn role(&self) -> Role {
+        self.state.lock().unwrap().role
+    }
+
+    /// Applies a write of a client and queues it for the followers.
+    ///
+    /// Writes are applied one at a time, each queued before the next one is
+    /// applied, so a follower goes through the same states as its leader.
+    pub(crate) fn write<E: KvsEngine>(&self, engine: &mut E, command: Command) -> Result<()> {
+        if let Role::Follower(leader) = self.role() {
+            return Err(read_only(leader));
+        }
+        let _writes = self.writes.read().unwrap();
+        let _applying = self.applying.lock().unwrap();
+        apply(engine, command.clone())?;
+        let mut state = self.state.lock().unwrap();
+        state
+            .followers
+            .retain(|follower| follower.push(command.clone()));
+        Ok(())
+    }
+
+    /// Registers a new follower, dropped once it lags `backlog` writes behind
+    /// after its snapshot. Returns a snapshot of `engine` and the queue of the
+    /// writes applied after it.
+    pub(crate) fn subscribe<E: KvsEngine>(
+        &self,
+        engine: &mut E,
+        backlog: usize,
+    ) -> Result<(E::Snapshot, Subscription)> {
+        let _writes = self.writes.write().unwrap();
+        let mut state = self.state.lock().unwrap();
+        if let Role::Follower(leader) = state.role {
+            return Err(MyError::StringError(format!(
+                "Not a leader, replicate from {}",
+                leader
+            )));
+        }
+        let snapshot = engine.snapshot()?;
+        let queue = Arc::new(Backlog::new(backlog));
+        state.followers.push(Arc::clone(&queue));
+        Ok((snapshot, Subscription(queue)))
+    }
+
+    /// Switches a follower to the leader role: it stops replicating, accepts
+    /// writes and followers.
+    pub(crate) fn promote(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let leader = match state.role {
+            Role::Follower(leader) => leader,
+            Role::Leader => return Err(MyError::StringError("Already a leader".to_owned())),
+        };
+        state.role = Role::Leader;
+        if let Some(upstream) = state.upstream.take() {
+            let _ = upstream.shutdown(Shutdown::Both);
+        }
+        info!("Promoted to leader, stopped replicating from {}", leader);
+        Ok(())
+    }
+
+    /// Replicates the leader into `engine` from a new thread, reconnecting
+    /// after failures, until this server is promoted.
+    pub(crate) fn start_following<E: KvsEngine + Send + 'static>(
+        self: &Arc<Self>,
+        mut engine: E,
+        credentials: Option<Credentials>,
+        tls: Option<Arc<TlsClientConfig>>,
+    ) {
+        let replication = Arc::clone(self);
+        thread::spawn(move || {
+            while let Role::Follower(leader) = replication.role() {
+                let tls = tls.as_deref();
+                match replication.follow(leader, &mut engine, credentials.clone(), tls) {
+                    Ok(()) => info!("Leader {} closed the replication stream", leader),
+                    Err(e) if replication.role() == Role::Follower(leader) => {
+                        warn!("Replication from {} failed: {}", leader, e)
+                    }
+                    Err(_) => break,
+                }
+                if replication.role() == Role::Follower(leader) {
+                    thread::sleep(RETRY_DELAY);
+                }
+            }
+            info!("Replication stopped");
+        });
+    }
+
+    /// Syncs `engine` with the leader, then applies its writes until the
+    /// stream ends.
+    fn follow<E: KvsEngine>(
+        &self,
+        leader: SocketAddr,
+        engine: &mut E,
+        credentials: Option<Credentials>,
+        tls: Option<&TlsClientConfig>,
+    ) -> Result<()> {
+        let stream = TcpStream::connect(leader)?;
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Follower(leader) {
+                return Ok(());
+            }
+            state.upstream = Some(stream.try_clone()?);
+        }
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match tls {
+            Some(config) => {
+                let stream = config.connect(stream)?;
+                (Box::new(stream.clone()), Box::new(stream))
+            }
+            None => (Box::new(stream.try_clone()?), Box::new(stream)),
+        };
+        let mut writer = BufWriter::new(writer);
+        let mut reader = Deserializer::from_reader(BufReader::new(reader));
+        if let Some(credentials) = credentials {
+            send(&mut writer, &Request::Auth { credentials })?;
+            if let AuthResponse::Unauthorized(msg) = AuthResponse::deserialize(&mut reader)? {
+                return Err(MyError::Unauthorized(msg));
+            }
+        }
+        send(&mut writer, &Request::Replicate)?;
+
+        // Local keys the snapshot has not set yet, removed once it is complete
+        let mut stale: Option<HashSet<Vec<u8>>> = None;
+        for message in reader.into_iter::<ReplicationMessage>() {
+            match message? {
+                ReplicationMessage::SnapshotStart => {
+                    info!("Receiving a snapshot from {}", leader);
+                    let mut snapshot = engine.snapshot()?;
+                    let keys = snapshot
+                        .iter()
+                        .map(|entry| entry.map(|(key, _)| key))
+                        .collect::<Result<_>>()?;
+                    stale = Some(keys);
+                }
+                ReplicationMessage::Command(command) => {
+                    if let (Some(stale), Command::Set { key, .. }) = (&mut stale, &command) {
+                        stale.remove(key);
+                    }
+                    self.apply_replicated(engine, command)?;
+                }
+                ReplicationMessage::SnapshotEnd => {
+                    for key in stale.take().unwrap_or_default() {
+                        self.apply_replicated(engine, Command::remove(key))?;
+                    }
+                    info!("In sync with {}", leader);
+                }
+                ReplicationMessage::Refused(msg) => return Err(MyError::StringError(msg)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a record of the leader, unless this server was promoted.
+    fn apply_replicated<E: KvsEngine>(&self, engine: &mut E, command: Command) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        if state.role == Role::Leader {
+            return Err(MyError::StringError("Promoted to leader".to_owned()));
+        }
+        match apply(engine, command) {
+            // The key was removed before this follower synced
+            Err(MyError::KeyNotFound) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Writes of a leader not sent yet to one of its followers.
+///
+/// The writes applied while the follower receives its snapshot are all kept,
+/// as it cannot catch up before the end of the snapshot, however long it
+/// takes. Only those queued later count against the limit.
+struct Backlog {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+    limit: usize,
+}
+
+struct Queue {
+    commands: VecDeque<Command>,
+    /// Leading `commands` queued during the snapshot.
+    during_snapshot: usize,
+    snapshot_sent: bool,
+    /// Set when the leader drops the follower or the follower disconnects.
+    closed: bool,
+}
+
+impl Backlog {
+    fn new(limit: usize) -> Backlog {
+        Backlog {
+            queue: Mutex::new(Queue {
+                commands: VecDeque::new(),
+                during_snapshot: 0,
+                snapshot_sent: false,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            limit,
+        }
+    }
+
+    /// Queues a write. Returns `false` once the follower is gone or has been
+    /// dropped for lagging behind.
+    fn push(&self, command: Command) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return false;
+        }
+        if !queue.snapshot_sent {
+            queue.during_snapshot += 1;
+        } else if queue.commands.len() - queue.during_snapshot >= self.limit {
+            warn!("Dropping a follower {} writes behind", self.limit);
+            queue.closed = true;
+            self.changed.notify_all();
+            return false;
+        }
+        queue.commands.push_back(command);
+        self.changed.notify_all();
+        true
+    }
+}
+
+/// The writes queued for a follower, closed when dropped.
+pub(crate) struct Subscription(Arc<Backlog>);
+
+impl Subscription {
+    /// Counts the writes queued from now on against the limit.
+    fn snapshot_sent(&self) {
+        self.0.queue.lock().unwrap().snapshot_sent = true;
+    }
+
+    /// Waits for the next write. Returns `None` once the follower is dropped.
+    fn next(&self) -> Option<Command> {
+        let mut queue = self.0.queue.lock().unwrap();
+        loop {
+            if queue.closed {
+                return None;
+            }
+            if let Some(command) = queue.commands.pop_front() {
+                queue.during_snapshot = queue.during_snapshot.saturating_sub(1);
+                return Some(command);
+            }
+            queue = self.0.changed.wait(queue).unwrap();
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.0.queue.lock().unwrap().closed = true;
+    }
+}
+
+/// Streams a snapshot, then the writes applied after it, to a follower until
+/// either side disconnects.
+pub(crate) fn stream<S: KvsSnapshot>(
+    mut snapshot: S,
+    writes: Subscription,
+    writer: &mut impl Write,
+) -> Result<()> {
+    send(writer, &ReplicationMessage::SnapshotStart)?;
+    for entry in snapshot.iter() {
+        let (key, value) = entry?;
+        serde_json::to_writer(
+            &mut *writer,
+            &ReplicationMessage::Command(Command::set(key, value)),
+        )?;
+    }
+    send(writer, &ReplicationMessage::SnapshotEnd)?;
+    writes.snapshot_sent();
+    // Lets the engine reclaim what the snapshot pinned, such as a `KvStore` log
+    drop(snapshot);
+    while let Some(command) = writes.next() {
+        send(writer, &ReplicationMessage::Command(command))?;
+    }
+    Ok(())
+}
+
+/// Error of a write sent to a follower.
+pub(crate) fn read_only(leader: SocketAddr) -> MyError {
+    MyError::StringError(format!("Read-only follower of {}", leader))
+}
+
+/// Applies a plain `Set` or `Remove` to `engine`.
+pub(crate) fn apply<E: KvsEngine>(engine: &mut E, command: Command) -> Result<()> {
+    match command {
+        Command::Set {
+            key,
+            value,
+            codec: None,
+        } => engine.set_bytes(key, value),
+        Command::Remove { key } => engine.remove_bytes(&key),
+        other => Err(MyError::StringError(format!(
+            "Unexpected replication record {:?}",
+            other
+        ))),
+    }
+}
+
+fn send(writer: &mut impl Write, message: &impl Serialize) -> Result<()> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.flush()?;
+    Ok(())
+}
@@ -1,29 +1,236 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::engine::KvsEngine;
-use crate::errors::Result;
+use crate::auth::{Acl, Credentials, Right, User};
+use crate::backup::BackupManifest;
+use crate::common::{
+    AdminResponse, AuthResponse, BackupResponse, ConfigResponse, DbSizeResponse, GetResponse,
+    InfoResponse, RemoveResponse, Request, ServerInfo, SetResponse,
+};
+use crate::engine::{
+    unknown_parameter, Command, EngineStats, KvsEngine, KvsSnapshot, DEFAULT_NAMESPACE,
+};
+use crate::errors::{MyError, Result};
+use crate::metrics::{self, Metrics, Op};
+use crate::raft::RaftNode;
+use crate::replication::{self, Replication, ReplicationMessage, Role, Subscription};
+use crate::tls::{SharedStream, TlsClientConfig, TlsServerConfig};
 
-use log::{error, info};
+use log::{error, info, warn, LevelFilter};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde::Serialize;
 use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+/// Parameter of `CONFIG GET` and `CONFIG SET` capping the level of the server log.
+const LOG_LEVEL: &str = "log-level";
+
+#[derive(Clone)]
 pub struct Server<E: KvsEngine> {
     engine: E,
+    started: Instant,
+    tls: Option<Arc<ServerConfig>>,
+    acl: Option<Arc<Acl>>,
+    metrics: Option<Arc<Metrics>>,
+    limits: Limits,
+    /// Connections accepted and not closed yet.
+    connections: Arc<AtomicUsize>,
+    replication: Option<Arc<Replication>>,
+    /// Credentials a follower logs in to its leader with.
+    leader_credentials: Option<Credentials>,
+    /// How a follower verifies its leader, over TLS.
+    leader_tls: Option<Arc<TlsClientConfig>>,
+    /// Writes a follower of this leader may lag behind.
+    follower_backlog: usize,
+    raft: Option<RaftNode<E>>,
+}
+
+/// Threads serving the connections of a `Server`, unless set otherwise.
+pub(crate) const DEFAULT_THREADS: usize = 64;
+
+/// Longest wait for the next request of a connection, unless set otherwise.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits on the connections and requests of a `Server`, unbounded if `None`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Limits {
+    threads: Option<usize>,
+    max_connections: Option<usize>,
+    idle_timeout: Option<Duration>,
+    max_key_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
 }
 
-impl<E: KvsEngine> Server<E> {
+impl<E: KvsEngine + Clone + Send + 'static> Server<E> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E) -> Self {
-        Server { engine }
+        Server {
+            engine,
+            started: Instant::now(),
+            tls: None,
+            acl: None,
+            metrics: None,
+            limits: Limits::default(),
+            connections: Arc::new(AtomicUsize::new(0)),
+            replication: None,
+            leader_credentials: None,
+            leader_tls: None,
+            follower_backlog: replication::DEFAULT_FOLLOWER_BACKLOG,
+            raft: None,
+        }
+    }
+
+    /// Serves every connection over TLS.
+    pub fn tls(mut self, config: TlsServerConfig) -> Result<Self> {
+        self.tls = Some(config.build()?);
+        Ok(self)
+    }
+
+    /// Requires every connection to authenticate, and checks each request
+    /// against the rights of its user.
+    pub fn auth(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Serves the connections with a pool of `threads` threads rather than
+    /// `DEFAULT_THREADS`. Connections wait for a free thread, and give it back
+    /// once idle for the `idle_timeout`. Followers are streamed to from
+    /// threads of their own.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.limits.threads = Some(threads);
+        self
+    }
+
+    /// Closes the connections accepted while `max` are open.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.limits.max_connections = Some(max);
+        self
+    }
+
+    /// Closes the connections which send no request for `timeout`, rather
+    /// than 60 seconds.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Rejects the requests with a key longer than `max` bytes.
+    pub fn max_key_bytes(mut self, max: usize) -> Self {
+        self.limits.max_key_bytes = Some(max);
+        self
+    }
+
+    /// Rejects the requests setting a value longer than `max` bytes.
+    pub fn max_value_bytes(mut self, max: usize) -> Self {
+        self.limits.max_value_bytes = Some(max);
+        self
+    }
+
+    /// Accepts followers, which replicate the default namespace. Writes to
+    /// other namespaces are then refused.
+    pub fn leader(mut self) -> Self {
+        self.replication = Some(Arc::new(Replication::new(Role::Leader)));
+        self
+    }
+
+    /// Drops the followers of this leader lagging `writes` behind, once they
+    /// received their snapshot. They then resync from a new snapshot. Defaults
+    /// to 10000.
+    pub fn follower_backlog(mut self, writes: usize) -> Self {
+        self.follower_backlog = writes;
+        self
     }
 
-    pub fn open<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
-        // accept connections and process them serially
-        let listener = TcpListener::bind(addr)?;
+    /// Replicates the default namespace of the leader at `leader` once the
+    /// server is serving, and refuses writes until the server is promoted.
+    ///
+    /// The follower connects over plain TCP, unless `leader_tls` is set.
+    pub fn follow(mut self, leader: SocketAddr) -> Self {
+        self.replication = Some(Arc::new(Replication::new(Role::Follower(leader))));
+        self
+    }
+
+    /// Logs in to a leader which checks access rights. The user needs the
+    /// admin right.
+    pub fn leader_credentials(mut self, credentials: Credentials) -> Self {
+        self.leader_credentials = Some(credentials);
+        self
+    }
+
+    /// Connects to the leader over TLS, verifying it with `config`.
+    pub fn leader_tls(mut self, config: TlsClientConfig) -> Self {
+        self.leader_tls = Some(Arc::new(config));
+        self
+    }
+
+    /// Serves the default namespace through a node of a Raft cluster running
+    /// on the engine of the server: writes go through the log of the cluster
+    /// and reads are linearizable. Only the leader of the cluster serves them.
+    ///
+    /// It replaces leader-follower replication.
+    pub fn raft(mut self, node: RaftNode<E>) -> Self {
+        self.replication = None;
+        self.raft = Some(node);
+        self
+    }
+
+    /// Exposes the metrics of the server and the stats of its engine, in the
+    /// Prometheus text format, on `GET /metrics` at `addr`.
+    pub fn metrics<A: ToSocketAddrs>(self, addr: A) -> Result<Self> {
+        self.serve_metrics(TcpListener::bind(addr)?)
+    }
+
+    /// Serves the metrics on an already bound listener.
+    pub fn serve_metrics(mut self, listener: TcpListener) -> Result<Self> {
+        info!("Metrics on http://{}/metrics", listener.local_addr()?);
+        let collected = Arc::new(Metrics::default());
+        let engine = self.engine.clone();
+        {
+            let collected = Arc::clone(&collected);
+            thread::spawn(move || metrics::serve(listener, &collected, engine));
+        }
+        self.metrics = Some(collected);
+        Ok(self)
+    }
+
+    /// Accept connections on `addr`, served by a pool of threads which each
+    /// hold a clone of the engine.
+    pub fn open<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Accept connections on a listener which is already bound.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        if let Some(replication) = &self.replication {
+            if let Role::Follower(_) = replication.role() {
+                replication.start_following(
+                    self.engine.clone(),
+                    self.leader_credentials.clone(),
+                    self.leader_tls.clone(),
+                );
+            }
+        }
+        let pool = self.spawn_workers(self.limits.threads.unwrap_or(DEFAULT_THREADS));
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    self.handle_connections(stream)?;
+                    if !self.admit() {
+                        warn!(
+                            "Closing connection from {:?}: {} connections open",
+                            stream.peer_addr(),
+                            self.connections.load(Ordering::SeqCst)
+                        );
+                        continue;
+                    }
+                    pool.send(stream)
+                        .map_err(|_| MyError::StringError("Worker threads exited".to_owned()))?;
                 }
                 Err(e) => error!("Connection failed {}", e),
             }
@@ -31,6 +238,55 @@ impl<E: KvsEngine> Server<E> {
         Ok(())
     }
 
+    /// Starts the threads of the pool, which serve the connections sent to
+    /// the returned channel one at a time.
+    fn spawn_workers(&self, threads: usize) -> mpsc::Sender<TcpStream> {
+        let (sender, receiver) = mpsc::channel::<TcpStream>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let mut server = self.clone();
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || loop {
+                let stream = match receiver.lock().unwrap().recv() {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                server.run_connection(stream);
+            });
+        }
+        sender
+    }
+
+    /// Counts a new connection, unless `max_connections` are already open.
+    fn admit(&self) -> bool {
+        let open = self.connections.fetch_add(1, Ordering::SeqCst);
+        match self.limits.max_connections {
+            Some(max) if open >= max => {
+                self.connections.fetch_sub(1, Ordering::SeqCst);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Serves a connection admitted by `admit` until it is closed.
+    fn run_connection(&mut self, stream: TcpStream) {
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_opened();
+        }
+        let result = self.handle_connections(stream);
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_closed();
+            if let Err(e) = &result {
+                metrics.error(e);
+            }
+        }
+        if let Err(e) = result {
+            error!("Error on serving client: {}", e);
+        }
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
     pub fn handle_connections(&mut self, stream: TcpStream) -> Result<()> {
         let peer_addr = stream.peer_addr()?;
         info!(
@@ -38,47 +294,545 @@ impl<E: KvsEngine> Server<E> {
             stream.peer_addr()?,
             stream.local_addr()?
         );
+        stream.set_read_timeout(Some(
+            self.limits.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+        ))?;
 
-        let reader = BufReader::new(&stream);
-        let mut bufwriter = BufWriter::new(&stream);
+        match &self.tls {
+            Some(config) => {
+                let connection = ServerConnection::new(Arc::clone(config))?;
+                let stream = SharedStream::new(StreamOwned::new(connection, stream));
+                self.serve_connection(stream.clone(), stream, peer_addr)
+            }
+            None => self.serve_connection(stream.try_clone()?, stream, peer_addr),
+        }
+    }
+
+    /// Answers the requests read from `reader` until the client disconnects.
+    fn serve_connection(
+        &mut self,
+        reader: impl Read,
+        writer: impl Write + Send + 'static,
+        peer_addr: SocketAddr,
+    ) -> Result<()> {
+        let reader = BufReader::new(reader);
+        let mut bufwriter = BufWriter::new(writer);
         let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
 
-        //let mut kvs = KvStore::open(current_dir()?)?;
+        // User the connection authenticated as, if the server checks rights
+        let mut user = None;
 
         for req in req_reader {
             info!("Receive request from {}: {:?}", peer_addr, req);
+            let started = Instant::now();
+            // Sent once the request is counted, so clients see it in the metrics
+            let mut reply = Vec::new();
 
-            match req? {
-                Request::Get { key } => {
-                    let response = match self.engine.get(key) {
-                        Ok(value) => GetResponse::Ok(value),
+            let req = match req {
+                Ok(req) => req,
+                Err(e) if is_timeout(e.io_error_kind()) => {
+                    info!("Closing idle connection from {}", peer_addr);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let (op, result) = match req {
+                Request::Get { key, namespace } => {
+                    let result = self
+                        .authorize(&user, &namespace, &key, Right::Read)
+                        .and_then(|()| self.check_limits(&key, None))
+                        .and_then(|()| self.read(namespace, &key));
+                    let response = match &result {
+                        Ok(value) => GetResponse::Ok(value.clone()),
+                        Err(MyError::Unauthorized(msg)) => GetResponse::Unauthorized(msg.clone()),
                         Err(err) => GetResponse::Err(err.to_string()),
                     };
-                    serde_json::to_writer(&mut bufwriter, &response)?;
-                    bufwriter.flush()?;
-                    info!("Response sent to {:?}: {:?}", peer_addr, response);
+                    respond(&mut reply, &response, peer_addr)?;
+                    (Op::Get, result.map(|_| ()))
                 }
-                Request::Set { key, value } => {
-                    let response = match self.engine.set(key, value) {
-                        Ok(_value) => SetResponse::Ok(()),
+                Request::Set {
+                    key,
+                    value,
+                    namespace,
+                } => {
+                    let result = self
+                        .authorize(&user, &namespace, &key, Right::Write)
+                        .and_then(|()| self.check_limits(&key, Some(&value)))
+                        .and_then(|()| self.write(namespace, Command::set(key, value)));
+                    let response = match &result {
+                        Ok(()) => SetResponse::Ok(()),
+                        Err(MyError::Unauthorized(msg)) => SetResponse::Unauthorized(msg.clone()),
                         Err(err) => SetResponse::Err(err.to_string()),
                     };
-                    serde_json::to_writer(&mut bufwriter, &response)?;
-                    bufwriter.flush()?;
-                    info!("Response sent to {:?}: {:?}", peer_addr, response);
+                    respond(&mut reply, &response, peer_addr)?;
+                    (Op::Set, result)
                 }
-                Request::Remove { key } => {
-                    let response = match self.engine.remove(key) {
-                        Ok(_value) => RemoveResponse::Ok(()),
+                Request::Remove { key, namespace } => {
+                    let result = self
+                        .authorize(&user, &namespace, &key, Right::Write)
+                        .and_then(|()| self.check_limits(&key, None))
+                        .and_then(|()| self.write(namespace, Command::remove(key)));
+                    let response = match &result {
+                        Ok(()) => RemoveResponse::Ok(()),
+                        Err(MyError::Unauthorized(msg)) => {
+                            RemoveResponse::Unauthorized(msg.clone())
+                        }
                         Err(err) => RemoveResponse::Err(err.to_string()),
                     };
-                    serde_json::to_writer(&mut bufwriter, &response)?;
-                    bufwriter.flush()?;
-                    info!("Response sent to {:?}: {:?}", peer_addr, response);
+                    respond(&mut reply, &response, peer_addr)?;
+                    (Op::Remove, result)
+                }
+                Request::Backup { dest, namespace } => {
+                    let result = self
+                        .authorize_backup(&user, &namespace)
+                        .and_then(|()| self.backup(namespace, Path::new(&dest)));
+                    let response = match &result {
+                        Ok(manifest) => BackupResponse::Ok(manifest.clone()),
+                        Err(MyError::Unauthorized(msg)) => {
+                            BackupResponse::Unauthorized(msg.clone())
+                        }
+                        Err(err) => BackupResponse::Err(err.to_string()),
+                    };
+                    respond(&mut reply, &response, peer_addr)?;
+                    (Op::Backup, result.map(|_| ()))
+                }
+                Request::Auth { credentials } => {
+                    let result = match &self.acl {
+                        None => Ok(()),
+                        Some(acl) => match acl.authenticate(&credentials, peer_addr.ip()) {
+                            Ok(found) => {
+                                info!("{} authenticated as {}", peer_addr, found.name());
+                                user = Some(found);
+                                Ok(())
+                            }
+                            Err(e) => {
+                                warn!("Authentication failed from {}: {}", peer_addr, e);
+                                user = None;
+                                Err(e)
+                            }
+                        },
+                    };
+                    let response = match &result {
+                        Ok(()) => AuthResponse::Ok(()),
+                        Err(err) => AuthResponse::Unauthorized(err.to_string()),
+                    };
+                    respond(&mut reply, &response, peer_addr)?;
+                    (Op::Auth, result)
+                }
+                Request::Info { namespace } => {
+                    let result = self
+                        .authorize(&user, &namespace, b"", Right::Admin)
+                        .and_then(|()| self.info(namespace));
+                    let response = match &result {
+                        Ok(info) => InfoResponse::Ok(info.clone()),
+                        Err(MyError::Unauthorized(msg)) => InfoResponse::Unauthorized(msg.clone()),
+                        Err(err) => InfoResponse::Err(err.to_string()),
+                    };
+                    respond(&mut reply, &response, peer_addr)?;
+                    (Op::Admin, result.map(|_| ()))
+                }
+                Request::Compact { namespace } => {
+                    let result = self
+                        .authorize(&user, &namespace, b"", Right::Admin)
+                        .and_then(|()| match self.select_existing(namespace)? {
+                            Some(mut engine) => engine.compact(),
+                            None => Ok(()),
+                        });
+                    respond(&mut reply, &admin_response(&result), peer_addr)?;
+                    (Op::Admin, result)
+                }
+                Request::Flush { namespace } => {
+                    let result = self
+                        .authorize(&user, &namespace, b"", Right::Admin)
+                        .and_then(|()| match self.select_existing(namespace)? {
+                            Some(mut engine) => engine.sync(),
+                            None => Ok(()),
+                        });
+                    respond(&mut reply, &admin_response(&result), peer_addr)?;
+                    (Op::Admin, result)
+                }
+                Request::DbSize { namespace } => {
+                    let result = self
+                        .authorize(&user, &namespace, b"", Right::Admin)
+                        .and_then(|()| match self.select_existing(namespace)? {
+                            Some(mut engine) => count_keys(&mut engine),
+                            None => Ok(0),
+                        });
+                    let response = match &result {
+                        Ok(keys) => DbSizeResponse::Ok(*keys),
+                        Err(MyError::Unauthorized(msg)) => {
+                            DbSizeResponse::Unauthorized(msg.clone())
+                        }
+                        Err(err) => DbSizeResponse::Err(err.to_string()),
+                    };
+                    respond(&mut reply, &response, peer_addr)?;
+                    (Op::Admin, result.map(|_| ()))
+                }
+                Request::ConfigGet { name } => {
+                    let result = self
+                        .authorize(&user, &None, b"", Right::Admin)
+                        .and_then(|()| self.config_get(name));
+                    let response = match &result {
+                        Ok(config) => ConfigResponse::Ok(config.clone()),
+                        Err(MyError::Unauthorized(msg)) => {
+                            ConfigResponse::Unauthorized(msg.clone())
+                        }
+                        Err(err) => ConfigResponse::Err(err.to_string()),
+                    };
+                    respond(&mut reply, &response, peer_addr)?;
+                    (Op::Admin, result.map(|_| ()))
+                }
+                Request::ConfigSet { name, value } => {
+                    let result = self
+                        .authorize(&user, &None, b"", Right::Admin)
+                        .and_then(|()| self.config_set(&name, &value));
+                    if result.is_ok() {
+                        info!("{} set {} to {}", peer_addr, name, value);
+                    }
+                    respond(&mut reply, &admin_response(&result), peer_addr)?;
+                    (Op::Admin, result)
+                }
+                Request::AddNode { id, addr } => {
+                    let result = self
+                        .authorize(&user, &None, b"", Right::Admin)
+                        .and_then(|()| self.raft_node()?.add_node(id, addr));
+                    respond(&mut reply, &admin_response(&result), peer_addr)?;
+                    (Op::Admin, result)
+                }
+                Request::RemoveNode { id } => {
+                    let result = self
+                        .authorize(&user, &None, b"", Right::Admin)
+                        .and_then(|()| self.raft_node()?.remove_node(id));
+                    respond(&mut reply, &admin_response(&result), peer_addr)?;
+                    (Op::Admin, result)
+                }
+                Request::Promote => {
+                    let result = self
+                        .authorize(&user, &None, b"", Right::Admin)
+                        .and_then(|()| self.promote());
+                    respond(&mut reply, &admin_response(&result), peer_addr)?;
+                    (Op::Admin, result)
+                }
+                Request::Replicate => {
+                    let result = self
+                        .authorize(&user, &None, b"", Right::Admin)
+                        .and_then(|()| self.subscribe());
+                    let (snapshot, writes) = match result {
+                        Ok(subscription) => subscription,
+                        Err(err) => {
+                            if let Some(metrics) = &self.metrics {
+                                metrics.request(Op::Admin, started.elapsed(), Some(&err));
+                            }
+                            let response = ReplicationMessage::Refused(err.to_string());
+                            respond(&mut bufwriter, &response, peer_addr)?;
+                            continue;
+                        }
+                    };
+                    if let Some(metrics) = &self.metrics {
+                        metrics.request(Op::Admin, started.elapsed(), None);
+                    }
+                    info!("{} follows", peer_addr);
+                    // The connection streams to the follower from now on, from
+                    // a thread of its own rather than a worker of the pool
+                    let connections = Arc::clone(&self.connections);
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    thread::spawn(move || {
+                        if let Err(e) = replication::stream(snapshot, writes, &mut bufwriter) {
+                            info!("{} stopped following: {}", peer_addr, e);
+                        }
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                    return Ok(());
                 }
             };
+            if let Some(metrics) = &self.metrics {
+                metrics.request(op, started.elapsed(), result.as_ref().err());
+            }
+            bufwriter.write_all(&reply)?;
+            bufwriter.flush()?;
         }
 
         Ok(())
     }
+
+    /// Returns the engine of a request, on its namespace if it names one,
+    /// which is created if missing. For writes only.
+    fn select(&mut self, namespace: Option<String>) -> Result<E> {
+        match namespace {
+            Some(name) if name != DEFAULT_NAMESPACE => self.engine.namespace(&name),
+            _ => Ok(self.engine.clone()),
+        }
+    }
+
+    /// Returns the engine of a request, on its namespace if it names one, or
+    /// `None` if that namespace does not exist.
+    fn select_existing(&mut self, namespace: Option<String>) -> Result<Option<E>> {
+        match namespace {
+            Some(name) if name != DEFAULT_NAMESPACE => self.engine.existing_namespace(&name),
+            _ => Ok(Some(self.engine.clone())),
+        }
+    }
+
+    /// Reads a key, through the Raft cluster if the server is a node of one.
+    fn read(&mut self, namespace: Option<String>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match &self.raft {
+            Some(node) if is_default(&namespace) => node.get_bytes(key),
+            _ => match self.select_existing(namespace)? {
+                Some(mut engine) => engine.get_bytes(key),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Backs up a namespace, which must exist.
+    fn backup(&mut self, namespace: Option<String>, dest: &Path) -> Result<BackupManifest> {
+        let name = namespace
+            .clone()
+            .unwrap_or_else(|| DEFAULT_NAMESPACE.to_owned());
+        match self.select_existing(namespace)? {
+            Some(mut engine) => engine.backup(dest),
+            None => Err(MyError::StringError(format!(
+                "Namespace {} does not exist",
+                name
+            ))),
+        }
+    }
+
+    /// Applies a write, through the Raft cluster or replication if the server
+    /// is part of one.
+    fn write(&mut self, namespace: Option<String>, command: Command) -> Result<()> {
+        if let Some(node) = &self.raft {
+            if !is_default(&namespace) {
+                return Err(not_replicated());
+            }
+            return node.write(command);
+        }
+        let replication = match &self.replication {
+            None => {
+                // Removing from a missing namespace would only create it
+                let engine = match command {
+                    Command::Remove { .. } => self.select_existing(namespace)?,
+                    _ => Some(self.select(namespace)?),
+                };
+                return match engine {
+                    Some(mut engine) => replication::apply(&mut engine, command),
+                    None => Err(MyError::KeyNotFound),
+                };
+            }
+            Some(replication) => replication,
+        };
+        if let Role::Follower(leader) = replication.role() {
+            return Err(replication::read_only(leader));
+        }
+        if !is_default(&namespace) {
+            return Err(not_replicated());
+        }
+        replication.write(&mut self.engine, command)
+    }
+
+    /// Registers a follower of this leader.
+    fn subscribe(&mut self) -> Result<(E::Snapshot, Subscription)> {
+        match &self.replication {
+            Some(replication) => replication.subscribe(&mut self.engine, self.follower_backlog),
+            None => Err(not_replicating()),
+        }
+    }
+
+    /// Makes this follower the leader.
+    fn promote(&self) -> Result<()> {
+        match &self.replication {
+            Some(replication) => replication.promote(),
+            None => Err(not_replicating()),
+        }
+    }
+
+    /// Returns the Raft node of the server, to change the members of its cluster.
+    fn raft_node(&self) -> Result<&RaftNode<E>> {
+        self.raft
+            .as_ref()
+            .ok_or_else(|| MyError::StringError("Raft is not enabled on this server".to_owned()))
+    }
+
+    /// Describes the server and a namespace of its engine.
+    fn info(&mut self, namespace: Option<String>) -> Result<ServerInfo> {
+        let (stats, keys) = match self.select_existing(namespace)? {
+            Some(mut engine) => (engine.stats()?, count_keys(&mut engine)?),
+            None => (EngineStats::default(), 0),
+        };
+        Ok(ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            engine: self.engine.engine_name().to_owned(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            keys,
+            disk_bytes: stats.log_bytes,
+            stale_bytes: stats.uncompacted_bytes,
+            role: match (&self.raft, &self.replication) {
+                (Some(node), _) => Some(node.status().to_string()),
+                (None, Some(replication)) => Some(replication.role().to_string()),
+                (None, None) => None,
+            },
+        })
+    }
+
+    /// Returns the parameters of the server and its engine, or only `name`.
+    fn config_get(&self, name: Option<String>) -> Result<BTreeMap<String, String>> {
+        let mut config = self.engine.config();
+        config.insert(
+            LOG_LEVEL.to_owned(),
+            log::max_level().to_string().to_lowercase(),
+        );
+        match name {
+            None => Ok(config),
+            Some(name) => match config.remove_entry(&name) {
+                Some((name, value)) => Ok(Some((name, value)).into_iter().collect()),
+                None => Err(unknown_parameter(&name)),
+            },
+        }
+    }
+
+    /// Changes a parameter of the server or its engine.
+    fn config_set(&mut self, name: &str, value: &str) -> Result<()> {
+        if name != LOG_LEVEL {
+            return self.engine.set_config(name, value);
+        }
+        let level = value.parse::<LevelFilter>().map_err(|_| {
+            MyError::StringError(format!(
+                "Invalid {}: {}, expected off, error, warn, info, debug or trace",
+                LOG_LEVEL, value
+            ))
+        })?;
+        // The logger filters on its own too: above its filter, records are dropped
+        let enabled = level.to_level().is_none_or(|level| {
+            log::logger().enabled(
+                &log::Metadata::builder()
+                    .level(level)
+                    .target(module_path!())
+                    .build(),
+            )
+        });
+        if !enabled {
+            return Err(MyError::StringError(format!(
+                "Invalid {}: {} is above the filter the log was started with",
+                LOG_LEVEL, value
+            )));
+        }
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    /// Checks the sizes of a key and of the value set, if any.
+    fn check_limits(&self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let checks = [
+            ("Key", Some(key), self.limits.max_key_bytes),
+            ("Value", value, self.limits.max_value_bytes),
+        ];
+        for (what, bytes, max) in checks.iter() {
+            if let (Some(bytes), Some(max)) = (bytes, max) {
+                if bytes.len() > *max {
+                    return Err(MyError::StringError(format!(
+                        "{} of {} bytes exceeds the limit of {} bytes",
+                        what,
+                        bytes.len(),
+                        max
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `user` may access `key` of `namespace` with `right`, when
+    /// the server has an ACL. `None` is the default namespace.
+    /// Needs admin on the empty prefix of the namespace, the only one the
+    /// empty key starts with, and on that of every namespace for a backup of
+    /// the default one, which holds them all.
+    fn authorize_backup(&self, user: &Option<Arc<User>>, namespace: &Option<String>) -> Result<()> {
+        self.authorize(user, namespace, b"", Right::Admin)?;
+        if self.acl.is_some() && is_default(namespace) {
+            for name in self.engine.namespaces()? {
+                self.authorize(user, &Some(name), b"", Right::Admin)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn authorize(
+        &self,
+        user: &Option<Arc<User>>,
+        namespace: &Option<String>,
+        key: &[u8],
+        right: Right,
+    ) -> Result<()> {
+        if self.acl.is_none() {
+            return Ok(());
+        }
+        let namespace = namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+        match user {
+            None => Err(MyError::Unauthorized("Authentication required".to_owned())),
+            Some(user) if user.allows(namespace, key, right) => Ok(()),
+            Some(user) => Err(MyError::Unauthorized(format!(
+                "{} has no {} right on {}:{}",
+                user.name(),
+                right,
+                namespace,
+                String::from_utf8_lossy(key)
+            ))),
+        }
+    }
+}
+
+/// Counts the keys of an engine, from its stats or else from a snapshot.
+fn count_keys<E: KvsEngine>(engine: &mut E) -> Result<u64> {
+    if let Some(keys) = engine.stats()?.keys {
+        return Ok(keys);
+    }
+    let mut snapshot = engine.snapshot()?;
+    let mut keys = 0;
+    for entry in snapshot.iter() {
+        entry?;
+        keys += 1;
+    }
+    Ok(keys)
+}
+
+fn is_default(namespace: &Option<String>) -> bool {
+    namespace
+        .as_ref()
+        .is_none_or(|name| name == DEFAULT_NAMESPACE)
+}
+
+fn not_replicated() -> MyError {
+    MyError::StringError("Namespaces are not replicated, write to the default one".to_owned())
+}
+
+fn not_replicating() -> MyError {
+    MyError::StringError("Replication is not enabled on this server".to_owned())
+}
+
+/// Whether a read failed because the connection was idle for too long.
+fn is_timeout(kind: Option<io::ErrorKind>) -> bool {
+    matches!(
+        kind,
+        Some(io::ErrorKind::WouldBlock) | Some(io::ErrorKind::TimedOut)
+    )
+}
+
+fn admin_response(result: &Result<()>) -> AdminResponse {
+    match result {
+        Ok(()) => AdminResponse::Ok(()),
+        Err(MyError::Unauthorized(msg)) => AdminResponse::Unauthorized(msg.clone()),
+        Err(err) => AdminResponse::Err(err.to_string()),
+    }
+}
+
+/// Writes the response to a request.
+fn respond(
+    writer: &mut impl Write,
+    response: &(impl Serialize + Debug),
+    peer_addr: SocketAddr,
+) -> Result<()> {
+    serde_json::to_writer(&mut *writer, response)?;
+    writer.flush()?;
+    info!("Response sent to {:?}: {:?}", peer_addr, response);
+    Ok(())
 }
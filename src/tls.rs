@@ -0,0 +1,216 @@
+//! TLS for the connections between `KvsClient` and `Server`
+//!
+//! Certificates and keys are read from PEM files. The server may require
+//! clients to present a certificate signed by a given CA (mutual TLS). The
+//! nodes of a Raft cluster always authenticate each other this way.
+use crate::errors::{MyError, Result};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Certificate and key of a `Server`, and the CA of its clients with mutual TLS.
+pub struct TlsServerConfig {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<RootCertStore>,
+}
+
+impl TlsServerConfig {
+    /// Reads the certificate chain and the private key of the server.
+    pub fn from_pem_files(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        Ok(TlsServerConfig {
+            certs: read_certs(cert.as_ref())?,
+            key: read_key(key.as_ref())?,
+            client_roots: None,
+        })
+    }
+
+    /// Requires every client to present a certificate signed by a CA of the
+    /// PEM file `ca`.
+    pub fn client_ca(mut self, ca: impl AsRef<Path>) -> Result<Self> {
+        self.client_roots = Some(read_roots(ca.as_ref())?);
+        Ok(self)
+    }
+
+    pub(crate) fn build(self) -> Result<Arc<ServerConfig>> {
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?;
+        let builder = match self.client_roots {
+            Some(roots) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .map_err(|e| MyError::Tls(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(builder.with_single_cert(self.certs, self.key)?))
+    }
+}
+
+/// CA a `KvsClient` verifies the server with, and its own certificate with
+/// mutual TLS.
+pub struct TlsClientConfig {
+    roots: RootCertStore,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    server_name: Option<String>,
+}
+
+impl TlsClientConfig {
+    /// Trusts the CA certificates of the PEM file `ca`.
+    pub fn from_ca_pem_file(ca: impl AsRef<Path>) -> Result<Self> {
+        Ok(TlsClientConfig {
+            roots: read_roots(ca.as_ref())?,
+            identity: None,
+            server_name: None,
+        })
+    }
+
+    /// Presents this certificate chain and key to servers requiring one.
+    pub fn client_cert(mut self, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        self.identity = Some((read_certs(cert.as_ref())?, read_key(key.as_ref())?));
+        Ok(self)
+    }
+
+    /// Sets the name the server certificate must be valid for. Defaults to the
+    /// IP address connected to.
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    /// Runs the handshake with the server on `stream`.
+    pub(crate) fn connect(&self, mut stream: TcpStream) -> Result<SharedStream> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(self.roots.clone());
+        let config = match &self.identity {
+            Some((certs, key)) => builder.with_client_auth_cert(certs.clone(), key.clone_key())?,
+            None => builder.with_no_client_auth(),
+        };
+        let name = match &self.server_name {
+            Some(name) => ServerName::try_from(name.clone())
+                .map_err(|e| MyError::Tls(format!("Invalid server name {}: {}", name, e)))?,
+            None => ServerName::from(stream.peer_addr()?.ip()),
+        };
+        let mut connection = ClientConnection::new(Arc::new(config), name)?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(SharedStream::new(StreamOwned::new(connection, stream)))
+    }
+}
+
+/// Certificate and key of a Raft node, and the CA of the cluster: every node
+/// presents its certificate to the others and requires theirs (mutual TLS).
+///
+/// Nodes connect to each other by the address of their `--raft-peer`, which
+/// their certificate must be valid for.
+pub struct TlsPeerConfig {
+    server: Arc<ServerConfig>,
+    client: TlsClientConfig,
+}
+
+impl TlsPeerConfig {
+    /// Reads the certificate chain and the private key of the node, and the
+    /// CA certificates its peers are signed by.
+    pub fn from_pem_files(
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        ca: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let server = TlsServerConfig::from_pem_files(&cert, &key)?
+            .client_ca(&ca)?
+            .build()?;
+        let client = TlsClientConfig::from_ca_pem_file(&ca)?.client_cert(&cert, &key)?;
+        Ok(TlsPeerConfig { server, client })
+    }
+
+    /// Runs the handshake with a node which connected on `stream`, which
+    /// fails unless it presents a certificate of the cluster.
+    pub(crate) fn accept(&self, mut stream: TcpStream) -> Result<SharedStream> {
+        let mut connection = ServerConnection::new(Arc::clone(&self.server))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(SharedStream::new(StreamOwned::new(connection, stream)))
+    }
+
+    /// Runs the handshake with the node at the other end of `stream`.
+    pub(crate) fn connect(&self, stream: TcpStream) -> Result<SharedStream> {
+        self.client.connect(stream)
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(MyError::Tls(format!(
+            "No certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| MyError::Tls(format!("No private key in {}", path.display())))
+}
+
+fn read_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// Any stream a connection is served on.
+pub(crate) trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Transport for T {}
+
+/// Handle on a stream which cannot be split in a read and a write half, such
+/// as a TLS session. Clones share the stream.
+#[derive(Clone)]
+pub(crate) struct SharedStream(Arc<Mutex<Box<dyn Transport>>>);
+
+impl SharedStream {
+    pub(crate) fn new(stream: impl Transport + 'static) -> SharedStream {
+        SharedStream(Arc::new(Mutex::new(Box::new(stream))))
+    }
+}
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
@@ -0,0 +1,142 @@
+//! Typed values on top of a `KvsEngine`.
+use crate::engine::KvsEngine;
+use crate::{MyError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+
+/// Trait for a value encoding used by `TypedStore` and the typed `KvsClient` methods.
+pub trait Codec {
+    /// Encodes a value to bytes.
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+
+    /// Decodes a value from bytes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `MyError::Codec` if the bytes were not written by this codec
+    /// for this type.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// JSON codec, based on `serde_json`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Json;
+
+/// Compact binary codec, based on `bincode`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bincode;
+
+/// MessagePack codec, based on `rmp-serde`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessagePack;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| MyError::Codec(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| MyError::Codec(e.to_string()))
+    }
+}
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| MyError::Codec(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|e| MyError::Codec(e.to_string()))
+    }
+}
+
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec(value).map_err(|e| MyError::Codec(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(bytes).map_err(|e| MyError::Codec(e.to_string()))
+    }
+}
+
+/// A `TypedStore` stores values of type `V` in a `KvsEngine`, encoded with a `Codec`.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, TypedStore};
+/// # use serde::{Deserialize, Serialize};
+/// # fn try_main() -> Result<()> {
+/// #[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// struct User {
+///     name: String,
+///     age: u32,
+/// }
+///
+/// let temp_dir = tempfile::TempDir::new()?;
+/// let mut users = TypedStore::new(KvStore::open(temp_dir.path())?);
+/// let user = User { name: "Ada".to_owned(), age: 36 };
+/// users.set("ada", &user)?;
+/// assert_eq!(users.get("ada")?, Some(user));
+/// # Ok(())
+/// # }
+/// # try_main().unwrap();
+/// ```
+pub struct TypedStore<E: KvsEngine, V, C: Codec = Json> {
+    engine: E,
+    codec: C,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<E: KvsEngine, V: Serialize + DeserializeOwned> TypedStore<E, V, Json> {
+    /// Creates a `TypedStore` encoding values as JSON.
+    pub fn new(engine: E) -> Self {
+        TypedStore::with_codec(engine, Json)
+    }
+}
+
+impl<E: KvsEngine, V: Serialize + DeserializeOwned, C: Codec> TypedStore<E, V, C> {
+    /// Creates a `TypedStore` encoding values with the given codec.
+    pub fn with_codec(engine: E, codec: C) -> Self {
+        TypedStore {
+            engine,
+            codec,
+            value: PhantomData,
+        }
+    }
+
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: &V) -> Result<()> {
+        let bytes = self.codec.encode(value)?;
+        self.engine.set_bytes(key.into(), bytes)
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<V>> {
+        self.engine
+            .get_bytes(key.as_ref())?
+            .map(|bytes| self.codec.decode(&bytes))
+            .transpose()
+    }
+
+    /// Removes a given key.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.engine.remove_bytes(key.as_ref())
+    }
+
+    /// Returns a mutable reference to the underlying engine.
+    pub fn engine_mut(&mut self) -> &mut E {
+        &mut self.engine
+    }
+
+    /// Returns the underlying engine.
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+}
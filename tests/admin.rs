@@ -0,0 +1,262 @@
+use assert_cmd::prelude::*;
+use kvs::auth::{generate_token, hash_secret, hash_token};
+use kvs::{
+    Acl, Credentials, KvStore, KvsClient, KvsEngine, LsmKvsEngine, MemoryKvsEngine, MyError,
+    Result, Server,
+};
+use predicates::str::contains;
+use serde_json::json;
+use std::net::{SocketAddr, TcpListener};
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Starts a server on a free port, left running until the test exits.
+fn start_server<E: KvsEngine + Clone + Send + 'static>(engine: E, acl: Option<Acl>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = Server::new(engine);
+    if let Some(acl) = acl {
+        server = server.auth(acl);
+    }
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+// INFO, COMPACT, FLUSH, DBSIZE and CONFIG on a KvStore
+#[test]
+fn kvs_store_admin() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(KvStore::open(temp_dir.path())?, None);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.config_set("compaction-threshold".to_owned(), "1000000".to_owned())?;
+    for i in 0..10 {
+        client.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    client.set("key2".to_owned(), "value".to_owned())?;
+    client.flush()?;
+
+    let info = client.info()?;
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.engine, "kvs");
+    assert_eq!(info.keys, 2);
+    assert!(info.disk_bytes.unwrap() > 0);
+    assert!(info.stale_bytes.unwrap() > 0);
+    assert_eq!(client.dbsize()?, 2);
+
+    client.compact()?;
+    let compacted = client.info()?;
+    assert_eq!(compacted.stale_bytes, Some(0));
+    assert!(compacted.disk_bytes < info.disk_bytes);
+    assert_eq!(client.get("key1".to_owned())?, Some("value9".to_owned()));
+
+    let config = client.config_get(None)?;
+    assert_eq!(config["compaction-threshold"], "1000000");
+    assert_eq!(config["durability"], "none");
+    assert!(config.contains_key("log-level"));
+    let config = client.config_get(Some("compaction-threshold".to_owned()))?;
+    assert_eq!(config.len(), 1);
+    assert!(client.config_get(Some("unknown".to_owned())).is_err());
+    assert!(client
+        .config_set("durability".to_owned(), "every-write".to_owned())
+        .is_err());
+    assert!(client
+        .config_set("compaction-threshold".to_owned(), "many".to_owned())
+        .is_err());
+    assert!(client
+        .config_set("log-level".to_owned(), "loud".to_owned())
+        .is_err());
+    // No logger is installed by the tests, so none of its levels are enabled
+    assert!(client
+        .config_set("log-level".to_owned(), "trace".to_owned())
+        .is_err());
+    client.config_set("log-level".to_owned(), "off".to_owned())?;
+    Ok(())
+}
+
+// Engines without key count or compaction on demand
+#[test]
+fn other_engines() -> Result<()> {
+    let addr = start_server(MemoryKvsEngine::new(), None);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.dbsize()?, 1);
+    assert_eq!(client.info()?.engine, "memory");
+    client.flush()?;
+    assert!(client.compact().is_err());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(LsmKvsEngine::open(temp_dir.path())?, None);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.dbsize()?, 1);
+    client.config_set("memtable-bytes".to_owned(), "4096".to_owned())?;
+    assert_eq!(
+        client.config_get(Some("memtable-bytes".to_owned()))?["memtable-bytes"],
+        "4096"
+    );
+    client.compact()?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// DBSIZE and INFO apply to the selected namespace
+#[test]
+fn admin_namespace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(KvStore::open(temp_dir.path())?, None);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.select("tenant");
+    assert_eq!(client.dbsize()?, 0);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.info()?.keys, 2);
+    Ok(())
+}
+
+// Admin commands need admin rights on every key
+#[test]
+fn admin_rights() -> Result<()> {
+    let ops_token = generate_token();
+    let credentials = json!({
+        "users": [
+            { "name": "writer", "password": hash_secret("writer-pass"), "grants": { "": "write" } },
+            { "name": "ops", "token": hash_token(&ops_token)?, "grants": { "": "admin" } }
+        ]
+    });
+    let acl = Acl::from_json(&credentials.to_string())?;
+    let addr = start_server(MemoryKvsEngine::new(), Some(acl));
+
+    let mut writer = KvsClient::connect(addr)?;
+    writer.authenticate(Credentials::password("writer", "writer-pass"))?;
+    let unauthorized = |result: Result<()>| matches!(result, Err(MyError::Unauthorized(_)));
+    assert!(unauthorized(writer.info().map(|_| ())));
+    assert!(unauthorized(writer.compact()));
+    assert!(unauthorized(writer.flush()));
+    assert!(unauthorized(writer.dbsize().map(|_| ())));
+    assert!(unauthorized(writer.config_get(None).map(|_| ())));
+    assert!(unauthorized(
+        writer.config_set("log-level".to_owned(), "warn".to_owned())
+    ));
+
+    let mut ops = KvsClient::connect(addr)?;
+    ops.authenticate(Credentials::token(ops_token.as_str()))?;
+    assert_eq!(ops.dbsize()?, 0);
+    ops.config_get(None)?;
+    Ok(())
+}
+
+// kvs-client admin subcommands
+#[test]
+fn cli_admin() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .env_remove("RUST_LOG")
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["admin", "dbsize"])
+        .assert()
+        .success()
+        .stdout(contains("1"));
+    client(&["admin", "info"])
+        .assert()
+        .success()
+        .stdout(contains("engine: kvs"))
+        .stdout(contains("keys: 1"));
+    client(&["admin", "compact"]).assert().success();
+    client(&["admin", "flush"]).assert().success();
+    client(&["admin", "config", "set", "compaction-threshold", "4096"])
+        .assert()
+        .success();
+    client(&["admin", "config", "get", "compaction-threshold"])
+        .assert()
+        .success()
+        .stdout(contains("compaction-threshold = 4096"));
+    client(&["admin", "config", "get"])
+        .assert()
+        .success()
+        .stdout(contains("durability = none"));
+    client(&["admin", "config", "set", "nope", "1"])
+        .assert()
+        .failure()
+        .stderr(contains("Unknown parameter nope"));
+    client(&["admin", "config", "set", "log-level", "debug"])
+        .assert()
+        .success();
+    client(&["admin", "config", "get", "log-level"])
+        .assert()
+        .success()
+        .stdout(contains("log-level = debug"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// The log level cannot be raised above the filter of RUST_LOG
+#[test]
+fn cli_log_level_above_filter() {
+    let addr = "127.0.0.1:4029";
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .env("RUST_LOG", "warn")
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["admin", "config", "set", "log-level", "debug"])
+        .assert()
+        .failure()
+        .stderr(contains("above the filter"));
+    client(&["admin", "config", "set", "log-level", "error"])
+        .assert()
+        .success();
+    client(&["admin", "config", "set", "log-level", "warn"])
+        .assert()
+        .success();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
@@ -0,0 +1,375 @@
+use assert_cmd::prelude::*;
+use kvs::auth::{generate_token, hash_secret, hash_token, MAX_FAILED_LOGINS};
+use kvs::{
+    Acl, Credentials, KvStore, KvsClient, MemoryKvsEngine, MyError, Result, Server,
+    DEFAULT_NAMESPACE,
+};
+use predicates::str::contains;
+use serde_json::json;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const OPS_TOKEN: &str =
+    "kvs_0123456789abcdef_00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+const TENANT_TOKEN: &str =
+    "kvs_fedcba9876543210_ffeeddccbbaa99887766554433221100ffeeddccbbaa99887766554433221100";
+
+fn acl() -> Acl {
+    let credentials = json!({
+        "users": [
+            { "name": "reader", "password": hash_secret("reader-pass"), "grants": { "app/": "read" } },
+            { "name": "writer", "password": hash_secret("writer-pass"),
+              "grants": { "app/": "write", "": "read" } },
+            { "name": "ops", "token": hash_token(OPS_TOKEN).unwrap(), "grants": { "": "admin" } }
+        ]
+    });
+    Acl::from_json(&credentials.to_string()).unwrap()
+}
+
+/// Starts a server on a free port, left running until the test exits.
+fn start_server(acl: Option<Acl>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = Server::new(MemoryKvsEngine::new());
+    if let Some(acl) = acl {
+        server = server.auth(acl);
+    }
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+fn is_unauthorized<T>(result: Result<T>) -> bool {
+    matches!(result, Err(MyError::Unauthorized(_)))
+}
+
+// Requests before logging in, or with wrong credentials, are refused
+#[test]
+fn authentication_required() -> Result<()> {
+    let addr = start_server(Some(acl()));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(is_unauthorized(client.get("app/key".to_owned())));
+    assert!(is_unauthorized(
+        client.set("app/key".to_owned(), "value".to_owned())
+    ));
+    assert!(is_unauthorized(client.remove("app/key".to_owned())));
+
+    assert!(is_unauthorized(
+        client.authenticate(Credentials::password("reader", "writer-pass"))
+    ));
+    assert!(is_unauthorized(
+        client.authenticate(Credentials::password("nobody", "reader-pass"))
+    ));
+    assert!(is_unauthorized(
+        client.authenticate(Credentials::token("reader-pass"))
+    ));
+    assert!(is_unauthorized(client.get("app/key".to_owned())));
+
+    client.authenticate(Credentials::password("reader", "reader-pass"))?;
+    assert_eq!(client.get("app/key".to_owned())?, None);
+    Ok(())
+}
+
+// Rights apply to the keys under the granted prefixes only
+#[test]
+fn rights_per_prefix() -> Result<()> {
+    let addr = start_server(Some(acl()));
+
+    let mut writer = KvsClient::connect(addr)?;
+    writer.authenticate(Credentials::password("writer", "writer-pass"))?;
+    writer.set("app/key".to_owned(), "value".to_owned())?;
+    assert!(is_unauthorized(
+        writer.set("other/key".to_owned(), "value".to_owned())
+    ));
+    assert!(is_unauthorized(writer.remove("other/key".to_owned())));
+    assert_eq!(writer.get("other/key".to_owned())?, None);
+
+    let mut reader = KvsClient::connect(addr)?;
+    reader.authenticate(Credentials::password("reader", "reader-pass"))?;
+    assert_eq!(reader.get("app/key".to_owned())?, Some("value".to_owned()));
+    assert!(is_unauthorized(reader.get("other/key".to_owned())));
+    assert!(is_unauthorized(
+        reader.set("app/key".to_owned(), "changed".to_owned())
+    ));
+    assert!(is_unauthorized(reader.remove("app/key".to_owned())));
+    assert_eq!(reader.get("app/key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Backups need admin rights on every key
+#[test]
+fn backup_needs_admin() -> Result<()> {
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(Some(acl()));
+
+    let mut writer = KvsClient::connect(addr)?;
+    writer.authenticate(Credentials::password("writer", "writer-pass"))?;
+    writer.set("app/key".to_owned(), "value".to_owned())?;
+    let dest = backup_dir.path().join("backup");
+    assert!(is_unauthorized(
+        writer.backup(dest.to_string_lossy().into_owned())
+    ));
+
+    let mut ops = KvsClient::connect(addr)?;
+    ops.authenticate(Credentials::token(OPS_TOKEN))?;
+    ops.set("other/key".to_owned(), "value".to_owned())?;
+    assert_eq!(ops.backup(dest.to_string_lossy().into_owned())?.keys, 2);
+    Ok(())
+}
+
+// A grant on a namespace gives no right on the same keys of another one
+#[test]
+fn rights_per_namespace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let credentials = json!({
+        "users": [
+            { "name": "tenant", "token": hash_token(TENANT_TOKEN).unwrap(),
+              "grants": { "app1:": "admin", "app2:public/": "read" } },
+            { "name": "ops", "token": hash_token(OPS_TOKEN).unwrap(), "grants": { "*:": "admin" } }
+        ]
+    });
+    let acl = Acl::from_json(&credentials.to_string())?;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(KvStore::open(temp_dir.path())?).auth(acl);
+    thread::spawn(move || server.serve(listener));
+
+    let mut ops = KvsClient::connect(addr)?;
+    ops.authenticate(Credentials::token(OPS_TOKEN))?;
+    ops.set("key".to_owned(), "default".to_owned())?;
+    ops.select("app2");
+    ops.set("key".to_owned(), "app2".to_owned())?;
+    ops.set("public/key".to_owned(), "app2".to_owned())?;
+
+    let mut tenant = KvsClient::connect(addr)?;
+    tenant.authenticate(Credentials::token(TENANT_TOKEN))?;
+    tenant.select("app1");
+    tenant.set("key".to_owned(), "app1".to_owned())?;
+    assert_eq!(tenant.get("key".to_owned())?, Some("app1".to_owned()));
+    assert_eq!(tenant.dbsize()?, 1);
+
+    tenant.select("app2");
+    assert!(is_unauthorized(tenant.get("key".to_owned())));
+    assert!(is_unauthorized(
+        tenant.set("key".to_owned(), "changed".to_owned())
+    ));
+    assert!(is_unauthorized(tenant.remove("key".to_owned())));
+    assert!(is_unauthorized(tenant.dbsize()));
+    assert_eq!(
+        tenant.get("public/key".to_owned())?,
+        Some("app2".to_owned())
+    );
+
+    let mut default = KvsClient::connect(addr)?;
+    default.authenticate(Credentials::token(TENANT_TOKEN))?;
+    assert!(is_unauthorized(default.get("key".to_owned())));
+    assert!(is_unauthorized(default.info()));
+    Ok(())
+}
+
+// A backup of the default namespace holds every namespace, so it needs admin
+// on all of them
+#[test]
+fn backup_covers_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let credentials = json!({
+        "users": [
+            { "name": "tenant", "token": hash_token(TENANT_TOKEN)?, "grants": { "": "admin" } },
+            { "name": "ops", "token": hash_token(OPS_TOKEN)?, "grants": { "*:": "admin" } }
+        ]
+    });
+    let acl = Acl::from_json(&credentials.to_string())?;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(KvStore::open(temp_dir.path())?).auth(acl);
+    thread::spawn(move || server.serve(listener));
+
+    let mut ops = KvsClient::connect(addr)?;
+    ops.authenticate(Credentials::token(OPS_TOKEN))?;
+    ops.set("key".to_owned(), "default".to_owned())?;
+    ops.select("app1");
+    ops.set("key".to_owned(), "app1".to_owned())?;
+
+    let mut tenant = KvsClient::connect(addr)?;
+    tenant.authenticate(Credentials::token(TENANT_TOKEN))?;
+    let dest = backup_dir.path().join("backup");
+    assert!(is_unauthorized(
+        tenant.backup(dest.to_string_lossy().into_owned())
+    ));
+    ops.select(DEFAULT_NAMESPACE);
+    assert_eq!(ops.backup(dest.to_string_lossy().into_owned())?.keys, 2);
+    Ok(())
+}
+
+// Without credentials file, every request is allowed and logging in is a no-op
+#[test]
+fn open_server() -> Result<()> {
+    let addr = start_server(None);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    client.authenticate(Credentials::token("anything"))?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Invalid credentials files are rejected when loaded
+#[test]
+fn invalid_credentials_file() {
+    let hash = hash_secret("secret");
+    let token = hash_token(OPS_TOKEN).unwrap();
+    let same_id = hash_token(&OPS_TOKEN.replace("_00", "_ff")).unwrap();
+    let (scheme, salt, digest) = ("pbkdf2-sha256", "00".repeat(16), "00".repeat(32));
+    let invalid = [
+        json!({ "users": [{ "name": "a", "grants": {} }] }),
+        json!({ "users": [{ "name": "a", "password": &hash, "token": &token }] }),
+        json!({ "users": [{ "name": "a", "password": "secret" }] }),
+        json!({ "users": [{ "name": "a", "password": &token }] }),
+        json!({ "users": [{ "name": "a", "password": format!("{}:0:{}:{}", scheme, salt, digest) }] }),
+        json!({ "users": [{ "name": "a", "token": &hash }] }),
+        json!({ "users": [{ "name": "a", "token": format!("sha256:0123:{}", digest) }] }),
+        json!({ "users": [{ "name": "a", "token": &token, "grants": { "": "root" } }] }),
+        json!({ "users": [{ "name": "a", "token": &token }, { "name": "a", "password": &hash }] }),
+        json!({ "users": [{ "name": "a", "token": &token }, { "name": "b", "token": &same_id }] }),
+        json!({ "users": [{ "name": "a", "token": &token, "grants": { "../app:": "read" } }] }),
+    ];
+    for credentials in &invalid {
+        assert!(
+            Acl::from_json(&credentials.to_string()).is_err(),
+            "{} was accepted",
+            credentials
+        );
+    }
+    let valid = json!({ "users": [{ "name": "a", "token": &token }] });
+    assert!(Acl::from_json(&valid.to_string()).is_ok());
+    assert!(hash_token("ops-token").is_err());
+}
+
+// Only the whole token logs in, not another secret under its id
+#[test]
+fn token_id_and_secret() -> Result<()> {
+    let token = generate_token();
+    let credentials = json!({
+        "users": [{ "name": "ops", "token": hash_token(&token)?, "grants": { "": "admin" } }]
+    });
+    let addr = start_server(Some(Acl::from_json(&credentials.to_string())?));
+
+    let mut client = KvsClient::connect(addr)?;
+    let (id, secret) = token.rsplit_once('_').unwrap();
+    let forged = format!("{}_{}", id, secret.replace(|c| c != '0', "0"));
+    assert!(is_unauthorized(
+        client.authenticate(Credentials::token(forged))
+    ));
+    assert!(is_unauthorized(client.authenticate(Credentials::token(id))));
+    assert!(is_unauthorized(
+        client.authenticate(Credentials::token(OPS_TOKEN))
+    ));
+    client.authenticate(Credentials::token(token))?;
+    assert_eq!(client.info()?.keys, 0);
+    Ok(())
+}
+
+// After too many failed logins, an address is refused even the right credentials
+#[test]
+fn failed_logins_limited() -> Result<()> {
+    let addr = start_server(Some(acl()));
+
+    let mut client = KvsClient::connect(addr)?;
+    for _ in 0..MAX_FAILED_LOGINS {
+        assert!(is_unauthorized(
+            client.authenticate(Credentials::password("reader", "wrong"))
+        ));
+    }
+    match client.authenticate(Credentials::password("reader", "reader-pass")) {
+        Err(MyError::Unauthorized(msg)) => assert!(msg.contains("Too many failed logins")),
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+    let mut other = KvsClient::connect(addr)?;
+    assert!(is_unauthorized(
+        other.authenticate(Credentials::token(OPS_TOKEN))
+    ));
+    Ok(())
+}
+
+// The binaries should read the credentials file and log in from flags or env
+#[test]
+fn cli_authentication() {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    let admin = |args: &[&str]| {
+        let output = Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .args(args)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let password = admin(&["hash-secret", "alice-pass"]);
+    let generated = admin(&["generate-token"]);
+    let (token, token_hash) = generated.trim().split_once('\n').unwrap();
+    let credentials = json!({
+        "users": [
+            { "name": "alice", "password": password.trim(), "grants": { "": "write" } },
+            { "name": "ci", "token": token_hash, "grants": { "": "read" } }
+        ]
+    });
+    let path = temp_dir.path().join("credentials.json");
+    fs::write(&path, credentials.to_string()).unwrap();
+
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--credentials"])
+        .arg(&path)
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .env_remove("KVS_TOKEN")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unauthorized"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--user", "alice"])
+        .env("KVS_PASSWORD", "alice-pass")
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .env("KVS_TOKEN", token)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr, "--token", token])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unauthorized"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
@@ -0,0 +1,148 @@
+use kvs::{backup, KvStore, KvsEngine, LsmKvsEngine, MyError, Result, SledKvsEngine};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn backup_and_restore<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let data_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut engine = open(data_dir.path())?;
+    for key_id in 0..100 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    engine.remove("key0".to_owned())?;
+    let manifest = engine.backup(&backup_dir.path().join("backup"))?;
+    assert_eq!(manifest.keys, 99);
+
+    // Writes after the backup are not part of it
+    engine.set("key1".to_owned(), "changed".to_owned())?;
+    drop(engine);
+
+    let restored = backup::restore(&backup_dir.path().join("backup"), restore_dir.path())?;
+    assert_eq!(restored, manifest);
+    let mut engine = open(restore_dir.path())?;
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_backup_and_restore() -> Result<()> {
+    backup_and_restore(|path| KvStore::open(path))
+}
+
+#[test]
+fn sled_backup_and_restore() -> Result<()> {
+    backup_and_restore(|path| SledKvsEngine::open(path))
+}
+
+#[test]
+fn lsm_backup_and_restore() -> Result<()> {
+    backup_and_restore(|path| LsmKvsEngine::open(path))
+}
+
+// A backup of the default namespace holds every namespace, which a restore
+// puts in place of those of the data directory
+fn namespaces_backup_and_restore<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let data_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut engine = open(data_dir.path())?;
+    engine.set("key".to_owned(), "default".to_owned())?;
+    let mut app1 = engine.namespace("app1")?;
+    app1.set("key".to_owned(), "app1".to_owned())?;
+    app1.set("other".to_owned(), "app1".to_owned())?;
+    let manifest = engine.backup(&backup_dir.path().join("all"))?;
+    assert_eq!(manifest.keys, 3);
+    let manifest = app1.backup(&backup_dir.path().join("app1"))?;
+    assert_eq!(manifest.keys, 2);
+    drop((engine, app1));
+
+    let mut stale = open(restore_dir.path())?;
+    stale
+        .namespace("stale")?
+        .set("key".to_owned(), "stale".to_owned())?;
+    drop(stale);
+    backup::restore(&backup_dir.path().join("all"), restore_dir.path())?;
+    let mut engine = open(restore_dir.path())?;
+    assert_eq!(engine.namespaces()?, vec!["app1".to_owned()]);
+    assert_eq!(engine.get("key".to_owned())?, Some("default".to_owned()));
+    let mut app1 = engine.namespace("app1")?;
+    assert_eq!(app1.get("key".to_owned())?, Some("app1".to_owned()));
+    assert_eq!(app1.get("other".to_owned())?, Some("app1".to_owned()));
+    drop((engine, app1));
+
+    // Moving a pair to another namespace is caught by the checksum
+    let all = backup_dir.path().join("all");
+    let manifest = backup::verify(&all)?;
+    let mut engine = open(&all)?;
+    engine.namespace("app1")?.remove("other".to_owned())?;
+    engine.set("other".to_owned(), "app1".to_owned())?;
+    drop(engine);
+    let moved = backup::verify(&all);
+    assert!(moved.is_err(), "{:?} matches {:?}", moved, manifest);
+    Ok(())
+}
+
+#[test]
+fn kvs_namespaces_backup_and_restore() -> Result<()> {
+    namespaces_backup_and_restore(|path| KvStore::open(path))
+}
+
+#[test]
+fn sled_namespaces_backup_and_restore() -> Result<()> {
+    namespaces_backup_and_restore(|path| SledKvsEngine::open(path))
+}
+
+// Backing up into a non-empty directory should fail
+#[test]
+fn backup_into_non_empty_dir() -> Result<()> {
+    let data_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(backup_dir.path().join("file"), "content")?;
+
+    let mut store = KvStore::open(data_dir.path())?;
+    assert!(store.backup(backup_dir.path()).is_err());
+    Ok(())
+}
+
+// A backup which does not match its manifest should be rejected, leaving the data untouched
+#[test]
+fn restore_rejects_corrupted_backup() -> Result<()> {
+    let data_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(data_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup(backup_dir.path())?;
+    drop(store);
+
+    let mut store = KvStore::open(restore_dir.path())?;
+    store.set("key1".to_owned(), "current".to_owned())?;
+    drop(store);
+
+    let log = backup_dir.path().join("log.json");
+    let content = fs::read_to_string(&log)?.replace("value1", "value2");
+    fs::write(&log, content)?;
+    match backup::restore(backup_dir.path(), restore_dir.path()) {
+        Err(MyError::StringError(msg)) => assert!(msg.contains("corrupted")),
+        other => panic!("expected a corrupted backup error, got {:?}", other),
+    }
+
+    let mut store = KvStore::open(restore_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("current".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open_cached(path: &Path, bytes: u64) -> Result<KvStore> {
+    KvStore::open_with(path, KvStoreOptions::default().cache_bytes(bytes))
+}
+
+// Reading a value again should be answered from the cache
+#[test]
+fn repeated_reads_hit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_cached(temp_dir.path(), 64 * 1024)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    for _ in 0..10 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let stats = store.cache_stats();
+    assert_eq!(stats.misses, 1);
+    assert_eq!(stats.hits, 9);
+    assert_eq!(stats.entries, 1);
+    assert!((stats.hit_rate() - 0.9).abs() < 1e-9);
+    Ok(())
+}
+
+// Writes should invalidate the cached value
+#[test]
+fn writes_invalidate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_cached(temp_dir.path(), 64 * 1024)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    store.set_batch(vec![(b"key1".to_vec(), b"value3".to_vec())])?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.cache_stats().entries, 0);
+    Ok(())
+}
+
+// The cache should stay within its capacity, keeping recently read values
+#[test]
+fn eviction_keeps_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_cached(temp_dir.path(), 4096)?;
+    for key_id in 0..500 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+
+    for _ in 0..2 {
+        for key_id in 0..500 {
+            assert_eq!(
+                store.get(format!("key{:03}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+            // A hot key read between every other read
+            store.get("key000".to_owned())?;
+        }
+    }
+
+    let stats = store.cache_stats();
+    assert!(stats.bytes <= 4096, "{:?}", stats);
+    assert!(stats.evictions > 0, "{:?}", stats);
+    assert!(stats.hits >= 999, "{:?}", stats);
+    Ok(())
+}
+
+// Values larger than the whole cache are read from the log every time
+#[test]
+fn oversized_values_are_not_cached() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_cached(temp_dir.path(), 256)?;
+    let value = "x".repeat(1024);
+    store.set("key1".to_owned(), value.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+
+    let stats = store.cache_stats();
+    assert_eq!(stats.entries, 0);
+    assert_eq!(stats.misses, 2);
+    Ok(())
+}
+
+// Without a capacity the cache is disabled
+#[test]
+fn disabled_by_default() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key1".to_owned())?;
+    assert_eq!(store.cache_stats(), kvs::CacheStats::default());
+    Ok(())
+}
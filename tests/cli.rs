@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use kvs::{Cipher, EncryptionKey};
 use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
@@ -21,28 +22,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -53,35 +54,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -92,28 +93,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -124,7 +125,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -135,7 +136,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -146,7 +147,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -158,13 +159,16 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .arg("--data-dir")
+        .arg(temp_dir.path())
         .current_dir(&temp_dir)
         .stdout(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -179,15 +183,20 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .arg("--data-dir")
+            .arg(temp_dir.path())
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "titit", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "titit", "--addr", "127.0.0.1:4003"])
+            .arg("--data-dir")
+            .arg(temp_dir.path())
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -198,15 +207,20 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .arg("--data-dir")
+            .arg(temp_dir.path())
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "qqq", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "qqq", "--addr", "127.0.0.1:4003"])
+            .arg("--data-dir")
+            .arg(temp_dir.path())
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -218,19 +232,22 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
+        .arg("--data-dir")
+        .arg(temp_dir.path())
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -238,7 +255,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -246,7 +263,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -254,7 +271,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -262,7 +279,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -270,7 +287,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -278,7 +295,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -286,7 +303,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -299,26 +316,29 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
+        .arg("--data-dir")
+        .arg(temp_dir.path())
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -336,3 +356,293 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_backup_and_restore() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let backup_path = backup_dir.path().join("backup");
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["backup", backup_path.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 keys"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", backup_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("Backup is valid"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args([
+            "restore",
+            backup_path.to_str().unwrap(),
+            restore_dir.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    let mut store = kvs::KvStore::open(restore_dir.path()).unwrap();
+    assert_eq!(
+        kvs::KvsEngine::get(&mut store, "key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn cli_export_and_import() {
+    let source_dir = TempDir::new().unwrap();
+    let target_dir = TempDir::new().unwrap();
+    let dump_dir = TempDir::new().unwrap();
+    let dump_path = dump_dir.path().join("dump.bin");
+
+    let mut store = kvs::KvStore::open(source_dir.path()).unwrap();
+    kvs::KvsEngine::set(&mut store, "key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args([
+            "export",
+            source_dir.path().to_str().unwrap(),
+            dump_path.to_str().unwrap(),
+            "--engine",
+            "kvs",
+            "--format",
+            "binary",
+        ])
+        .assert()
+        .success()
+        .stdout(contains("Exported 1 keys"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args([
+            "import",
+            dump_path.to_str().unwrap(),
+            target_dir.path().to_str().unwrap(),
+            "--engine",
+            "sled",
+            "--format",
+            "binary",
+        ])
+        .assert()
+        .success()
+        .stdout(contains("Imported 1 keys"));
+
+    let mut db = kvs::SledKvsEngine::open(target_dir.path()).unwrap();
+    assert_eq!(
+        kvs::KvsEngine::get(&mut db, "key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn cli_engine_marker_and_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4007"])
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    // The directory belongs to sled now
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4008"])
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs-migrate"));
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(["--from", "sled", "--to", "kvs"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Migration verified"));
+
+    let marker = fs::read_to_string(temp_dir.path().join("engine")).unwrap();
+    assert_eq!(marker, "kvs");
+}
+
+#[test]
+fn cli_fsck() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    kvs::KvsEngine::set(&mut store, "key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-fsck")
+        .unwrap()
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("No corruption found"));
+
+    let log = temp_dir.path().join("log.json");
+    let mut content = fs::read(&log).unwrap();
+    content.extend_from_slice(b"\r\n{\"Set\":{\"ke");
+    fs::write(&log, content).unwrap();
+
+    Command::cargo_bin("kvs-fsck")
+        .unwrap()
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("--repair"));
+
+    Command::cargo_bin("kvs-fsck")
+        .unwrap()
+        .arg("--repair")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Log repaired"));
+}
+
+#[test]
+fn cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--sync", "sometimes", "--addr", "127.0.0.1:4009"])
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_kvs_options_on_other_engines() {
+    let temp_dir = TempDir::new().unwrap();
+    let key_file = temp_dir.path().join("key");
+    fs::write(
+        &key_file,
+        EncryptionKey::generate(Cipher::Aes256Gcm).to_base64(),
+    )
+    .unwrap();
+    for engine in &["sled", "lsm", "memory"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", "127.0.0.1:4026"])
+            .arg("--encryption-key-file")
+            .arg(&key_file)
+            .arg("--data-dir")
+            .arg(temp_dir.path())
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains(format!(
+                "--encryption-key-file only applies to the kvs engine, not {}",
+                engine
+            )));
+    }
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4026"])
+        .env("KVS_ENCRYPTION_KEY", "unused")
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "KVS_ENCRYPTION_KEY only applies to the kvs engine",
+        ));
+    for flag in &[
+        &["--compression", "lz4"][..],
+        &["--index-memory", "4096"],
+        &["--cache-memory", "4096"],
+        &["--no-mmap"],
+    ] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "lsm", "--addr", "127.0.0.1:4026"])
+            .args(*flag)
+            .arg("--data-dir")
+            .arg(temp_dir.path())
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains(format!(
+                "{} only applies to the kvs engine",
+                flag[0]
+            )));
+    }
+}
+
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "memory", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    // Nothing is written without --memory-snapshot
+    assert!(fs::read_dir(temp_dir.path()).unwrap().next().is_none());
+}
@@ -0,0 +1,123 @@
+use kvs::{Compression, KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open_with(path: &Path, compression: Compression) -> Result<KvStore> {
+    KvStore::open_with(path, KvStoreOptions::default().compression(compression))
+}
+
+fn document(id: u32) -> String {
+    let items = (0..20)
+        .map(|item| {
+            format!(
+                r#"{{"item":{},"name":"widget","tags":["a","b","c"]}}"#,
+                item
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(r#"{{"id":{},"items":[{}]}}"#, id, items.join(","))
+}
+
+fn log_size(path: &Path) -> u64 {
+    fs::metadata(path.join("log.json")).unwrap().len()
+}
+
+// Repetitive values should take less room in the log and read back unchanged
+fn compresses(compression: Compression) -> Result<()> {
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compressed_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut plain = open_with(plain_dir.path(), Compression::None)?;
+    let mut compressed = open_with(compressed_dir.path(), compression)?;
+    for id in 0..100 {
+        plain.set(format!("doc{}", id), document(id))?;
+        compressed.set(format!("doc{}", id), document(id))?;
+    }
+    assert!(log_size(compressed_dir.path()) * 2 < log_size(plain_dir.path()));
+
+    let stats = compressed.compression_stats();
+    assert_eq!(stats.values, 100);
+    assert_eq!(stats.compressed, 100);
+    assert!(stats.ratio() < 0.5, "{:?}", stats);
+    assert_eq!(plain.compression_stats().compressed, 0);
+
+    for id in 0..100 {
+        assert_eq!(compressed.get(format!("doc{}", id))?, Some(document(id)));
+    }
+    drop(compressed);
+
+    let mut compressed = KvStore::open(compressed_dir.path())?;
+    assert_eq!(compressed.get("doc42".to_owned())?, Some(document(42)));
+    Ok(())
+}
+
+#[test]
+fn compresses_lz4() -> Result<()> {
+    compresses(Compression::Lz4)
+}
+
+#[test]
+fn compresses_zstd() -> Result<()> {
+    compresses(Compression::Zstd)
+}
+
+// Logs mixing plain and compressed records should read back whatever the
+// current setting
+#[test]
+fn mixed_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let settings = [
+        Compression::None,
+        Compression::Lz4,
+        Compression::Zstd,
+        Compression::None,
+    ];
+    for (step, compression) in settings.iter().enumerate() {
+        let mut store = open_with(temp_dir.path(), *compression)?;
+        store.set(format!("doc{}", step), document(step as u32))?;
+        for previous in 0..=step {
+            assert_eq!(
+                store.get(format!("doc{}", previous))?,
+                Some(document(previous as u32))
+            );
+        }
+    }
+
+    let log = fs::read_to_string(temp_dir.path().join("log.json"))?;
+    assert!(log.contains(r#""codec":"lz4""#));
+    assert!(log.contains(r#""codec":"zstd""#));
+    Ok(())
+}
+
+// Values which do not get smaller are written as they are
+#[test]
+fn small_values_stay_plain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_with(temp_dir.path(), Compression::Zstd)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let stats = store.compression_stats();
+    assert_eq!(stats.values, 1);
+    assert_eq!(stats.compressed, 0);
+    assert_eq!(stats.raw_bytes, stats.stored_bytes);
+    let log = fs::read_to_string(temp_dir.path().join("log.json"))?;
+    assert!(!log.contains("codec"));
+    Ok(())
+}
+
+// Compaction copies compressed records as they are
+#[test]
+fn compressed_records_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_with(temp_dir.path(), Compression::Lz4)?;
+    for iter in 0..20 {
+        for id in 0..20 {
+            store.set(format!("doc{}", id), document(iter * 100 + id))?;
+        }
+    }
+    for id in 0..20 {
+        assert_eq!(store.get(format!("doc{}", id))?, Some(document(1900 + id)));
+    }
+    Ok(())
+}
@@ -0,0 +1,447 @@
+use assert_cmd::prelude::*;
+use kvs::config::Config;
+use kvs::migrate::detect_engine;
+use kvs::{Durability, KvsClient, MemoryKvsEngine, Result, Server};
+use log::LevelFilter;
+use predicates::str::contains;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn write_config(dir: &TempDir, content: &str) -> std::path::PathBuf {
+    let path = dir.path().join("kvs.toml");
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn from_vars(vars: &[(&str, &str)]) -> Result<Config> {
+    let vars: HashMap<String, String> = vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    Config::from_vars(|name| vars.get(name).cloned())
+}
+
+// Every setting can be read from a file
+#[test]
+fn config_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = write_config(
+        &temp_dir,
+        r#"
+        data_dir = "/var/lib/kvs"
+        addr = "0.0.0.0:4000"
+        metrics_addr = "127.0.0.1:9100"
+        engine = "sled"
+        threads = 8
+        sync = "every-100ms"
+        compaction_threshold = 4096
+        memtable_bytes = 65536
+        log_level = "warn"
+        max_connections = 16
+        idle_timeout = 30
+        max_key_bytes = 256
+        max_value_bytes = 1024
+        "#,
+    );
+    let config = Config::from_file(&path)?;
+    config.validate()?;
+    assert_eq!(config.data_dir, Some("/var/lib/kvs".into()));
+    assert_eq!(config.addr, Some("0.0.0.0:4000".parse().unwrap()));
+    assert_eq!(config.metrics_addr, Some("127.0.0.1:9100".parse().unwrap()));
+    assert_eq!(config.engine.as_deref(), Some("sled"));
+    assert_eq!(config.threads, Some(8));
+    assert_eq!(
+        config.sync,
+        Some(Durability::Interval(Duration::from_millis(100)))
+    );
+    assert_eq!(config.compaction_threshold, Some(4096));
+    assert_eq!(config.memtable_bytes, Some(65536));
+    assert_eq!(config.log_level, Some(LevelFilter::Warn));
+    assert_eq!(config.max_connections, Some(16));
+    assert_eq!(config.idle_timeout, Some(30));
+    assert_eq!(config.max_key_bytes, Some(256));
+    assert_eq!(config.max_value_bytes, Some(1024));
+
+    assert_eq!(
+        Config::from_file(write_config(&temp_dir, ""))?,
+        Config::default()
+    );
+    assert!(Config::from_file(temp_dir.path().join("missing.toml")).is_err());
+    Ok(())
+}
+
+// Malformed files, and settings out of range, are rejected
+#[test]
+fn invalid_config() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let unreadable = [
+        "port = 4000",
+        "addr = \"localhost\"",
+        "sync = \"sometimes\"",
+        "log_level = \"loud\"",
+        "threads = -1",
+        "threads = \"many\"",
+        "engine = ",
+    ];
+    for content in unreadable.iter() {
+        assert!(
+            Config::from_file(write_config(&temp_dir, content)).is_err(),
+            "{} was accepted",
+            content
+        );
+    }
+
+    let invalid = [
+        "engine = \"rocksdb\"",
+        "threads = 0",
+        "max_connections = 0",
+        "idle_timeout = 0",
+        "compaction_threshold = 0",
+        "addr = \"127.0.0.1:4000\"\nmetrics_addr = \"127.0.0.1:4000\"",
+    ];
+    for content in invalid.iter() {
+        let config = Config::from_file(write_config(&temp_dir, content)).unwrap();
+        assert!(config.validate().is_err(), "{} was accepted", content);
+    }
+
+    let file = write_config(&temp_dir, "");
+    let config = Config {
+        data_dir: Some(file),
+        ..Config::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+// KVS_* variables are read, empty ones are ignored
+#[test]
+fn environment() -> Result<()> {
+    let config = from_vars(&[
+        ("KVS_ADDR", "127.0.0.1:5000"),
+        ("KVS_ENGINE", "lsm"),
+        ("KVS_THREADS", "4"),
+        ("KVS_SYNC", "every-write"),
+        ("KVS_LOG_LEVEL", "debug"),
+        ("KVS_MAX_VALUE_BYTES", ""),
+        ("ADDR", "127.0.0.1:6000"),
+    ])?;
+    assert_eq!(config.addr, Some("127.0.0.1:5000".parse().unwrap()));
+    assert_eq!(config.engine.as_deref(), Some("lsm"));
+    assert_eq!(config.threads, Some(4));
+    assert_eq!(config.sync, Some(Durability::EveryWrite));
+    assert_eq!(config.log_level, Some(LevelFilter::Debug));
+    assert_eq!(config.max_value_bytes, None);
+
+    let err = from_vars(&[("KVS_THREADS", "four")]).unwrap_err();
+    assert!(err.to_string().contains("KVS_THREADS"));
+    Ok(())
+}
+
+// Flags override the environment, which overrides the file
+#[test]
+fn precedence() -> Result<()> {
+    let file = Config {
+        addr: Some("127.0.0.1:1000".parse().unwrap()),
+        engine: Some("kvs".to_owned()),
+        threads: Some(2),
+        ..Config::default()
+    };
+    let env = from_vars(&[("KVS_ADDR", "127.0.0.1:2000"), ("KVS_ENGINE", "sled")])?;
+    let flags = Config {
+        addr: Some("127.0.0.1:3000".parse().unwrap()),
+        ..Config::default()
+    };
+    let config = file.merge(env).merge(flags);
+    assert_eq!(config.addr, Some("127.0.0.1:3000".parse().unwrap()));
+    assert_eq!(config.engine.as_deref(), Some("sled"));
+    assert_eq!(config.threads, Some(2));
+    Ok(())
+}
+
+/// Starts a server on a free port, left running until the test exits.
+fn start_server(server: Server<MemoryKvsEngine>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+// Keys and values over the limits are rejected
+#[test]
+fn request_limits() -> Result<()> {
+    let server = Server::new(MemoryKvsEngine::new())
+        .max_key_bytes(4)
+        .max_value_bytes(8);
+    let addr = start_server(server);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.set("key12".to_owned(), "value".to_owned()).is_err());
+    assert!(client
+        .set("key2".to_owned(), "value12345".to_owned())
+        .is_err());
+    assert!(client.get("key12".to_owned()).is_err());
+    assert!(client.remove("key12".to_owned()).is_err());
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Connections over the limit are closed
+#[test]
+fn connection_limit() -> Result<()> {
+    let addr = start_server(Server::new(MemoryKvsEngine::new()).max_connections(1));
+
+    let mut first = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    let refused = KvsClient::connect(addr).and_then(|mut client| client.get("key1".to_owned()));
+    assert!(refused.is_err());
+    drop(first);
+
+    // The server notices the first connection is closed shortly after
+    let mut attempts = 0;
+    loop {
+        match KvsClient::connect(addr).and_then(|mut client| client.get("key1".to_owned())) {
+            Ok(value) => {
+                assert_eq!(value, Some("value1".to_owned()));
+                break;
+            }
+            Err(_) if attempts < 50 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// A pool of threads serves connections one after the other
+#[test]
+fn thread_pool() -> Result<()> {
+    let addr = start_server(Server::new(MemoryKvsEngine::new()).threads(2));
+
+    let mut first = KvsClient::connect(addr)?;
+    let mut second = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(first);
+    drop(second);
+    for i in 0..10 {
+        let mut client = KvsClient::connect(addr)?;
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    Ok(())
+}
+
+// Connections beyond the threads of the pool wait for one to close
+#[test]
+fn thread_pool_bounded() -> Result<()> {
+    let addr = start_server(Server::new(MemoryKvsEngine::new()).threads(1));
+
+    let mut first = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let value = KvsClient::connect(addr).and_then(|mut client| client.get("key1".to_owned()));
+        sender.send(value).unwrap();
+    });
+    assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+    drop(first);
+    let value = receiver.recv_timeout(Duration::from_secs(5)).unwrap()?;
+    assert_eq!(value, Some("value1".to_owned()));
+    Ok(())
+}
+
+// An idle connection is closed, which frees its thread
+#[test]
+fn idle_timeout() -> Result<()> {
+    let server = Server::new(MemoryKvsEngine::new())
+        .threads(1)
+        .idle_timeout(Duration::from_millis(200));
+    let addr = start_server(server);
+
+    let mut first = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let value = KvsClient::connect(addr).and_then(|mut client| client.get("key1".to_owned()));
+        sender.send(value).unwrap();
+    });
+    let value = receiver.recv_timeout(Duration::from_secs(5)).unwrap()?;
+    assert_eq!(value, Some("value1".to_owned()));
+    assert!(first.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Flags override KVS_* variables, which override the configuration file
+#[test]
+fn cli_precedence() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let config = format!(
+        "addr = \"127.0.0.1:4019\"\nengine = \"lsm\"\ndata_dir = {:?}\n",
+        data_dir.to_str().unwrap()
+    );
+    let path = write_config(&temp_dir, &config);
+
+    let addr = "127.0.0.1:4018";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .arg("--config")
+        .arg(&path)
+        .args(["--addr", addr])
+        .env("KVS_ADDR", "127.0.0.1:4017")
+        .env("KVS_ENGINE", "sled")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(detect_engine(&data_dir).unwrap().as_deref(), Some("sled"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Invalid settings stop the server before it starts
+#[test]
+fn cli_invalid_settings() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = write_config(&temp_dir, "threads = 0");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("threads must be at least 1"));
+
+    let path = write_config(&temp_dir, "treads = 2");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .env("KVS_CONFIG", &path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `treads`"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .env("KVS_MAX_CONNECTIONS", "lots")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("KVS_MAX_CONNECTIONS"));
+}
+
+// The store is kept in --data-dir rather than the working directory
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let work_dir = temp_dir.path().join("work");
+    fs::create_dir(&work_dir).unwrap();
+
+    let addr = "127.0.0.1:4020";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&work_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&work_dir)
+        .assert()
+        .success();
+    assert_eq!(detect_engine(&data_dir).unwrap().as_deref(), Some("kvs"));
+    assert!(data_dir.join("log.json").exists());
+    assert_eq!(fs::read_dir(&work_dir).unwrap().count(), 0);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// The server refuses to start on a data directory it cannot write to
+#[test]
+fn cli_unwritable_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let file = write_config(&temp_dir, "");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4021", "--data-dir"])
+        .arg(file.join("data"))
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("is not writable"));
+    assert!(TcpListener::bind("127.0.0.1:4021").is_ok());
+}
+
+// The server refuses to start without a data directory, rather than
+// writing to whatever its working directory happens to be
+#[test]
+fn cli_missing_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4021"])
+        .env_remove("KVS_DATA_DIR")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("No data directory set"));
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+    assert!(TcpListener::bind("127.0.0.1:4021").is_ok());
+}
+
+// Only directories which accept new files pass the check
+#[cfg(unix)]
+#[test]
+fn read_only_data_dir() {
+    use kvs::config::check_data_dir;
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("data");
+    check_data_dir(&dir).unwrap();
+    assert!(dir.is_dir());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+    // Permissions do not apply to root
+    let enforced = fs::write(dir.join("probe"), b"").is_err();
+    if enforced {
+        assert!(check_data_dir(&dir).is_err());
+    }
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+}
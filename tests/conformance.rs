@@ -0,0 +1,83 @@
+// Every engine shipped with kvs should pass the conformance suite
+
+mod kvs_store {
+    kvs::engine_conformance_tests!(|path| kvs::KvStore::open(path));
+}
+
+// A tiny index, so that the checks go through the spill files
+mod kvs_store_bounded_index {
+    kvs::engine_conformance_tests!(|path| {
+        let options = kvs::KvStoreOptions::default().index_memory(512);
+        kvs::KvStore::open_with(path, options)
+    });
+}
+
+// A small value cache, so that the checks go through evictions
+mod kvs_store_value_cache {
+    kvs::engine_conformance_tests!(|path| {
+        let options = kvs::KvStoreOptions::default().cache_bytes(1024);
+        kvs::KvStore::open_with(path, options)
+    });
+}
+
+mod kvs_store_pread {
+    kvs::engine_conformance_tests!(|path| {
+        let options = kvs::KvStoreOptions::default().mmap(false);
+        kvs::KvStore::open_with(path, options)
+    });
+}
+
+mod kvs_store_zstd {
+    kvs::engine_conformance_tests!(|path| {
+        let options = kvs::KvStoreOptions::default().compression(kvs::Compression::Zstd);
+        kvs::KvStore::open_with(path, options)
+    });
+}
+
+mod kvs_store_encrypted {
+    kvs::engine_conformance_tests!(|path| {
+        let key = kvs::EncryptionKey::new(kvs::Cipher::ChaCha20Poly1305, &[7; 32])?;
+        let options = kvs::KvStoreOptions::default().encryption(key);
+        kvs::KvStore::open_with(path, options)
+    });
+}
+
+// A namespace should behave as a store of its own
+mod kvs_store_namespace {
+    use kvs::KvsEngine;
+    kvs::engine_conformance_tests!(|path| kvs::KvStore::open(path)?.namespace("tenant"));
+}
+
+mod sled_engine {
+    kvs::engine_conformance_tests!(|path| kvs::SledKvsEngine::open(path));
+}
+
+mod sled_engine_namespace {
+    use kvs::KvsEngine;
+    kvs::engine_conformance_tests!(|path| kvs::SledKvsEngine::open(path)?.namespace("tenant"));
+}
+
+// Small tables, so that the checks go through flushes and compactions
+mod lsm_engine {
+    kvs::engine_conformance_tests!(|path| {
+        let options = kvs::LsmOptions::default()
+            .memtable_bytes(1024)
+            .table_bytes(2048)
+            .level_base_bytes(8192);
+        kvs::LsmKvsEngine::open_with(path, options)
+    });
+}
+
+mod memory_engine {
+    kvs::engine_conformance_tests!(|path| kvs::MemoryKvsEngine::open(path));
+}
+
+#[test]
+fn memory_without_snapshot_file() -> kvs::Result<()> {
+    let open = |_: &std::path::Path| Ok(kvs::MemoryKvsEngine::new());
+    kvs::conformance::get_stored_value(open)?;
+    kvs::conformance::remove_non_existent_key(open)?;
+    kvs::conformance::snapshot_isolation(open)?;
+    kvs::conformance::scan_ranges(open)?;
+    kvs::conformance::concurrent_writers(open)
+}
@@ -0,0 +1,166 @@
+use kvs::{dump, DumpFormat, KvStore, KvsEngine, MemoryKvsEngine, Result, SledKvsEngine};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn fill<E: KvsEngine>(engine: &mut E) -> Result<()> {
+    for key_id in 0..2000 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    engine.set_bytes(vec![0, 255, 10], vec![13, 10, 0, 200])?;
+    engine.set_bytes(Vec::new(), Vec::new())?;
+    engine.remove("key0".to_owned())
+}
+
+fn check<E: KvsEngine>(engine: &mut E) -> Result<()> {
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        engine.get("key1999".to_owned())?,
+        Some("value1999".to_owned())
+    );
+    assert_eq!(engine.get_bytes(&[0, 255, 10])?, Some(vec![13, 10, 0, 200]));
+    assert_eq!(engine.get_bytes(&[])?, Some(Vec::new()));
+    Ok(())
+}
+
+fn round_trip<S, T, F, G>(open_source: F, open_target: G, format: DumpFormat) -> Result<()>
+where
+    S: KvsEngine,
+    T: KvsEngine,
+    F: Fn(&Path) -> Result<S>,
+    G: Fn(&Path) -> Result<T>,
+{
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = open_source(source_dir.path())?;
+    fill(&mut source)?;
+    source
+        .namespace("app1")?
+        .set("key1".to_owned(), "app1".to_owned())?;
+
+    let mut buffer = Vec::new();
+    let exported = dump::export_all(&mut source, &mut buffer, format)?;
+    assert_eq!(exported, 2002);
+
+    let mut target = open_target(target_dir.path())?;
+    let imported = dump::import(&mut target, buffer.as_slice(), format)?;
+    assert_eq!(imported, exported);
+    check(&mut target)?;
+
+    // Open from disk again and check persistent data
+    drop(target);
+    let mut target = open_target(target_dir.path())?;
+    check(&mut target)?;
+    assert_eq!(target.namespaces()?, vec!["app1".to_owned()]);
+    let mut app1 = target.namespace("app1")?;
+    assert_eq!(app1.get("key1".to_owned())?, Some("app1".to_owned()));
+    assert_eq!(app1.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn kvs_to_sled() -> Result<()> {
+    round_trip(
+        |path| KvStore::open(path),
+        |path| SledKvsEngine::open(path),
+        DumpFormat::JsonLines,
+    )?;
+    round_trip(
+        |path| KvStore::open(path),
+        |path| SledKvsEngine::open(path),
+        DumpFormat::Binary,
+    )
+}
+
+#[test]
+fn sled_to_kvs() -> Result<()> {
+    round_trip(
+        |path| SledKvsEngine::open(path),
+        |path| KvStore::open(path),
+        DumpFormat::JsonLines,
+    )?;
+    round_trip(
+        |path| SledKvsEngine::open(path),
+        |path| KvStore::open(path),
+        DumpFormat::Binary,
+    )
+}
+
+// A binary dump missing its end marker should be rejected
+#[test]
+fn truncated_binary_dump() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = KvStore::open(source_dir.path())?;
+    source.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut buffer = Vec::new();
+    dump::export(&mut source.snapshot()?, &mut buffer, DumpFormat::Binary)?;
+    buffer.truncate(buffer.len() - 2);
+
+    let mut target = KvStore::open(target_dir.path())?;
+    assert!(dump::import(&mut target, buffer.as_slice(), DumpFormat::Binary).is_err());
+    Ok(())
+}
+
+// Binary dumps written before namespaces are still read, and dumps with
+// namespaces are refused by engines without them
+#[test]
+fn binary_dump_versions() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut v1 = b"KVSDUMP\x01".to_vec();
+    for chunk in [&b"key1"[..], b"value1"] {
+        v1.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        v1.extend_from_slice(chunk);
+    }
+    v1.extend_from_slice(&u32::MAX.to_le_bytes());
+    let mut target = KvStore::open(target_dir.path())?;
+    assert_eq!(
+        dump::import(&mut target, v1.as_slice(), DumpFormat::Binary)?,
+        1
+    );
+    assert_eq!(target.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut source = KvStore::open(source_dir.path())?;
+    source
+        .namespace("app1")?
+        .set("key1".to_owned(), "app1".to_owned())?;
+    let mut buffer = Vec::new();
+    dump::export_all(&mut source, &mut buffer, DumpFormat::Binary)?;
+    let mut memory = MemoryKvsEngine::new();
+    assert!(dump::import(&mut memory, buffer.as_slice(), DumpFormat::Binary).is_err());
+    Ok(())
+}
+
+// JSON Lines carry bytes which are not valid UTF-8 in base64, under a tag
+#[test]
+fn json_lines_base64() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = KvStore::open(source_dir.path())?;
+    source.set_bytes(vec![0, 255, 10], vec![13, 10, 0, 200])?;
+    source.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut buffer = Vec::new();
+    dump::export(&mut source.snapshot()?, &mut buffer, DumpFormat::JsonLines)?;
+    let text = String::from_utf8(buffer).unwrap();
+    assert_eq!(
+        text.lines().collect::<Vec<_>>(),
+        vec![
+            r#"{"key":{"b64":"AP8K"},"value":{"b64":"DQoAyA=="}}"#,
+            r#"{"key":"key1","value":"value1"}"#,
+        ]
+    );
+
+    // Arrays of numbers, as written by earlier versions, are still read
+    let legacy = "{\"key\":[0,255,11],\"value\":[200]}\n";
+    let mut target = KvStore::open(target_dir.path())?;
+    dump::import(&mut target, text.as_bytes(), DumpFormat::JsonLines)?;
+    dump::import(&mut target, legacy.as_bytes(), DumpFormat::JsonLines)?;
+    assert_eq!(target.get_bytes(&[0, 255, 10])?, Some(vec![13, 10, 0, 200]));
+    assert_eq!(target.get_bytes(&[0, 255, 11])?, Some(vec![200]));
+    let invalid = "{\"key\":{\"b64\":\"not base64!\"},\"value\":\"\"}\n";
+    assert!(dump::import(&mut target, invalid.as_bytes(), DumpFormat::JsonLines).is_err());
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine, Result, SledKvsEngine};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn parse_durability() {
+    for policy in &["none", "every-write", "every-250ms", "group-commit"] {
+        let durability: Durability = policy.parse().unwrap();
+        assert_eq!(durability.to_string(), *policy);
+    }
+    assert_eq!(
+        "every-250ms".parse::<Durability>().unwrap(),
+        Durability::Interval(Duration::from_millis(250))
+    );
+    assert!("every-0ms".parse::<Durability>().is_err());
+    assert!("sometimes".parse::<Durability>().is_err());
+}
+
+// Concurrent writers on clones of the same store should all be acknowledged and persisted
+fn concurrent_writers<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine + Clone + Send + 'static,
+    F: Fn() -> Result<E>,
+{
+    let engine = open()?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let mut engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..50 {
+                    engine.set(
+                        format!("key{}-{}", thread_id, key_id),
+                        format!("value{}", key_id),
+                    )?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(engine);
+
+    let mut engine = open()?;
+    for thread_id in 0..8 {
+        for key_id in 0..50 {
+            assert_eq!(
+                engine.get(format!("key{}-{}", thread_id, key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn kvs_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().durability(Durability::GroupCommit);
+    concurrent_writers(|| KvStore::open_with(temp_dir.path(), options.clone()))
+}
+
+#[test]
+fn kvs_every_write_and_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().durability(Durability::EveryWrite);
+    concurrent_writers(|| KvStore::open_with(temp_dir.path(), options.clone()))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options =
+        KvStoreOptions::default().durability(Durability::Interval(Duration::from_millis(10)));
+    concurrent_writers(|| KvStore::open_with(temp_dir.path(), options.clone()))
+}
+
+#[test]
+fn sled_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_writers(|| SledKvsEngine::open_with(temp_dir.path(), Durability::GroupCommit))
+}
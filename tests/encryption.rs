@@ -0,0 +1,266 @@
+use kvs::backup;
+use kvs::fsck;
+use kvs::{Cipher, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open_encrypted(path: &Path, key: &EncryptionKey) -> Result<KvStore> {
+    KvStore::open_with(path, KvStoreOptions::default().encryption(key.clone()))
+}
+
+fn read_log(path: &Path) -> String {
+    fs::read_to_string(path.join("log.json")).unwrap()
+}
+
+// Keys and values should not appear in the log, and read back with the key
+fn round_trip(cipher: Cipher) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate(cipher);
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    store.set("secret-key".to_owned(), "secret-value".to_owned())?;
+    store.set("other-key".to_owned(), "other-value".to_owned())?;
+    store.remove("other-key".to_owned())?;
+    store.set("secret-key".to_owned(), "secret-value2".to_owned())?;
+    drop(store);
+
+    let log = read_log(temp_dir.path());
+    assert!(!log.contains("secret"));
+    assert!(!log.contains("other"));
+    assert!(log.contains(key.id()));
+
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value2".to_owned())
+    );
+    assert_eq!(store.get("other-key".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn round_trip_aes_gcm() -> Result<()> {
+    round_trip(Cipher::Aes256Gcm)
+}
+
+#[test]
+fn round_trip_chacha20_poly1305() -> Result<()> {
+    round_trip(Cipher::ChaCha20Poly1305)
+}
+
+// An encrypted log should not open without its key
+#[test]
+fn missing_or_wrong_key_fails() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate(Cipher::Aes256Gcm);
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let other = EncryptionKey::generate(Cipher::Aes256Gcm);
+    assert!(open_encrypted(temp_dir.path(), &other).is_err());
+    assert!(open_encrypted(temp_dir.path(), &key).is_ok());
+    Ok(())
+}
+
+// A record which does not authenticate should fail the store, not be skipped
+#[test]
+fn tampered_record_fails() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate(Cipher::ChaCha20Poly1305);
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = read_log(temp_dir.path());
+    let data = log.find(r#""data":""#).unwrap() + 8;
+    let mut tampered = log.into_bytes();
+    tampered[data] = if tampered[data] == b'A' { b'B' } else { b'A' };
+    fs::write(temp_dir.path().join("log.json"), tampered)?;
+
+    assert!(open_encrypted(temp_dir.path(), &key).is_err());
+    Ok(())
+}
+
+// A sealed record is bound to its offset: moved or duplicated, it does not authenticate
+#[test]
+fn moved_record_fails() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate(Cipher::Aes256Gcm);
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Replaying the first value after the second one
+    let log = read_log(temp_dir.path());
+    let first = log.split("\r\n").nth(1).unwrap();
+    fs::write(
+        temp_dir.path().join("log.json"),
+        format!("{}\r\n{}", log, first),
+    )?;
+    assert!(open_encrypted(temp_dir.path(), &key).is_err());
+
+    // Shifting every record
+    fs::write(temp_dir.path().join("log.json"), format!(" {}", log))?;
+    assert!(open_encrypted(temp_dir.path(), &key).is_err());
+
+    fs::write(temp_dir.path().join("log.json"), log)?;
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.compact()?;
+    drop(store);
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A record is opened with the cipher of its key, not the one it names
+#[test]
+fn relabelled_cipher_fails() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate(Cipher::ChaCha20Poly1305);
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = read_log(temp_dir.path()).replace("chacha20-poly1305", "aes-256-gcm");
+    fs::write(temp_dir.path().join("log.json"), log)?;
+    assert!(open_encrypted(temp_dir.path(), &key).is_err());
+    Ok(())
+}
+
+// Plaintext records are rejected once a key is set, unless asked to encrypt them
+#[test]
+fn encrypt_existing_plaintext() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("plain-key".to_owned(), "plain-value".to_owned())?;
+    drop(store);
+
+    let key = EncryptionKey::generate(Cipher::Aes256Gcm);
+    assert!(open_encrypted(temp_dir.path(), &key).is_err());
+
+    let options = KvStoreOptions::default()
+        .encryption(key.clone())
+        .encrypt_plaintext(true);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.compact()?;
+    drop(store);
+    assert!(!read_log(temp_dir.path()).contains("plain"));
+
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    assert_eq!(
+        store.get("plain-key".to_owned())?,
+        Some("plain-value".to_owned())
+    );
+    Ok(())
+}
+
+// Compaction should re-seal every live record with the new key
+#[test]
+fn key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old = EncryptionKey::generate(Cipher::Aes256Gcm);
+    let new = EncryptionKey::generate(Cipher::ChaCha20Poly1305);
+    let mut store = open_encrypted(temp_dir.path(), &old)?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions::default()
+        .encryption(new.clone())
+        .previous_key(old.clone());
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key50".to_owned(), "value50".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let log = read_log(temp_dir.path());
+    assert!(!log.contains(old.id()));
+    assert!(open_encrypted(temp_dir.path(), &old).is_err());
+    let mut store = open_encrypted(temp_dir.path(), &new)?;
+    for key_id in 0..=50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Keys are read as base64 from files and environment variables
+#[test]
+fn key_sources() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate(Cipher::Aes256Gcm);
+    let path = temp_dir.path().join("key");
+    fs::write(&path, format!("{}\n", key.to_base64()))?;
+    assert_eq!(
+        EncryptionKey::from_file(Cipher::Aes256Gcm, &path)?.id(),
+        key.id()
+    );
+
+    std::env::set_var("KVS_TEST_ENCRYPTION_KEY", key.to_base64());
+    assert_eq!(
+        EncryptionKey::from_env(Cipher::Aes256Gcm, "KVS_TEST_ENCRYPTION_KEY")?.id(),
+        key.id()
+    );
+    assert!(EncryptionKey::from_env(Cipher::Aes256Gcm, "KVS_TEST_UNSET_KEY").is_err());
+    assert!(EncryptionKey::from_base64(Cipher::Aes256Gcm, "c2hvcnQ=").is_err());
+    assert!(!format!("{:?}", key).contains(&key.to_base64()));
+    Ok(())
+}
+
+// Backups of an encrypted store stay encrypted
+#[test]
+fn encrypted_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate(Cipher::Aes256Gcm);
+    let mut store = open_encrypted(temp_dir.path(), &key)?;
+    store.set("secret-key".to_owned(), "secret-value".to_owned())?;
+    let dest = backup_dir.path().join("backup");
+    store.backup(&dest)?;
+    drop(store);
+
+    assert!(!read_log(&dest).contains("secret"));
+    assert!(backup::verify(&dest).is_err());
+    let options = KvStoreOptions::default().encryption(key);
+    assert_eq!(backup::verify_with(&dest, options)?.keys, 1);
+
+    let report = fsck::check(temp_dir.path())?;
+    assert_eq!(report.sealed, 1);
+    assert!(fsck::repair(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Keys spilled from a bounded index should not appear in the spill files
+#[test]
+fn encrypted_index_spill() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate(Cipher::ChaCha20Poly1305);
+    let options = KvStoreOptions::default().encryption(key).index_memory(4096);
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..2000 {
+        store.set(format!("secret{:04}", key_id), "value".to_owned())?;
+    }
+    assert!(store.index_stats().cold_keys > 0);
+
+    let mut spilled = 0;
+    for entry in fs::read_dir(temp_dir.path().join("index"))? {
+        let bytes = fs::read(entry?.path())?;
+        assert!(!String::from_utf8_lossy(&bytes).contains("secret"));
+        spilled += 1;
+    }
+    assert!(spilled > 0);
+    for key_id in (0..2000).step_by(7) {
+        assert_eq!(
+            store.get(format!("secret{:04}", key_id))?,
+            Some("value".to_owned())
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use kvs::{fsck, KvStore, KvsEngine, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// A log written by KvStore should be clean, with stale bytes accounted for
+#[test]
+fn check_clean_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let report = fsck::check(temp_dir.path())?;
+    assert!(report.is_clean());
+    assert_eq!(report.sets, 3);
+    assert_eq!(report.removes, 1);
+    assert_eq!(report.live_keys, 1);
+    assert!(report.live_bytes > 0 && report.stale_bytes > 0);
+    assert!(report.live_bytes + report.stale_bytes <= report.log_bytes);
+    Ok(())
+}
+
+// Corrupt records should be reported and the readable ones salvaged
+#[test]
+fn repair_corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("log.json");
+    let content = fs::read_to_string(&log)?.replace("\"key\":\"key2\"", "\"kex\":\"key2\"");
+    fs::write(&log, &content)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = fsck::check(temp_dir.path())?;
+    assert!(!report.is_clean());
+    assert_eq!(report.corrupt_ranges.len(), 1);
+    assert_eq!(report.live_keys, 2);
+
+    fsck::repair(temp_dir.path())?;
+    assert!(temp_dir.path().join("log.json.corrupt").exists());
+    assert!(fsck::check(temp_dir.path())?.is_clean());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
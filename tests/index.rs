@@ -0,0 +1,204 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsSnapshot, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const INDEX_MEMORY: u64 = 4096;
+
+fn open_bounded(path: &Path) -> Result<KvStore> {
+    KvStore::open_with(path, KvStoreOptions::default().index_memory(INDEX_MEMORY))
+}
+
+// Keys beyond the memory bound should be spilled and still be found
+#[test]
+fn spilled_keys_are_found() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_bounded(temp_dir.path())?;
+    for key_id in 0..2000 {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+
+    let stats = store.index_stats();
+    assert!(stats.spills > 0, "{:?}", stats);
+    assert!(stats.cold_keys > 0, "{:?}", stats);
+    assert!(stats.hot_bytes <= INDEX_MEMORY, "{:?}", stats);
+
+    for key_id in 0..2000 {
+        assert_eq!(
+            store.get(format!("key{:04}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("key2000".to_owned())?, None);
+
+    let stats = store.index_stats();
+    assert!(stats.misses > 0, "{:?}", stats);
+    assert!(stats.hot_bytes <= INDEX_MEMORY, "{:?}", stats);
+    assert!(stats.hit_rate() < 1.0);
+    Ok(())
+}
+
+// Recently read keys should stay in memory
+#[test]
+fn hot_keys_stay_in_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_bounded(temp_dir.path())?;
+    for key_id in 0..2000 {
+        store.set(format!("key{:04}", key_id), "value".to_owned())?;
+    }
+
+    store.get("key0000".to_owned())?;
+    let before = store.index_stats();
+    for _ in 0..100 {
+        store.get("key0000".to_owned())?;
+    }
+    let after = store.index_stats();
+    assert_eq!(after.hits, before.hits + 100);
+    assert_eq!(after.misses, before.misses);
+    Ok(())
+}
+
+// Overwrites and removals of spilled keys should survive compaction and reopen
+#[test]
+fn spilled_keys_survive_compaction_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_bounded(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..1000 {
+            store.set(format!("key{:04}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in (0..1000).step_by(3) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+    assert!(store.remove("key0000".to_owned()).is_err());
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            let expected = if key_id % 3 == 0 {
+                None
+            } else {
+                Some("value2".to_owned())
+            };
+            assert_eq!(store.get(format!("key{:04}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+
+    let mut store = open_bounded(temp_dir.path())?;
+    check(&mut store)?;
+    drop(store);
+
+    // The bound is not part of the data: the store opens without one too
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)
+}
+
+// Snapshots should scan spilled and in-memory keys in order
+#[test]
+fn snapshot_scan_merges_spilled_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_bounded(temp_dir.path())?;
+    for key_id in (0..1500).rev() {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..1500).step_by(2) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+
+    let mut snapshot = store.snapshot()?;
+    store.set("key0000".to_owned(), "after".to_owned())?;
+    store.remove("key0001".to_owned())?;
+
+    let entries = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    let expected = (1..1500)
+        .step_by(2)
+        .map(|key_id| {
+            (
+                format!("key{:04}", key_id).into_bytes(),
+                format!("value{}", key_id).into_bytes(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(entries, expected);
+    assert_eq!(snapshot.get("key0000".to_owned())?, None);
+    assert_eq!(
+        snapshot.get("key0001".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// Snapshots share the keys in memory with the store, which copies them when
+// it changes them, so each snapshot keeps its own view
+#[test]
+fn snapshots_keep_their_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), "first".to_owned())?;
+    }
+    let mut first = store.snapshot()?;
+    assert_eq!(store.get("key000".to_owned())?, Some("first".to_owned()));
+    for key_id in 0..50 {
+        store.set(format!("key{:03}", key_id), "second".to_owned())?;
+    }
+    store.remove("key099".to_owned())?;
+    let mut second = store.snapshot()?;
+    store.set("key100".to_owned(), "third".to_owned())?;
+    store.remove("key000".to_owned())?;
+
+    assert_eq!(first.iter().count(), 100);
+    assert_eq!(first.get("key000".to_owned())?, Some("first".to_owned()));
+    assert_eq!(first.get("key099".to_owned())?, Some("first".to_owned()));
+    assert_eq!(second.iter().count(), 99);
+    assert_eq!(second.get("key000".to_owned())?, Some("second".to_owned()));
+    assert_eq!(second.get("key050".to_owned())?, Some("first".to_owned()));
+    assert_eq!(second.get("key099".to_owned())?, None);
+    assert_eq!(second.get("key100".to_owned())?, None);
+    assert_eq!(store.get("key000".to_owned())?, None);
+    assert_eq!(store.get("key100".to_owned())?, Some("third".to_owned()));
+    Ok(())
+}
+
+// Spills should add small spill files instead of rewriting a single one, and
+// keep few of them, with the newest file holding a key winning
+#[test]
+fn spills_append_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_bounded(temp_dir.path())?;
+    let spill_files =
+        || -> Result<usize> { Ok(fs::read_dir(temp_dir.path().join("index"))?.count()) };
+    let mut most_files = 0;
+    for key_id in 0..5000 {
+        store.set(format!("key{:04}", key_id), "first".to_owned())?;
+        if key_id % 100 == 99 {
+            most_files = most_files.max(spill_files()?);
+        }
+    }
+    assert!(most_files > 1, "{}", most_files);
+    assert!(most_files <= 16, "{}", most_files);
+
+    for key_id in (0..5000).step_by(2) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+    for key_id in (1..5000).step_by(4) {
+        store.set(format!("key{:04}", key_id), "second".to_owned())?;
+    }
+    assert!(spill_files()? <= 16);
+
+    let mut snapshot = store.snapshot()?;
+    let entries = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 2500);
+    for key_id in 0..5000 {
+        let expected = match key_id % 4 {
+            1 => Some("second".to_owned()),
+            3 => Some("first".to_owned()),
+            _ => None,
+        };
+        assert_eq!(store.get(format!("key{:04}", key_id))?, expected);
+    }
+    Ok(())
+}
@@ -124,3 +124,37 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should store and reload keys and values which are not valid UTF-8
+#[test]
+fn binary_key_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let key = vec![0, 159, 146, 150];
+    let value = vec![255, 0, 10, 13, 34];
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(store.get_bytes(b"key1")?, Some(b"value1".to_vec()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.remove_bytes(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+
+    Ok(())
+}
+
+// The String API should report values which are not valid UTF-8
+#[test]
+fn get_non_utf8_value_as_string() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(b"key1".to_vec(), vec![0xff, 0xfe])?;
+    assert!(store.get("key1".to_owned()).is_err());
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use kvs::{key_range, KvsEngine, KvsSnapshot, LsmKvsEngine, LsmOptions, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open_small(path: &Path) -> Result<LsmKvsEngine> {
+    let options = LsmOptions::default()
+        .memtable_bytes(4096)
+        .table_bytes(4096)
+        .level_base_bytes(16 * 1024);
+    LsmKvsEngine::open_with(path, options)
+}
+
+fn table_files(path: &Path) -> usize {
+    fs::read_dir(path.join("lsm"))
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+        .count()
+}
+
+// Writes should be flushed to level 0 and compacted into deeper levels
+#[test]
+fn leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_small(temp_dir.path())?;
+    for iter in 0..5 {
+        for key_id in 0..1000 {
+            store.set(
+                format!("key{:04}", key_id),
+                format!("value{}-{}", iter, key_id),
+            )?;
+        }
+    }
+
+    let levels = store.tables_per_level();
+    assert!(levels[0] < 4, "level 0 should be compacted: {:?}", levels);
+    assert!(levels[1..].iter().sum::<usize>() > 0);
+    assert!(levels[2..].iter().sum::<usize>() > 0, "{:?}", levels);
+    assert_eq!(table_files(temp_dir.path()), levels.iter().sum::<usize>());
+
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{:04}", key_id))?,
+            Some(format!("value4-{}", key_id))
+        );
+    }
+    drop(store);
+
+    let mut store = open_small(temp_dir.path())?;
+    assert_eq!(
+        store.get("key0999".to_owned())?,
+        Some("value4-999".to_owned())
+    );
+    assert_eq!(store.snapshot()?.iter().count(), 1000);
+    Ok(())
+}
+
+// Removed keys should stay removed once their tombstones reach the tables
+#[test]
+fn tombstones_shadow_older_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_small(temp_dir.path())?;
+    for key_id in 0..500 {
+        store.set(format!("key{:04}", key_id), "value".to_owned())?;
+    }
+    store.flush()?;
+    for key_id in (0..500).step_by(2) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+    store.flush()?;
+
+    assert_eq!(store.get("key0000".to_owned())?, None);
+    assert_eq!(store.get("key0001".to_owned())?, Some("value".to_owned()));
+    assert!(store.remove("key0000".to_owned()).is_err());
+    let keys = store
+        .snapshot()?
+        .scan(key_range(b"key0000".to_vec()..b"key0010".to_vec()))
+        .map(|entry| entry.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        keys,
+        vec!["key0001", "key0003", "key0005", "key0007", "key0009"]
+    );
+    Ok(())
+}
+
+// A snapshot should keep reading tables compacted away after it was taken
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_small(temp_dir.path())?;
+    for key_id in 0..500 {
+        store.set(format!("key{:04}", key_id), "old".to_owned())?;
+    }
+    store.flush()?;
+
+    let mut snapshot = store.snapshot()?;
+    for iter in 0..10 {
+        for key_id in 0..500 {
+            store.set(format!("key{:04}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 0..500 {
+        assert_eq!(
+            snapshot.get(format!("key{:04}", key_id))?,
+            Some("old".to_owned())
+        );
+    }
+    assert_eq!(snapshot.iter().count(), 500);
+
+    drop(snapshot);
+    let levels = store.tables_per_level();
+    assert_eq!(table_files(temp_dir.path()), levels.iter().sum::<usize>());
+    Ok(())
+}
+
+// A torn record at the end of the log should be discarded on reopen
+#[test]
+fn torn_wal_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut wal = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("lsm").join("wal.log"))?;
+    wal.write_all(&[42, 0, 0, 0, 1, 2])?;
+    drop(wal);
+
+    let mut store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Tables which are not in the manifest are left over by a crash and removed
+#[test]
+fn leftover_tables_are_removed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open_small(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    drop(store);
+
+    let leftover = temp_dir.path().join("lsm").join("999999.sst");
+    fs::write(&leftover, b"half written")?;
+    let mut store = open_small(temp_dir.path())?;
+    assert!(!leftover.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use kvs::{key_range, KvsEngine, KvsSnapshot, MemoryKvsEngine, MyError, Result};
+use std::thread;
+use tempfile::TempDir;
+
+// Should get, overwrite and remove values without touching the disk
+#[test]
+fn basic_operations() -> Result<()> {
+    let mut store = MemoryKvsEngine::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    match store.remove("key1".to_owned()) {
+        Err(MyError::KeyNotFound) => {}
+        _ => panic!("removing a missing key should fail"),
+    }
+
+    Ok(())
+}
+
+// Snapshots should not see later writes and should scan in key order
+#[test]
+fn snapshot_scan() -> Result<()> {
+    let mut store = MemoryKvsEngine::new();
+    for key in &["c", "a", "b", "d"] {
+        store.set_bytes(key.as_bytes().to_vec(), b"v".to_vec())?;
+    }
+    let mut snapshot = store.snapshot()?;
+    store.remove_bytes(b"a")?;
+
+    let keys: Vec<Vec<u8>> = snapshot
+        .scan(key_range(b"a".to_vec()..b"d".to_vec()))
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    assert_eq!(store.get_bytes(b"a")?, None);
+
+    Ok(())
+}
+
+// Clones should share the same map across threads
+#[test]
+fn concurrent_handles() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let mut store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store
+                        .set(format!("key{}-{}", thread_id, i), format!("{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut store = store;
+    for thread_id in 0..4 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+// An engine opened on a directory should be saved when dropped
+#[test]
+fn snapshot_to_disk_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = MemoryKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_bytes(vec![0xff, 0x00], vec![0xfe])?;
+    let clone = store.clone();
+    drop(store);
+    assert!(!temp_dir.path().join("memory.bin").exists());
+    drop(clone);
+
+    let mut store = MemoryKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_bytes(&[0xff, 0x00])?, Some(vec![0xfe]));
+    Ok(())
+}
+
+// `persist` should save the engine while it is still in use
+#[test]
+fn persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = MemoryKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.persist()?;
+
+    let mut reopened = MemoryKvsEngine::open(temp_dir.path())?;
+    assert_eq!(reopened.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, MemoryKvsEngine, Result, Server};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Starts a server and its metrics listener on free ports, left running until the test exits.
+fn start_server<E: kvs::KvsEngine + Clone + Send + 'static>(engine: E) -> (SocketAddr, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let metrics = TcpListener::bind("127.0.0.1:0").unwrap();
+    let metrics_addr = metrics.local_addr().unwrap();
+    let server = Server::new(engine).serve_metrics(metrics).unwrap();
+    thread::spawn(move || server.serve(listener));
+    (addr, metrics_addr)
+}
+
+/// Sends a GET request, returning the status line and the body.
+fn http_get(addr: SocketAddr, path: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_owned(), body.to_owned())
+}
+
+fn has_line(body: &str, line: &str) -> bool {
+    body.lines().any(|l| l == line)
+}
+
+// Requests, errors and connections should be counted
+#[test]
+fn request_metrics() -> Result<()> {
+    let (addr, metrics_addr) = start_server(MemoryKvsEngine::new());
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.get("key1".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+    drop(client);
+    let mut client = KvsClient::connect(addr)?;
+    client.get("key2".to_owned())?;
+
+    let (status, body) = http_get(metrics_addr, "/metrics");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(has_line(&body, "kvs_requests_total{op=\"set\"} 2"));
+    assert!(has_line(&body, "kvs_requests_total{op=\"get\"} 2"));
+    assert!(has_line(&body, "kvs_requests_total{op=\"remove\"} 2"));
+    assert!(has_line(&body, "kvs_requests_total{op=\"backup\"} 0"));
+    assert!(has_line(
+        &body,
+        "kvs_request_duration_seconds_bucket{op=\"set\",le=\"+Inf\"} 2"
+    ));
+    assert!(has_line(
+        &body,
+        "kvs_request_duration_seconds_count{op=\"remove\"} 2"
+    ));
+    assert!(has_line(
+        &body,
+        "kvs_errors_total{kind=\"key_not_found\"} 1"
+    ));
+    assert!(has_line(&body, "kvs_connections_total 2"));
+    assert!(has_line(&body, "kvs_engine_keys 1"));
+    assert!(body.contains("# TYPE kvs_request_duration_seconds histogram"));
+    Ok(())
+}
+
+// The KvStore reports the size of its log and its compactions
+#[test]
+fn engine_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, metrics_addr) = start_server(KvStore::open(temp_dir.path())?);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+
+    let (_, body) = http_get(metrics_addr, "/metrics");
+    assert!(has_line(&body, "kvs_engine_keys 1"));
+    assert!(has_line(&body, "kvs_engine_compactions_total 0"));
+    let gauge = |name: &str| -> u64 {
+        let line = body
+            .lines()
+            .find(|l| l.starts_with(&format!("{} ", name)))
+            .unwrap();
+        line.split(' ').nth(1).unwrap().parse().unwrap()
+    };
+    assert!(gauge("kvs_engine_log_bytes") > 0);
+    assert!(gauge("kvs_engine_uncompacted_bytes") > 0);
+    Ok(())
+}
+
+// Only GET /metrics is served
+#[test]
+fn unknown_path() {
+    let (_, metrics_addr) = start_server(MemoryKvsEngine::new());
+    let (status, _) = http_get(metrics_addr, "/");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+}
+
+// kvs-server should serve the metrics on --metrics-addr
+#[test]
+fn cli_metrics_addr() {
+    let addr = "127.0.0.1:4014";
+    let metrics_addr: SocketAddr = "127.0.0.1:4015".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--metrics-addr"])
+        .arg(metrics_addr.to_string())
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let (status, body) = http_get(metrics_addr, "/metrics");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(has_line(&body, "kvs_requests_total{op=\"set\"} 1"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
@@ -0,0 +1,101 @@
+use kvs::migrate::{self, detect_engine};
+use kvs::{KvStore, KvsEngine, LsmKvsEngine, Result, SledKvsEngine};
+use tempfile::TempDir;
+
+#[test]
+fn kvs_to_sled_and_back() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let report = migrate::migrate(temp_dir.path(), "kvs", "sled", false)?;
+    assert_eq!(report.keys, 2999);
+    assert_eq!(detect_engine(temp_dir.path())?, Some("sled".to_owned()));
+    assert!(!temp_dir.path().join("log.json").exists());
+
+    let mut db = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(db.get("key0".to_owned())?, None);
+    assert_eq!(db.get("key2999".to_owned())?, Some("value2999".to_owned()));
+    drop(db);
+
+    let back = migrate::migrate(temp_dir.path(), "sled", "kvs", true)?;
+    assert_eq!(back, report);
+    assert_eq!(detect_engine(temp_dir.path())?, Some("kvs".to_owned()));
+    assert!(temp_dir.path().join("sled-db").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_to_lsm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let report = migrate::migrate(temp_dir.path(), "kvs", "lsm", false)?;
+    assert_eq!(report.keys, 3000);
+    assert_eq!(detect_engine(temp_dir.path())?, Some("lsm".to_owned()));
+
+    let mut tree = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(
+        tree.get("key2999".to_owned())?,
+        Some("value2999".to_owned())
+    );
+    Ok(())
+}
+
+// Migrating from an engine which does not own the directory should fail
+#[test]
+fn migrate_from_wrong_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(migrate::migrate(temp_dir.path(), "kvs", "sled", false).is_err());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(migrate::migrate(temp_dir.path(), "sled", "kvs", false).is_err());
+    assert!(migrate::migrate(temp_dir.path(), "kvs", "kvs", false).is_err());
+    assert_eq!(detect_engine(temp_dir.path())?, Some("kvs".to_owned()));
+    Ok(())
+}
+
+// Every namespace is migrated, or none if the target engine has no namespaces
+#[test]
+fn migrate_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "default".to_owned())?;
+    store
+        .namespace("app1")?
+        .set("key".to_owned(), "app1".to_owned())?;
+    drop(store);
+
+    assert!(migrate::migrate(temp_dir.path(), "kvs", "lsm", false).is_err());
+    assert_eq!(detect_engine(temp_dir.path())?, Some("kvs".to_owned()));
+
+    let report = migrate::migrate(temp_dir.path(), "kvs", "sled", false)?;
+    assert_eq!(report.keys, 2);
+    assert!(!temp_dir.path().join("namespaces").exists());
+    let mut db = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(db.namespaces()?, vec!["app1".to_owned()]);
+    let mut app1 = db.namespace("app1")?;
+    assert_eq!(app1.get("key".to_owned())?, Some("app1".to_owned()));
+    drop((db, app1));
+
+    let back = migrate::migrate(temp_dir.path(), "sled", "kvs", false)?;
+    assert_eq!(back, report);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    let mut app1 = store.namespace("app1")?;
+    assert_eq!(app1.get("key".to_owned())?, Some("app1".to_owned()));
+    Ok(())
+}
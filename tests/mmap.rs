@@ -0,0 +1,147 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsSnapshot, Result};
+use std::thread;
+use tempfile::TempDir;
+
+// Records appended after the log was mapped should be readable
+#[test]
+fn reads_past_initial_mapping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key200".to_owned(), "value200".to_owned())?;
+    for key_id in 0..=200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Readers on several threads should see every value while a writer keeps
+// appending and compacting the log
+fn concurrent_reads(options: KvStoreOptions) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut writer = store.clone();
+    let writes = thread::spawn(move || -> Result<()> {
+        for iter in 0..20 {
+            for key_id in 0..50 {
+                writer.set(format!("other{:02}", key_id), format!("{}", iter))?;
+            }
+        }
+        Ok(())
+    });
+    let readers = (0..8)
+        .map(|_| {
+            let mut reader = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..5 {
+                    for key_id in 0..200 {
+                        assert_eq!(
+                            reader.get(format!("key{:03}", key_id))?,
+                            Some(format!("value{}", key_id))
+                        );
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    writes.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(store.get("other49".to_owned())?, Some("19".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn concurrent_reads_mmap() -> Result<()> {
+    concurrent_reads(KvStoreOptions::default())
+}
+
+#[test]
+fn concurrent_reads_pread() -> Result<()> {
+    concurrent_reads(KvStoreOptions::default().mmap(false))
+}
+
+// A value read while the key is overwritten must not be left in the cache
+#[test]
+fn cache_is_not_stale_under_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().cache_bytes(64 * 1024);
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "0".to_owned())?;
+
+    let mut writer = store.clone();
+    let writes = thread::spawn(move || -> Result<()> {
+        for iter in 1..=500 {
+            writer.set("key".to_owned(), format!("{}", iter))?;
+        }
+        Ok(())
+    });
+    let readers = (0..4)
+        .map(|_| {
+            let mut reader = store.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut last = 0;
+                for _ in 0..500 {
+                    let value: u32 = reader.get("key".to_owned())?.unwrap().parse().unwrap();
+                    assert!(value >= last, "read {} after {}", value, last);
+                    last = value;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    writes.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("500".to_owned()));
+    Ok(())
+}
+
+// Snapshots share the reader of the log they were taken on
+#[test]
+fn snapshot_reads_from_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), "old".to_owned())?;
+    }
+
+    let handles = (0..4)
+        .map(|_| {
+            let mut snapshot = store.snapshot()?;
+            Ok(thread::spawn(move || -> Result<usize> {
+                let count = snapshot.iter().count();
+                assert_eq!(snapshot.get("key000".to_owned())?, Some("old".to_owned()));
+                Ok(count)
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), "new".to_owned())?;
+    }
+    for handle in handles {
+        assert_eq!(handle.join().unwrap()?, 100);
+    }
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsSnapshot, MemoryKvsEngine, Result, Server, SledKvsEngine,
+    DEFAULT_NAMESPACE,
+};
+use predicates::str::contains;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Keys of different namespaces should not collide, and survive a reopen
+fn isolated_namespaces<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+    let mut app1 = store.namespace("app1")?;
+    let mut app2 = store.namespace("app2")?;
+    store.set("key".to_owned(), "default".to_owned())?;
+    app1.set("key".to_owned(), "app1".to_owned())?;
+    app2.set("key".to_owned(), "app2".to_owned())?;
+    app2.set("only-app2".to_owned(), "value".to_owned())?;
+    app1.remove("key".to_owned())?;
+
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(app1.get("key".to_owned())?, None);
+    assert_eq!(app2.get("key".to_owned())?, Some("app2".to_owned()));
+    assert_eq!(store.get("only-app2".to_owned())?, None);
+    assert_eq!(app2.snapshot()?.iter().count(), 2);
+    drop((store, app1, app2));
+
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    let mut app2 = store.namespace("app2")?;
+    assert_eq!(app2.get("key".to_owned())?, Some("app2".to_owned()));
+    let mut default = app2.namespace(DEFAULT_NAMESPACE)?;
+    assert_eq!(default.get("key".to_owned())?, Some("default".to_owned()));
+    Ok(())
+}
+
+fn invalid_names<E: KvsEngine>(mut engine: E) {
+    for name in [
+        "",
+        "-app",
+        "_app",
+        "app/1",
+        "../app",
+        "app 1",
+        &"a".repeat(65),
+    ] {
+        assert!(engine.namespace(name).is_err(), "{:?} was accepted", name);
+    }
+    assert!(engine.namespace("App_1-b").is_ok());
+}
+
+#[test]
+fn kvs_store_namespaces() -> Result<()> {
+    isolated_namespaces(|path| KvStore::open(path))
+}
+
+#[test]
+fn sled_namespaces() -> Result<()> {
+    isolated_namespaces(|path| SledKvsEngine::open(path))
+}
+
+#[test]
+fn invalid_namespace_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    invalid_names(KvStore::open(temp_dir.path().join("kvs"))?);
+    invalid_names(SledKvsEngine::open(temp_dir.path().join("sled"))?);
+    Ok(())
+}
+
+// Each namespace of a KvStore has a log of its own, opened once for all handles
+#[test]
+fn kvs_store_namespace_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut first = store.namespace("app")?;
+    let mut second = store.clone().namespace("app")?;
+    first.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(second.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(temp_dir
+        .path()
+        .join("namespaces")
+        .join("app")
+        .join("log.json")
+        .exists());
+    Ok(())
+}
+
+// Idle namespaces are closed to open others, and those in use are not
+#[test]
+fn kvs_store_open_namespaces_capped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .namespace(&format!("app{}", i))?
+            .set("key".to_owned(), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        let mut app = store.namespace(&format!("app{}", i))?;
+        assert_eq!(app.get("key".to_owned())?, Some(format!("value{}", i)));
+    }
+
+    let held = (0..64)
+        .map(|i| store.namespace(&format!("app{}", i)))
+        .collect::<Result<Vec<_>>>()?;
+    assert!(store.namespace("app99").is_err());
+    drop(held);
+    assert!(store.namespace("app99").is_ok());
+    Ok(())
+}
+
+#[test]
+fn memory_engine_has_no_namespaces() {
+    assert!(MemoryKvsEngine::new().namespace("app").is_err());
+}
+
+/// Starts a server on a free port, left running until the test exits.
+fn start_server<E: KvsEngine + Clone + Send + 'static>(engine: E) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || Server::new(engine).serve(listener));
+    addr
+}
+
+// Clients select the namespace of their requests
+#[test]
+fn client_select() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(KvStore::open(temp_dir.path())?);
+
+    let mut default = KvsClient::connect(addr)?;
+    let mut app = KvsClient::connect(addr)?;
+    app.select("app");
+    default.set("key".to_owned(), "default".to_owned())?;
+    app.set("key".to_owned(), "app".to_owned())?;
+    assert_eq!(default.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(app.get("key".to_owned())?, Some("app".to_owned()));
+
+    app.select(DEFAULT_NAMESPACE);
+    assert_eq!(app.get("key".to_owned())?, Some("default".to_owned()));
+    app.select("../escape");
+    assert!(app.get("key".to_owned()).is_err());
+    Ok(())
+}
+
+// Reading a namespace which does not exist finds nothing and creates nothing
+#[test]
+fn client_select_missing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(KvStore::open(temp_dir.path())?);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.select("missing");
+    assert_eq!(client.get("key".to_owned())?, None);
+    assert_eq!(client.dbsize()?, 0);
+    assert_eq!(client.info()?.keys, 0);
+    client.compact()?;
+    client.flush()?;
+    let dest = backup_dir.path().join("backup");
+    assert!(client.backup(dest.to_string_lossy().into_owned()).is_err());
+    assert!(client.remove("key".to_owned()).is_err());
+    let namespace = temp_dir.path().join("namespaces").join("missing");
+    assert!(!namespace.exists());
+
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert!(namespace.exists());
+    assert_eq!(client.dbsize()?, 1);
+    Ok(())
+}
+
+#[test]
+fn client_select_without_namespaces() -> Result<()> {
+    let addr = start_server(MemoryKvsEngine::new());
+
+    let mut client = KvsClient::connect(addr)?;
+    client.select(DEFAULT_NAMESPACE);
+    client.set("key".to_owned(), "value".to_owned())?;
+    client.select("app");
+    assert!(client.get("key".to_owned()).is_err());
+    Ok(())
+}
+
+// kvs-client should take the namespace from the command line
+#[test]
+fn cli_namespace() {
+    let addr = "127.0.0.1:4013";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "app", "--namespace", "app"])
+        .assert()
+        .success();
+    client(&["set", "key1", "default"]).assert().success();
+    client(&["get", "key1", "--namespace", "app"])
+        .assert()
+        .success()
+        .stdout(contains("app"));
+    client(&["get", "key1", "--namespace", "other"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("default"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
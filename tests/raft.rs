@@ -0,0 +1,728 @@
+use assert_cmd::prelude::*;
+use kvs::raft::{
+    Message, NodeId, RaftNode, RaftOptions, RaftRole, SimNetwork, TcpTransport, Transport,
+};
+use kvs::TlsPeerConfig;
+use kvs::{KvStore, KvsClient, KvsEngine, MemoryKvsEngine, MyError, Result, Server};
+use predicates::str::contains;
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Nodes of a cluster on a simulated network, with their engines.
+struct Cluster {
+    network: SimNetwork,
+    nodes: BTreeMap<NodeId, RaftNode<MemoryKvsEngine>>,
+    engines: BTreeMap<NodeId, MemoryKvsEngine>,
+}
+
+impl Cluster {
+    /// Starts nodes 1 to `size`, all members of the cluster.
+    fn new(size: u64, options: RaftOptions) -> Result<Cluster> {
+        let mut cluster = Cluster {
+            network: SimNetwork::new(),
+            nodes: BTreeMap::new(),
+            engines: BTreeMap::new(),
+        };
+        let options = (1..=size).fold(options, |options, id| options.member(id, "sim"));
+        for id in 1..=size {
+            cluster.start(id, options.clone())?;
+        }
+        Ok(cluster)
+    }
+
+    fn start(&mut self, id: NodeId, options: RaftOptions) -> Result<()> {
+        let engine = MemoryKvsEngine::new();
+        let node = RaftNode::start(id, engine.clone(), self.network.clone(), options)?;
+        self.nodes.insert(id, node);
+        self.engines.insert(id, engine);
+        Ok(())
+    }
+
+    fn node(&self, id: NodeId) -> &RaftNode<MemoryKvsEngine> {
+        &self.nodes[&id]
+    }
+
+    /// Waits for a leader among the nodes not in `excluded`.
+    fn leader(&self, excluded: &[NodeId]) -> NodeId {
+        let mut found = None;
+        eventually("a leader", || {
+            found = self
+                .nodes
+                .values()
+                .filter(|node| !excluded.contains(&node.id()))
+                .map(|node| node.status())
+                .find(|status| status.role == RaftRole::Leader)
+                .map(|status| status.id);
+            Ok(found.is_some())
+        });
+        found.unwrap()
+    }
+
+    /// Waits for the nodes not in `excluded` to follow the same leader in the
+    /// same term, so that no election is under way.
+    fn settle(&self, excluded: &[NodeId]) -> NodeId {
+        let nodes: Vec<_> = self
+            .nodes
+            .values()
+            .filter(|node| !excluded.contains(&node.id()))
+            .collect();
+        settle(&nodes)
+    }
+
+    /// Sets a key through whichever node leads, retrying while leadership
+    /// changes.
+    fn set(&self, key: &str, value: &str) {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            let leader = self.leader(&[]);
+            match self
+                .node(leader)
+                .set_bytes(key.as_bytes().to_vec(), value.as_bytes().to_vec())
+            {
+                Ok(()) => return,
+                Err(e) => assert!(Instant::now() < deadline, "set {} failed: {}", key, e),
+            }
+        }
+    }
+
+    /// Waits for the engine of node `id` to hold `value` for `key`.
+    fn converges(&self, id: NodeId, key: &str, value: Option<&str>) {
+        let mut engine = self.engines[&id].clone();
+        eventually(&format!("node {} to hold {}", id, key), || {
+            Ok(engine.get(key.to_owned())? == value.map(str::to_owned))
+        });
+    }
+}
+
+fn fast() -> RaftOptions {
+    RaftOptions::default().tick(Duration::from_millis(5))
+}
+
+/// Polls `condition` until it holds, failing the test after 20 seconds.
+/// Writes `ca.pem` and, signed by it, `node.pem` and `node.key` for
+/// 127.0.0.1, plus `intruder.pem` and `intruder.key` signed by another CA.
+fn write_pki(dir: &Path) {
+    let ca = |name: &str| {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        (params.self_signed(&key).unwrap(), key)
+    };
+    let (cluster_ca, cluster_key) = ca("kvs test cluster");
+    let (other_ca, other_key) = ca("untrusted CA");
+    fs::write(dir.join("ca.pem"), cluster_ca.pem()).unwrap();
+    for (name, ca, ca_key) in &[
+        ("node", &cluster_ca, &cluster_key),
+        ("intruder", &other_ca, &other_key),
+    ] {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["127.0.0.1".to_owned()])
+            .unwrap()
+            .signed_by(&key, ca, ca_key)
+            .unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+        fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+    }
+}
+
+/// Arguments of kvs-server authenticating its node with the PKI written to
+/// `dir`.
+fn raft_tls_args(dir: &Path) -> Vec<PathBuf> {
+    write_pki(dir);
+    vec![
+        "--raft-tls-cert".into(),
+        dir.join("node.pem"),
+        "--raft-tls-key".into(),
+        dir.join("node.key"),
+        "--raft-tls-ca".into(),
+        dir.join("ca.pem"),
+    ]
+}
+
+fn peer_config(dir: &Path, name: &str) -> TlsPeerConfig {
+    TlsPeerConfig::from_pem_files(
+        dir.join(format!("{}.pem", name)),
+        dir.join(format!("{}.key", name)),
+        dir.join("ca.pem"),
+    )
+    .unwrap()
+}
+
+fn eventually(what: &str, mut condition: impl FnMut() -> Result<bool>) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while !condition().unwrap() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Waits for `nodes` to follow the same leader in the same term.
+fn settle<E: KvsEngine + Clone + Send + 'static>(nodes: &[&RaftNode<E>]) -> NodeId {
+    let mut found = None;
+    eventually("the nodes to agree on a leader", || {
+        let statuses: Vec<_> = nodes.iter().map(|node| node.status()).collect();
+        let first = &statuses[0];
+        found = first.leader.filter(|leader| {
+            statuses
+                .iter()
+                .all(|status| status.leader == first.leader && status.term == first.term)
+                && statuses
+                    .iter()
+                    .any(|status| status.id == *leader && status.role == RaftRole::Leader)
+        });
+        Ok(found.is_some())
+    });
+    found.unwrap()
+}
+
+fn not_leader(result: Result<()>) -> bool {
+    matches!(result, Err(MyError::NotLeader(_)))
+}
+
+// A cluster elects a leader, which replicates writes to every node
+#[test]
+fn election_and_replication() -> Result<()> {
+    let cluster = Cluster::new(3, fast())?;
+    let leader = cluster.settle(&[]);
+    let node = cluster.node(leader);
+    node.set_bytes(b"key1".to_vec(), b"value1".to_vec())?;
+    node.set_bytes(b"key2".to_vec(), b"value2".to_vec())?;
+    node.remove_bytes(b"key2".to_vec())?;
+    assert!(matches!(
+        node.remove_bytes(b"key2".to_vec()),
+        Err(MyError::KeyNotFound)
+    ));
+    assert_eq!(node.get_bytes(b"key1")?, Some(b"value1".to_vec()));
+    for id in 1..=3 {
+        cluster.converges(id, "key1", Some("value1"));
+        cluster.converges(id, "key2", None);
+    }
+
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+    let follower = cluster.node(follower);
+    let err = follower
+        .set_bytes(b"key3".to_vec(), b"value3".to_vec())
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains(&format!("node {} at sim leads", leader)),
+        "{}",
+        err
+    );
+    assert!(not_leader(follower.get_bytes(b"key1").map(|_| ())));
+    let status = follower.status();
+    assert_eq!(status.role, RaftRole::Follower);
+    assert_eq!(status.leader, Some(leader));
+    assert_eq!(status.members.len(), 3);
+    Ok(())
+}
+
+// A new leader is elected when the leader crashes, and keeps the data
+#[test]
+fn leader_failover() -> Result<()> {
+    let cluster = Cluster::new(3, fast())?;
+    cluster.set("key1", "value1");
+    let old = cluster.leader(&[]);
+    let old_term = cluster.node(old).status().term;
+    cluster.node(old).stop();
+
+    let new = cluster.settle(&[old]);
+    assert_ne!(new, old);
+    assert!(cluster.node(new).status().term > old_term);
+    let node = cluster.node(new);
+    assert_eq!(node.get_bytes(b"key1")?, Some(b"value1".to_vec()));
+    node.set_bytes(b"key2".to_vec(), b"value2".to_vec())?;
+    assert!(node.set_bytes(b"key3".to_vec(), b"value3".to_vec()).is_ok());
+    Ok(())
+}
+
+// A leader cut off from the majority can neither write nor read, and catches
+// up once the partition heals
+#[test]
+fn partition() -> Result<()> {
+    let cluster = Cluster::new(5, fast().request_timeout(Duration::from_millis(500)))?;
+    cluster.set("key", "before");
+    let old = cluster.leader(&[]);
+    let minority: Vec<NodeId> = vec![old, old % 5 + 1];
+    let majority: Vec<NodeId> = (1..=5).filter(|id| !minority.contains(id)).collect();
+    cluster.network.partition(&[&minority, &majority]);
+
+    assert!(cluster
+        .node(old)
+        .set_bytes(b"key".to_vec(), b"lost".to_vec())
+        .is_err());
+    let new = cluster.settle(&minority);
+    assert!(majority.contains(&new));
+    cluster
+        .node(new)
+        .set_bytes(b"key".to_vec(), b"after".to_vec())?;
+    // The old leader never answers with a stale value
+    assert!(cluster.node(old).get_bytes(b"key").is_err());
+
+    cluster.network.heal();
+    for id in 1..=5 {
+        cluster.converges(id, "key", Some("after"));
+    }
+    eventually("the old leader to step down", || {
+        Ok(cluster.node(old).status().role != RaftRole::Leader)
+    });
+    Ok(())
+}
+
+// Reads on the leader reflect every acknowledged write
+#[test]
+fn linearizable_reads() -> Result<()> {
+    let cluster = Cluster::new(3, fast())?;
+    cluster
+        .network
+        .set_delay(Duration::from_millis(1), Duration::from_millis(5));
+    let leader = cluster.settle(&[]);
+    let node = cluster.node(leader);
+    for i in 0..20 {
+        let value = i.to_string().into_bytes();
+        node.set_bytes(b"counter".to_vec(), value.clone())?;
+        assert_eq!(node.get_bytes(b"counter")?, Some(value));
+    }
+    Ok(())
+}
+
+// The nodes agree despite lost, delayed and reordered messages
+#[test]
+fn lossy_network() -> Result<()> {
+    // Elections wait for several round trips of the slowest messages
+    let options = fast()
+        .election_ticks(40)
+        .request_timeout(Duration::from_secs(1));
+    let cluster = Cluster::new(3, options)?;
+    cluster.network.set_drop_rate(0.2);
+    cluster
+        .network
+        .set_delay(Duration::from_millis(0), Duration::from_millis(20));
+    for i in 0..30 {
+        cluster.set(&format!("key{}", i), &format!("value{}", i));
+    }
+    cluster.network.set_drop_rate(0.0);
+    for id in 1..=3 {
+        for i in 0..30 {
+            cluster.converges(id, &format!("key{}", i), Some(&format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// A node back from a partition receives a snapshot of the compacted entries
+#[test]
+fn snapshot_catch_up() -> Result<()> {
+    let cluster = Cluster::new(3, fast().snapshot_entries(10))?;
+    let leader = cluster.settle(&[]);
+    let lagging = (1..=3).find(|id| *id != leader).unwrap();
+    cluster.set("removed", "value");
+    cluster.converges(lagging, "removed", Some("value"));
+    cluster.network.isolate(lagging);
+
+    let node = cluster.node(leader);
+    node.remove_bytes(b"removed".to_vec())?;
+    for i in 0..50 {
+        node.set_bytes(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+    }
+    assert!(node.status().snapshot_index > 0);
+
+    cluster.network.heal();
+    cluster.converges(lagging, "key49", Some("value"));
+    cluster.converges(lagging, "removed", None);
+    assert!(cluster.node(lagging).status().snapshot_index > 0);
+    Ok(())
+}
+
+// A snapshot of many chunks reaches a node back from a partition over a lossy
+// network, staged on disk until its last chunk
+#[test]
+fn snapshot_in_chunks() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let network = SimNetwork::new();
+    let options = (1..=3).fold(
+        fast().snapshot_entries(10).snapshot_chunk_bytes(256),
+        |options, id| options.member(id, "sim"),
+    );
+    let engines: Vec<KvStore> = dirs
+        .iter()
+        .map(|dir| KvStore::open(dir.path()))
+        .collect::<Result<_>>()?;
+    let nodes: Vec<_> = engines
+        .iter()
+        .enumerate()
+        .map(|(i, engine)| {
+            let options = options.clone().dir(dirs[i].path().join("raft"));
+            RaftNode::start(i as u64 + 1, engine.clone(), network.clone(), options)
+        })
+        .collect::<Result<_>>()?;
+    let leader = settle(&nodes.iter().collect::<Vec<_>>()) as usize - 1;
+    let lagging = (leader + 1) % 3;
+    nodes[leader].set_bytes(b"removed".to_vec(), b"value".to_vec())?;
+    let mut engine = engines[lagging].clone();
+    eventually("the lagging node to hold removed", || {
+        Ok(engine.get_bytes(b"removed")?.is_some())
+    });
+    network.isolate(lagging as u64 + 1);
+
+    nodes[leader].remove_bytes(b"removed".to_vec())?;
+    let value = vec![b'x'; 100];
+    for i in 0..100 {
+        nodes[leader].set_bytes(format!("key{:03}", i).into_bytes(), value.clone())?;
+    }
+    assert!(nodes[leader].status().snapshot_index > 0);
+
+    network.set_drop_rate(0.2);
+    network.heal();
+    eventually("the lagging node to catch up", || {
+        Ok(engine.get_bytes(b"key099")? == Some(value.clone()))
+    });
+    for i in 0..100 {
+        assert_eq!(
+            engine.get_bytes(format!("key{:03}", i).as_bytes())?,
+            Some(value.clone())
+        );
+    }
+    assert_eq!(engine.get_bytes(b"removed")?, None);
+    assert!(nodes[lagging].status().snapshot_index > 0);
+    assert!(!dirs[lagging].path().join("raft").join("snapshot").exists());
+    Ok(())
+}
+
+// Nodes join a single-node cluster, then the first node leaves it
+#[test]
+fn membership_changes() -> Result<()> {
+    let mut cluster = Cluster::new(1, fast())?;
+    assert_eq!(cluster.leader(&[]), 1);
+    cluster.set("key1", "value1");
+
+    for id in 2..=3 {
+        cluster.start(id, fast())?;
+        cluster.node(1).add_node(id, "sim")?;
+        cluster.converges(id, "key1", Some("value1"));
+    }
+    assert!(cluster.node(1).add_node(2, "sim").is_err());
+    eventually("node 3 to know every member", || {
+        Ok(cluster.node(3).status().members.len() == 3)
+    });
+
+    cluster.node(1).remove_node(1)?;
+    let leader = cluster.settle(&[1]);
+    assert_ne!(leader, 1);
+    let node = cluster.node(leader);
+    node.set_bytes(b"key2".to_vec(), b"value2".to_vec())?;
+    assert_eq!(
+        node.status().members.keys().copied().collect::<Vec<_>>(),
+        vec![2, 3]
+    );
+    cluster.converges(2, "key2", Some("value2"));
+    cluster.converges(3, "key2", Some("value2"));
+    assert_ne!(cluster.node(1).status().role, RaftRole::Leader);
+    Ok(())
+}
+
+// A node restarted on its directory remembers its log and term
+#[test]
+fn restart() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let network = SimNetwork::new();
+    let options = |id: usize| {
+        (1..=3)
+            .fold(fast(), |options, id| options.member(id, "sim"))
+            .dir(dirs[id].path().join("raft"))
+    };
+    let start = |id: usize| -> Result<RaftNode<KvStore>> {
+        let engine = KvStore::open(dirs[id].path())?;
+        RaftNode::start(id as u64 + 1, engine, network.clone(), options(id))
+    };
+    let mut nodes: Vec<_> = (0..3).map(start).collect::<Result<_>>()?;
+
+    let find_leader = |nodes: &[RaftNode<KvStore>]| {
+        let leader = settle(&nodes.iter().collect::<Vec<_>>());
+        nodes.iter().position(|node| node.id() == leader).unwrap()
+    };
+    let leader = find_leader(&nodes);
+    nodes[leader].set_bytes(b"key1".to_vec(), b"value1".to_vec())?;
+    let term = nodes[leader].status().term;
+    for node in &nodes {
+        node.stop();
+    }
+
+    nodes = (0..3).map(start).collect::<Result<_>>()?;
+    let leader = find_leader(&nodes);
+    assert!(nodes[leader].status().term > term);
+    assert_eq!(nodes[leader].get_bytes(b"key1")?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// A restarted node keeps its term and the vote it cast in that term
+#[test]
+fn restart_keeps_term_and_vote() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let network = SimNetwork::new();
+    // Nodes 2 and 3 never start: node 1 campaigns, voting for itself
+    let options = (1..=3)
+        .fold(fast(), |options, id| options.member(id, "sim"))
+        .dir(temp_dir.path().join("raft"));
+    let start = || RaftNode::start(1, MemoryKvsEngine::new(), network.clone(), options.clone());
+
+    let node = start()?;
+    eventually("a vote", || {
+        let status = node.status();
+        Ok(status.term > 1 && status.voted_for == Some(1))
+    });
+    node.stop();
+    let before = node.status();
+
+    let node = start()?;
+    let after = node.status();
+    assert!(after.term >= before.term);
+    assert_eq!(after.voted_for, Some(1));
+    node.stop();
+    Ok(())
+}
+
+// A record torn by a crash is dropped on restart, other corruption is an error
+#[test]
+fn torn_log_record() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let network = SimNetwork::new();
+    let options = fast().member(1, "sim").dir(temp_dir.path().join("raft"));
+    let start = || RaftNode::start(1, MemoryKvsEngine::new(), network.clone(), options.clone());
+    let log_path = temp_dir.path().join("raft").join("log.json");
+
+    let node = start()?;
+    settle(&[&node]);
+    node.set_bytes(b"key1".to_vec(), b"value1".to_vec())?;
+    node.stop();
+    let log = fs::read(&log_path)?;
+
+    let mut torn = log.clone();
+    torn.extend_from_slice(br#"{"Append":{"index":"#);
+    fs::write(&log_path, &torn)?;
+    let node = start()?;
+    settle(&[&node]);
+    assert_eq!(node.get_bytes(b"key1")?, Some(b"value1".to_vec()));
+    node.stop();
+
+    let mut corrupt = log.clone();
+    corrupt.splice(1..1, b"garbage".iter().cloned());
+    fs::write(&log_path, &corrupt)?;
+    assert!(start().is_err());
+    Ok(())
+}
+
+// kvs-server refuses to run a Raft node on an engine which forgets its data
+#[test]
+fn cli_raft_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "memory",
+            "--addr",
+            "127.0.0.1:4030",
+            "--raft-id",
+            "1",
+            "--raft-addr",
+            "127.0.0.1:4031",
+        ])
+        .args(["--data-dir".as_ref(), temp_dir.path().as_os_str()])
+        .args(raft_tls_args(temp_dir.path()))
+        .assert()
+        .failure()
+        .stderr(contains(
+            "--raft-id needs an engine which keeps its data on disk",
+        ));
+}
+
+// kvs-server refuses to run a Raft node whose peers are not authenticated
+#[test]
+fn cli_raft_requires_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            "127.0.0.1:4030",
+            "--raft-id",
+            "1",
+            "--raft-addr",
+            "127.0.0.1:4031",
+        ])
+        .args(["--data-dir".as_ref(), temp_dir.path().as_os_str()])
+        .assert()
+        .failure()
+        .stderr(contains("--raft-tls-cert"));
+}
+
+// Nodes exchange messages over mutual TLS, and ignore nodes without a
+// certificate of the cluster
+#[test]
+fn tcp_transport() -> Result<()> {
+    let pki = TempDir::new().unwrap();
+    write_pki(pki.path());
+    let transports: Vec<TcpTransport> = (0..3)
+        .map(|_| Ok(TcpTransport::bind("127.0.0.1:0")?.tls(peer_config(pki.path(), "node"))))
+        .collect::<Result<_>>()?;
+    let options = transports
+        .iter()
+        .enumerate()
+        .fold(fast(), |options, (i, transport)| {
+            options.member(i as u64 + 1, transport.local_addr().to_string())
+        });
+    let engines: Vec<MemoryKvsEngine> = (0..3).map(|_| MemoryKvsEngine::new()).collect();
+    let nodes: Vec<_> = transports
+        .into_iter()
+        .zip(engines.iter().cloned())
+        .enumerate()
+        .map(|(i, (transport, engine))| {
+            RaftNode::start(i as u64 + 1, engine, transport, options.clone())
+        })
+        .collect::<Result<_>>()?;
+
+    let leader = settle(&nodes.iter().collect::<Vec<_>>());
+    nodes[leader as usize - 1].set_bytes(b"key1".to_vec(), b"value1".to_vec())?;
+    for engine in engines {
+        let mut engine = engine.clone();
+        eventually("the write", || {
+            Ok(engine.get("key1".to_owned())? == Some("value1".to_owned()))
+        });
+    }
+
+    // Messages forged over plain TCP or with a certificate of another CA
+    // are never read
+    let intruders = vec![
+        TcpTransport::bind("127.0.0.1:0")?,
+        TcpTransport::bind("127.0.0.1:0")?.tls(peer_config(pki.path(), "intruder")),
+    ];
+    for intruder in &intruders {
+        for (id, addr) in &nodes[0].status().members {
+            let forged = format!(
+                r#"{{"from":9,"to":{},"term":1000000,"body":{{"Vote":{{"granted":true}}}}}}"#,
+                id
+            );
+            intruder.send(addr, serde_json::from_str::<Message>(&forged)?);
+        }
+    }
+    thread::sleep(Duration::from_secs(1));
+    for node in &nodes {
+        assert!(node.status().term < 1000000);
+    }
+    Ok(())
+}
+
+/// Serves node `id` of a cluster on a free port, left running until the test
+/// exits.
+fn serve(cluster: &Cluster, id: NodeId) -> SocketAddr {
+    let server = Server::new(cluster.engines[&id].clone()).raft(cluster.node(id).clone());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+// Servers of a cluster take writes on the leader only, and change its members
+#[test]
+fn servers() -> Result<()> {
+    let mut cluster = Cluster::new(2, fast())?;
+    let leader = cluster.settle(&[]);
+    let follower = 3 - leader;
+    let mut client = KvsClient::connect(serve(&cluster, leader))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+    let info = client.info()?;
+    assert_eq!(info.role, Some(cluster.node(leader).status().to_string()));
+    assert!(info.role.unwrap().starts_with("raft leader, term "));
+
+    let mut other = KvsClient::connect(serve(&cluster, follower))?;
+    let err = other
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap_err();
+    assert!(err.to_string().contains("Not the leader"), "{}", err);
+    assert!(other.get("key1".to_owned()).is_err());
+    assert!(other
+        .info()?
+        .role
+        .unwrap()
+        .starts_with(&format!("raft follower of node {}", leader)));
+    assert!(other.promote().is_err());
+
+    cluster.start(3, fast())?;
+    assert!(other.add_node(3, "sim".to_owned()).is_err());
+    client.add_node(3, "sim".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    cluster.converges(3, "key3", Some("value3"));
+    client.remove_node(follower)?;
+    eventually("node 3 to know the members", || {
+        Ok(cluster.node(3).status().members.len() == 2)
+    });
+    Ok(())
+}
+
+// kvs-server runs a single-node cluster, served through its leader
+#[test]
+fn cli_raft() {
+    let addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "--raft-id",
+            "1",
+            "--raft-addr",
+            "127.0.0.1:4025",
+        ])
+        .args(["--data-dir".as_ref(), temp_dir.path().as_os_str()])
+        .args(raft_tls_args(temp_dir.path()))
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    client(&["admin", "info"])
+        .assert()
+        .success()
+        .stdout(contains("role: raft leader, term "));
+    client(&["set", "key2", "value2", "--namespace", "other"])
+        .assert()
+        .failure()
+        .stderr(contains("Namespaces are not replicated"));
+    client(&["admin", "remove-node", "2"])
+        .assert()
+        .failure()
+        .stderr(contains("Node 2 is not a member"));
+    assert!(temp_dir.path().join("raft").join("log.json").exists());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
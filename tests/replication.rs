@@ -0,0 +1,522 @@
+use assert_cmd::prelude::*;
+use kvs::auth::{generate_token, hash_token};
+use kvs::{
+    Acl, BackupManifest, Credentials, KvStore, KvsClient, KvsEngine, MemoryKvsEngine,
+    MemorySnapshot, Result, Server,
+};
+use predicates::str::contains;
+use serde_json::json;
+use serde_json::Deserializer;
+use std::fs;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Starts a server on a free port, left running until the test exits.
+fn start<E: KvsEngine + Clone + Send + 'static>(server: Server<E>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+/// Polls `condition` until it holds, failing the test after 10 seconds.
+fn eventually(what: &str, mut condition: impl FnMut() -> Result<bool>) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition().unwrap() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn has(client: &mut KvsClient, key: &str, value: Option<&str>) -> Result<bool> {
+    Ok(client.get(key.to_owned())? == value.map(str::to_owned))
+}
+
+// A follower is streamed to without holding a thread of the leader's pool
+#[test]
+fn follower_outside_pool() -> Result<()> {
+    let leader = start(Server::new(MemoryKvsEngine::new()).threads(1).leader());
+    KvsClient::connect(leader)?.set("key1".to_owned(), "value1".to_owned())?;
+    let follower = start(Server::new(MemoryKvsEngine::new()).follow(leader));
+    let mut reader = KvsClient::connect(follower)?;
+    eventually("the snapshot", || has(&mut reader, "key1", Some("value1")));
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = KvsClient::connect(leader)
+            .and_then(|mut client| client.set("key2".to_owned(), "value2".to_owned()));
+        sender.send(result).unwrap();
+    });
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap()?;
+    eventually("the stream", || has(&mut reader, "key2", Some("value2")));
+    Ok(())
+}
+
+// A follower receives the snapshot, then the writes of the leader
+#[test]
+fn snapshot_then_stream() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = start(Server::new(KvStore::open(temp_dir.path())?).leader());
+    let mut writer = KvsClient::connect(leader)?;
+    for i in 0..100 {
+        writer.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    writer.remove("key0".to_owned())?;
+
+    let follower = start(Server::new(MemoryKvsEngine::new()).follow(leader));
+    let mut reader = KvsClient::connect(follower)?;
+    eventually("the snapshot", || {
+        has(&mut reader, "key99", Some("value99"))
+    });
+    assert_eq!(reader.get("key0".to_owned())?, None);
+    assert_eq!(reader.get("key50".to_owned())?, Some("value50".to_owned()));
+
+    writer.set("key1".to_owned(), "updated".to_owned())?;
+    writer.remove("key2".to_owned())?;
+    writer.set("new".to_owned(), "value".to_owned())?;
+    eventually("the stream", || has(&mut reader, "new", Some("value")));
+    assert_eq!(reader.get("key1".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    assert_eq!(reader.dbsize()?, 99);
+    Ok(())
+}
+
+// Several followers, started before any write
+#[test]
+fn many_followers() -> Result<()> {
+    let leader = start(Server::new(MemoryKvsEngine::new()).leader());
+    let followers: Vec<_> = (0..3)
+        .map(|_| start(Server::new(MemoryKvsEngine::new()).follow(leader)))
+        .collect();
+    let mut writer = KvsClient::connect(leader)?;
+    for i in 0..20 {
+        writer.set("counter".to_owned(), i.to_string())?;
+    }
+    for follower in followers {
+        let mut reader = KvsClient::connect(follower)?;
+        eventually("the last write", || has(&mut reader, "counter", Some("19")));
+    }
+    Ok(())
+}
+
+// Concurrent writes of the same keys end with the same values on the follower
+#[test]
+fn concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = start(Server::new(KvStore::open(temp_dir.path())?).leader());
+    let follower = start(Server::new(MemoryKvsEngine::new()).follow(leader));
+    let writers: Vec<_> = (0..4)
+        .map(|writer| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(leader)?;
+                for i in 0..50 {
+                    let key = format!("key{}", i % 5);
+                    if i % 7 == 0 {
+                        let _ = client.remove(key);
+                    } else {
+                        client.set(key, format!("{}-{}", writer, i))?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    let mut leader = KvsClient::connect(leader)?;
+    let mut reader = KvsClient::connect(follower)?;
+    for i in 0..5 {
+        let key = format!("key{}", i);
+        let value = leader.get(key.clone())?;
+        eventually(&key, || has(&mut reader, &key, value.as_deref()));
+    }
+    Ok(())
+}
+
+/// Engine recording the keys written to it, and slow to return from writing
+/// `slow`.
+#[derive(Clone)]
+struct Recording {
+    engine: MemoryKvsEngine,
+    slow: &'static [u8],
+    written: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Recording {
+    fn new(slow: &'static [u8]) -> Recording {
+        Recording {
+            engine: MemoryKvsEngine::new(),
+            slow,
+            written: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl KvsEngine for Recording {
+    type Snapshot = MemorySnapshot;
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let slow = key == self.slow;
+        self.written.lock().unwrap().push(key.clone());
+        self.engine.set_bytes(key, value)?;
+        if slow {
+            // As a sync would, once the write is visible
+            thread::sleep(Duration::from_millis(300));
+        }
+        Ok(())
+    }
+
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.engine.get_bytes(key)
+    }
+
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.written.lock().unwrap().push(key.to_vec());
+        self.engine.remove_bytes(key)
+    }
+
+    fn snapshot(&mut self) -> Result<MemorySnapshot> {
+        self.engine.snapshot()
+    }
+
+    fn backup(&mut self, dest: &Path) -> Result<BackupManifest> {
+        self.engine.backup(dest)
+    }
+
+    fn engine_name(&self) -> &'static str {
+        "memory"
+    }
+}
+
+// Writes of different keys reach the followers in the order the leader
+// applied them, even when a slow one holds the next back
+#[test]
+fn writes_in_leader_order() -> Result<()> {
+    let leader_engine = Recording::new(b"slow");
+    let leader = start(Server::new(leader_engine.clone()).leader());
+    let follower_engine = Recording::new(b"");
+    let follower = start(Server::new(follower_engine.clone()).follow(leader));
+    let mut writer = KvsClient::connect(leader)?;
+    writer.set("ready".to_owned(), "value".to_owned())?;
+    let mut reader = KvsClient::connect(follower)?;
+    eventually("the follower", || has(&mut reader, "ready", Some("value")));
+
+    let slow = thread::spawn(move || {
+        KvsClient::connect(leader)?.set("slow".to_owned(), "value".to_owned())
+    });
+    thread::sleep(Duration::from_millis(100));
+    writer.set("fast".to_owned(), "value".to_owned())?;
+    slow.join().unwrap()?;
+    eventually("the writes", || {
+        Ok(has(&mut reader, "slow", Some("value"))? && has(&mut reader, "fast", Some("value"))?)
+    });
+    let written = leader_engine.written.lock().unwrap().clone();
+    assert_eq!(*follower_engine.written.lock().unwrap(), written);
+    Ok(())
+}
+
+// Writes applied while a follower receives its snapshot do not count in its
+// backlog, however many they are
+#[test]
+fn backlog_after_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = start(
+        Server::new(KvStore::open(temp_dir.path())?)
+            .leader()
+            .follower_backlog(10),
+    );
+    let mut writer = KvsClient::connect(leader)?;
+    let value = "v".repeat(1000);
+    for i in 0..5000 {
+        writer.set(format!("key{}", i), value.clone())?;
+    }
+
+    // Not reading yet stalls the snapshot in the socket buffers
+    let mut follower = TcpStream::connect(leader)?;
+    follower.set_read_timeout(Some(Duration::from_secs(10)))?;
+    follower.write_all(b"\"Replicate\"")?;
+    thread::sleep(Duration::from_millis(200));
+    for i in 0..100 {
+        writer.set("during".to_owned(), i.to_string())?;
+    }
+
+    let mut messages = Deserializer::from_reader(BufReader::new(follower.try_clone()?))
+        .into_iter::<serde_json::Value>();
+    let mut next = || messages.next().unwrap().unwrap();
+    assert_eq!(next(), json!("SnapshotStart"));
+    while next() != json!("SnapshotEnd") {}
+    for _ in 0..100 {
+        assert!(next().get("Command").is_some());
+    }
+    writer.set("after".to_owned(), "value".to_owned())?;
+    assert!(next().to_string().contains("after"));
+    Ok(())
+}
+
+// Followers serve reads and refuse writes, leaders refuse writes to namespaces
+#[test]
+fn read_only_follower() -> Result<()> {
+    let leader = start(Server::new(MemoryKvsEngine::new()).leader());
+    let follower = start(Server::new(MemoryKvsEngine::new()).follow(leader));
+    KvsClient::connect(leader)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut client = KvsClient::connect(follower)?;
+    eventually("the write", || has(&mut client, "key1", Some("value1")));
+    let err = client
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap_err();
+    assert!(err.to_string().contains("Read-only follower"), "{}", err);
+    assert!(client.remove("key1".to_owned()).is_err());
+    assert_eq!(client.info()?.role, Some(format!("follower of {}", leader)));
+
+    let mut tenant = KvsClient::connect(leader)?;
+    tenant.select("tenant");
+    let err = tenant
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(err.to_string().contains("not replicated"), "{}", err);
+    assert_eq!(
+        KvsClient::connect(leader)?.info()?.role,
+        Some("leader".to_owned())
+    );
+    Ok(())
+}
+
+// Keys a follower holds which its leader does not are removed on sync
+#[test]
+fn stale_keys_removed() -> Result<()> {
+    let leader = start(Server::new(MemoryKvsEngine::new()).leader());
+    KvsClient::connect(leader)?.set("kept".to_owned(), "leader".to_owned())?;
+
+    let mut engine = MemoryKvsEngine::new();
+    engine.set("kept".to_owned(), "follower".to_owned())?;
+    engine.set("stale".to_owned(), "follower".to_owned())?;
+    let follower = start(Server::new(engine).follow(leader));
+    let mut reader = KvsClient::connect(follower)?;
+    eventually("the sync", || has(&mut reader, "stale", None));
+    assert_eq!(reader.get("kept".to_owned())?, Some("leader".to_owned()));
+    Ok(())
+}
+
+// A promoted follower accepts writes and followers, and no longer replicates
+#[test]
+fn promotion() -> Result<()> {
+    let leader = start(Server::new(MemoryKvsEngine::new()).leader());
+    let follower = start(Server::new(MemoryKvsEngine::new()).follow(leader));
+    let mut old_leader = KvsClient::connect(leader)?;
+    old_leader.set("key1".to_owned(), "value1".to_owned())?;
+    let mut client = KvsClient::connect(follower)?;
+    eventually("the write", || has(&mut client, "key1", Some("value1")));
+
+    client.promote()?;
+    assert!(client.promote().is_err());
+    assert_eq!(client.info()?.role, Some("leader".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    old_leader.set("key3".to_owned(), "value3".to_owned())?;
+
+    let second = start(Server::new(MemoryKvsEngine::new()).follow(follower));
+    let mut reader = KvsClient::connect(second)?;
+    eventually("the new leader", || {
+        has(&mut reader, "key2", Some("value2"))
+    });
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key3".to_owned())?, None);
+    assert_eq!(reader.get("key3".to_owned())?, None);
+
+    let standalone = start(Server::new(MemoryKvsEngine::new()));
+    assert!(KvsClient::connect(standalone)?.promote().is_err());
+    Ok(())
+}
+
+// A follower retries until its leader is up
+#[test]
+fn follower_retries() -> Result<()> {
+    let reserved = TcpListener::bind("127.0.0.1:0").unwrap();
+    let leader_addr = reserved.local_addr().unwrap();
+    drop(reserved);
+    let follower = start(Server::new(MemoryKvsEngine::new()).follow(leader_addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let leader = Server::new(MemoryKvsEngine::new()).leader();
+    let listener = TcpListener::bind(leader_addr).unwrap();
+    thread::spawn(move || leader.serve(listener));
+    KvsClient::connect(leader_addr)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut reader = KvsClient::connect(follower)?;
+    eventually("the leader", || has(&mut reader, "key1", Some("value1")));
+    Ok(())
+}
+
+// Replicating from a leader with an ACL needs the admin right
+#[test]
+fn replication_rights() -> Result<()> {
+    let (writer_token, ops_token) = (generate_token(), generate_token());
+    let credentials = json!({
+        "users": [
+            { "name": "writer", "token": hash_token(&writer_token)?, "grants": { "": "write" } },
+            { "name": "ops", "token": hash_token(&ops_token)?, "grants": { "": "admin" } }
+        ]
+    });
+    let leader = start(
+        Server::new(MemoryKvsEngine::new())
+            .leader()
+            .auth(Acl::from_json(&credentials.to_string())?),
+    );
+    let mut writer = KvsClient::connect(leader)?;
+    writer.authenticate(Credentials::token(writer_token.as_str()))?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    let refused = start(
+        Server::new(MemoryKvsEngine::new())
+            .follow(leader)
+            .leader_credentials(Credentials::token(writer_token.as_str())),
+    );
+    let allowed = start(
+        Server::new(MemoryKvsEngine::new())
+            .follow(leader)
+            .leader_credentials(Credentials::token(ops_token.as_str())),
+    );
+    let mut reader = KvsClient::connect(allowed)?;
+    eventually("the snapshot", || has(&mut reader, "key1", Some("value1")));
+    assert_eq!(KvsClient::connect(refused)?.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// kvs-server --leader and --follow, kvs-client admin promote
+#[test]
+fn cli_replication() {
+    let leader = "127.0.0.1:4022";
+    let follower = "127.0.0.1:4023";
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut children = vec![
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", leader, "--leader"])
+            .arg("--data-dir")
+            .arg(leader_dir.path())
+            .current_dir(&leader_dir)
+            .spawn()
+            .unwrap(),
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", follower, "--follow", leader])
+            .arg("--data-dir")
+            .arg(follower_dir.path())
+            .current_dir(&follower_dir)
+            .spawn()
+            .unwrap(),
+    ];
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        for child in &mut children {
+            child.kill().expect("server exited before killed");
+            child.wait().expect("failed to wait on server");
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |addr: &str, args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", addr])
+            .current_dir(&leader_dir);
+        cmd
+    };
+    client(leader, &["set", "key1", "value1"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+    client(follower, &["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    client(follower, &["set", "key2", "value2"])
+        .assert()
+        .failure()
+        .stderr(contains("Read-only follower of 127.0.0.1:4022"));
+    client(follower, &["admin", "info"])
+        .assert()
+        .success()
+        .stdout(contains("role: follower of 127.0.0.1:4022"));
+    client(follower, &["admin", "promote"]).assert().success();
+    client(follower, &["set", "key2", "value2"])
+        .assert()
+        .success();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// The follower should log in with the token of a file, kept off the command line
+#[test]
+fn cli_leader_token_file() -> Result<()> {
+    let leader = "127.0.0.1:4027";
+    let follower = "127.0.0.1:4028";
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let ops_token = generate_token();
+    let credentials = json!({
+        "users": [
+            { "name": "ops", "token": hash_token(&ops_token)?, "grants": { "": "admin" } }
+        ]
+    });
+    let credentials_file = leader_dir.path().join("credentials.json");
+    fs::write(&credentials_file, credentials.to_string())?;
+    let token_file = follower_dir.path().join("leader-token");
+    fs::write(&token_file, format!("{}\n", ops_token))?;
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut children = vec![
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", leader, "--leader", "--credentials"])
+            .arg(&credentials_file)
+            .arg("--data-dir")
+            .arg(leader_dir.path())
+            .current_dir(&leader_dir)
+            .spawn()
+            .unwrap(),
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args([
+                "--addr",
+                follower,
+                "--follow",
+                leader,
+                "--leader-token-file",
+            ])
+            .arg(&token_file)
+            .arg("--data-dir")
+            .arg(follower_dir.path())
+            .current_dir(&follower_dir)
+            .spawn()
+            .unwrap(),
+    ];
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        for child in &mut children {
+            child.kill().expect("server exited before killed");
+            child.wait().expect("failed to wait on server");
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut writer = KvsClient::connect(leader)?;
+    writer.authenticate(Credentials::token(ops_token.as_str()))?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+    let mut reader = KvsClient::connect(follower)?;
+    eventually("the write", || has(&mut reader, "key1", Some("value1")));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use kvs::{key_range, KvStore, KvStoreOptions, KvsEngine, KvsSnapshot, Result, SledKvsEngine};
+use tempfile::TempDir;
+
+fn point_in_time_reads<E: KvsEngine>(mut engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut snapshot = engine.snapshot()?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key4".to_owned())?, None);
+    let entries = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec())
+        ]
+    );
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A snapshot should not see writes made after it was taken
+#[test]
+fn kvs_point_in_time_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    point_in_time_reads(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_point_in_time_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    point_in_time_reads(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Writes made while a sled snapshot is being scanned should not show in it,
+// nor in an older one
+#[test]
+fn sled_writes_during_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = SledKvsEngine::open(temp_dir.path())?;
+    let expected: Vec<_> = (0..100)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+    for (key, value) in &expected {
+        store.set(key.clone(), value.clone())?;
+    }
+    let as_bytes = |pairs: &[(String, String)]| -> Vec<(Vec<u8>, Vec<u8>)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.clone().into_bytes(), value.clone().into_bytes()))
+            .collect()
+    };
+
+    let mut first = store.snapshot()?;
+    let mut scan = first.iter();
+    let mut entries = scan.by_ref().take(10).collect::<Result<Vec<_>>>()?;
+    for i in (0..100).step_by(3) {
+        store.remove(format!("key{:03}", i))?;
+    }
+    store.set("key050a".to_owned(), "new".to_owned())?;
+    let mut second = store.snapshot()?;
+    for i in (1..100).step_by(3) {
+        store.set(format!("key{:03}", i), "updated".to_owned())?;
+    }
+    store.set_batch(vec![(b"key099".to_vec(), b"batch".to_vec())])?;
+    entries.extend(scan.collect::<Result<Vec<_>>>()?);
+    assert_eq!(entries, as_bytes(&expected));
+    drop(first);
+
+    assert_eq!(second.get("key000".to_owned())?, None);
+    assert_eq!(second.get("key001".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(second.get("key050a".to_owned())?, Some("new".to_owned()));
+    let range = key_range(b"key048".to_vec()..b"key052".to_vec());
+    let keys = second
+        .scan(range)
+        .map(|entry| entry.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["key049", "key050", "key050a"]);
+    Ok(())
+}
+
+// Should only return the keys in the range, in order
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut snapshot = store.snapshot()?;
+    let keys = snapshot
+        .scan(key_range(b"key3".to_vec()..b"key6".to_vec()))
+        .map(|entry| entry.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["key3", "key4", "key5"]);
+    Ok(())
+}
+
+// Compaction should go on while a snapshot lives, without reclaiming the
+// records it reads
+fn snapshot_survives_compaction(options: KvStoreOptions) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+
+    let mut snapshot = store.snapshot()?;
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert!(store.stats()?.compactions > Some(0));
+    for key_id in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some("old".to_owned())
+        );
+    }
+    drop(snapshot);
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("new".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn mmap_snapshot_survives_compaction() -> Result<()> {
+    snapshot_survives_compaction(KvStoreOptions::default())
+}
+
+#[test]
+fn pread_snapshot_survives_compaction() -> Result<()> {
+    snapshot_survives_compaction(KvStoreOptions::default().mmap(false))
+}
@@ -0,0 +1,230 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, MemoryKvsEngine, Result, Server, TlsClientConfig, TlsServerConfig};
+use predicates::str::contains;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Self-signed CA with a server and a client certificate, written as PEM files.
+struct Pki {
+    dir: TempDir,
+}
+
+impl Pki {
+    fn new() -> Pki {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let (ca, ca_key) = Pki::ca("kvs test CA");
+        fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+        Pki::leaf(&dir, "server", &["localhost", "127.0.0.1"], &ca, &ca_key);
+        Pki::leaf(&dir, "client", &["client"], &ca, &ca_key);
+
+        let (other_ca, other_key) = Pki::ca("untrusted CA");
+        fs::write(dir.path().join("other-ca.pem"), other_ca.pem()).unwrap();
+        Pki::leaf(&dir, "intruder", &["client"], &other_ca, &other_key);
+        Pki { dir }
+    }
+
+    fn ca(name: &str) -> (Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    fn leaf(dir: &TempDir, name: &str, sans: &[&str], ca: &Certificate, ca_key: &KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let sans = sans.iter().map(|san| san.to_string()).collect::<Vec<_>>();
+        let cert = CertificateParams::new(sans)
+            .unwrap()
+            .signed_by(&key, ca, ca_key)
+            .unwrap();
+        fs::write(dir.path().join(format!("{}.pem", name)), cert.pem()).unwrap();
+        fs::write(
+            dir.path().join(format!("{}.key", name)),
+            key.serialize_pem(),
+        )
+        .unwrap();
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn server_config(&self) -> TlsServerConfig {
+        TlsServerConfig::from_pem_files(self.path("server.pem"), self.path("server.key")).unwrap()
+    }
+
+    fn client_config(&self) -> TlsClientConfig {
+        TlsClientConfig::from_ca_pem_file(self.path("ca.pem")).unwrap()
+    }
+}
+
+/// Starts a server on a free port, left running until the test exits.
+fn start_server(tls: TlsServerConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(MemoryKvsEngine::new()).tls(tls).unwrap();
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+// Requests should round-trip over TLS, the server being verified by its CA
+#[test]
+fn requests_over_tls() -> Result<()> {
+    let pki = Pki::new();
+    let addr = start_server(pki.server_config());
+
+    let mut client = KvsClient::connect_tls(addr, &pki.client_config())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    let config = pki.client_config().server_name("localhost");
+    let mut client = KvsClient::connect_tls(addr, &config)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// The client should refuse servers its CA did not sign, or for another name
+#[test]
+fn untrusted_server_is_refused() {
+    let pki = Pki::new();
+    let addr = start_server(pki.server_config());
+
+    let untrusted = TlsClientConfig::from_ca_pem_file(pki.path("other-ca.pem")).unwrap();
+    assert!(KvsClient::connect_tls(addr, &untrusted).is_err());
+    let wrong_name = pki.client_config().server_name("kvs.example.com");
+    assert!(KvsClient::connect_tls(addr, &wrong_name).is_err());
+    assert!(KvsClient::connect(addr)
+        .and_then(|mut client| client.get("key1".to_owned()))
+        .is_err());
+}
+
+// With mutual TLS, only clients with a certificate of the client CA get in
+#[test]
+fn mutual_tls() -> Result<()> {
+    let pki = Pki::new();
+    let tls = pki.server_config().client_ca(pki.path("ca.pem"))?;
+    let addr = start_server(tls);
+
+    let config = pki
+        .client_config()
+        .client_cert(pki.path("client.pem"), pki.path("client.key"))?;
+    let mut client = KvsClient::connect_tls(addr, &config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let anonymous = KvsClient::connect_tls(addr, &pki.client_config())
+        .and_then(|mut client| client.get("key1".to_owned()));
+    assert!(anonymous.is_err());
+
+    let intruder = pki
+        .client_config()
+        .client_cert(pki.path("intruder.pem"), pki.path("intruder.key"))?;
+    let intruder = KvsClient::connect_tls(addr, &intruder)
+        .and_then(|mut client| client.get("key1".to_owned()));
+    assert!(intruder.is_err());
+    Ok(())
+}
+
+// A follower should replicate a leader requiring mutual TLS
+#[test]
+fn replication_over_tls() -> Result<()> {
+    let pki = Pki::new();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let leader = listener.local_addr().unwrap();
+    let tls = pki.server_config().client_ca(pki.path("ca.pem"))?;
+    let server = Server::new(MemoryKvsEngine::new()).tls(tls)?.leader();
+    thread::spawn(move || server.serve(listener));
+
+    let config = pki
+        .client_config()
+        .client_cert(pki.path("client.pem"), pki.path("client.key"))?;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let follower = listener.local_addr().unwrap();
+    let server = Server::new(MemoryKvsEngine::new())
+        .follow(leader)
+        .leader_tls(config);
+    thread::spawn(move || server.serve(listener));
+
+    let config = pki
+        .client_config()
+        .client_cert(pki.path("client.pem"), pki.path("client.key"))?;
+    let mut writer = KvsClient::connect_tls(leader, &config)?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+    let mut reader = KvsClient::connect(follower)?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while reader.get("key1".to_owned())?.is_none() {
+        assert!(Instant::now() < deadline, "timed out waiting for the write");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The binaries should take the certificates from the command line
+#[test]
+fn cli_over_tls() {
+    let pki = Pki::new();
+    let addr = "127.0.0.1:4011";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .arg("--tls-cert")
+        .arg(pki.path("server.pem"))
+        .arg("--tls-key")
+        .arg(pki.path("server.key"))
+        .arg("--tls-client-ca")
+        .arg(pki.path("ca.pem"))
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", addr])
+            .arg("--tls-ca")
+            .arg(pki.path("ca.pem"))
+            .arg("--tls-cert")
+            .arg(pki.path("client.pem"))
+            .arg("--tls-key")
+            .arg(pki.path("client.key"))
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .arg("--tls-ca")
+        .arg(pki.path("ca.pem"))
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
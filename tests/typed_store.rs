@@ -0,0 +1,103 @@
+use kvs::{
+    Bincode, Codec, Json, KvStore, KvsClient, KvsEngine, MessagePack, MyError, Result, Server,
+    TypedStore,
+};
+use serde::{Deserialize, Serialize};
+use std::net::TcpListener;
+use std::thread;
+use tempfile::TempDir;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct Document {
+    id: u64,
+    title: String,
+    tags: Vec<String>,
+    parent: Option<u64>,
+}
+
+fn document() -> Document {
+    Document {
+        id: 42,
+        title: "Report".to_owned(),
+        tags: vec!["a".to_owned(), "b".to_owned()],
+        parent: None,
+    }
+}
+
+fn round_trip<C: Codec>(codec: C) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = TypedStore::with_codec(KvStore::open(temp_dir.path())?, codec);
+
+    store.set("doc1", &document())?;
+    assert_eq!(store.get("doc1")?, Some(document()));
+    assert_eq!(store.get("doc2")?, None);
+    Ok(())
+}
+
+// Should round-trip structs with every codec
+#[test]
+fn round_trip_every_codec() -> Result<()> {
+    round_trip(Json)?;
+    round_trip(Bincode)?;
+    round_trip(MessagePack)
+}
+
+// Should keep values after reopening the engine
+#[test]
+fn typed_value_persists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = TypedStore::with_codec(KvStore::open(temp_dir.path())?, MessagePack);
+    store.set("doc1", &document())?;
+    drop(store);
+
+    let mut store: TypedStore<_, Document, _> =
+        TypedStore::with_codec(KvStore::open(temp_dir.path())?, MessagePack);
+    assert_eq!(store.get("doc1")?, Some(document()));
+    store.remove("doc1")?;
+    assert_eq!(store.get("doc1")?, None);
+    Ok(())
+}
+
+// Reading a value with another codec should return a codec error
+#[test]
+fn codec_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = KvStore::open(temp_dir.path())?;
+    engine.set("doc1".to_owned(), "not a document".to_owned())?;
+
+    let mut store: TypedStore<_, Document, _> = TypedStore::with_codec(engine, Bincode);
+    match store.get("doc1") {
+        Err(MyError::Codec(_)) => Ok(()),
+        other => panic!("expected a codec error, got {:?}", other),
+    }
+}
+
+// Values set with a codec through a server are read back, by clients and on
+// the server's engine
+#[test]
+fn client_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = Server::new(engine.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set_typed("json", &document(), &Json)?;
+    client.set_typed("bincode", &document(), &Bincode)?;
+    client.set_typed("msgpack", &document(), &MessagePack)?;
+    let mut other = KvsClient::connect(addr)?;
+    assert_eq!(other.get_typed("json", &Json)?, Some(document()));
+    assert_eq!(other.get_typed("bincode", &Bincode)?, Some(document()));
+    assert_eq!(other.get_typed("msgpack", &MessagePack)?, Some(document()));
+    assert_eq!(other.get_typed::<Document, _>("missing", &Json)?, None);
+    assert!(matches!(
+        other.get_typed::<Document, _>("json", &Bincode),
+        Err(MyError::Codec(_))
+    ));
+
+    let mut store: TypedStore<_, Document, _> = TypedStore::with_codec(engine, Bincode);
+    assert_eq!(store.get("bincode")?, Some(document()));
+    Ok(())
+}